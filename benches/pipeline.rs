@@ -0,0 +1,73 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kvd::engine::memory::MemoryEngine;
+use kvd::server::Server;
+use std::io::{BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+const PORT: u16 = 24601;
+const REPLY: &[u8] = b"+OK\r\n";
+
+fn start_server() {
+    thread::spawn(|| {
+        let server = Server::new(MemoryEngine::new(), PORT).unwrap();
+        server.serve_net().unwrap();
+    });
+    // wait until the listener is ready
+    for _ in 0..100 {
+        if TcpStream::connect(("127.0.0.1", PORT)).is_ok() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("server is not started");
+}
+
+fn connect() -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(("127.0.0.1", PORT)).unwrap();
+    stream.set_nodelay(true).unwrap();
+    let reader = BufReader::new(stream.try_clone().unwrap());
+    (stream, reader)
+}
+
+/// send one request and wait for its reply before sending the next one
+fn one_by_one(stream: &mut TcpStream, reader: &mut BufReader<TcpStream>, n: usize) {
+    let mut reply = vec![0; REPLY.len()];
+    for i in 0..n {
+        let request = format!("set key{} value{}\r\n", i, i);
+        stream.write_all(request.as_bytes()).unwrap();
+        reader.read_exact(&mut reply).unwrap();
+    }
+}
+
+/// send all the requests at once and then read all the replies
+fn pipelined(stream: &mut TcpStream, reader: &mut BufReader<TcpStream>, n: usize) {
+    let mut requests = Vec::new();
+    for i in 0..n {
+        write!(requests, "set key{} value{}\r\n", i, i).unwrap();
+    }
+    stream.write_all(&requests).unwrap();
+    let mut replies = vec![0; REPLY.len() * n];
+    reader.read_exact(&mut replies).unwrap();
+}
+
+fn pipeline_bench(c: &mut Criterion) {
+    start_server();
+    let mut group = c.benchmark_group("set");
+    for n in [10, 100, 1000].iter() {
+        group.throughput(Throughput::Elements(*n as u64));
+        group.bench_with_input(BenchmarkId::new("one_by_one", n), n, |b, n| {
+            let (mut stream, mut reader) = connect();
+            b.iter(|| one_by_one(&mut stream, &mut reader, *n));
+        });
+        group.bench_with_input(BenchmarkId::new("pipelined", n), n, |b, n| {
+            let (mut stream, mut reader) = connect();
+            b.iter(|| pipelined(&mut stream, &mut reader, *n));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, pipeline_bench);
+criterion_main!(benches);
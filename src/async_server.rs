@@ -0,0 +1,579 @@
+//! An alternative server built on the tokio event loop.
+//!
+//! Connections are multiplexed on a few reactor threads, so an idle
+//! connection costs a socket instead of a thread. Requests go through the
+//! same dispatch as `Server`, but every batch of requests is executed on the
+//! blocking thread pool, since the engine may block on disk I/O and must not
+//! stall the reactor. A client blocked by BLPOP or BRPOP is parked until it
+//! is woken, so it does not hold a thread of the pool while it waits.
+
+use crate::engine::KvdEngine;
+use crate::model::{KvdError, KvdErrorKind, KvdResult, Request};
+use crate::protocol::{Reply, RequestBuffer, READ_CHUNK_SIZE};
+use crate::server::blocking::BlockedPop;
+use crate::server::clients::Client;
+use crate::server::monitor::{MonitorGuard, MONITOR_BUFFER_SIZE};
+use crate::server::{is_timeout, Server, KILL_POLL_INTERVAL};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime;
+use tokio::sync::{mpsc, Notify};
+use tokio::task;
+use tokio::time;
+
+pub struct AsyncServer<T: KvdEngine> {
+    server: Server<T>,
+}
+
+impl<T: KvdEngine> AsyncServer<T> {
+    pub fn new(server: Server<T>) -> AsyncServer<T> {
+        AsyncServer { server }
+    }
+
+    /// Start a tokio runtime and serve on the port of the server.
+    pub fn serve_net(&self) -> KvdResult<()> {
+        self.server.spawn_metrics_listener()?;
+        self.server.spawn_replication();
+        self.server.spawn_raft()?;
+        let runtime = runtime::Builder::new_multi_thread().enable_all().build()?;
+        runtime.block_on(async {
+            let listener = TcpListener::bind(("0.0.0.0", self.server.port())).await?;
+            self.serve_listener(listener).await
+        })
+    }
+
+    /// Serve the connections accepted by the listener, must be called in a tokio runtime.
+    pub async fn serve_listener(&self, listener: TcpListener) -> KvdResult<()> {
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    // a killed client is woken up by the notify and closes itself
+                    let kill_notify = Arc::new(Notify::new());
+                    let notify = kill_notify.clone();
+                    let closer = Box::new(move || notify.notify_one());
+                    let guard = match self.server.register_client(addr.to_string(), Some(closer)) {
+                        Some(guard) => guard,
+                        None => {
+                            tokio::spawn(reject_conn(stream));
+                            continue;
+                        }
+                    };
+                    let server = self.server.clone();
+                    tokio::spawn(async move {
+                        let client = guard.client().clone();
+                        tokio::select! {
+                            result = handle_conn(server, client, stream) => {
+                                if let Err(e) = result {
+                                    warn!(guard.client().logger(), "handle connection error"; "error" => %e);
+                                }
+                            }
+                            _ = kill_notify.notified() => {}
+                        }
+                    });
+                }
+                Err(e) => warn!(self.server.config().logger, "accept stream error"; "error" => %e),
+            }
+        }
+    }
+}
+
+async fn handle_conn<T: KvdEngine>(
+    server: Server<T>,
+    client: Arc<Client>,
+    mut stream: TcpStream,
+) -> KvdResult<()> {
+    stream.set_nodelay(true)?;
+    client.set_nonblocking();
+    let config = server.config().clone();
+    let _subscriber = server.pubsub().guard(&client);
+    // woken up when the output of a subscriber is queued
+    let notify = Arc::new(Notify::new());
+    let mut waker_set = false;
+    let mut buffer = RequestBuffer::with_max_request_size(config.max_request_size);
+    let mut data = vec![0; READ_CHUNK_SIZE];
+    let mut last_active = Instant::now();
+    loop {
+        let read = tokio::select! {
+            read = with_timeout(config.read_timeout(), stream.read(&mut data)) => read,
+            _ = notify.notified(), if waker_set => {
+                push_output(&server, &client, &mut stream).await?;
+                continue;
+            }
+        };
+        match read {
+            Ok(0) => return Ok(()),
+            Ok(n) => {
+                server.record_bytes_in(&client, n);
+                last_active = Instant::now();
+                buffer.extend(&data[..n]);
+            }
+            Err(ref e) if is_timeout(e) => {
+                // a subscriber waiting for messages is not idle
+                let waiting = client.subscriptions() > 0 && buffer.is_empty();
+                if config.is_expired(&buffer, last_active) && !waiting {
+                    return Ok(());
+                }
+                continue;
+            }
+            Err(e) => return Err(KvdError::from(e)),
+        }
+
+        let mut requests = VecDeque::new();
+        let mut parse_error = None;
+        loop {
+            match buffer.next_request() {
+                Ok(Some(request)) => requests.push_back(request),
+                Ok(None) => break,
+                Err(e) => {
+                    parse_error = Some(e);
+                    break;
+                }
+            }
+        }
+
+        let mut output = execute_requests(&server, &client, requests, &mut stream).await?;
+
+        let closing = parse_error.is_some();
+        if let Some(e) = parse_error {
+            // the rest of the stream can not be parsed, give up the connection
+            server.write_reply(&client, Reply::from(e), &mut output)?;
+        }
+        if !waker_set && client.is_subscriber() {
+            let notify = notify.clone();
+            server
+                .pubsub()
+                .set_waker(&client, Box::new(move || notify.notify_one()));
+            waker_set = true;
+        }
+        // a monitor is subscribed before the reply is sent, so it does not
+        // miss the commands sent by the others right after the reply
+        let monitor = if client.take_monitor_request() {
+            Some(subscribe_monitor(&server, &client))
+        } else {
+            None
+        };
+        let changes = client.take_changes_request();
+        if !output.is_empty() {
+            server.record_bytes_out(&client, output.len());
+            with_timeout(config.io_timeout, stream.write_all(&output)).await?;
+            // a client blocked by a command is not idle, its idle time
+            // starts again when the reply is sent
+            last_active = Instant::now();
+        }
+        push_output(&server, &client, &mut stream).await?;
+        if closing || client.is_killed() {
+            return Ok(());
+        }
+        if let Some((_guard, receiver)) = monitor {
+            return serve_monitor(&server, &client, receiver, &mut stream).await;
+        }
+        if let Some(seq) = changes {
+            return serve_changes(&server, &client, seq, &mut stream).await;
+        }
+    }
+}
+
+/// Execute the requests in turn on the blocking thread pool, and wait for
+/// a BLPOP or BRPOP parked among them before the requests after it.
+async fn execute_requests<T: KvdEngine>(
+    server: &Server<T>,
+    client: &Arc<Client>,
+    mut requests: VecDeque<Request>,
+    stream: &mut TcpStream,
+) -> KvdResult<Vec<u8>> {
+    let mut output = Vec::new();
+    while !requests.is_empty() {
+        let (rest, executed, blocked) = task::spawn_blocking({
+            let server = server.clone();
+            let client = client.clone();
+            move || -> io::Result<_> {
+                // the replies are written in turn, since the client may
+                // subscribe in the middle of the batch
+                let mut output = Vec::new();
+                while let Some(request) = requests.pop_front() {
+                    let reply = server.handle_request(&client, request);
+                    if let Some(pop) = client.take_blocked_pop() {
+                        return Ok((requests, output, Some(pop)));
+                    }
+                    server.write_reply(&client, reply, &mut output)?;
+                }
+                Ok((requests, output, None))
+            }
+        })
+        .await
+        .map_err(io::Error::from)??;
+        requests = rest;
+        output.extend(executed);
+        if let Some(pop) = blocked {
+            // the replies before the blocked request are sent while it waits
+            if !output.is_empty() {
+                server.record_bytes_out(client, output.len());
+                with_timeout(server.config().io_timeout, stream.write_all(&output)).await?;
+                output.clear();
+            }
+            let reply = wait_blocked_pop(server, client, pop).await?;
+            server.write_reply(client, reply, &mut output)?;
+        }
+    }
+    Ok(output)
+}
+
+/// Wait until the parked BLPOP or BRPOP is woken or its deadline is reached,
+/// then pop again on the blocking thread pool, until it is replied. The
+/// waiter is unblocked if the connection is closed meanwhile.
+async fn wait_blocked_pop<T: KvdEngine>(
+    server: &Server<T>,
+    client: &Arc<Client>,
+    mut pop: BlockedPop,
+) -> KvdResult<Reply> {
+    loop {
+        let notify = Arc::new(Notify::new());
+        let waker = notify.clone();
+        pop.waiter().set_waker(Box::new(move || waker.notify_one()));
+        match pop.deadline {
+            Some(deadline) => {
+                let deadline = time::Instant::from_std(deadline);
+                let _ = time::timeout_at(deadline, notify.notified()).await;
+            }
+            None => notify.notified().await,
+        }
+        let (reply, parked) = task::spawn_blocking({
+            let server = server.clone();
+            let client = client.clone();
+            move || {
+                let reply = server.resume_blocking_pop(&client, pop);
+                (reply, client.take_blocked_pop())
+            }
+        })
+        .await
+        .map_err(io::Error::from)?;
+        if let Some(reply) = reply {
+            return Ok(reply);
+        }
+        // another client took the element, so the pop is parked again
+        pop = parked.expect("a pop not replied is parked again");
+    }
+}
+
+/// Write the output queued for a subscriber.
+async fn push_output<T: KvdEngine>(
+    server: &Server<T>,
+    client: &Arc<Client>,
+    stream: &mut TcpStream,
+) -> io::Result<()> {
+    let output = server.pubsub().take_output(client).unwrap_or_default();
+    if !output.is_empty() {
+        server.record_bytes_out(client, output.len());
+        with_timeout(server.config().io_timeout, stream.write_all(&output)).await?;
+    }
+    Ok(())
+}
+
+fn subscribe_monitor<T: KvdEngine>(
+    server: &Server<T>,
+    client: &Arc<Client>,
+) -> (MonitorGuard, mpsc::Receiver<Vec<u8>>) {
+    let (sender, receiver) = mpsc::channel(MONITOR_BUFFER_SIZE);
+    let guard = server.monitors().subscribe(
+        client.clone(),
+        Box::new(move |line| sender.try_send(line).is_ok()),
+    );
+    (guard, receiver)
+}
+
+/// Write the commands of the other clients until the monitor is killed or too slow.
+async fn serve_monitor<T: KvdEngine>(
+    server: &Server<T>,
+    client: &Arc<Client>,
+    mut receiver: mpsc::Receiver<Vec<u8>>,
+    stream: &mut TcpStream,
+) -> KvdResult<()> {
+    // the receiver is closed when the monitor is dropped for being too slow
+    while let Some(mut output) = receiver.recv().await {
+        // the lines already queued are sent in one write
+        while let Ok(line) = receiver.try_recv() {
+            output.extend(line);
+        }
+        server.record_bytes_out(client, output.len());
+        with_timeout(server.config().io_timeout, stream.write_all(&output)).await?;
+    }
+    Ok(())
+}
+
+/// Stream the changes logged by the engine, which are read on the blocking
+/// thread pool, until the client is killed.
+async fn serve_changes<T: KvdEngine>(
+    server: &Server<T>,
+    client: &Arc<Client>,
+    mut seq: u64,
+    stream: &mut TcpStream,
+) -> KvdResult<()> {
+    loop {
+        let reader = server.clone();
+        let (more, next_seq, output) = task::spawn_blocking(move || {
+            let mut output = Vec::new();
+            let more = reader.next_changes(&mut seq, &mut output)?;
+            Ok::<_, KvdError>((more, seq, output))
+        })
+        .await
+        .map_err(io::Error::from)??;
+        seq = next_seq;
+        if output.is_empty() {
+            time::sleep(KILL_POLL_INTERVAL).await;
+            continue;
+        }
+        server.record_bytes_out(client, output.len());
+        with_timeout(server.config().io_timeout, stream.write_all(&output)).await?;
+        if !more {
+            return Ok(());
+        }
+    }
+}
+
+async fn reject_conn(mut stream: TcpStream) {
+    let reply = Reply::from(KvdError::from(KvdErrorKind::MaxClients));
+    let timeout = Some(Duration::from_secs(1));
+    let _ = with_timeout(timeout, stream.write_all(&reply.to_bytes())).await;
+}
+
+async fn with_timeout<T, F>(timeout: Option<Duration>, future: F) -> io::Result<T>
+where
+    F: Future<Output = io::Result<T>>,
+{
+    match timeout {
+        Some(timeout) => match time::timeout(timeout, future).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut)),
+        },
+        None => future.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::bitcask::BitcaskEngine;
+    use crate::engine::memory::MemoryEngine;
+    use crate::server::ServerConfig;
+    use std::io::{Read, Write};
+    use std::net::SocketAddr;
+
+    /// Serve on a runtime with few blocking threads, so that a connection
+    /// holding one while it waits stalls the others.
+    fn start_server<T: KvdEngine>(
+        engine: T,
+        config: ServerConfig,
+    ) -> (runtime::Runtime, SocketAddr) {
+        let runtime = runtime::Builder::new_multi_thread()
+            .max_blocking_threads(2)
+            .enable_all()
+            .build()
+            .unwrap();
+        let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ServerConfig {
+            port: addr.port(),
+            ..config
+        };
+        let server = AsyncServer::new(Server::with_config(engine, config).unwrap());
+        runtime.spawn(async move { server.serve_listener(listener).await });
+        (runtime, addr)
+    }
+
+    #[test]
+    fn test_serve_pipelined_requests() {
+        let (_runtime, addr) = start_server(MemoryEngine::new(), ServerConfig::default());
+
+        let mut clients = Vec::new();
+        for i in 0..10 {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            write!(stream, "set key{} {}\r\nget key{}\r\n", i, i, i).unwrap();
+            clients.push(stream);
+        }
+        for (i, stream) in clients.iter_mut().enumerate() {
+            let expect = format!("+OK\r\n$1\r\n{}\r\n", i);
+            let mut reply = vec![0; expect.len()];
+            stream.read_exact(&mut reply).unwrap();
+            assert_eq!(expect.as_bytes(), reply.as_slice());
+        }
+    }
+
+    #[test]
+    fn test_max_clients_and_idle_timeout() {
+        let config = ServerConfig {
+            max_clients: 1,
+            idle_timeout: Some(Duration::from_millis(200)),
+            ..ServerConfig::default()
+        };
+        let (_runtime, addr) = start_server(MemoryEngine::new(), config);
+
+        let mut first = std::net::TcpStream::connect(addr).unwrap();
+        first.write_all(b"get key\r\n").unwrap();
+        let mut reply = vec![0; 5];
+        first.read_exact(&mut reply).unwrap();
+        assert_eq!(b"$-1\r\n".to_vec(), reply);
+
+        let mut second = std::net::TcpStream::connect(addr).unwrap();
+        let mut reply = Vec::new();
+        second.read_to_end(&mut reply).unwrap();
+        assert_eq!(b"-ERR max number of clients reached\r\n".to_vec(), reply);
+
+        // the first client is closed since it is idle
+        let mut reply = Vec::new();
+        first.read_to_end(&mut reply).unwrap();
+        assert!(reply.is_empty());
+    }
+
+    #[test]
+    fn test_monitor() {
+        let (_runtime, addr) = start_server(MemoryEngine::new(), ServerConfig::default());
+
+        let mut monitor = std::net::TcpStream::connect(addr).unwrap();
+        monitor.write_all(b"monitor\r\n").unwrap();
+        let mut reply = vec![0; 5];
+        monitor.read_exact(&mut reply).unwrap();
+        assert_eq!(b"+OK\r\n".to_vec(), reply);
+
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client.write_all(b"set key value\r\n").unwrap();
+        let mut reply = vec![0; 5];
+        client.read_exact(&mut reply).unwrap();
+
+        let mut line = String::new();
+        std::io::BufRead::read_line(&mut std::io::BufReader::new(&monitor), &mut line).unwrap();
+        let expect = format!(
+            " [{}] \"set\" \"key\" \"value\"\r\n",
+            client.local_addr().unwrap()
+        );
+        assert!(
+            line.ends_with(&expect),
+            "{} does not end with {}",
+            line,
+            expect
+        );
+    }
+
+    #[test]
+    fn test_publish() {
+        let (_runtime, addr) = start_server(MemoryEngine::new(), ServerConfig::default());
+
+        // the reply before SUBSCRIBE in the same batch is written first
+        let mut subscriber = std::net::TcpStream::connect(addr).unwrap();
+        subscriber
+            .write_all(b"get key\r\nsubscribe news\r\n")
+            .unwrap();
+        let expect = b"$-1\r\n*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n";
+        let mut reply = vec![0; expect.len()];
+        subscriber.read_exact(&mut reply).unwrap();
+        assert_eq!(expect.to_vec(), reply);
+
+        let mut publisher = std::net::TcpStream::connect(addr).unwrap();
+        publisher.write_all(b"publish news hello\r\n").unwrap();
+        let mut reply = vec![0; 4];
+        publisher.read_exact(&mut reply).unwrap();
+        assert_eq!(b":1\r\n".to_vec(), reply);
+
+        let expect = b"*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$5\r\nhello\r\n";
+        let mut reply = vec![0; expect.len()];
+        subscriber.read_exact(&mut reply).unwrap();
+        assert_eq!(expect.to_vec(), reply);
+    }
+
+    #[test]
+    fn test_changes() {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let path = std::path::PathBuf::from(format!("/tmp/kvd_store/{}", nanos));
+        let engine = BitcaskEngine::open(path).unwrap();
+        let (_runtime, addr) = start_server(engine, ServerConfig::default());
+
+        let mut consumer = std::net::TcpStream::connect(addr).unwrap();
+        consumer.write_all(b"changes 0\r\n").unwrap();
+        let mut reply = vec![0; 5];
+        consumer.read_exact(&mut reply).unwrap();
+        assert_eq!(b"+OK\r\n".to_vec(), reply);
+
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client.write_all(b"set key value\r\n").unwrap();
+        let mut reply = vec![0; 5];
+        client.read_exact(&mut reply).unwrap();
+
+        let expect = "*5\r\n:1\r\n$3\r\nset\r\n$3\r\nkey\r\n$5\r\nvalue\r\n$-1\r\n";
+        let mut reply = vec![0; expect.len()];
+        consumer.read_exact(&mut reply).unwrap();
+        assert_eq!(expect, String::from_utf8(reply).unwrap());
+    }
+
+    #[test]
+    fn test_blocking_pop() {
+        let (_runtime, addr) = start_server(MemoryEngine::new(), ServerConfig::default());
+        // more clients are blocked than the blocking threads of the runtime
+        let mut blocked = Vec::new();
+        for _ in 0..4 {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            stream.write_all(b"blpop list 0\r\n").unwrap();
+            blocked.push(stream);
+        }
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut data = [0; 4096];
+        loop {
+            client.write_all(b"info clients\r\n").unwrap();
+            let n = client.read(&mut data).unwrap();
+            if String::from_utf8_lossy(&data[..n]).contains("blocked_clients:4\r\n") {
+                break;
+            }
+        }
+
+        client.write_all(b"rpush list a b c d\r\n").unwrap();
+        let mut reply = vec![0; 4];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(b":4\r\n".to_vec(), reply);
+        let mut elements = Vec::new();
+        for stream in blocked.iter_mut() {
+            let mut reply = vec![0; 21];
+            stream.read_exact(&mut reply).unwrap();
+            let reply = String::from_utf8(reply).unwrap();
+            assert!(reply.starts_with("*2\r\n$4\r\nlist\r\n$1\r\n"), "{}", reply);
+            elements.push(reply[18..19].to_string());
+        }
+        elements.sort();
+        assert_eq!(vec!["a", "b", "c", "d"], elements);
+
+        // the requests after a blocked one are executed once it is replied
+        client.write_all(b"blpop list 0.1\r\nget key\r\n").unwrap();
+        let mut reply = vec![0; 10];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(b"$-1\r\n$-1\r\n".to_vec(), reply);
+    }
+
+    #[test]
+    fn test_kill_client() {
+        let (_runtime, addr) = start_server(MemoryEngine::new(), ServerConfig::default());
+
+        let mut first = std::net::TcpStream::connect(addr).unwrap();
+        first.write_all(b"client id\r\n").unwrap();
+        let mut reply = vec![0; 4];
+        first.read_exact(&mut reply).unwrap();
+        assert_eq!(b":1\r\n".to_vec(), reply);
+
+        let mut second = std::net::TcpStream::connect(addr).unwrap();
+        second.write_all(b"client kill id 1\r\n").unwrap();
+        let mut reply = vec![0; 4];
+        second.read_exact(&mut reply).unwrap();
+        assert_eq!(b":1\r\n".to_vec(), reply);
+
+        let mut reply = Vec::new();
+        first.read_to_end(&mut reply).unwrap();
+        assert!(reply.is_empty());
+    }
+}
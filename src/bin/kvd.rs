@@ -5,18 +5,24 @@ extern crate slog_stdlog;
 extern crate log;
 
 use clap::{App, Arg};
-use config::Config;
+use config::{Config, ConfigError};
+#[cfg(feature = "async")]
+use kvd::async_server::AsyncServer;
+use kvd::cluster::{parse_nodes, ClusterConfig};
 use kvd::engine::bitcask::BitcaskEngine;
+use kvd::engine::memory::MemoryEngine;
+use kvd::engine::notify::{EventClasses, EventFilter};
 use kvd::engine::KvdEngine;
-use kvd::model::KvdResult;
-use kvd::server::Server;
-use slog::Drain;
-use slog_async;
-use slog_scope;
+use kvd::logging::{LevelFilter, LogLevel};
+use kvd::model::{KvdError, KvdErrorKind, KvdResult};
+use kvd::raft::{NodeId, RaftConfig, DEFAULT_SNAPSHOT_ENTRIES};
+use kvd::server::{Server, ServerConfig};
+use slog::{Drain, Logger};
 use slog_scope::GlobalLoggerGuard;
-use slog_term;
+use std::convert::TryFrom;
 use std::fs::OpenOptions;
 use std::path::PathBuf;
+use std::time::Duration;
 
 fn main() -> KvdResult<()> {
     let matches = App::new(env!("CARGO_PKG_NAME"))
@@ -36,40 +42,198 @@ fn main() -> KvdResult<()> {
     let mut settings = config::Config::default();
     settings.merge(config::File::with_name(config_path))?;
 
-    let _log_guard = init_logger(&settings)?;
+    let log_level = LogLevel::new(LogLevel::parse(&settings.get_str("log_level")?)?);
+    let (logger, _log_guard) = init_logger(&settings, &log_level)?;
 
-    let mut server = get_server(&settings)?;
+    let mut server_config = get_server_config(&settings)?;
+    server_config.config_file = Some(config_path.to_string());
+    server_config.logger = logger;
+    server_config.log_level = log_level;
+    if server_config.raft.is_some() {
+        // the keys are rebuilt from the raft log, which is kept in the wal_dir
+        let server = Server::with_config(MemoryEngine::new(), server_config)?;
+        return serve(&settings, server);
+    }
+    let server = get_server(&settings, server_config)?;
+    serve(&settings, server)
+}
+
+#[cfg(feature = "async")]
+fn serve<T: KvdEngine>(settings: &Config, server: Server<T>) -> KvdResult<()> {
+    if settings.get_bool("async_server").unwrap_or(false) {
+        info!("serving with the async server");
+        return AsyncServer::new(server).serve_net();
+    }
+    server.serve_net()
+}
+
+#[cfg(not(feature = "async"))]
+fn serve<T: KvdEngine>(_settings: &Config, server: Server<T>) -> KvdResult<()> {
     server.serve_net()
 }
 
-fn init_logger(settings: &Config) -> KvdResult<GlobalLoggerGuard> {
+/// the level of the logger can be changed later through the log_level
+fn init_logger(settings: &Config, log_level: &LogLevel) -> KvdResult<(Logger, GlobalLoggerGuard)> {
     let log_path = settings.get_str("log_path")?;
-    let log_level = settings.get_str("log_level")?; // TODO: unused
+    let log_format = settings
+        .get_str("log_format")
+        .unwrap_or_else(|_| "text".to_string());
 
     let file = OpenOptions::new()
         .create(true)
-        .write(true)
         .append(true)
         .open(log_path)?;
 
-    let decorator = slog_term::PlainDecorator::new(file);
-    let drain = slog_term::FullFormat::new(decorator).build().fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
+    let drain = match log_format.as_str() {
+        "text" => {
+            let decorator = slog_term::PlainDecorator::new(file);
+            let drain = slog_term::FullFormat::new(decorator).build().fuse();
+            slog_async::Async::new(drain).build()
+        }
+        "json" => {
+            let drain = slog_json::Json::new(file).add_default_keys().build().fuse();
+            slog_async::Async::new(drain).build()
+        }
+        _ => return Err(KvdError::from(KvdErrorKind::Config)),
+    };
+    let drain = LevelFilter::new(drain, log_level.clone()).fuse();
 
     let logger = slog::Logger::root(drain, o!());
-    let guard = slog_scope::set_global_logger(logger);
+    let guard = slog_scope::set_global_logger(logger.clone());
     slog_stdlog::init().unwrap();
 
     info!("standard logging redirected to slog");
 
-    Ok(guard)
+    Ok((logger, guard))
 }
 
 // TODO: 这里必须用impl KvdEngine, 否则编译报错. 原因?
-fn get_server(config: &Config) -> KvdResult<Server<impl KvdEngine>> {
+fn get_server(config: &Config, server_config: ServerConfig) -> KvdResult<Server<impl KvdEngine>> {
     let wal_dir = config.get_str("wal_dir")?;
-    let server_port = config.get_int("server_port")? as u16;
     let engine = BitcaskEngine::open(PathBuf::from(wal_dir))?;
-    let mut server = Server::new(engine, server_port)?;
+    let server = Server::with_config(engine, server_config)?;
     Ok(server)
 }
+
+fn get_server_config(config: &Config) -> KvdResult<ServerConfig> {
+    let mut server_config = ServerConfig {
+        port: to_config_num(config.get_int("server_port")?)?,
+        ..ServerConfig::default()
+    };
+    if let Some(max_clients) = get_optional_num(config, "max_clients")? {
+        server_config.max_clients = max_clients;
+    }
+    // 0 means the connections are never closed for being idle
+    if let Some(secs) = get_optional_num(config, "idle_timeout")? {
+        server_config.idle_timeout = Some(Duration::from_secs(secs)).filter(|_| secs > 0);
+    }
+    if let Some(secs) = get_optional_num(config, "io_timeout")? {
+        server_config.io_timeout = Some(Duration::from_secs(secs)).filter(|_| secs > 0);
+    }
+    if let Some(size) = get_optional_num(config, "max_request_size")? {
+        server_config.max_request_size = size;
+    }
+    // a negative value means no command is logged
+    if let Some(micros) = get_optional_int(config, "slowlog_log_slower_than")? {
+        server_config.slowlog_threshold = u64::try_from(micros).ok().map(Duration::from_micros);
+    }
+    if let Some(max_len) = get_optional_num(config, "slowlog_max_len")? {
+        server_config.slowlog_max_len = max_len;
+    }
+    if let Some(limit) = get_optional_num(config, "pubsub_buffer_limit")? {
+        server_config.pubsub_buffer_limit = limit;
+    }
+    // an empty string means no keyspace event is published
+    if let Some(flags) = get_optional_str(config, "notify_keyspace_events")? {
+        server_config.notify_keyspace_events = EventFilter::new(EventClasses::parse(&flags)?);
+    }
+    // an empty string means the server is a leader
+    if let Some(leader) = get_optional_str(config, "replicaof")? {
+        server_config.replica_of = Some(leader).filter(|leader| !leader.is_empty());
+    }
+    server_config.raft = get_raft_config(config)?;
+    server_config.cluster = get_cluster_config(config)?;
+    // 0 means the metrics endpoint is disabled
+    if let Some(port) = get_optional_num::<u16>(config, "metrics_port")? {
+        server_config.metrics_port = Some(port).filter(|_| port > 0);
+    }
+    Ok(server_config)
+}
+
+/// the raft config if raft_id is set, raft_nodes lists every node of the
+/// group like "1@host:port,2@host:port,3@host:port"
+fn get_raft_config(config: &Config) -> KvdResult<Option<RaftConfig>> {
+    let id = match get_optional_num::<NodeId>(config, "raft_id")? {
+        Some(id) if id > 0 => id,
+        _ => return Ok(None),
+    };
+    let nodes = config
+        .get_str("raft_nodes")?
+        .split(',')
+        .map(|node| {
+            let (id, addr) = node
+                .trim()
+                .split_once('@')
+                .ok_or_else(|| KvdError::from(KvdErrorKind::Config))?;
+            let id = id
+                .parse::<NodeId>()
+                .map_err(|_| KvdError::from(KvdErrorKind::Config))?;
+            Ok((id, addr.to_string()))
+        })
+        .collect::<KvdResult<Vec<(NodeId, String)>>>()?;
+    if !nodes.iter().any(|(node, _)| *node == id) {
+        return Err(KvdError::from(KvdErrorKind::Config));
+    }
+    let snapshot_entries =
+        get_optional_num(config, "raft_snapshot_entries")?.unwrap_or(DEFAULT_SNAPSHOT_ENTRIES);
+    Ok(Some(RaftConfig {
+        id,
+        nodes,
+        dir: PathBuf::from(config.get_str("wal_dir")?),
+        snapshot_entries,
+    }))
+}
+
+/// the cluster config if cluster_id is set, cluster_nodes lists every node
+/// of the cluster with its slots like "1@host:port 0-8191,2@host:port 8192-16383",
+/// and the slot map is saved to nodes.conf in the wal_dir when it changes
+fn get_cluster_config(config: &Config) -> KvdResult<Option<ClusterConfig>> {
+    let id = match get_optional_num::<NodeId>(config, "cluster_id")? {
+        Some(id) if id > 0 => id,
+        _ => return Ok(None),
+    };
+    let nodes = parse_nodes(&config.get_str("cluster_nodes")?)?;
+    Ok(Some(ClusterConfig {
+        id,
+        nodes,
+        nodes_file: Some(PathBuf::from(config.get_str("wal_dir")?).join("nodes.conf")),
+    }))
+}
+
+fn get_optional_int(config: &Config, key: &str) -> KvdResult<Option<i64>> {
+    match config.get_int(key) {
+        Ok(value) => Ok(Some(value)),
+        Err(ConfigError::NotFound(_)) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// the integer of the key if it is set, a value out of the range of N is a
+/// config error rather than wrapped around
+fn get_optional_num<N: TryFrom<i64>>(config: &Config, key: &str) -> KvdResult<Option<N>> {
+    get_optional_int(config, key)?
+        .map(to_config_num)
+        .transpose()
+}
+
+fn to_config_num<N: TryFrom<i64>>(value: i64) -> KvdResult<N> {
+    N::try_from(value).map_err(|_| KvdError::from(KvdErrorKind::Config))
+}
+
+fn get_optional_str(config: &Config, key: &str) -> KvdResult<Option<String>> {
+    match config.get_str(key) {
+        Ok(value) => Ok(Some(value)),
+        Err(ConfigError::NotFound(_)) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
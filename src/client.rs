@@ -0,0 +1,337 @@
+//! A blocking client of kvd, which sends the requests as RESP arrays and
+//! reads the replies over one connection.
+//!
+//! `ClusterClient` connects to the nodes of a cluster, it caches the slot
+//! map so that a request is sent to the node serving its keys directly.
+
+use crate::cluster::{command_keys, key_slot, parse_redirect, SLOTS};
+use crate::model::{KvdError, KvdErrorKind, KvdResult};
+use crate::protocol::{Reply, ReplyBuffer};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// the max number of redirects followed by a request
+const MAX_REDIRECTS: usize = 5;
+
+pub struct Connection {
+    stream: TcpStream,
+    buffer: ReplyBuffer,
+}
+
+impl Connection {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> KvdResult<Connection> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(Connection {
+            stream,
+            buffer: ReplyBuffer::new(),
+        })
+    }
+
+    /// the read timeout of a reply, None means never
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> KvdResult<()> {
+        self.stream.set_read_timeout(timeout)?;
+        Ok(())
+    }
+
+    /// send the request and read its reply
+    pub fn request(&mut self, args: &[&[u8]]) -> KvdResult<Reply> {
+        self.send(args)?;
+        self.read_reply()
+    }
+
+    /// send the request without reading its reply, e.g. to pipeline requests
+    pub fn send(&mut self, args: &[&[u8]]) -> KvdResult<()> {
+        // a request is encoded the same way as an array of bulk replies
+        let request = Reply::Array(args.iter().map(|arg| Reply::Bulk(arg.to_vec())).collect());
+        self.stream.write_all(&request.to_bytes())?;
+        Ok(())
+    }
+
+    /// read the next reply, which can also be one streamed by the server
+    pub fn read_reply(&mut self) -> KvdResult<Reply> {
+        loop {
+            if let Some(reply) = self.buffer.next_reply()? {
+                return Ok(reply);
+            }
+            if self.buffer.read_from(&mut self.stream)? == 0 {
+                // the connection is closed in the middle of a reply
+                return Err(KvdError::from(KvdErrorKind::Io));
+            }
+        }
+    }
+}
+
+/// A client of a cluster, which keeps a connection to every node it sends
+/// requests to.
+pub struct ClusterClient {
+    /// the addresses to load the slot map from besides the known nodes
+    seeds: Vec<String>,
+    /// the address of the node serving each slot
+    slots: Vec<Option<String>>,
+    connections: HashMap<String, Connection>,
+}
+
+impl ClusterClient {
+    /// Connect to the cluster and load its slot map from one of the seeds.
+    pub fn connect(seeds: &[&str]) -> KvdResult<ClusterClient> {
+        let mut client = ClusterClient {
+            seeds: seeds.iter().map(|seed| seed.to_string()).collect(),
+            slots: vec![None; SLOTS as usize],
+            connections: HashMap::new(),
+        };
+        client.refresh_slots()?;
+        Ok(client)
+    }
+
+    /// the address of the node the key is sent to
+    pub fn node_of(&self, key: &[u8]) -> Option<&str> {
+        self.slots[key_slot(key) as usize].as_deref()
+    }
+
+    /// Reload the slot map by CLUSTER SLOTS from the first node which replies.
+    pub fn refresh_slots(&mut self) -> KvdResult<()> {
+        let mut addrs: Vec<String> = self.slots.iter().flatten().cloned().collect();
+        addrs.sort();
+        addrs.dedup();
+        addrs.extend(self.seeds.iter().cloned());
+        let mut error = KvdError::from(KvdErrorKind::Io);
+        for addr in addrs {
+            let reply = self
+                .connection(&addr)
+                .and_then(|conn| conn.request(&[b"cluster", b"slots"]));
+            match reply {
+                Ok(Reply::Array(ranges)) => return self.load_slots(ranges),
+                Ok(_) => error = KvdError::from(KvdErrorKind::InvalidReply),
+                Err(e) => {
+                    self.connections.remove(&addr);
+                    error = e;
+                }
+            }
+        }
+        Err(error)
+    }
+
+    /// replace the slot map with the ranges replied by CLUSTER SLOTS
+    fn load_slots(&mut self, ranges: Vec<Reply>) -> KvdResult<()> {
+        let mut slots = vec![None; SLOTS as usize];
+        for range in ranges {
+            let (start, end, node) = match range {
+                Reply::Array(fields) => match <[Reply; 3]>::try_from(fields) {
+                    Ok([Reply::Integer(start), Reply::Integer(end), Reply::Array(node)]) => {
+                        (start, end, node)
+                    }
+                    _ => return Err(KvdError::from(KvdErrorKind::InvalidReply)),
+                },
+                _ => return Err(KvdError::from(KvdErrorKind::InvalidReply)),
+            };
+            let addr = match node.as_slice() {
+                [Reply::Bulk(host), Reply::Integer(port), ..] => {
+                    format!("{}:{}", String::from_utf8_lossy(host), port)
+                }
+                _ => return Err(KvdError::from(KvdErrorKind::InvalidReply)),
+            };
+            if !(0 <= start && start <= end && end < SLOTS as i64) {
+                return Err(KvdError::from(KvdErrorKind::InvalidReply));
+            }
+            for slot in &mut slots[start as usize..=end as usize] {
+                *slot = Some(addr.clone());
+            }
+        }
+        self.slots = slots;
+        Ok(())
+    }
+
+    /// Send the request to the node serving its keys, or any node if it
+    /// has none, and follow the redirects. The slot map is updated by a
+    /// MOVED, but not by an ASK, which only redirects the one request.
+    pub fn request(&mut self, args: &[&[u8]]) -> KvdResult<Reply> {
+        let slot = command_keys(args).first().map(|key| key_slot(key));
+        let mut addr = match slot.and_then(|slot| self.slots[slot as usize].clone()) {
+            Some(addr) => addr,
+            None => self
+                .slots
+                .iter()
+                .flatten()
+                .chain(self.seeds.iter())
+                .next()
+                .cloned()
+                .ok_or_else(|| KvdError::from(KvdErrorKind::SlotNotServed))?,
+        };
+        let mut asking = false;
+        for _ in 0..MAX_REDIRECTS {
+            let reply = match self.request_node(&addr, args, asking) {
+                Ok(reply) => reply,
+                Err(e) => {
+                    // the node may be gone, the next request reloads the map
+                    self.connections.remove(&addr);
+                    let _ = self.refresh_slots();
+                    return Err(e);
+                }
+            };
+            let (ask, moved_slot, to) = match &reply {
+                Reply::Error(e) => match parse_redirect(e) {
+                    Some(redirect) => redirect,
+                    None => return Ok(reply),
+                },
+                _ => return Ok(reply),
+            };
+            if !ask {
+                self.slots[moved_slot as usize] = Some(to.to_string());
+            }
+            asking = ask;
+            addr = to.to_string();
+        }
+        Err(KvdError::from(KvdErrorKind::TooManyRedirects))
+    }
+
+    /// Move the slots from start to end, both included, to the node at the
+    /// address while the cluster keeps serving them, and return the number
+    /// of keys moved. Every slot is set IMPORTING on the target and
+    /// MIGRATING on its owner, its keys are moved by MIGRATE in batches, and
+    /// then it is assigned to the target on every node.
+    pub fn migrate_slots(&mut self, start: u16, end: u16, to: &str) -> KvdResult<usize> {
+        let nodes = self.cluster_nodes(to)?;
+        let id_of = |addr: &str| {
+            nodes
+                .iter()
+                .find(|(_, node)| node == addr)
+                .map(|(id, _)| id.clone())
+                .ok_or_else(|| KvdError::from(KvdErrorKind::UnknownNode))
+        };
+        let target = id_of(to)?;
+        let mut moved = 0;
+        for slot in start..=end.min(SLOTS - 1) {
+            let from = match &self.slots[slot as usize] {
+                Some(from) if from != to => from.clone(),
+                _ => continue,
+            };
+            let source = id_of(&from)?;
+            let slot_arg = slot.to_string();
+            let setslot = |state: &'static [u8], id: &str| -> Vec<Vec<u8>> {
+                vec![
+                    b"cluster".to_vec(),
+                    b"setslot".to_vec(),
+                    slot_arg.clone().into_bytes(),
+                    state.to_vec(),
+                    id.as_bytes().to_vec(),
+                ]
+            };
+            self.request_ok(to, &setslot(b"importing", &source))?;
+            self.request_ok(&from, &setslot(b"migrating", &target))?;
+            loop {
+                let getkeys: &[&[u8]] =
+                    &[b"cluster", b"getkeysinslot", slot_arg.as_bytes(), b"100"];
+                let keys = match self.connection(&from)?.request(getkeys)? {
+                    Reply::Array(keys) if keys.is_empty() => break,
+                    Reply::Array(keys) => keys,
+                    _ => return Err(KvdError::from(KvdErrorKind::InvalidReply)),
+                };
+                let mut args: Vec<&[u8]> = vec![b"migrate", to.as_bytes()];
+                for key in keys.iter() {
+                    match key {
+                        Reply::Bulk(key) => args.push(key),
+                        _ => return Err(KvdError::from(KvdErrorKind::InvalidReply)),
+                    }
+                }
+                match self.connection(&from)?.request(&args)? {
+                    Reply::Integer(count) => moved += count as usize,
+                    _ => return Err(KvdError::from(KvdErrorKind::InvalidReply)),
+                }
+            }
+            // the target serves the slot first, so no request is redirected
+            // back and forth between the nodes
+            let mut addrs = vec![to.to_string(), from];
+            for (_, addr) in nodes.iter() {
+                if !addrs.contains(addr) {
+                    addrs.push(addr.clone());
+                }
+            }
+            for addr in addrs {
+                self.request_ok(&addr, &setslot(b"node", &target))?;
+            }
+            self.slots[slot as usize] = Some(to.to_string());
+        }
+        Ok(moved)
+    }
+
+    /// the ids and the addresses of the nodes known by the node at the address
+    fn cluster_nodes(&mut self, addr: &str) -> KvdResult<Vec<(String, String)>> {
+        let nodes = match self.connection(addr)?.request(&[b"cluster", b"nodes"])? {
+            Reply::Bulk(nodes) => {
+                String::from_utf8(nodes).map_err(|_| KvdError::from(KvdErrorKind::InvalidReply))?
+            }
+            _ => return Err(KvdError::from(KvdErrorKind::InvalidReply)),
+        };
+        nodes
+            .lines()
+            .map(|line| {
+                let mut fields = line.split(' ');
+                match (fields.next(), fields.next()) {
+                    (Some(id), Some(addr)) => Ok((id.to_string(), addr.to_string())),
+                    _ => Err(KvdError::from(KvdErrorKind::InvalidReply)),
+                }
+            })
+            .collect()
+    }
+
+    /// send the request to the node at the address, which should reply OK
+    fn request_ok(&mut self, addr: &str, args: &[Vec<u8>]) -> KvdResult<()> {
+        let args: Vec<&[u8]> = args.iter().map(Vec::as_slice).collect();
+        match self.connection(addr)?.request(&args)? {
+            Reply::Status(_) => Ok(()),
+            _ => Err(KvdError::from(KvdErrorKind::InvalidReply)),
+        }
+    }
+
+    fn request_node(&mut self, addr: &str, args: &[&[u8]], asking: bool) -> KvdResult<Reply> {
+        let conn = self.connection(addr)?;
+        if asking {
+            conn.send(&[b"asking"])?;
+            conn.send(args)?;
+            // the reply of ASKING
+            conn.read_reply()?;
+            return conn.read_reply();
+        }
+        conn.request(args)
+    }
+
+    fn connection(&mut self, addr: &str) -> KvdResult<&mut Connection> {
+        if !self.connections.contains_key(addr) {
+            let conn = Connection::connect(addr)?;
+            self.connections.insert(addr.to_string(), conn);
+        }
+        Ok(self.connections.get_mut(addr).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::memory::MemoryEngine;
+    use crate::server::{Server, ServerConfig};
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::with_config(MemoryEngine::new(), ServerConfig::default()).unwrap();
+        thread::spawn(move || server.serve_listener(listener));
+
+        let mut conn = Connection::connect(addr).unwrap();
+        assert_eq!(Reply::ok(), conn.request(&[b"set", b"a b", b""]).unwrap());
+        conn.send(&[b"get", b"a b"]).unwrap();
+        conn.send(&[b"get", b"c"]).unwrap();
+        assert_eq!(Reply::Bulk(Vec::new()), conn.read_reply().unwrap());
+        assert_eq!(Reply::Nil, conn.read_reply().unwrap());
+        assert_eq!(
+            Reply::Error("ERR unknown command".to_string()),
+            conn.request(&[b"nope"]).unwrap()
+        );
+    }
+}
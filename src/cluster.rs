@@ -0,0 +1,212 @@
+//! Partitioning the keys of a cluster by hash slot.
+//!
+//! Every key belongs to one of the 16384 slots, which is the CRC16 of the
+//! key modulo 16384 like in redis, and every slot is served by one node of
+//! the cluster. Only the part of a key between the first { and the next }
+//! is hashed if it is not empty, so keys like {user1}.name and {user1}.age
+//! are in the same slot and can be used in one request.
+
+use crate::model::{KvdError, KvdErrorKind, KvdResult};
+use crate::raft::NodeId;
+use std::path::PathBuf;
+
+/// the number of hash slots
+pub const SLOTS: u16 = 16384;
+
+/// A node of a cluster and the slots it serves.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClusterNode {
+    pub id: NodeId,
+    /// the address of the node, host:port
+    pub addr: String,
+    /// the ranges of the slots, both ends included
+    pub slots: Vec<(u16, u16)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ClusterConfig {
+    /// the id of this node, which should be one of the nodes
+    pub id: NodeId,
+    pub nodes: Vec<ClusterNode>,
+    /// the file the slot map is saved to when it changes, which replaces the
+    /// nodes if it exists
+    pub nodes_file: Option<PathBuf>,
+}
+
+/// the hash slot of the key
+pub fn key_slot(key: &[u8]) -> u16 {
+    let hashed = key
+        .iter()
+        .position(|&b| b == b'{')
+        .and_then(|start| {
+            let tag = &key[start + 1..];
+            tag.iter()
+                .position(|&b| b == b'}')
+                .filter(|&end| end > 0)
+                .map(|end| &tag[..end])
+        })
+        .unwrap_or(key);
+    crc16(hashed) % SLOTS
+}
+
+/// CRC16-CCITT (XMODEM), the checksum redis hashes the keys with
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// The keys of a request, which should all be in one slot. A request
+/// without keys, like INFO, can be served by any node.
+pub fn command_keys<T: AsRef<[u8]>>(request: &[T]) -> Vec<&[u8]> {
+    let cmd = match request.first() {
+        Some(cmd) => cmd.as_ref().to_ascii_lowercase(),
+        None => return Vec::new(),
+    };
+    let args = &request[1..];
+    let keys: &[T] = match cmd.as_slice() {
+        b"client" | b"config" | b"info" | b"monitor" | b"slowlog" | b"changes" | b"sync"
+        | b"raft" | b"subscribe" | b"psubscribe" | b"unsubscribe" | b"punsubscribe"
+        | b"publish" | b"cluster" | b"asking" | b"migrate" => &[],
+        b"del" | b"exists" | b"mget" | b"sinter" | b"sunion" | b"sdiff" | b"sinterstore"
+        | b"sunionstore" | b"sdiffstore" => args,
+        // the last argument is the timeout
+        b"blpop" | b"brpop" => &args[..args.len().saturating_sub(1)],
+        b"mset" | b"msetnx" => {
+            return args.iter().step_by(2).map(|key| key.as_ref()).collect();
+        }
+        _ => &args[..args.len().min(1)],
+    };
+    keys.iter().map(|key| key.as_ref()).collect()
+}
+
+/// Parse the nodes of a cluster, separated by commas, like
+/// "1@host:port 0-8191,2@host:port 8192-16383", where a node is followed by
+/// the ranges or single slots it serves.
+pub fn parse_nodes(spec: &str) -> KvdResult<Vec<ClusterNode>> {
+    let invalid = || KvdError::from(KvdErrorKind::Config);
+    spec.split(',')
+        .map(|node| {
+            let mut fields = node.split_whitespace();
+            let (id, addr) = fields
+                .next()
+                .and_then(|field| field.split_once('@'))
+                .ok_or_else(invalid)?;
+            let slots = fields
+                .map(|range| parse_slot_range(range).ok_or_else(invalid))
+                .collect::<KvdResult<Vec<(u16, u16)>>>()?;
+            Ok(ClusterNode {
+                id: id.parse().map_err(|_| invalid())?,
+                addr: addr.to_string(),
+                slots,
+            })
+        })
+        .collect()
+}
+
+/// parse "start-end" or a single slot
+pub fn parse_slot_range(range: &str) -> Option<(u16, u16)> {
+    let (start, end) = range.split_once('-').unwrap_or((range, range));
+    let (start, end) = (parse_slot(start.as_bytes())?, parse_slot(end.as_bytes())?);
+    Some((start, end)).filter(|_| start <= end)
+}
+
+pub fn parse_slot(slot: &[u8]) -> Option<u16> {
+    std::str::from_utf8(slot)
+        .ok()
+        .and_then(|slot| slot.parse::<u16>().ok())
+        .filter(|&slot| slot < SLOTS)
+}
+
+/// Parse a redirect of a misrouted key, -MOVED slot host:port or
+/// -ASK slot host:port, into whether it is an ASK, the slot and the address.
+pub fn parse_redirect(error: &str) -> Option<(bool, u16, &str)> {
+    let mut fields = error.split(' ');
+    let ask = match fields.next()? {
+        "MOVED" => false,
+        "ASK" => true,
+        _ => return None,
+    };
+    let slot = parse_slot(fields.next()?.as_bytes())?;
+    let addr = fields.next()?;
+    Some((ask, slot, addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_slot() {
+        // the same slots as redis
+        assert_eq!(12739, key_slot(b"123456789"));
+        assert_eq!(12182, key_slot(b"foo"));
+        assert_eq!(5061, key_slot(b"bar"));
+        assert_eq!(
+            key_slot(b"{user1000}.following"),
+            key_slot(b"{user1000}.followers")
+        );
+        assert_eq!(key_slot(b"user1000"), key_slot(b"a{user1000}b{c}"));
+        // an empty tag is not a tag
+        assert_eq!(crc16(b"{}foo") % SLOTS, key_slot(b"{}foo"));
+        assert_eq!(crc16(b"foo{") % SLOTS, key_slot(b"foo{"));
+    }
+
+    #[test]
+    fn test_command_keys() {
+        let keys = |request: &[&str]| -> Vec<String> {
+            command_keys(request)
+                .into_iter()
+                .map(|key| String::from_utf8(key.to_vec()).unwrap())
+                .collect()
+        };
+        assert_eq!(vec!["a"], keys(&["GET", "a"]));
+        assert_eq!(vec!["a"], keys(&["hset", "a", "f", "v"]));
+        assert_eq!(vec!["a", "b"], keys(&["mset", "a", "1", "b", "2"]));
+        assert_eq!(vec!["a", "b"], keys(&["blpop", "a", "b", "0"]));
+        assert_eq!(vec!["d", "a"], keys(&["sinterstore", "d", "a"]));
+        assert!(keys(&["info", "keyspace"]).is_empty());
+        assert!(keys(&["publish", "c", "m"]).is_empty());
+        assert!(keys(&["get"]).is_empty());
+    }
+
+    #[test]
+    fn test_parse_nodes() {
+        let nodes =
+            parse_nodes("1@127.0.0.1:7001 0-8191, 2@127.0.0.1:7002 8192-16382 16383").unwrap();
+        assert_eq!(
+            vec![
+                ClusterNode {
+                    id: 1,
+                    addr: "127.0.0.1:7001".to_string(),
+                    slots: vec![(0, 8191)],
+                },
+                ClusterNode {
+                    id: 2,
+                    addr: "127.0.0.1:7002".to_string(),
+                    slots: vec![(8192, 16382), (16383, 16383)],
+                },
+            ],
+            nodes
+        );
+        assert!(parse_nodes("1@127.0.0.1:7001 0-16384").is_err());
+        assert!(parse_nodes("1@127.0.0.1:7001 10-1").is_err());
+        assert!(parse_nodes("127.0.0.1:7001 0-1").is_err());
+
+        assert_eq!(
+            Some((false, 3999, "127.0.0.1:7002")),
+            parse_redirect("MOVED 3999 127.0.0.1:7002")
+        );
+        assert_eq!(Some((true, 1, "host:1")), parse_redirect("ASK 1 host:1"));
+        assert_eq!(None, parse_redirect("ERR unknown command"));
+    }
+}
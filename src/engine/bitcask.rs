@@ -1,34 +1,167 @@
-use crate::engine::KvdEngine;
-use crate::model::KvdErrorKind::KeyNotFound;
+use crate::engine::notify::{EventClasses, Notifier};
+use crate::engine::value::{Change, Collection};
+use crate::engine::{now_millis, resolve_range, KvdEngine, SetOptions};
+use crate::metrics::WalMetrics;
 use crate::model::{KvdError, KvdErrorKind, KvdResult};
+use serde::de::{
+    self, DeserializeSeed, EnumAccess, IgnoredAny, MapAccess, SeqAccess, VariantAccess, Visitor,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
 use std::collections::BTreeMap;
 use std::ffi::OsStr;
+use std::fmt;
 use std::fs::{File, OpenOptions};
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::Bound::{Excluded, Unbounded};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
 use std::{fs, io};
 
 const DEFAULT_FILE_CAPACITY: u64 = 1024;
+/// the wal is not compacted until there are so many dead bytes
+const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+/// the most keys and bytes a step of a compaction copies after a write
+const COMPACTION_STEP_KEYS: usize = 1024;
+const COMPACTION_STEP_BYTES: u64 = 256 * 1024;
+/// how often the expired keys are swept out of the index, in milliseconds
+const EXPIRE_SWEEP_INTERVAL: u64 = 1000;
 
 pub struct BitcaskEngine {
     file_store: FileStore,
     index: BTreeMap<Vec<u8>, CommandPosition>,
+    /// the keys which hold a collection, they are never in the index
+    collections: BTreeMap<Vec<u8>, CollectionEntry>,
+    /// the expire times of the keys which have one
+    expires: BTreeMap<Vec<u8>, u64>,
+    /// the size of the commands referenced by the index and the collections,
+    /// and of the last compaction record
+    live_bytes: u64,
+    /// the size of the last compaction record, which keeps the sequence
+    /// number compacted up to
+    compaction_len: u64,
+    /// the unix time in milliseconds of the next sweep of the expired keys
+    next_sweep: u64,
+    /// the compaction in progress
+    compaction: Option<Compaction>,
+    notifier: Notifier,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// A compaction copies the live commands in steps, between which the
+/// commands are written as usual. They all go to the wal files after the
+/// ones being compacted, so only the keys still in the older files are
+/// copied, and the older files are removed after the last step.
+struct Compaction {
+    /// the first wal file written since the compaction started
+    first_file_num: u64,
+    /// the sequence number compacted up to
+    seq: u64,
+    /// the last key copied, the next step goes on after it
+    cursor: Option<Vec<u8>>,
+    /// whether the strings are all copied, and the collections are next
+    collections: bool,
+}
+
+/// A collection is kept in memory, it is rebuilt by replaying its changes.
+struct CollectionEntry {
+    value: Collection,
+    /// the size of the commands the value is built from
+    len: u64,
+}
+
+/// A record of the wal.
+///
+/// Every change of a key carries a sequence number, which increases by one
+/// with every change written. A record written before the sequence numbers
+/// has 0 instead.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum Command {
-    Set { key: Vec<u8>, value: Vec<u8> },
-    Del { key: Vec<u8> },
+    Set {
+        #[serde(default)]
+        seq: u64,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        /// the unix time in milliseconds when the key expires
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expire_at: Option<u64>,
+    },
+    Del {
+        #[serde(default)]
+        seq: u64,
+        key: Vec<u8>,
+    },
+    /// the next len commands are applied all or none
+    Batch { len: u64 },
+    /// a change of the collection of the key
+    Change {
+        #[serde(default)]
+        seq: u64,
+        key: Vec<u8>,
+        change: Change,
+    },
+    /// The changes up to seq are compacted away, so they can not be read by
+    /// `changes_since`. It is the first record written by a compaction.
+    Compaction { seq: u64 },
 }
 
 impl Command {
     pub fn set(key: Vec<u8>, value: Vec<u8>) -> Self {
-        Self::Set { key, value }
+        Self::Set {
+            seq: 0,
+            key,
+            value,
+            expire_at: None,
+        }
+    }
+    pub fn set_expire_at(key: Vec<u8>, value: Vec<u8>, expire_at: Option<u64>) -> Self {
+        Self::Set {
+            seq: 0,
+            key,
+            value,
+            expire_at,
+        }
     }
     pub fn del(key: Vec<u8>) -> Self {
-        Self::Del { key }
+        Self::Del { seq: 0, key }
+    }
+    pub fn batch(len: u64) -> Self {
+        Self::Batch { len }
+    }
+    pub fn change(key: Vec<u8>, change: Change) -> Self {
+        Self::Change {
+            seq: 0,
+            key,
+            change,
+        }
+    }
+
+    /// the sequence number of a change, 0 for the other records
+    pub fn seq(&self) -> u64 {
+        match self {
+            Command::Set { seq, .. } | Command::Del { seq, .. } | Command::Change { seq, .. } => {
+                *seq
+            }
+            Command::Batch { .. } | Command::Compaction { .. } => 0,
+        }
+    }
+
+    fn set_seq(&mut self, new: u64) {
+        match self {
+            Command::Set { seq, .. } | Command::Del { seq, .. } | Command::Change { seq, .. } => {
+                *seq = new
+            }
+            Command::Batch { .. } | Command::Compaction { .. } => {}
+        }
+    }
+
+    fn value_len(&self) -> u64 {
+        match self {
+            Command::Set { value, .. } => value.len() as u64,
+            _ => 0,
+        }
     }
 }
 
@@ -37,13 +170,51 @@ struct CommandPosition {
     pub file_num: u64,
     pub pos: u64,
     pub len: u64,
+    /// the length of the value of a set command, 0 for the other commands
+    pub value_len: u64,
+}
+
+/// Deserializes a set command into the bytes of its value in the range.
+///
+/// The value is a json array in the wal, so it can not be sought into, but
+/// the bytes out of the range are skipped instead of being collected.
+struct ValueRange(Range<usize>);
+
+/// the fields of a set command
+struct SetFields(Range<usize>);
+
+/// the value of a set command
+struct ValueBytes(Range<usize>);
+
+/// a batch being loaded, whose commands are not all read yet
+struct PendingBatch {
+    pos: u64,
+    len: u64,
+    cmds: Vec<(Command, CommandPosition)>,
 }
 
 struct FileStore {
     dir: PathBuf,
+    /// the size of all the wal files
+    total_bytes: u64,
     current_file_num: u64,
     current_write_log: WalWriter<File>,
-    read_logs: Vec<WalReader<File>>,
+    read_logs: BTreeMap<u64, WalReader<File>>,
+    metrics: Arc<WalMetrics>,
+    seqs: Sequences,
+}
+
+/// The sequence numbers of the changes in the wal files.
+#[derive(Default)]
+struct Sequences {
+    /// the sequence number of the last change written
+    last: u64,
+    /// the changes up to the sequence number are compacted away
+    compacted: u64,
+    /// the last sequence number in every wal file, which increases with the
+    /// file number, to find the file to read the changes after a sequence
+    /// number from
+    last_by_file: BTreeMap<u64, u64>,
 }
 
 struct WalWriter<W: Write + Seek> {
@@ -56,10 +227,6 @@ struct WalReader<R: Read + Seek> {
     pos: u64,
 }
 
-struct StoreIndex {
-    map: BTreeMap<Vec<u8>, CommandPosition>,
-}
-
 impl BitcaskEngine {
     /// the path must be a directory that all the data are stored in the directory
     pub fn open(path: PathBuf) -> KvdResult<Self> {
@@ -68,75 +235,751 @@ impl BitcaskEngine {
         // return store
         let mut file_store = FileStore::open(path)?;
         let mut index = BTreeMap::new();
+        let mut collections = BTreeMap::new();
+        let mut expires = BTreeMap::new();
+
+        let compaction_len =
+            Self::load(&mut file_store, &mut index, &mut collections, &mut expires)?;
+        // the keys expired while the store is closed are dropped, they are
+        // skipped again by the next load or removed by a compaction
+        let now = now_millis();
+        expires.retain(|key, at| {
+            if *at <= now {
+                index.remove(key);
+                return false;
+            }
+            true
+        });
+        let live_bytes = index.values().map(|pos| pos.len).sum::<u64>()
+            + collections.values().map(|entry| entry.len).sum::<u64>()
+            + compaction_len;
+
+        Ok(BitcaskEngine {
+            file_store,
+            index,
+            collections,
+            expires,
+            live_bytes,
+            compaction_len,
+            next_sweep: 0,
+            compaction: None,
+            notifier: Notifier::default(),
+        })
+    }
+
+    /// fail with WrongType if the key holds a collection
+    fn check_string(&self, key: &[u8]) -> KvdResult<()> {
+        if self.collections.contains_key(key) {
+            return Err(KvdError::from(KvdErrorKind::WrongType));
+        }
+        Ok(())
+    }
+
+    /// forget the collection of the key, its commands become dead
+    fn remove_collection(&mut self, key: &[u8]) {
+        if let Some(entry) = self.collections.remove(key) {
+            self.live_bytes -= entry.len;
+        }
+    }
 
-        Self::load(&mut file_store, &mut index)?;
+    /// Forget the key if it is expired.
+    ///
+    /// Nothing is written, the set command of the key is skipped by the next
+    /// load since its expire time is passed.
+    fn expire(&mut self, key: &[u8]) {
+        if self.expires.get(key).is_some_and(|&at| at <= now_millis()) {
+            self.expires.remove(key);
+            if let Some(old_pos) = self.index.remove(key) {
+                self.live_bytes -= old_pos.len;
+            }
+            self.notifier
+                .emit(EventClasses::EXPIRED, "expired", key, None);
+        }
+    }
 
-        Ok(BitcaskEngine { file_store, index })
+    /// Forget the keys expired but never read again, so their commands are
+    /// counted as dead and can trigger a compaction. It runs at most once in
+    /// EXPIRE_SWEEP_INTERVAL.
+    fn sweep_expired(&mut self) {
+        let now = now_millis();
+        if now < self.next_sweep {
+            return;
+        }
+        self.next_sweep = now + EXPIRE_SWEEP_INTERVAL;
+        let expired: Vec<Vec<u8>> = self
+            .expires
+            .iter()
+            .filter(|(_, &at)| at <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.expire(&key);
+        }
     }
 
+    fn write_set(&mut self, key: Vec<u8>, value: Vec<u8>, expire_at: Option<u64>) -> KvdResult<()> {
+        let event_value = self.notifier.is_value_enabled().then(|| value.clone());
+        let cmd = Command::set_expire_at(key.clone(), value, expire_at);
+        let cmd_pos = self.file_store.write_command(cmd)?;
+        self.notifier
+            .emit(EventClasses::STRING, "set", &key, event_value);
+        self.live_bytes += cmd_pos.len;
+        self.remove_collection(&key);
+        match expire_at {
+            Some(at) => self.expires.insert(key.clone(), at),
+            None => self.expires.remove(&key),
+        };
+        if let Some(old_pos) = self.index.insert(key, cmd_pos) {
+            self.live_bytes -= old_pos.len;
+        }
+        self.maybe_compact();
+        Ok(())
+    }
+
+    /// Rewrite the live commands into new wal files and remove the old
+    /// files, all at once, or finish the compaction in progress.
+    pub fn compact(&mut self) -> KvdResult<()> {
+        if self.compaction.is_none() {
+            self.start_compaction()?;
+        }
+        while !self.compaction_step()? {}
+        Ok(())
+    }
+
+    /// Start a compaction in a new wal file.
+    ///
+    /// The changes written before are compacted away, which is recorded
+    /// first, so `changes_since` never skips a change silently.
+    fn start_compaction(&mut self) -> KvdResult<()> {
+        let first_file_num = self.file_store.change_to_new_wal()?;
+        let seq = self.file_store.seqs.last;
+        let cmd_pos = self.file_store.write_record(&Command::Compaction { seq })?;
+        self.live_bytes = self.live_bytes - self.compaction_len + cmd_pos.len;
+        self.compaction_len = cmd_pos.len;
+        self.compaction = Some(Compaction {
+            first_file_num,
+            seq,
+            cursor: None,
+            collections: false,
+        });
+        Ok(())
+    }
+
+    /// Copy the next keys of the compaction in progress, at most
+    /// COMPACTION_STEP_KEYS of them or COMPACTION_STEP_BYTES, return whether
+    /// the compaction is done.
+    ///
+    /// The old files are removed only after the new ones are synced, and in
+    /// the order they are written, so the index loaded after a crash in the
+    /// middle of a compaction is still correct.
+    fn compaction_step(&mut self) -> KvdResult<bool> {
+        let compaction = match self.compaction.as_mut() {
+            Some(compaction) => compaction,
+            None => return Ok(true),
+        };
+        let (mut visited, mut copied) = (0, 0);
+        loop {
+            let bounds = (
+                compaction.cursor.clone().map_or(Unbounded, Excluded),
+                Unbounded,
+            );
+            let limit = COMPACTION_STEP_KEYS - visited;
+            let keys: Vec<Vec<u8>> = if compaction.collections {
+                let keys = self.collections.range(bounds).map(|(key, _)| key);
+                keys.take(limit).cloned().collect()
+            } else {
+                let keys = self.index.range(bounds).map(|(key, _)| key);
+                keys.take(limit).cloned().collect()
+            };
+            for key in keys.iter() {
+                if copied >= COMPACTION_STEP_BYTES {
+                    return Ok(false);
+                }
+                if compaction.collections {
+                    // a collection is written as one command which replaces
+                    // the value, with the sequence number compacted up to
+                    // like the copied commands
+                    let entry = self.collections.get_mut(key).unwrap();
+                    let cmd = Command::Change {
+                        seq: compaction.seq,
+                        key: key.clone(),
+                        change: Change::Restore(entry.value.clone()),
+                    };
+                    let cmd_pos = self.file_store.write_record(&cmd)?;
+                    self.live_bytes = self.live_bytes - entry.len + cmd_pos.len;
+                    entry.len = cmd_pos.len;
+                    copied += cmd_pos.len;
+                } else {
+                    let cmd_pos = self.index.get_mut(key).unwrap();
+                    // a key written since the compaction started is not copied
+                    if cmd_pos.file_num < compaction.first_file_num {
+                        let data = self.file_store.read_data(cmd_pos)?;
+                        let value_len = cmd_pos.value_len;
+                        *cmd_pos = self.file_store.write_data(&data)?;
+                        cmd_pos.value_len = value_len;
+                        copied += cmd_pos.len;
+                    }
+                }
+                compaction.cursor = Some(key.clone());
+            }
+            visited += keys.len();
+            if visited == COMPACTION_STEP_KEYS {
+                return Ok(false);
+            }
+            if compaction.collections {
+                break;
+            }
+            compaction.collections = true;
+            compaction.cursor = None;
+        }
+        let first_file_num = compaction.first_file_num;
+        self.file_store.sync()?;
+        self.file_store.remove_wal_files_before(first_file_num)?;
+        self.file_store.metrics.record_compaction();
+        self.compaction = None;
+        Ok(true)
+    }
+
+    /// Compact the wal when most of it is dead, a step after every write so
+    /// that no write waits for the whole wal to be rewritten. It is called
+    /// after a write is durable already, so a failed step is only logged,
+    /// and tried again after the next write.
+    fn maybe_compact(&mut self) {
+        self.sweep_expired();
+        let dead_bytes = self.file_store.total_bytes.saturating_sub(self.live_bytes);
+        let result = if self.compaction.is_some() {
+            self.compaction_step().map(|_| ())
+        } else if dead_bytes >= COMPACTION_THRESHOLD && dead_bytes > self.live_bytes {
+            self.start_compaction()
+                .and_then(|_| self.compaction_step())
+                .map(|_| ())
+        } else {
+            Ok(())
+        };
+        if let Err(e) = result {
+            log::warn!("failed to compact the wal: {}", e);
+        }
+    }
+
+    /// Build the index from the wal files, return the size of the last
+    /// compaction record.
+    ///
+    /// A record torn by a crash, or a batch which is not completely written,
+    /// can only be at the end of the last file. It is ignored and truncated,
+    /// so that the next records are appended after the last complete one.
     fn load(
         file_store: &mut FileStore,
         index: &mut BTreeMap<Vec<u8>, CommandPosition>,
-    ) -> KvdResult<()> {
-        for i in 0..file_store.read_logs.len() {
-            let reader = file_store
-                .read_logs
-                .get_mut(i)
-                .ok_or_else(|| KvdError::from(KvdErrorKind::FileNotFound))?;
+        collections: &mut BTreeMap<Vec<u8>, CollectionEntry>,
+        expires: &mut BTreeMap<Vec<u8>, u64>,
+    ) -> KvdResult<u64> {
+        let mut torn_pos = None;
+        let mut compaction_len = 0;
+        let seqs = &mut file_store.seqs;
+        for (file_num, reader) in file_store.read_logs.iter_mut() {
             let mut pos = reader.seek(SeekFrom::Start(0))?;
+            let mut batch: Option<PendingBatch> = None;
             let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
             while let Some(cmd) = stream.next() {
-                let new_pos = stream.byte_offset();
-                match cmd? {
-                    Command::Set { key, .. } => {
-                        let cmd_pos = CommandPosition {
-                            file_num: i as u64,
-                            pos,
-                            len: new_pos as u64 - pos,
-                        };
-                        index.insert(key, cmd_pos);
+                let cmd = match cmd {
+                    Ok(cmd) => cmd,
+                    Err(ref e) if e.is_eof() => break,
+                    Err(e) => return Err(KvdError::from(e)),
+                };
+                let new_pos = stream.byte_offset() as u64;
+                let cmd_pos = CommandPosition {
+                    file_num: *file_num,
+                    pos,
+                    len: new_pos - pos,
+                    value_len: cmd.value_len(),
+                };
+                pos = new_pos;
+                match (cmd, batch.as_mut()) {
+                    (Command::Batch { len }, None) => {
+                        batch = Some(PendingBatch {
+                            pos: cmd_pos.pos,
+                            len,
+                            cmds: Vec::new(),
+                        })
+                    }
+                    (Command::Batch { .. }, Some(_)) => {
+                        return Err(KvdError::from(KvdErrorKind::InvalidCommand))
+                    }
+                    (cmd, Some(pending)) => {
+                        pending.cmds.push((cmd, cmd_pos));
+                        if pending.cmds.len() as u64 == pending.len {
+                            for (cmd, cmd_pos) in batch.take().unwrap().cmds {
+                                seqs.record(*file_num, &cmd);
+                                Self::apply(index, collections, expires, cmd, cmd_pos);
+                            }
+                        }
                     }
-                    Command::Del { key } => {
-                        index.remove(&key);
+                    (cmd, None) => {
+                        if let Command::Compaction { .. } = cmd {
+                            compaction_len = cmd_pos.len;
+                        }
+                        seqs.record(*file_num, &cmd);
+                        Self::apply(index, collections, expires, cmd, cmd_pos)
                     }
                 }
             }
+            if let Some(pending) = batch {
+                pos = pending.pos;
+            }
+            torn_pos = Some((*file_num, pos));
+        }
+        if let Some((file_num, pos)) = torn_pos {
+            file_store.truncate(file_num, pos)?;
         }
 
-        Ok(())
+        Ok(compaction_len)
+    }
+
+    fn apply(
+        index: &mut BTreeMap<Vec<u8>, CommandPosition>,
+        collections: &mut BTreeMap<Vec<u8>, CollectionEntry>,
+        expires: &mut BTreeMap<Vec<u8>, u64>,
+        cmd: Command,
+        cmd_pos: CommandPosition,
+    ) {
+        match cmd {
+            Command::Set { key, expire_at, .. } => {
+                match expire_at {
+                    Some(at) => expires.insert(key.clone(), at),
+                    None => expires.remove(&key),
+                };
+                collections.remove(&key);
+                index.insert(key, cmd_pos);
+            }
+            Command::Del { key, .. } => {
+                expires.remove(&key);
+                collections.remove(&key);
+                index.remove(&key);
+            }
+            Command::Change { key, change, .. } => {
+                // the string replaced can only be an expired one
+                expires.remove(&key);
+                index.remove(&key);
+                let (current, len) = collections
+                    .remove(&key)
+                    .map_or((None, 0), |entry| (Some(entry.value), entry.len));
+                if let Some(value) = change.apply(current) {
+                    let len = len + cmd_pos.len;
+                    collections.insert(key, CollectionEntry { value, len });
+                }
+            }
+            Command::Batch { .. } | Command::Compaction { .. } => {}
+        }
     }
 }
 
 impl KvdEngine for BitcaskEngine {
     fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> KvdResult<()> {
-        let cmd = Command::set(key.clone(), value);
-        let cmd_pos = self.file_store.write_command(cmd)?;
-        self.index.insert(key.clone(), cmd_pos);
-        Ok(())
+        self.write_set(key, value, None)
     }
 
     /// TODO: change &mut to &
     fn get(&mut self, key: Vec<u8>) -> KvdResult<Option<Vec<u8>>> {
-        let cmd_pos = self.index.get(&key);
-        if let None = cmd_pos {
-            return Ok(None);
-        }
-        let cmd = self.file_store.read_command_position(cmd_pos.unwrap())?;
+        self.expire(&key);
+        self.check_string(&key)?;
+        let cmd_pos = match self.index.get(&key) {
+            Some(cmd_pos) => cmd_pos,
+            None => return Ok(None),
+        };
+        let cmd = self.file_store.read_command_position(cmd_pos)?;
         match cmd {
-            Command::Set { key: _, value: v } => Ok(Some(v)),
+            Command::Set { value, .. } => Ok(Some(value)),
             _ => Err(KvdError::from(KvdErrorKind::InvalidCommand)),
         }
     }
 
     fn del(&mut self, key: Vec<u8>) -> KvdResult<()> {
-        if let None = self.index.get(&key) {
+        self.expire(&key);
+        if !self.index.contains_key(&key) && !self.collections.contains_key(&key) {
             return Err(KvdError::from(KvdErrorKind::KeyNotFound));
         }
         let cmd = Command::del(key.clone());
+        self.file_store.write_command(cmd)?;
+        self.notifier.emit(EventClasses::GENERIC, "del", &key, None);
+        self.expires.remove(&key);
+        self.remove_collection(&key);
+        if let Some(old_pos) = self.index.remove(&key) {
+            self.live_bytes -= old_pos.len;
+        }
+        self.maybe_compact();
+        Ok(())
+    }
+
+    /// the old value is only read from the wal if it is asked for
+    fn set_with(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        options: SetOptions,
+    ) -> KvdResult<(bool, Option<Vec<u8>>)> {
+        let old = if options.get {
+            self.get(key.clone())?
+        } else {
+            None
+        };
+        self.expire(&key);
+        let exists = self.index.contains_key(&key) || self.collections.contains_key(&key);
+        if !options.condition.holds(exists) {
+            return Ok((false, old));
+        }
+        let expire_key = options.expire_at.map(|_| key.clone());
+        self.write_set(key, value, options.expire_at)?;
+        if let Some(key) = expire_key {
+            self.notifier
+                .emit(EventClasses::GENERIC, "expire", &key, None);
+        }
+        Ok((true, old))
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> KvdResult<Result<(), Option<Vec<u8>>>> {
+        let current = self.get(key.clone())?;
+        if current != expected {
+            return Ok(Err(current));
+        }
+        match new {
+            Some(value) => self.write_set(key, value, None)?,
+            // nothing to delete when both are None
+            None if current.is_none() => {}
+            None => self.del(key)?,
+        }
+        Ok(Ok(()))
+    }
+
+    fn update<F>(&mut self, key: Vec<u8>, f: F) -> KvdResult<Vec<u8>>
+    where
+        F: FnOnce(Option<Vec<u8>>) -> KvdResult<Vec<u8>>,
+    {
+        let new = f(self.get(key.clone())?)?;
+        let expire_at = self.expires.get(&key).copied();
+        self.write_set(key, new.clone(), expire_at)?;
+        Ok(new)
+    }
+
+    /// answered by the index without reading the wal
+    fn strlen(&mut self, key: Vec<u8>) -> KvdResult<usize> {
+        self.expire(&key);
+        self.check_string(&key)?;
+        Ok(self
+            .index
+            .get(&key)
+            .map_or(0, |cmd_pos| cmd_pos.value_len as usize))
+    }
+
+    /// only the bytes in the range are collected from the record
+    fn get_range(&mut self, key: Vec<u8>, start: i64, end: i64) -> KvdResult<Vec<u8>> {
+        self.expire(&key);
+        self.check_string(&key)?;
+        let cmd_pos = match self.index.get(&key) {
+            Some(cmd_pos) => cmd_pos,
+            None => return Ok(Vec::new()),
+        };
+        let range = match resolve_range(cmd_pos.value_len as usize, start, end) {
+            Some(range) => range,
+            None => return Ok(Vec::new()),
+        };
+        let data = self.file_store.read_data(cmd_pos)?;
+        let mut deserializer = Deserializer::from_slice(&data);
+        Ok(ValueRange(range).deserialize(&mut deserializer)?)
+    }
+
+    fn set_notifier(&mut self, notifier: Notifier) {
+        self.notifier = notifier;
+    }
+
+    /// the changes are read from the wal files
+    fn changes_since(&mut self, seq: u64, limit: usize) -> KvdResult<Result<Vec<Command>, u64>> {
+        let compacted = self.file_store.seqs.compacted;
+        if seq < compacted {
+            return Ok(Err(compacted));
+        }
+        Ok(Ok(self.file_store.read_changes(seq, limit)?))
+    }
+
+    /// the strings are read from the wal, the collections are restored as a whole
+    fn snapshot(&mut self) -> KvdResult<(u64, Vec<Command>)> {
+        let seq = self.file_store.seqs.last;
+        let now = now_millis();
+        let mut cmds = Vec::with_capacity(self.key_count());
+        for (key, cmd_pos) in self.index.iter() {
+            if self.expires.get(key).is_some_and(|&at| at <= now) {
+                continue;
+            }
+            let mut cmd = self.file_store.read_command_position(cmd_pos)?;
+            cmd.set_seq(seq);
+            cmds.push(cmd);
+        }
+        for (key, entry) in self.collections.iter() {
+            cmds.push(Command::Change {
+                seq,
+                key: key.clone(),
+                change: Change::Restore(entry.value.clone()),
+            });
+        }
+        Ok((seq, cmds))
+    }
+
+    /// a string is read from the wal with its expire time
+    fn dump(&mut self, key: &[u8]) -> KvdResult<Option<Command>> {
+        self.expire(key);
+        if let Some(cmd_pos) = self.index.get(key) {
+            let mut cmd = self.file_store.read_command_position(cmd_pos)?;
+            cmd.set_seq(0);
+            return Ok(Some(cmd));
+        }
+        Ok(self
+            .collections
+            .get(key)
+            .map(|entry| Command::change(key.to_vec(), Change::Restore(entry.value.clone()))))
+    }
+
+    fn collection(&mut self, key: &[u8]) -> KvdResult<Option<&Collection>> {
+        self.expire(key);
+        if self.index.contains_key(key) {
+            return Err(KvdError::from(KvdErrorKind::WrongType));
+        }
+        Ok(self.collections.get(key).map(|entry| &entry.value))
+    }
+
+    /// The change is logged before it is applied. A restore replaces a
+    /// string too, in the same record.
+    fn change(&mut self, key: Vec<u8>, change: Change) -> KvdResult<()> {
+        if let Change::Restore(_) = change {
+            self.expire(&key);
+        } else {
+            change.check(self.collection(&key)?)?;
+        }
+        let cmd = Command::change(key.clone(), change.clone());
         let cmd_pos = self.file_store.write_command(cmd)?;
-        self.index.remove(&key);
+        // the same as the replay of the change by the next load
+        self.expires.remove(&key);
+        if let Some(old_pos) = self.index.remove(&key) {
+            self.live_bytes -= old_pos.len;
+        }
+        let (current, mut len) = self
+            .collections
+            .remove(&key)
+            .map_or((None, 0), |entry| (Some(entry.value), entry.len));
+        if let Change::Restore(_) = change {
+            // the restore record alone holds the new collection
+            self.live_bytes -= len;
+            len = 0;
+        }
+        let before = current.as_ref().map(Collection::value_type);
+        let name = change.name();
+        let value = change.apply(current);
+        self.notifier
+            .emit_change(name, &key, before, value.as_ref());
+        match value {
+            Some(value) => {
+                let len = len + cmd_pos.len;
+                self.live_bytes += cmd_pos.len;
+                self.collections.insert(key, CollectionEntry { value, len });
+            }
+            // the collection becomes empty and all its commands are dead
+            None => self.live_bytes -= len,
+        }
+        self.maybe_compact();
+        Ok(())
+    }
+
+    fn exists(&mut self, key: Vec<u8>) -> KvdResult<bool> {
+        self.expire(&key);
+        Ok(self.index.contains_key(&key) || self.collections.contains_key(&key))
+    }
+
+    /// the pairs are written as one batch
+    fn mset(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> KvdResult<()> {
+        let keys: Vec<Vec<u8>> = pairs.iter().map(|(key, _)| key.clone()).collect();
+        let event_values: Vec<Option<Vec<u8>>> = pairs
+            .iter()
+            .map(|(_, value)| self.notifier.is_value_enabled().then(|| value.clone()))
+            .collect();
+        let cmds = pairs
+            .into_iter()
+            .map(|(key, value)| Command::set(key, value))
+            .collect();
+        let cmd_positions = self.file_store.write_batch(cmds)?;
+        for ((key, cmd_pos), event_value) in keys.into_iter().zip(cmd_positions).zip(event_values) {
+            self.notifier
+                .emit(EventClasses::STRING, "set", &key, event_value);
+            self.live_bytes += cmd_pos.len;
+            self.expires.remove(&key);
+            self.remove_collection(&key);
+            if let Some(old_pos) = self.index.insert(key, cmd_pos) {
+                self.live_bytes -= old_pos.len;
+            }
+        }
+        self.maybe_compact();
         Ok(())
     }
+
+    /// the deletions are written as one batch
+    fn mdel(&mut self, keys: Vec<Vec<u8>>) -> KvdResult<usize> {
+        for key in keys.iter() {
+            self.expire(key);
+        }
+        let mut keys: Vec<Vec<u8>> = keys
+            .into_iter()
+            .filter(|key| self.index.contains_key(key) || self.collections.contains_key(key))
+            .collect();
+        keys.sort_unstable();
+        keys.dedup();
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        let cmds = keys.iter().cloned().map(Command::del).collect();
+        self.file_store.write_batch(cmds)?;
+        for key in keys.iter() {
+            self.notifier.emit(EventClasses::GENERIC, "del", key, None);
+            self.expires.remove(key);
+            self.remove_collection(key);
+            if let Some(old_pos) = self.index.remove(key) {
+                self.live_bytes -= old_pos.len;
+            }
+        }
+        self.maybe_compact();
+        Ok(keys.len())
+    }
+
+    fn key_count(&self) -> usize {
+        self.index.len() + self.collections.len()
+    }
+
+    fn keys(&self) -> Vec<Vec<u8>> {
+        self.index
+            .keys()
+            .chain(self.collections.keys())
+            .cloned()
+            .collect()
+    }
+
+    fn info(&self) -> Vec<(String, String)> {
+        let total_bytes = self.file_store.total_bytes;
+        let live_bytes = self.live_bytes;
+        // a rough estimation, the overhead of the btree nodes is not counted
+        let index_bytes: usize = self
+            .index
+            .keys()
+            .map(|key| {
+                key.capacity() + mem::size_of::<Vec<u8>>() + mem::size_of::<CommandPosition>()
+            })
+            .sum();
+        vec![
+            ("engine".to_string(), "bitcask".to_string()),
+            (
+                "wal_files".to_string(),
+                self.file_store.read_logs.len().to_string(),
+            ),
+            ("wal_total_bytes".to_string(), total_bytes.to_string()),
+            ("wal_live_bytes".to_string(), live_bytes.to_string()),
+            (
+                "wal_dead_bytes".to_string(),
+                total_bytes.saturating_sub(live_bytes).to_string(),
+            ),
+            ("index_memory_bytes".to_string(), index_bytes.to_string()),
+            (
+                "wal_last_seq".to_string(),
+                self.file_store.seqs.last.to_string(),
+            ),
+            (
+                "wal_compacted_seq".to_string(),
+                self.file_store.seqs.compacted.to_string(),
+            ),
+        ]
+    }
+
+    fn wal_metrics(&self) -> Option<Arc<WalMetrics>> {
+        Some(self.file_store.metrics.clone())
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for ValueRange {
+    type Value = Vec<u8>;
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<Vec<u8>, D::Error> {
+        deserializer.deserialize_enum(
+            "Command",
+            &["Set", "Del", "Batch", "Change", "Compaction"],
+            self,
+        )
+    }
+}
+
+impl<'de> Visitor<'de> for ValueRange {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a set command")
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Vec<u8>, A::Error> {
+        let (variant, access) = data.variant::<String>()?;
+        if variant != "Set" {
+            return Err(de::Error::invalid_value(
+                de::Unexpected::Str(&variant),
+                &self,
+            ));
+        }
+        access.struct_variant(&["seq", "key", "value", "expire_at"], SetFields(self.0))
+    }
+}
+
+impl<'de> Visitor<'de> for SetFields {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("the fields of a set command")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Vec<u8>, A::Error> {
+        let mut value = None;
+        while let Some(field) = map.next_key::<String>()? {
+            if field == "value" {
+                value = Some(map.next_value_seed(ValueBytes(self.0.clone()))?);
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        value.ok_or_else(|| de::Error::missing_field("value"))
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for ValueBytes {
+    type Value = Vec<u8>;
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<Vec<u8>, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ValueBytes {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("the bytes of a value")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+        let mut bytes = Vec::with_capacity(self.0.len());
+        let mut i = 0;
+        while let Some(byte) = seq.next_element::<u8>()? {
+            if self.0.contains(&i) {
+                bytes.push(byte);
+            }
+            i += 1;
+        }
+        Ok(bytes)
+    }
 }
 
 impl FileStore {
@@ -145,82 +988,201 @@ impl FileStore {
 
         let mut sorted_file_number_list = Self::get_sorted_file_number_list(&path)?;
         if sorted_file_number_list.is_empty() {
-            let mut readers: Vec<WalReader<File>> = Vec::new();
+            let mut readers = BTreeMap::new();
             let writer = Self::build_wal_writer(&path, 0)?;
             let wal_path = Self::wal_path(&path, 0);
             let read_wal = File::open(wal_path)?;
             let reader = WalReader::new(read_wal)?;
-            readers.push(reader);
+            readers.insert(0, reader);
             Ok(FileStore {
                 dir: path.clone(),
+                total_bytes: 0,
                 current_file_num: 0,
                 current_write_log: writer,
                 read_logs: readers,
+                metrics: Arc::new(WalMetrics::new()),
+                seqs: Sequences::default(),
             })
         } else {
             // take out the last file, and put all other files into reader list
             let last_file_num = sorted_file_number_list.pop().unwrap();
-            let mut readers: Vec<WalReader<File>> = Vec::new();
+            let mut readers = BTreeMap::new();
+            let mut total_bytes = 0;
             for file_num in sorted_file_number_list.iter() {
                 let wal_path = Self::wal_path(&path, *file_num);
                 let read_wal = File::open(wal_path)?;
+                total_bytes += read_wal.metadata()?.len();
                 let reader = WalReader::new(read_wal)?;
-                readers.push(reader);
+                readers.insert(*file_num, reader);
             }
 
             let wal_path = Self::wal_path(&path, last_file_num);
             let read_wal = File::open(wal_path)?;
+            total_bytes += read_wal.metadata()?.len();
             let reader = WalReader::new(read_wal)?;
-            readers.push(reader);
+            readers.insert(last_file_num, reader);
 
             let writer = Self::build_wal_writer(&path, last_file_num)?;
             Ok(FileStore {
                 dir: path.clone(),
+                total_bytes,
                 current_file_num: last_file_num,
                 current_write_log: writer,
                 read_logs: readers,
+                metrics: Arc::new(WalMetrics::new()),
+                seqs: Sequences::default(),
             })
         }
     }
 
-    fn write_command(&mut self, cmd: Command) -> KvdResult<CommandPosition> {
+    /// write the command as the next change
+    fn write_command(&mut self, mut cmd: Command) -> KvdResult<CommandPosition> {
+        cmd.set_seq(self.seqs.last + 1);
+        self.write_record(&cmd)
+    }
+
+    /// write the record with the sequence number it already has
+    fn write_record(&mut self, cmd: &Command) -> KvdResult<CommandPosition> {
+        let data = serde_json::to_vec(cmd)?;
+        let mut cmd_pos = self.write_data(&data)?;
+        cmd_pos.value_len = cmd.value_len();
+        self.seqs.record(cmd_pos.file_num, cmd);
+        Ok(cmd_pos)
+    }
+
+    /// Write the commands with one write, after a batch header.
+    ///
+    /// The batch is never split across wal files.
+    fn write_batch(&mut self, cmds: Vec<Command>) -> KvdResult<Vec<CommandPosition>> {
+        if self.current_write_log.is_full() {
+            self.change_to_new_wal()?;
+        }
+
+        let mut data = serde_json::to_vec(&Command::batch(cmds.len() as u64))?;
+        let mut cmd_positions = Vec::with_capacity(cmds.len());
+        let mut seq = self.seqs.last;
+        for mut cmd in cmds {
+            seq += 1;
+            cmd.set_seq(seq);
+            let pos = data.len() as u64;
+            serde_json::to_writer(&mut data, &cmd)?;
+            cmd_positions.push(CommandPosition {
+                file_num: self.current_file_num,
+                pos: self.current_write_log.pos + pos,
+                len: data.len() as u64 - pos,
+                value_len: cmd.value_len(),
+            });
+        }
+        self.current_write_log.write_all(&data)?;
+        self.current_write_log.flush()?;
+        self.total_bytes += data.len() as u64;
+        self.metrics.add_bytes_written(data.len());
+        self.seqs.last = seq;
+        self.seqs.last_by_file.insert(self.current_file_num, seq);
+
+        Ok(cmd_positions)
+    }
+
+    /// append a serialized command to the current wal
+    fn write_data(&mut self, data: &[u8]) -> KvdResult<CommandPosition> {
         if self.current_write_log.is_full() {
             self.change_to_new_wal()?;
         }
 
-        let data = serde_json::to_vec(&cmd)?;
         let pos = self.current_write_log.pos;
-        self.current_write_log.write(&data)?;
+        self.current_write_log.write_all(data)?;
         self.current_write_log.flush()?; // important, the reader may not read the correct data if not flush.
+        self.total_bytes += data.len() as u64;
+        self.metrics.add_bytes_written(data.len());
 
         Ok(CommandPosition {
             file_num: self.current_file_num,
             pos,
             len: data.len() as u64,
+            value_len: 0,
         })
     }
 
     fn read_command_position(&mut self, cmd_pos: &CommandPosition) -> KvdResult<Command> {
-        let mut wal_reader = self
+        let data = self.read_data(cmd_pos)?;
+        let cmd = serde_json::from_slice::<Command>(&data)?;
+
+        // TODO: use take to reduce copy?
+        //         let cmd_reader = wal_reader.take(cmd_pos.len);
+        //        let cmd = serde_json::from_reader(cmd_reader)?;
+        Ok(cmd)
+    }
+
+    /// read a serialized command
+    fn read_data(&mut self, cmd_pos: &CommandPosition) -> KvdResult<Vec<u8>> {
+        let wal_reader = self
             .read_logs
-            .get_mut(cmd_pos.file_num as usize)
+            .get_mut(&cmd_pos.file_num)
             .ok_or_else(|| KvdError::from(KvdErrorKind::KeyNotFound))?;
 
         wal_reader.seek(SeekFrom::Start(cmd_pos.pos))?;
 
         // cannot use Vec::with_capacity(), since the len() is 0
         let mut data = vec![0; cmd_pos.len as usize];
-        let read_size = wal_reader.read(data.as_mut_slice())?;
-        let cmd = serde_json::from_slice::<Command>(&data)?;
+        wal_reader.read_exact(data.as_mut_slice())?;
+        Ok(data)
+    }
 
-        // TODO: use take to reduce copy?
-        //         let cmd_reader = wal_reader.take(cmd_pos.len);
-        //        let cmd = serde_json::from_reader(cmd_reader)?;
-        Ok(cmd)
+    /// Read the changes after the sequence number from the wal files, at
+    /// most limit of them.
+    fn read_changes(&mut self, seq: u64, limit: usize) -> KvdResult<Vec<Command>> {
+        let mut changes = Vec::new();
+        let first_file_num = match self.seqs.last_by_file.iter().find(|(_, last)| **last > seq) {
+            Some((file_num, _)) => *file_num,
+            None => return Ok(changes),
+        };
+        for reader in self.read_logs.range_mut(first_file_num..).map(|(_, r)| r) {
+            reader.seek(SeekFrom::Start(0))?;
+            for cmd in Deserializer::from_reader(reader).into_iter::<Command>() {
+                if changes.len() >= limit {
+                    return Ok(changes);
+                }
+                let cmd = cmd?;
+                if cmd.seq() > seq {
+                    changes.push(cmd);
+                }
+            }
+        }
+        Ok(changes)
+    }
+
+    /// drop the data of the current wal after the position
+    fn truncate(&mut self, file_num: u64, pos: u64) -> KvdResult<()> {
+        if file_num != self.current_file_num || pos == self.current_write_log.pos {
+            return Ok(());
+        }
+        self.total_bytes -= self.current_write_log.pos - pos;
+        self.current_write_log.truncate(pos)?;
+        Ok(())
+    }
+
+    /// sync the current wal to disk
+    fn sync(&mut self) -> KvdResult<()> {
+        let start = Instant::now();
+        self.current_write_log.sync()?;
+        self.metrics.observe_fsync(start.elapsed());
+        Ok(())
+    }
+
+    fn remove_wal_files_before(&mut self, file_num: u64) -> KvdResult<()> {
+        let file_nums: Vec<u64> = self.read_logs.range(..file_num).map(|(n, _)| *n).collect();
+        for file_num in file_nums {
+            self.read_logs.remove(&file_num);
+            self.seqs.last_by_file.remove(&file_num);
+            let wal_path = Self::wal_path(&self.dir, file_num);
+            self.total_bytes -= fs::metadata(&wal_path)?.len();
+            fs::remove_file(wal_path)?;
+        }
+        Ok(())
     }
 
     fn build_wal_writer(path: &Path, file_num: u64) -> KvdResult<WalWriter<File>> {
-        let path = Self::wal_path(&path, file_num);
+        let path = Self::wal_path(path, file_num);
         let file = Self::new_wal_file(path)?;
         let writer = WalWriter::new(file)?;
         Ok(writer)
@@ -234,7 +1196,7 @@ impl FileStore {
             .flat_map(|path| {
                 path.file_name()
                     .and_then(OsStr::to_str)
-                    .map(|s| s.trim_end_matches(".wal"))
+                    .map(|s| s.trim_start_matches("kvd_").trim_end_matches(".wal"))
                     .map(str::parse::<u64>)
             })
             .flatten()
@@ -243,19 +1205,26 @@ impl FileStore {
         Ok(file_number_list)
     }
 
-    fn change_to_new_wal(&mut self) -> KvdResult<()> {
+    /// seal the current wal and start a new one, return the number of the new wal
+    fn change_to_new_wal(&mut self) -> KvdResult<u64> {
+        self.sync()?;
         let current_num = self.current_file_num + 1;
         self.current_write_log = Self::build_wal_writer(&self.dir, current_num)?;
         self.current_file_num = current_num;
         let wal_path = Self::wal_path(&self.dir, current_num);
         let read_wal = File::open(wal_path)?;
         let reader = WalReader::new(read_wal)?;
-        self.read_logs.push(reader);
-        Ok(())
+        self.read_logs.insert(current_num, reader);
+        Ok(current_num)
     }
 
     fn is_wal_file(path: &Path) -> bool {
-        path.is_file() && path.starts_with("kvd_") && path.ends_with(".wal")
+        path.is_file()
+            && path.extension() == Some(OsStr::new("wal"))
+            && path
+                .file_name()
+                .and_then(OsStr::to_str)
+                .is_some_and(|name| name.starts_with("kvd_"))
     }
 
     fn wal_path(path: &Path, file_number: u64) -> PathBuf {
@@ -263,26 +1232,39 @@ impl FileStore {
     }
 
     fn new_wal_file(path: PathBuf) -> KvdResult<File> {
-        let result = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(path)?;
+        let result = OpenOptions::new().create(true).append(true).open(path)?;
         Ok(result)
     }
 }
 
-impl StoreIndex {
-    pub fn new() -> StoreIndex {
-        StoreIndex {
-            map: BTreeMap::new(),
+impl Sequences {
+    /// count a record loaded from or written to the file
+    fn record(&mut self, file_num: u64, cmd: &Command) {
+        match *cmd {
+            Command::Batch { .. } => {}
+            Command::Compaction { seq } => {
+                self.last = self.last.max(seq);
+                self.compacted = self.compacted.max(seq);
+            }
+            // a change without a sequence number can not be read by
+            // `changes_since`, like a compacted one
+            _ if cmd.seq() == 0 => {
+                self.last = self.last.max(1);
+                self.compacted = self.compacted.max(self.last);
+            }
+            _ => {
+                self.last = self.last.max(cmd.seq());
+                let last = self.last_by_file.entry(file_num).or_default();
+                *last = (*last).max(cmd.seq());
+            }
         }
     }
 }
 
 impl<W: Write + Seek> WalWriter<W> {
     fn new(mut inner: W) -> KvdResult<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        // the file is opened in append mode, so the position starts at the end
+        let pos = inner.seek(SeekFrom::End(0))?;
         Ok(WalWriter {
             writer: BufWriter::new(inner),
             pos,
@@ -294,6 +1276,21 @@ impl<W: Write + Seek> WalWriter<W> {
     }
 }
 
+impl WalWriter<File> {
+    fn sync(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()
+    }
+
+    /// the file is in append mode, so the next write is at the new end
+    fn truncate(&mut self, pos: u64) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().set_len(pos)?;
+        self.pos = pos;
+        Ok(())
+    }
+}
+
 impl<W: Write + Seek> Write for WalWriter<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let length = self.writer.write(buf)?;
@@ -315,7 +1312,7 @@ impl<W: Write + Seek> Seek for WalWriter<W> {
 
 impl<R: Read + Seek> WalReader<R> {
     fn new(mut inner: R) -> KvdResult<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(WalReader {
             reader: BufReader::new(inner),
             pos,
@@ -341,7 +1338,12 @@ impl<R: Read + Seek> Seek for WalReader<R> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::time::{SystemTime, UNIX_EPOCH};
+    use crate::engine::notify::{EventFilter, KeyEvent};
+    use crate::engine::sorted_set::Score;
+    use crate::engine::value::ValueType;
+    use crate::engine::{SetCondition, MAX_VALUE_SIZE};
+    use std::thread;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
     #[test]
     fn test_open() {
@@ -421,6 +1423,764 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_info() {
+        let mut store = get_test_store();
+        store.set(Vec::from("key"), Vec::from("value")).unwrap();
+        store.set(Vec::from("key"), Vec::from("value2")).unwrap();
+        store.set(Vec::from("other"), Vec::from("value")).unwrap();
+        store.del(Vec::from("other")).unwrap();
+        assert_eq!(1, store.key_count());
+
+        let info: BTreeMap<String, String> = store.info().into_iter().collect();
+        let field = |name: &str| info.get(name).unwrap().parse::<u64>().unwrap();
+        assert_eq!(1, field("wal_files"));
+        let live_bytes = serde_json::to_vec(&Command::set(Vec::from("key"), Vec::from("value2")))
+            .unwrap()
+            .len() as u64;
+        assert_eq!(live_bytes, field("wal_live_bytes"));
+        assert_eq!(
+            field("wal_total_bytes") - live_bytes,
+            field("wal_dead_bytes")
+        );
+        assert!(field("wal_dead_bytes") > 0);
+        assert!(field("index_memory_bytes") > 0);
+    }
+
+    #[test]
+    fn test_compact() {
+        let path = get_tmp_store_path();
+        // about 64KiB after being serialized
+        let value = vec![b'v'; 16 * 1024];
+        {
+            let mut store = BitcaskEngine::open(path.clone()).unwrap();
+            store.set(Vec::from("other"), Vec::from("value")).unwrap();
+            store.set(Vec::from("deleted"), Vec::from("value")).unwrap();
+            store.del(Vec::from("deleted")).unwrap();
+            // the dead bytes of the overwritten values trigger a compaction
+            for _ in 0..20 {
+                store.set(Vec::from("key"), value.clone()).unwrap();
+            }
+            let metrics = store.wal_metrics().unwrap();
+            assert_eq!(1, metrics.compaction_runs());
+            assert!(metrics.bytes_written() > 20 * 64 * 1024);
+
+            let info: BTreeMap<String, String> = store.info().into_iter().collect();
+            let total_bytes = info.get("wal_total_bytes").unwrap().parse::<u64>().unwrap();
+            assert!(total_bytes < COMPACTION_THRESHOLD);
+            store.compact().unwrap();
+            assert_eq!("0", info_field(&store, "wal_dead_bytes"));
+        }
+
+        // the compacted wal is loaded correctly
+        let mut store = BitcaskEngine::open(path).unwrap();
+        assert_eq!(Ok(Some(value)), store.get(Vec::from("key")));
+        assert_eq!(Ok(Some(Vec::from("value"))), store.get(Vec::from("other")));
+        assert_eq!(Ok(None), store.get(Vec::from("deleted")));
+        assert_eq!("0", info_field(&store, "wal_dead_bytes"));
+    }
+
+    #[test]
+    fn test_compact_expired() {
+        let mut store = get_test_store();
+        // about 64KiB after being serialized
+        let value = vec![b'v'; 16 * 1024];
+        let options = SetOptions {
+            expire_at: Some(now_millis() + 100),
+            ..SetOptions::default()
+        };
+        for i in 0..20 {
+            let key = format!("key{}", i).into_bytes();
+            store.set_with(key, value.clone(), options).unwrap();
+        }
+        assert_eq!(0, store.wal_metrics().unwrap().compaction_runs());
+
+        // the expired keys are never read, but their commands are dead
+        thread::sleep(Duration::from_millis(200));
+        store.next_sweep = 0;
+        store.set(Vec::from("other"), Vec::from("value")).unwrap();
+        assert_eq!(1, store.wal_metrics().unwrap().compaction_runs());
+        assert_eq!(1, store.key_count());
+        assert_eq!("0", info_field(&store, "wal_dead_bytes"));
+    }
+
+    #[test]
+    fn test_compact_in_steps() {
+        let path = get_tmp_store_path();
+        let key = |i: usize| format!("key{:04}", i).into_bytes();
+        let count = COMPACTION_STEP_KEYS * 6;
+        let members = |s: &str| s.split(' ').map(Vec::from).collect::<Vec<Vec<u8>>>();
+        {
+            let mut store = BitcaskEngine::open(path.clone()).unwrap();
+            for i in 0..count {
+                store.set(key(i), key(i)).unwrap();
+            }
+            store
+                .change(Vec::from("set"), Change::SAdd(members("a")))
+                .unwrap();
+            store.start_compaction().unwrap();
+            assert!(!store.compaction_step().unwrap());
+
+            // the keys written between the steps, copied already or not, are kept
+            store.set(key(0), Vec::from("first")).unwrap();
+            store.set(key(count - 1), Vec::from("last")).unwrap();
+            store.del(key(count - 2)).unwrap();
+            store
+                .change(Vec::from("set"), Change::SAdd(members("b")))
+                .unwrap();
+            assert!(store.compaction.is_some());
+            store.compact().unwrap();
+            assert!(store.compaction.is_none());
+            assert_eq!(1, store.wal_metrics().unwrap().compaction_runs());
+        }
+
+        let mut store = BitcaskEngine::open(path).unwrap();
+        assert_eq!(count, store.key_count());
+        assert_eq!(Ok(Some(Vec::from("first"))), store.get(key(0)));
+        assert_eq!(Ok(Some(key(1))), store.get(key(1)));
+        assert_eq!(Ok(Some(key(count - 3))), store.get(key(count - 3)));
+        assert_eq!(Ok(None), store.get(key(count - 2)));
+        assert_eq!(Ok(Some(Vec::from("last"))), store.get(key(count - 1)));
+        let expect = Change::SAdd(members("a b")).apply(None);
+        assert_eq!(expect.as_ref(), store.collection(b"set").unwrap());
+    }
+
+    #[test]
+    fn test_batch() {
+        let path = get_tmp_store_path();
+        {
+            let mut store = BitcaskEngine::open(path.clone()).unwrap();
+            store.set(Vec::from("a"), Vec::from("0")).unwrap();
+            let pairs = vec![
+                (Vec::from("a"), Vec::from("1")),
+                (Vec::from("b"), Vec::from("2")),
+                (Vec::from("a"), Vec::from("3")),
+            ];
+            store.mset(pairs).unwrap();
+            assert_eq!(
+                Ok(vec![Some(Vec::from("3")), Some(Vec::from("2")), None]),
+                store.mget(vec![Vec::from("a"), Vec::from("b"), Vec::from("c")])
+            );
+            assert_eq!(
+                Ok(false),
+                store.msetnx(vec![(Vec::from("b"), Vec::from("4"))])
+            );
+            assert_eq!(
+                Ok(true),
+                store.msetnx(vec![(Vec::from("c"), Vec::from("5"))])
+            );
+            assert_eq!(
+                Ok(2),
+                store.mdel(vec![
+                    Vec::from("a"),
+                    Vec::from("a"),
+                    Vec::from("c"),
+                    Vec::from("d")
+                ])
+            );
+            assert_eq!(Ok(false), store.exists(Vec::from("a")));
+            assert_eq!(Ok(true), store.exists(Vec::from("b")));
+        }
+
+        let mut store = BitcaskEngine::open(path).unwrap();
+        assert_eq!(1, store.key_count());
+        assert_eq!(Ok(Some(Vec::from("2"))), store.get(Vec::from("b")));
+        let live_bytes = serde_json::to_vec(&Command::set(Vec::from("b"), Vec::from("2")))
+            .unwrap()
+            .len();
+        assert_eq!(live_bytes.to_string(), info_field(&store, "wal_live_bytes"));
+    }
+
+    #[test]
+    fn test_torn_batch() {
+        let path = get_tmp_store_path();
+        {
+            let mut store = BitcaskEngine::open(path.clone()).unwrap();
+            store.set(Vec::from("a"), Vec::from("1")).unwrap();
+        }
+        // a batch of two commands is cut off by a crash
+        let wal_path = FileStore::wal_path(&path, 0);
+        let complete_len = fs::metadata(&wal_path).unwrap().len();
+        let mut data = serde_json::to_vec(&Command::batch(2)).unwrap();
+        serde_json::to_writer(&mut data, &Command::set(Vec::from("a"), Vec::from("2"))).unwrap();
+        let torn = serde_json::to_vec(&Command::set(Vec::from("b"), Vec::from("2"))).unwrap();
+        data.extend(&torn[..torn.len() / 2]);
+        let mut file = OpenOptions::new().append(true).open(&wal_path).unwrap();
+        file.write_all(&data).unwrap();
+
+        {
+            let mut store = BitcaskEngine::open(path.clone()).unwrap();
+            assert_eq!(complete_len, fs::metadata(&wal_path).unwrap().len());
+            assert_eq!(Ok(Some(Vec::from("1"))), store.get(Vec::from("a")));
+            assert_eq!(Ok(None), store.get(Vec::from("b")));
+            store.set(Vec::from("b"), Vec::from("3")).unwrap();
+        }
+
+        // the records written after the truncation are readable
+        let mut store = BitcaskEngine::open(path).unwrap();
+        assert_eq!(Ok(Some(Vec::from("1"))), store.get(Vec::from("a")));
+        assert_eq!(Ok(Some(Vec::from("3"))), store.get(Vec::from("b")));
+    }
+
+    #[test]
+    fn test_expire() {
+        let path = get_tmp_store_path();
+        let expiring = |condition, expire_at| SetOptions {
+            condition,
+            expire_at: Some(expire_at),
+            get: true,
+        };
+        {
+            let mut store = BitcaskEngine::open(path.clone()).unwrap();
+            let now = now_millis();
+            store
+                .set_with(
+                    Vec::from("a"),
+                    Vec::from("1"),
+                    expiring(SetCondition::Always, now + 50),
+                )
+                .unwrap();
+            store
+                .set_with(
+                    Vec::from("b"),
+                    Vec::from("1"),
+                    expiring(SetCondition::Always, now + 60_000),
+                )
+                .unwrap();
+            assert_eq!(
+                Ok((false, Some(Vec::from("1")))),
+                store.set_with(
+                    Vec::from("a"),
+                    Vec::from("2"),
+                    expiring(SetCondition::NotExists, now + 50)
+                )
+            );
+        }
+
+        thread::sleep(Duration::from_millis(60));
+        let mut store = BitcaskEngine::open(path.clone()).unwrap();
+        assert_eq!(1, store.key_count());
+        assert_eq!(Ok(false), store.exists(Vec::from("a")));
+        assert_eq!(Ok(Some(Vec::from("1"))), store.get(Vec::from("b")));
+        assert_eq!(
+            Ok((true, None)),
+            store.set_with(
+                Vec::from("a"),
+                Vec::from("3"),
+                expiring(SetCondition::NotExists, now_millis() + 50)
+            )
+        );
+        store.set(Vec::from("a"), Vec::from("4")).unwrap();
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(Ok(Some(Vec::from("4"))), store.getdel(Vec::from("a")));
+        assert_eq!(Ok(None), store.getdel(Vec::from("a")));
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let path = get_tmp_store_path();
+        let key = Vec::from("key");
+        {
+            let mut store = BitcaskEngine::open(path.clone()).unwrap();
+            assert_eq!(
+                Ok(Err(None)),
+                store.compare_and_swap(key.clone(), Some(Vec::from("1")), None)
+            );
+            assert_eq!(
+                Ok(Ok(())),
+                store.compare_and_swap(key.clone(), None, Some(Vec::from("1")))
+            );
+            assert_eq!(
+                Ok(Err(Some(Vec::from("1")))),
+                store.compare_and_swap(key.clone(), None, Some(Vec::from("2")))
+            );
+            assert_eq!(
+                Ok(Ok(())),
+                store.compare_and_swap(key.clone(), Some(Vec::from("1")), Some(Vec::from("2")))
+            );
+            assert_eq!(
+                Ok(Ok(())),
+                store.compare_and_swap(Vec::from("other"), None, None)
+            );
+            store.set(Vec::from("deleted"), Vec::from("1")).unwrap();
+            assert_eq!(
+                Ok(Ok(())),
+                store.compare_and_swap(Vec::from("deleted"), Some(Vec::from("1")), None)
+            );
+        }
+
+        let mut store = BitcaskEngine::open(path).unwrap();
+        assert_eq!(1, store.key_count());
+        assert_eq!(Ok(Some(Vec::from("2"))), store.get(key));
+    }
+
+    #[test]
+    fn test_incr() {
+        let path = get_tmp_store_path();
+        let key = Vec::from("counter");
+        {
+            let mut store = BitcaskEngine::open(path.clone()).unwrap();
+            assert_eq!(Ok(5), store.incr_by(key.clone(), 5));
+            assert_eq!(Ok(3), store.incr_by(key.clone(), -2));
+            assert_eq!(
+                Err(KvdError::from(KvdErrorKind::IncrementOverflow)),
+                store.incr_by(key.clone(), i64::MAX)
+            );
+            assert_eq!(Ok(3.5), store.incr_by_float(key.clone(), 0.5));
+            assert_eq!(
+                Err(KvdError::from(KvdErrorKind::NotAnInteger)),
+                store.incr_by(key.clone(), 1)
+            );
+            // each successful update is logged once, a failed one is not logged
+            let total_bytes: usize = ["5", "3", "3.5"]
+                .iter()
+                .map(|value| {
+                    serde_json::to_vec(&Command::set(key.clone(), Vec::from(*value)))
+                        .unwrap()
+                        .len()
+                })
+                .sum();
+            assert_eq!(
+                total_bytes.to_string(),
+                info_field(&store, "wal_total_bytes")
+            );
+
+            store.set(Vec::from("text"), Vec::from("abc")).unwrap();
+            assert_eq!(
+                Err(KvdError::from(KvdErrorKind::NotAFloat)),
+                store.incr_by_float(Vec::from("text"), 1.0)
+            );
+        }
+
+        let mut store = BitcaskEngine::open(path).unwrap();
+        assert_eq!(Ok(Some(Vec::from("3.5"))), store.get(key));
+    }
+
+    #[test]
+    fn test_string_range() {
+        let mut store = get_test_store();
+        let key = Vec::from("key");
+        assert_eq!(Ok(0), store.strlen(key.clone()));
+        assert_eq!(Ok(Vec::new()), store.get_range(key.clone(), 0, -1));
+        assert_eq!(Ok(5), store.append(key.clone(), Vec::from("hello")));
+        assert_eq!(Ok(11), store.append(key.clone(), Vec::from(" world")));
+        assert_eq!(Ok(11), store.strlen(key.clone()));
+        assert_eq!(Ok(Vec::from("hello")), store.get_range(key.clone(), 0, 4));
+        assert_eq!(Ok(Vec::from("world")), store.get_range(key.clone(), -5, -1));
+        assert_eq!(
+            Ok(Vec::from("hello world")),
+            store.get_range(key.clone(), -100, 100)
+        );
+        assert_eq!(Ok(Vec::new()), store.get_range(key.clone(), 5, 3));
+
+        assert_eq!(Ok(11), store.set_range(key.clone(), 6, Vec::from("WORLD")));
+        // an empty value changes nothing
+        assert_eq!(Ok(11), store.set_range(key.clone(), 13, Vec::new()));
+        assert_eq!(Ok(13), store.set_range(key.clone(), 12, Vec::from("!")));
+        assert_eq!(
+            Ok(Some(Vec::from("hello WORLD\0!"))),
+            store.get(key.clone())
+        );
+        assert_eq!(Ok(0), store.set_range(Vec::from("missing"), 3, Vec::new()));
+        assert_eq!(Ok(false), store.exists(Vec::from("missing")));
+        assert_eq!(
+            Err(KvdError::from(KvdErrorKind::ValueTooLarge)),
+            store.set_range(key.clone(), MAX_VALUE_SIZE, Vec::from("x"))
+        );
+
+        // the lengths are kept by a compaction
+        store.compact().unwrap();
+        assert_eq!(Ok(13), store.strlen(key.clone()));
+        assert_eq!(Ok(Vec::from("WORLD")), store.get_range(key, 6, 10));
+    }
+
+    #[test]
+    fn test_collection() {
+        let path = get_tmp_store_path();
+        let key = Vec::from("hash");
+        let field = |name: &str| Vec::from(name);
+        {
+            let mut store = BitcaskEngine::open(path.clone()).unwrap();
+            store
+                .change(
+                    key.clone(),
+                    Change::HSet(vec![(field("a"), field("1")), (field("b"), field("2"))]),
+                )
+                .unwrap();
+            store
+                .change(key.clone(), Change::HDel(vec![field("a")]))
+                .unwrap();
+            store.set(Vec::from("string"), field("1")).unwrap();
+            assert_eq!(
+                Err(KvdError::from(KvdErrorKind::WrongType)),
+                store.change(Vec::from("string"), Change::HDel(vec![field("a")]))
+            );
+            assert_eq!(
+                Err(KvdError::from(KvdErrorKind::WrongType)),
+                store.get(key.clone())
+            );
+            // an emptied hash is removed
+            store
+                .change(
+                    Vec::from("emptied"),
+                    Change::HSet(vec![(field("a"), field("1"))]),
+                )
+                .unwrap();
+            store
+                .change(Vec::from("emptied"), Change::HDel(vec![field("a")]))
+                .unwrap();
+            assert_eq!(2, store.key_count());
+        }
+
+        let expect = Change::HSet(vec![(field("b"), field("2"))]).apply(None);
+        let mut store = BitcaskEngine::open(path.clone()).unwrap();
+        assert_eq!(2, store.key_count());
+        assert_eq!(expect.as_ref(), store.collection(&key).unwrap());
+        store.compact().unwrap();
+        assert_eq!("0", info_field(&store, "wal_dead_bytes"));
+
+        // the compacted collection is loaded correctly
+        let mut store = BitcaskEngine::open(path).unwrap();
+        assert_eq!(expect.as_ref(), store.collection(&key).unwrap());
+        assert_eq!(Ok(None), store.collection(b"emptied"));
+        store.del(key.clone()).unwrap();
+        assert_eq!(Ok(false), store.exists(key));
+    }
+
+    #[test]
+    fn test_list() {
+        let path = get_tmp_store_path();
+        let key = Vec::from("list");
+        let elements = |names: &str| -> Vec<Vec<u8>> { names.split(' ').map(Vec::from).collect() };
+        let changes = vec![
+            Change::RPush(elements("a b c d")),
+            Change::LPush(elements("x y")),
+            Change::LPop(1),
+            Change::RPop(1),
+            Change::LSet(1, Vec::from("z")),
+            Change::LRem(0, Vec::from("b")),
+            Change::LTrim(0, 2),
+        ];
+        let expect = changes
+            .iter()
+            .cloned()
+            .fold(None, |value, change| change.apply(value));
+        assert_eq!(Change::RPush(elements("x z")).apply(None), expect);
+        {
+            let mut store = BitcaskEngine::open(path.clone()).unwrap();
+            for change in changes {
+                store.change(key.clone(), change).unwrap();
+            }
+            assert_eq!(expect.as_ref(), store.collection(&key).unwrap());
+        }
+
+        // the list survives a restart and a compaction
+        let mut store = BitcaskEngine::open(path.clone()).unwrap();
+        assert_eq!(expect.as_ref(), store.collection(&key).unwrap());
+        store.compact().unwrap();
+        let mut store = BitcaskEngine::open(path).unwrap();
+        assert_eq!(expect.as_ref(), store.collection(&key).unwrap());
+        store.change(key.clone(), Change::LPop(2)).unwrap();
+        assert_eq!(Ok(false), store.exists(key));
+    }
+
+    #[test]
+    fn test_set_collection() {
+        let path = get_tmp_store_path();
+        let key = Vec::from("set");
+        let members = |names: &str| -> Vec<Vec<u8>> { names.split(' ').map(Vec::from).collect() };
+        {
+            let mut store = BitcaskEngine::open(path.clone()).unwrap();
+            store
+                .change(key.clone(), Change::SAdd(members("a b c")))
+                .unwrap();
+            store
+                .change(key.clone(), Change::SRem(members("b")))
+                .unwrap();
+            assert_eq!(Ok(Some(ValueType::Set)), store.key_type(&key));
+        }
+
+        let expect = Change::SAdd(members("a c")).apply(None);
+        let mut store = BitcaskEngine::open(path.clone()).unwrap();
+        assert_eq!(expect.as_ref(), store.collection(&key).unwrap());
+        store.compact().unwrap();
+        let mut store = BitcaskEngine::open(path).unwrap();
+        assert_eq!(expect.as_ref(), store.collection(&key).unwrap());
+        store.set(key.clone(), Vec::from("1")).unwrap();
+        assert_eq!(Ok(Some(ValueType::String)), store.key_type(&key));
+        assert_eq!(Ok(None), store.key_type(b"missing"));
+    }
+
+    #[test]
+    fn test_sorted_set() {
+        let path = get_tmp_store_path();
+        let key = Vec::from("zset");
+        let score = |score: f64| Score::new(score).unwrap();
+        {
+            let mut store = BitcaskEngine::open(path.clone()).unwrap();
+            let members = vec![
+                (Vec::from("a"), score(1.0)),
+                (Vec::from("b"), score(f64::NEG_INFINITY)),
+                (Vec::from("c"), score(2.5)),
+            ];
+            store.change(key.clone(), Change::ZAdd(members)).unwrap();
+            store
+                .change(key.clone(), Change::ZRem(vec![Vec::from("a")]))
+                .unwrap();
+        }
+
+        let expect = Change::ZAdd(vec![
+            (Vec::from("b"), score(f64::NEG_INFINITY)),
+            (Vec::from("c"), score(2.5)),
+        ])
+        .apply(None);
+        let mut store = BitcaskEngine::open(path.clone()).unwrap();
+        assert_eq!(expect.as_ref(), store.collection(&key).unwrap());
+        store.compact().unwrap();
+        let mut store = BitcaskEngine::open(path).unwrap();
+        assert_eq!(expect.as_ref(), store.collection(&key).unwrap());
+    }
+
+    #[test]
+    fn test_notify() {
+        let mut store = get_test_store();
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let filter = EventFilter::new(EventClasses::parse("g$vhx").unwrap());
+        let sink = events.clone();
+        store.set_notifier(Notifier::new(
+            filter.clone(),
+            Box::new(move |event: KeyEvent| {
+                let value = event.value.map(|value| String::from_utf8(value).unwrap());
+                let key = String::from_utf8(event.key).unwrap();
+                sink.lock().unwrap().push((event.op, key, value));
+            }),
+        ));
+
+        store.set(Vec::from("a"), Vec::from("1")).unwrap();
+        store.append(Vec::from("a"), Vec::from("2")).unwrap();
+        let expire_at = now_millis() + 20;
+        let options = SetOptions {
+            expire_at: Some(expire_at),
+            ..SetOptions::default()
+        };
+        store
+            .set_with(Vec::from("b"), Vec::from("3"), options)
+            .unwrap();
+        store.mset(vec![(Vec::from("c"), Vec::from("4"))]).unwrap();
+        store.mdel(vec![Vec::from("a"), Vec::from("x")]).unwrap();
+        // the events of a disabled class are not emitted
+        store
+            .change(Vec::from("s"), Change::SAdd(vec![Vec::from("m")]))
+            .unwrap();
+        store
+            .change(
+                Vec::from("h"),
+                Change::HSet(vec![(Vec::from("f"), Vec::from("v"))]),
+            )
+            .unwrap();
+        store
+            .change(Vec::from("h"), Change::HDel(vec![Vec::from("f")]))
+            .unwrap();
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(Ok(None), store.get(Vec::from("b")));
+        filter.set(EventClasses::NONE);
+        store.del(Vec::from("c")).unwrap();
+
+        let event =
+            |op, key: &str, value: Option<&str>| (op, key.to_string(), value.map(str::to_string));
+        assert_eq!(
+            vec![
+                event("set", "a", Some("1")),
+                event("set", "a", Some("12")),
+                event("set", "b", Some("3")),
+                event("expire", "b", None),
+                event("set", "c", Some("4")),
+                event("del", "a", None),
+                event("hset", "h", None),
+                event("hdel", "h", None),
+                event("expired", "b", None),
+            ],
+            *events.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_changes_since() {
+        let path = get_tmp_store_path();
+        let keys = |changes: Vec<Command>| -> Vec<(u64, String)> {
+            changes
+                .into_iter()
+                .map(|cmd| {
+                    let key = match &cmd {
+                        Command::Set { key, .. }
+                        | Command::Del { key, .. }
+                        | Command::Change { key, .. } => key.clone(),
+                        _ => panic!("{:?} is not a change", cmd),
+                    };
+                    (cmd.seq(), String::from_utf8(key).unwrap())
+                })
+                .collect()
+        };
+        {
+            let mut store = BitcaskEngine::open(path.clone()).unwrap();
+            assert_eq!(Ok(Ok(Vec::new())), store.changes_since(0, 10));
+            store.set(Vec::from("a"), Vec::from("1")).unwrap();
+            store
+                .mset(vec![
+                    (Vec::from("b"), Vec::from("2")),
+                    (Vec::from("c"), Vec::from("3")),
+                ])
+                .unwrap();
+            store
+                .change(Vec::from("s"), Change::SAdd(vec![Vec::from("m")]))
+                .unwrap();
+            store.del(Vec::from("a")).unwrap();
+            // the records are spread over several wal files
+            for i in 0..50 {
+                store.set(Vec::from("x"), vec![b'x'; i]).unwrap();
+            }
+            assert!(store.file_store.read_logs.len() > 1);
+
+            let changes = store.changes_since(0, 5).unwrap().unwrap();
+            assert_eq!(
+                Command::Set {
+                    seq: 1,
+                    key: Vec::from("a"),
+                    value: Vec::from("1"),
+                    expire_at: None,
+                },
+                changes[0]
+            );
+            assert_eq!(
+                vec![
+                    (1, "a".to_string()),
+                    (2, "b".to_string()),
+                    (3, "c".to_string()),
+                    (4, "s".to_string()),
+                    (5, "a".to_string()),
+                ],
+                keys(changes)
+            );
+            let changes = store.changes_since(53, 10).unwrap().unwrap();
+            assert_eq!(
+                vec![(54, "x".to_string()), (55, "x".to_string())],
+                keys(changes)
+            );
+            assert_eq!(Ok(Ok(Vec::new())), store.changes_since(55, 10));
+        }
+
+        // the sequence numbers go on after the store is opened again
+        let mut store = BitcaskEngine::open(path.clone()).unwrap();
+        assert_eq!("55", info_field(&store, "wal_last_seq"));
+        store.set(Vec::from("y"), Vec::from("1")).unwrap();
+        let changes = store.changes_since(54, 10).unwrap().unwrap();
+        assert_eq!(
+            vec![(55, "x".to_string()), (56, "y".to_string())],
+            keys(changes)
+        );
+
+        // the changes before a compaction can not be read any more
+        store.compact().unwrap();
+        store.set(Vec::from("z"), Vec::from("1")).unwrap();
+        assert_eq!(Ok(Err(56)), store.changes_since(0, 10));
+        assert_eq!(Ok(Err(56)), store.changes_since(55, 10));
+        let changes = store.changes_since(56, 10).unwrap().unwrap();
+        assert_eq!(vec![(57, "z".to_string())], keys(changes));
+        drop(store);
+
+        let mut store = BitcaskEngine::open(path.clone()).unwrap();
+        assert_eq!("56", info_field(&store, "wal_compacted_seq"));
+        assert_eq!(Ok(Err(56)), store.changes_since(10, 10));
+        assert_eq!("0", info_field(&store, "wal_dead_bytes"));
+
+        // a record written before the sequence numbers counts as compacted
+        let path = get_tmp_store_path();
+        fs::create_dir_all(&path).unwrap();
+        let data = br#"{"Set":{"key":[97],"value":[49]}}"#;
+        fs::write(FileStore::wal_path(&path, 0), data).unwrap();
+        let mut store = BitcaskEngine::open(path).unwrap();
+        assert_eq!(Ok(Some(Vec::from("1"))), store.get(Vec::from("a")));
+        assert_eq!(Ok(Err(1)), store.changes_since(0, 10));
+        store.set(Vec::from("b"), Vec::from("2")).unwrap();
+        let changes = store.changes_since(1, 10).unwrap().unwrap();
+        assert_eq!(vec![(2, "b".to_string())], keys(changes));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut store = get_test_store();
+        assert_eq!(Ok((0, Vec::new())), store.snapshot());
+        let expire_at = now_millis() + 100_000;
+        let options = SetOptions {
+            expire_at: Some(expire_at),
+            ..SetOptions::default()
+        };
+        store
+            .set_with(Vec::from("a"), Vec::from("1"), options)
+            .unwrap();
+        store
+            .mset(vec![
+                (Vec::from("b"), Vec::from("2")),
+                (Vec::from("c"), Vec::from("3")),
+            ])
+            .unwrap();
+        store
+            .change(Vec::from("s"), Change::SAdd(vec![Vec::from("m")]))
+            .unwrap();
+        store.del(Vec::from("c")).unwrap();
+        let set = store.collection(b"s").unwrap().unwrap().clone();
+
+        assert_eq!(
+            Ok((
+                5,
+                vec![
+                    Command::Set {
+                        seq: 5,
+                        key: Vec::from("a"),
+                        value: Vec::from("1"),
+                        expire_at: Some(expire_at),
+                    },
+                    Command::Set {
+                        seq: 5,
+                        key: Vec::from("b"),
+                        value: Vec::from("2"),
+                        expire_at: None,
+                    },
+                    Command::Change {
+                        seq: 5,
+                        key: Vec::from("s"),
+                        change: Change::Restore(set.clone()),
+                    },
+                ]
+            )),
+            store.snapshot()
+        );
+        assert_eq!(
+            vec![Vec::from("a"), Vec::from("b"), Vec::from("s")],
+            store.keys()
+        );
+
+        // a single key is dumped the same way without a sequence number
+        assert_eq!(
+            Ok(Some(Command::set_expire_at(
+                Vec::from("a"),
+                Vec::from("1"),
+                Some(expire_at)
+            ))),
+            store.dump(b"a")
+        );
+        assert_eq!(
+            Ok(Some(Command::change(Vec::from("s"), Change::Restore(set)))),
+            store.dump(b"s")
+        );
+        assert_eq!(Ok(None), store.dump(b"c"));
+    }
+
+    fn info_field(store: &BitcaskEngine, name: &str) -> String {
+        let (_, value) = store.info().into_iter().find(|(k, _)| k == name).unwrap();
+        value
+    }
+
     fn get_test_store() -> BitcaskEngine {
         let path = get_tmp_store_path();
         BitcaskEngine::open(path).unwrap()
@@ -1,31 +1,228 @@
-use super::KvdEngine;
-use crate::model::KvdResult;
+use super::bitcask::Command;
+use super::notify::{EventClasses, Notifier};
+use super::value::{Change, Collection};
+use super::{now_millis, KvdEngine, SetOptions};
+use crate::model::{KvdError, KvdErrorKind, KvdResult};
 use std::collections::HashMap;
 
 pub struct MemoryEngine {
     map: HashMap<Vec<u8>, Vec<u8>>,
+    /// the keys which hold a collection, they are never in map
+    collections: HashMap<Vec<u8>, Collection>,
+    /// the expire times of the keys which have one
+    expires: HashMap<Vec<u8>, u64>,
+    notifier: Notifier,
 }
 
 impl MemoryEngine {
     pub fn new() -> MemoryEngine {
         MemoryEngine {
             map: HashMap::new(),
+            collections: HashMap::new(),
+            expires: HashMap::new(),
+            notifier: Notifier::default(),
         }
     }
+
+    /// remove the key if it is expired
+    fn expire(&mut self, key: &[u8]) {
+        if self.expires.get(key).is_some_and(|&at| at <= now_millis()) {
+            self.expires.remove(key);
+            self.map.remove(key);
+            self.notifier
+                .emit(EventClasses::EXPIRED, "expired", key, None);
+        }
+    }
+
+    /// fail with WrongType if the key holds a collection
+    fn check_string(&self, key: &[u8]) -> KvdResult<()> {
+        if self.collections.contains_key(key) {
+            return Err(KvdError::from(KvdErrorKind::WrongType));
+        }
+        Ok(())
+    }
+}
+
+impl Default for MemoryEngine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl KvdEngine for MemoryEngine {
     fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> KvdResult<()> {
+        self.expires.remove(&key);
+        self.collections.remove(&key);
+        let event_value = self.notifier.is_value_enabled().then(|| value.clone());
+        self.notifier
+            .emit(EventClasses::STRING, "set", &key, event_value);
         self.map.insert(key, value);
         Ok(())
     }
 
     fn get(&mut self, key: Vec<u8>) -> KvdResult<Option<Vec<u8>>> {
+        self.expire(&key);
+        self.check_string(&key)?;
         Ok(self.map.get(&key).cloned())
     }
 
     fn del(&mut self, key: Vec<u8>) -> KvdResult<()> {
-        self.map.remove(&key);
+        self.expires.remove(&key);
+        let collection = self.collections.remove(&key);
+        let value = self.map.remove(&key);
+        if collection.is_some() || value.is_some() {
+            self.notifier.emit(EventClasses::GENERIC, "del", &key, None);
+        }
+        Ok(())
+    }
+
+    fn set_with(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        options: SetOptions,
+    ) -> KvdResult<(bool, Option<Vec<u8>>)> {
+        self.expire(&key);
+        let old = if options.get {
+            self.check_string(&key)?;
+            self.map.get(&key).cloned()
+        } else {
+            None
+        };
+        let exists = self.map.contains_key(&key) || self.collections.contains_key(&key);
+        if !options.condition.holds(exists) {
+            return Ok((false, old));
+        }
+        self.set(key.clone(), value)?;
+        if let Some(at) = options.expire_at {
+            self.expires.insert(key.clone(), at);
+            self.notifier
+                .emit(EventClasses::GENERIC, "expire", &key, None);
+        }
+        Ok((true, old))
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> KvdResult<Result<(), Option<Vec<u8>>>> {
+        self.expire(&key);
+        self.check_string(&key)?;
+        let current = self.map.get(&key);
+        if current != expected.as_ref() {
+            return Ok(Err(current.cloned()));
+        }
+        match new {
+            Some(value) => self.set(key, value)?,
+            None => self.del(key)?,
+        }
+        Ok(Ok(()))
+    }
+
+    fn update<F>(&mut self, key: Vec<u8>, f: F) -> KvdResult<Vec<u8>>
+    where
+        F: FnOnce(Option<Vec<u8>>) -> KvdResult<Vec<u8>>,
+    {
+        self.expire(&key);
+        self.check_string(&key)?;
+        let new = f(self.map.get(&key).cloned())?;
+        let event_value = self.notifier.is_value_enabled().then(|| new.clone());
+        self.notifier
+            .emit(EventClasses::STRING, "set", &key, event_value);
+        self.map.insert(key, new.clone());
+        Ok(new)
+    }
+
+    fn set_notifier(&mut self, notifier: Notifier) {
+        self.notifier = notifier;
+    }
+
+    fn collection(&mut self, key: &[u8]) -> KvdResult<Option<&Collection>> {
+        self.expire(key);
+        if self.map.contains_key(key) {
+            return Err(KvdError::from(KvdErrorKind::WrongType));
+        }
+        Ok(self.collections.get(key))
+    }
+
+    /// the engine does not log its changes, so the sequence number is always 0
+    fn snapshot(&mut self) -> KvdResult<(u64, Vec<Command>)> {
+        let now = now_millis();
+        let mut cmds = Vec::with_capacity(self.key_count());
+        for (key, value) in self.map.iter() {
+            let expire_at = self.expires.get(key).copied();
+            if expire_at.is_some_and(|at| at <= now) {
+                continue;
+            }
+            cmds.push(Command::set_expire_at(
+                key.clone(),
+                value.clone(),
+                expire_at,
+            ));
+        }
+        for (key, value) in self.collections.iter() {
+            cmds.push(Command::change(key.clone(), Change::Restore(value.clone())));
+        }
+        Ok((0, cmds))
+    }
+
+    fn dump(&mut self, key: &[u8]) -> KvdResult<Option<Command>> {
+        self.expire(key);
+        if let Some(value) = self.map.get(key) {
+            let expire_at = self.expires.get(key).copied();
+            return Ok(Some(Command::set_expire_at(
+                key.to_vec(),
+                value.clone(),
+                expire_at,
+            )));
+        }
+        Ok(self
+            .collections
+            .get(key)
+            .map(|value| Command::change(key.to_vec(), Change::Restore(value.clone()))))
+    }
+
+    /// a restore replaces a string too
+    fn change(&mut self, key: Vec<u8>, change: Change) -> KvdResult<()> {
+        if let Change::Restore(_) = change {
+            self.expire(&key);
+            self.expires.remove(&key);
+            self.map.remove(&key);
+        } else {
+            change.check(self.collection(&key)?)?;
+        }
+        let current = self.collections.remove(&key);
+        let before = current.as_ref().map(Collection::value_type);
+        let name = change.name();
+        let value = change.apply(current);
+        self.notifier
+            .emit_change(name, &key, before, value.as_ref());
+        if let Some(value) = value {
+            self.collections.insert(key, value);
+        }
         Ok(())
     }
+
+    fn exists(&mut self, key: Vec<u8>) -> KvdResult<bool> {
+        self.expire(&key);
+        Ok(self.map.contains_key(&key) || self.collections.contains_key(&key))
+    }
+
+    fn key_count(&self) -> usize {
+        self.map.len() + self.collections.len()
+    }
+
+    fn keys(&self) -> Vec<Vec<u8>> {
+        self.map
+            .keys()
+            .chain(self.collections.keys())
+            .cloned()
+            .collect()
+    }
+
+    fn info(&self) -> Vec<(String, String)> {
+        vec![("engine".to_string(), "memory".to_string())]
+    }
 }
@@ -1,10 +1,284 @@
 pub mod bitcask;
 pub mod memory;
+pub mod notify;
+pub mod sorted_set;
+pub mod value;
 
-use crate::model::KvdResult;
+use crate::metrics::WalMetrics;
+use crate::model::{KvdError, KvdErrorKind, KvdResult};
+use bitcask::Command;
+use notify::Notifier;
+use std::str;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use value::{Change, Collection, ValueType};
 
-pub trait KvdEngine {
+/// the max size of a value built by SETRANGE
+pub const MAX_VALUE_SIZE: usize = 512 * 1024 * 1024;
+
+/// When a conditional set writes the value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SetCondition {
+    Always,
+    /// only if the key does not exist
+    NotExists,
+    /// only if the key exists
+    Exists,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SetOptions {
+    pub condition: SetCondition,
+    /// the unix time in milliseconds when the key expires, None means never
+    pub expire_at: Option<u64>,
+    /// whether the old value is returned
+    pub get: bool,
+}
+
+impl SetCondition {
+    pub fn holds(self, exists: bool) -> bool {
+        match self {
+            SetCondition::Always => true,
+            SetCondition::NotExists => !exists,
+            SetCondition::Exists => exists,
+        }
+    }
+}
+
+impl Default for SetOptions {
+    fn default() -> Self {
+        SetOptions {
+            condition: SetCondition::Always,
+            expire_at: None,
+            get: false,
+        }
+    }
+}
+
+/// the unix time in milliseconds, which the expire times are compared with
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |t| t.as_millis() as u64)
+}
+
+/// Turn the inclusive offsets of GETRANGE or LRANGE into a range of a value of the
+/// length, a negative offset counts from the end. None if the range is empty.
+pub fn resolve_range(len: usize, start: i64, end: i64) -> Option<std::ops::Range<usize>> {
+    let len = len as i64;
+    let start = if start < 0 { start + len } else { start }.max(0);
+    let end = if end < 0 { end + len } else { end }.min(len - 1);
+    if start > end {
+        return None;
+    }
+    Some(start as usize..end as usize + 1)
+}
+
+/// parse a value stored as a decimal string
+pub fn parse_number<N: str::FromStr>(value: &[u8]) -> Option<N> {
+    str::from_utf8(value).ok()?.parse().ok()
+}
+
+/// A key set with an expire time is treated as missing once the time is
+/// reached, and a plain set of the key removes its expire time.
+///
+/// A key holds either a string or a collection. The string methods fail
+/// with WrongType on a collection, except set, del and exists which work on
+/// any key.
+///
+/// Every successful change of a key, including the removal of an expired
+/// key, is emitted as a keyspace event to the notifier of the engine.
+pub trait KvdEngine: Send + 'static {
     fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> KvdResult<()>;
     fn get(&mut self, key: Vec<u8>) -> KvdResult<Option<Vec<u8>>>;
     fn del(&mut self, key: Vec<u8>) -> KvdResult<()>;
+    /// Set the value if the condition holds, return whether it is set and the
+    /// old value if `options.get` is true. The check and the set are atomic.
+    fn set_with(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        options: SetOptions,
+    ) -> KvdResult<(bool, Option<Vec<u8>>)>;
+    /// Replace the value with `new` if the current value is `expected`, where
+    /// None means the key does not exist, so a new of None deletes the key.
+    /// The current value is returned on mismatch. A swapped key loses its
+    /// expire time like a plain set.
+    fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> KvdResult<Result<(), Option<Vec<u8>>>>;
+    /// Replace the value with the one computed from it, None if the key does
+    /// not exist, and return the new value. The new value is written once and
+    /// the expire time of the key is kept. Nothing is written if f fails.
+    fn update<F>(&mut self, key: Vec<u8>, f: F) -> KvdResult<Vec<u8>>
+    where
+        F: FnOnce(Option<Vec<u8>>) -> KvdResult<Vec<u8>>;
+    /// add the delta to the integer value, a missing key counts as 0
+    fn incr_by(&mut self, key: Vec<u8>, delta: i64) -> KvdResult<i64> {
+        let value = self.update(key, |value| {
+            let current = match value {
+                Some(value) => parse_number::<i64>(&value)
+                    .ok_or_else(|| KvdError::from(KvdErrorKind::NotAnInteger))?,
+                None => 0,
+            };
+            let new = current
+                .checked_add(delta)
+                .ok_or_else(|| KvdError::from(KvdErrorKind::IncrementOverflow))?;
+            Ok(new.to_string().into_bytes())
+        })?;
+        Ok(parse_number(&value).unwrap_or_default())
+    }
+    /// add the delta to the float value, a missing key counts as 0
+    fn incr_by_float(&mut self, key: Vec<u8>, delta: f64) -> KvdResult<f64> {
+        let value = self.update(key, |value| {
+            let current = match value {
+                Some(value) => parse_number::<f64>(&value)
+                    .filter(|current| current.is_finite())
+                    .ok_or_else(|| KvdError::from(KvdErrorKind::NotAFloat))?,
+                None => 0.0,
+            };
+            let new = current + delta;
+            if !new.is_finite() {
+                return Err(KvdError::from(KvdErrorKind::IncrementOverflow));
+            }
+            Ok(new.to_string().into_bytes())
+        })?;
+        Ok(parse_number(&value).unwrap_or_default())
+    }
+    /// append the suffix to the value, a missing key counts as empty, return the new length
+    fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> KvdResult<usize> {
+        let value = self.update(key, |value| {
+            let mut value = value.unwrap_or_default();
+            value.extend(suffix);
+            Ok(value)
+        })?;
+        Ok(value.len())
+    }
+    /// the length of the value, 0 if the key does not exist
+    fn strlen(&mut self, key: Vec<u8>) -> KvdResult<usize> {
+        Ok(self.get(key)?.map_or(0, |value| value.len()))
+    }
+    /// the bytes from start to end inclusive, where a negative offset counts from the end
+    fn get_range(&mut self, key: Vec<u8>, start: i64, end: i64) -> KvdResult<Vec<u8>> {
+        let value = self.get(key)?.unwrap_or_default();
+        Ok(resolve_range(value.len(), start, end)
+            .map_or_else(Vec::new, |range| value[range].to_vec()))
+    }
+    /// Overwrite the value from the offset, the value is padded with zeros if
+    /// it is shorter than the offset. Return the new length.
+    fn set_range(&mut self, key: Vec<u8>, offset: usize, bytes: Vec<u8>) -> KvdResult<usize> {
+        let end = offset
+            .checked_add(bytes.len())
+            .filter(|&end| end <= MAX_VALUE_SIZE)
+            .ok_or_else(|| KvdError::from(KvdErrorKind::ValueTooLarge))?;
+        if bytes.is_empty() {
+            // nothing is written, and a missing key is not created
+            return self.strlen(key);
+        }
+        let value = self.update(key, |value| {
+            let mut value = value.unwrap_or_default();
+            if value.len() < end {
+                value.resize(end, 0);
+            }
+            value[offset..end].copy_from_slice(&bytes);
+            Ok(value)
+        })?;
+        Ok(value.len())
+    }
+    /// Emit the keyspace events through the notifier, which replaces the
+    /// previous one.
+    fn set_notifier(&mut self, notifier: Notifier);
+    /// The changes logged after the sequence number in order, at most limit
+    /// of them. Err with the oldest sequence number which can be asked for
+    /// if the changes after seq are compacted away.
+    fn changes_since(&mut self, _seq: u64, _limit: usize) -> KvdResult<Result<Vec<Command>, u64>> {
+        Err(KvdError::from(KvdErrorKind::NoChangeLog))
+    }
+    /// The current value of every key as a change which replaces it, with
+    /// the sequence number of the last change logged, so that the changes
+    /// after the number are the ones made after the snapshot.
+    fn snapshot(&mut self) -> KvdResult<(u64, Vec<Command>)> {
+        Err(KvdError::from(KvdErrorKind::NoChangeLog))
+    }
+    /// the current value of the key as a change which replaces it, None if
+    /// the key is missing
+    fn dump(&mut self, key: &[u8]) -> KvdResult<Option<Command>>;
+    /// the collection of the key, WrongType if the key holds a string
+    fn collection(&mut self, key: &[u8]) -> KvdResult<Option<&Collection>>;
+    /// Apply the change to the collection of the key, it is logged as one
+    /// record. WrongType if the key holds a value of another type.
+    fn change(&mut self, key: Vec<u8>, change: Change) -> KvdResult<()>;
+    /// delete the key and return its value
+    fn getdel(&mut self, key: Vec<u8>) -> KvdResult<Option<Vec<u8>>> {
+        let value = self.get(key.clone())?;
+        if value.is_some() {
+            self.del(key)?;
+        }
+        Ok(value)
+    }
+    /// the type of the value of the key, None if the key is missing
+    fn key_type(&mut self, key: &[u8]) -> KvdResult<Option<ValueType>> {
+        match self.collection(key) {
+            Ok(Some(collection)) => Ok(Some(collection.value_type())),
+            Ok(None) => Ok(None),
+            // only a string key makes collection fail
+            Err(ref e) if e.kind() == KvdErrorKind::WrongType => Ok(Some(ValueType::String)),
+            Err(e) => Err(e),
+        }
+    }
+    fn exists(&mut self, key: Vec<u8>) -> KvdResult<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+    /// a key which is not a string is returned as None
+    fn mget(&mut self, keys: Vec<Vec<u8>>) -> KvdResult<Vec<Option<Vec<u8>>>> {
+        keys.into_iter()
+            .map(|key| match self.get(key) {
+                Err(ref e) if e.kind() == KvdErrorKind::WrongType => Ok(None),
+                result => result,
+            })
+            .collect()
+    }
+    /// set all the pairs or none of them, an engine with a log should write them as one record
+    fn mset(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> KvdResult<()> {
+        for (key, value) in pairs {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+    /// set all the pairs only if none of the keys exists, return whether they are set
+    fn msetnx(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> KvdResult<bool> {
+        for (key, _) in pairs.iter() {
+            if self.exists(key.clone())? {
+                return Ok(false);
+            }
+        }
+        self.mset(pairs)?;
+        Ok(true)
+    }
+    /// delete the existing keys, return the number of deleted keys
+    fn mdel(&mut self, keys: Vec<Vec<u8>>) -> KvdResult<usize> {
+        let mut deleted = 0;
+        for key in keys {
+            if self.exists(key.clone())? {
+                self.del(key)?;
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+    /// the number of keys stored in the engine
+    fn key_count(&self) -> usize;
+    /// the keys stored in the engine, an expired key may be included until it is removed
+    fn keys(&self) -> Vec<Vec<u8>>;
+    /// engine specific fields shown in the engine section of INFO
+    fn info(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+    /// the metrics of the write ahead log, None if the engine does not have one
+    fn wal_metrics(&self) -> Option<Arc<WalMetrics>> {
+        None
+    }
 }
@@ -0,0 +1,243 @@
+//! The keyspace events emitted by the engines when a key is changed.
+//!
+//! Every successful change of a key is passed to the listener of the engine
+//! as a `KeyEvent`, if its class is enabled by the filter. The classes follow
+//! the notify-keyspace-events flags of redis.
+
+use super::value::{Collection, ValueType};
+use crate::model::{KvdError, KvdErrorKind, KvdResult};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+/// A set of event classes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EventClasses(u8);
+
+/// A change of a key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyEvent {
+    pub class: EventClasses,
+    pub key: Vec<u8>,
+    /// like "set", "del" or "hset"
+    pub op: &'static str,
+    /// the new value of a set if the value class is enabled, None for the
+    /// other operations
+    pub value: Option<Vec<u8>>,
+}
+
+pub type EventListener = Box<dyn Fn(KeyEvent) + Send>;
+
+/// The classes emitted, shared by the engine and the server so that they
+/// can be changed while serving, cheap to clone.
+#[derive(Clone, Debug, Default)]
+pub struct EventFilter(Arc<AtomicU8>);
+
+/// Passes the enabled events to the listener, nothing is emitted by default.
+#[derive(Default)]
+pub struct Notifier {
+    filter: EventFilter,
+    listener: Option<EventListener>,
+}
+
+/// the flag of every class, in the order they are formatted
+const FLAGS: [(char, EventClasses); 8] = [
+    ('g', EventClasses::GENERIC),
+    ('$', EventClasses::STRING),
+    ('l', EventClasses::LIST),
+    ('s', EventClasses::SET),
+    ('h', EventClasses::HASH),
+    ('z', EventClasses::SORTED_SET),
+    ('x', EventClasses::EXPIRED),
+    ('v', EventClasses::VALUE),
+];
+
+impl EventClasses {
+    pub const NONE: EventClasses = EventClasses(0);
+    /// del, and expire when an expire time is set
+    pub const GENERIC: EventClasses = EventClasses(1);
+    /// set of a string
+    pub const STRING: EventClasses = EventClasses(1 << 1);
+    pub const LIST: EventClasses = EventClasses(1 << 2);
+    pub const SET: EventClasses = EventClasses(1 << 3);
+    pub const HASH: EventClasses = EventClasses(1 << 4);
+    pub const SORTED_SET: EventClasses = EventClasses(1 << 5);
+    /// a key removed since its expire time is reached
+    pub const EXPIRED: EventClasses = EventClasses(1 << 6);
+    /// all but the value
+    pub const ALL: EventClasses = EventClasses((1 << 7) - 1);
+    /// the new value is carried by the events of a set of a string, it is
+    /// left out of ALL since it is copied
+    pub const VALUE: EventClasses = EventClasses(1 << 7);
+
+    /// the class of the changes of a collection
+    pub fn of(value_type: ValueType) -> EventClasses {
+        match value_type {
+            ValueType::String => EventClasses::STRING,
+            ValueType::Hash => EventClasses::HASH,
+            ValueType::List => EventClasses::LIST,
+            ValueType::Set => EventClasses::SET,
+            ValueType::SortedSet => EventClasses::SORTED_SET,
+        }
+    }
+
+    pub fn contains(self, classes: EventClasses) -> bool {
+        self.0 & classes.0 == classes.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Parse flags like "g$x", where "A" is an alias of all the classes and
+    /// an empty string disables the events.
+    pub fn parse(flags: &str) -> KvdResult<EventClasses> {
+        let mut classes = EventClasses::NONE;
+        for flag in flags.chars() {
+            let class = match flag {
+                'A' => EventClasses::ALL,
+                flag => FLAGS
+                    .iter()
+                    .find(|(c, _)| *c == flag)
+                    .map(|(_, class)| *class)
+                    .ok_or_else(|| KvdError::from(KvdErrorKind::InvalidEventClasses))?,
+            };
+            classes.0 |= class.0;
+        }
+        Ok(classes)
+    }
+}
+
+impl std::fmt::Display for EventClasses {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (flag, class) in FLAGS.iter() {
+            if self.contains(*class) {
+                write!(f, "{}", flag)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl EventFilter {
+    pub fn new(classes: EventClasses) -> EventFilter {
+        EventFilter(Arc::new(AtomicU8::new(classes.0)))
+    }
+
+    pub fn get(&self) -> EventClasses {
+        EventClasses(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, classes: EventClasses) {
+        self.0.store(classes.0, Ordering::Relaxed);
+    }
+}
+
+impl Notifier {
+    pub fn new(filter: EventFilter, listener: EventListener) -> Notifier {
+        Notifier {
+            filter,
+            listener: Some(listener),
+        }
+    }
+
+    /// whether the events of the class are emitted, so that the value of an
+    /// event is only copied when it is needed
+    pub(crate) fn is_enabled(&self, class: EventClasses) -> bool {
+        self.listener.is_some() && self.filter.get().contains(class)
+    }
+
+    /// whether the events of a set carry the new value
+    pub(crate) fn is_value_enabled(&self) -> bool {
+        self.is_enabled(EventClasses::STRING) && self.is_enabled(EventClasses::VALUE)
+    }
+
+    pub(crate) fn emit(
+        &self,
+        class: EventClasses,
+        op: &'static str,
+        key: &[u8],
+        value: Option<Vec<u8>>,
+    ) {
+        if let Some(listener) = self.listener.as_ref().filter(|_| self.is_enabled(class)) {
+            listener(KeyEvent {
+                class,
+                key: key.to_vec(),
+                op,
+                value,
+            });
+        }
+    }
+
+    /// Emit the change of a collection, whose class is the type of the value
+    /// before or after the change.
+    pub(crate) fn emit_change(
+        &self,
+        op: &'static str,
+        key: &[u8],
+        before: Option<ValueType>,
+        after: Option<&Collection>,
+    ) {
+        let value_type = after.map(Collection::value_type).or(before);
+        if let Some(value_type) = value_type {
+            self.emit(EventClasses::of(value_type), op, key, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_event_classes() {
+        assert_eq!(EventClasses::NONE, EventClasses::parse("").unwrap());
+        assert_eq!(EventClasses::ALL, EventClasses::parse("A").unwrap());
+        assert_eq!("g$lshzx", EventClasses::ALL.to_string());
+        assert_eq!("$v", EventClasses::parse("v$").unwrap().to_string());
+        let classes = EventClasses::parse("x$g").unwrap();
+        assert_eq!("g$x", classes.to_string());
+        assert!(classes.contains(EventClasses::EXPIRED));
+        assert!(!classes.contains(EventClasses::HASH));
+        assert_eq!(
+            KvdErrorKind::InvalidEventClasses,
+            EventClasses::parse("gK").unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn test_notifier() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let filter = EventFilter::new(EventClasses::STRING);
+        let notifier = {
+            let events = events.clone();
+            Notifier::new(
+                filter.clone(),
+                Box::new(move |event| events.lock().unwrap().push(event)),
+            )
+        };
+        notifier.emit(EventClasses::STRING, "set", b"a", Some(b"1".to_vec()));
+        notifier.emit(EventClasses::GENERIC, "del", b"a", None);
+        filter.set(EventClasses::ALL);
+        notifier.emit_change("hdel", b"b", Some(ValueType::Hash), None);
+        assert_eq!(
+            vec![
+                KeyEvent {
+                    class: EventClasses::STRING,
+                    key: b"a".to_vec(),
+                    op: "set",
+                    value: Some(b"1".to_vec()),
+                },
+                KeyEvent {
+                    class: EventClasses::HASH,
+                    key: b"b".to_vec(),
+                    op: "hdel",
+                    value: None,
+                },
+            ],
+            *events.lock().unwrap()
+        );
+        assert!(!notifier.is_value_enabled());
+        assert!(!Notifier::default().is_enabled(EventClasses::STRING));
+    }
+}
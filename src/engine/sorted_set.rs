@@ -0,0 +1,539 @@
+//! The value of a sorted set, ordered by score and then by member.
+
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cmp::Ordering;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::ops::Range;
+
+/// A score which is not NaN, so that scores are totally ordered.
+///
+/// It is written as a string since json has no infinity.
+#[derive(Clone, Copy, Debug)]
+pub struct Score(f64);
+
+/// The members of a sorted set, with a tree ordered on score and member and
+/// a map to find the score of a member.
+///
+/// The tree counts the members under each of its nodes, so a member is
+/// inserted or removed, its rank is found and a range is started in
+/// O(log n). Every member iterated costs O(log n) too.
+#[derive(Clone, Debug, Default)]
+pub struct SortedSet {
+    scores: HashMap<Vec<u8>, Score>,
+    ordered: Tree,
+}
+
+type Entry = (Score, Vec<u8>);
+
+type Link = Option<Box<Node>>;
+
+/// A treap of the entries of a sorted set, a binary search tree which is
+/// also a heap of random priorities so that it stays balanced.
+#[derive(Clone, Debug, Default)]
+struct Tree {
+    root: Link,
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    entry: Entry,
+    priority: u64,
+    /// the number of entries of the subtree
+    size: usize,
+    left: Link,
+    right: Link,
+}
+
+/// The members and their scores at a range of ranks.
+struct Iter<'a> {
+    tree: &'a Tree,
+    ranks: Range<usize>,
+}
+
+/// A bound of a range of scores, or of members ordered by their bytes.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RangeBound<T> {
+    Inclusive(T),
+    Exclusive(T),
+    Unbounded,
+}
+
+impl Score {
+    /// None if the score is NaN
+    pub fn new(score: f64) -> Option<Score> {
+        if score.is_nan() {
+            return None;
+        }
+        // -0 and 0 are the same score
+        Some(Score(score + 0.0))
+    }
+
+    pub fn value(self) -> f64 {
+        self.0
+    }
+}
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl Serialize for Score {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Score {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let score = String::deserialize(deserializer)?;
+        score
+            .parse()
+            .ok()
+            .and_then(Score::new)
+            .ok_or_else(|| D::Error::custom(format!("invalid score {}", score)))
+    }
+}
+
+impl SortedSet {
+    pub fn new() -> SortedSet {
+        SortedSet::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).map(|score| score.0)
+    }
+
+    /// set the score of the member, return whether it is added
+    pub fn insert(&mut self, member: Vec<u8>, score: Score) -> bool {
+        match self.scores.insert(member.clone(), score) {
+            Some(old) => {
+                self.ordered.remove(&(old, member.clone()));
+                self.ordered.insert((score, member));
+                false
+            }
+            None => {
+                self.ordered.insert((score, member));
+                true
+            }
+        }
+    }
+
+    /// return whether the member is removed
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => self.ordered.remove(&(score, member.to_vec())),
+            None => false,
+        }
+    }
+
+    /// the position of the member in the order, counted from the lowest score
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let score = *self.scores.get(member)?;
+        Some(self.ordered.count_before(&(score, member.to_vec()), false))
+    }
+
+    /// The members and their scores from the lowest score. Skipping members
+    /// with nth costs O(log n) whatever their number.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&Vec<u8>, f64)> {
+        self.ordered.iter(0..self.len())
+    }
+
+    /// the members with a score in the range, from the lowest score
+    pub fn range_by_score(
+        &self,
+        min: RangeBound<f64>,
+        max: RangeBound<f64>,
+    ) -> impl DoubleEndedIterator<Item = (&Vec<u8>, f64)> {
+        // the number of members with a lower score, the empty member is the
+        // lowest of a score
+        let below = |score: f64| {
+            self.ordered
+                .count_before(&(Score(score), Vec::new()), false)
+        };
+        let start = match min {
+            RangeBound::Inclusive(min) => below(min),
+            // nothing is after +inf
+            RangeBound::Exclusive(min) if min == f64::INFINITY => self.len(),
+            RangeBound::Exclusive(min) => below(min.next_up()),
+            RangeBound::Unbounded => 0,
+        };
+        let end = match max {
+            RangeBound::Inclusive(max) if max == f64::INFINITY => self.len(),
+            RangeBound::Inclusive(max) => below(max.next_up()),
+            RangeBound::Exclusive(max) => below(max),
+            RangeBound::Unbounded => self.len(),
+        };
+        self.ordered.iter(start..end.max(start))
+    }
+
+    /// The members in the range of their bytes, in the order of their
+    /// scores, which only makes sense if all the scores are the same.
+    ///
+    /// The range is found in O(log n) if all the scores are the same, else
+    /// every member is looked at.
+    pub fn range_by_lex<'a>(
+        &'a self,
+        min: &'a RangeBound<Vec<u8>>,
+        max: &'a RangeBound<Vec<u8>>,
+    ) -> impl DoubleEndedIterator<Item = (&'a Vec<u8>, f64)> {
+        let lowest = self.ordered.get(0).map(|(score, _)| *score);
+        let highest = self
+            .len()
+            .checked_sub(1)
+            .and_then(|last| self.ordered.get(last));
+        let ranks = match (lowest, highest) {
+            (Some(score), Some((highest, _))) if score == *highest => {
+                let position = |bound: &RangeBound<Vec<u8>>, inclusive: bool, unbounded| match bound
+                {
+                    RangeBound::Inclusive(member) => self
+                        .ordered
+                        .count_before(&(score, member.clone()), inclusive),
+                    RangeBound::Exclusive(member) => self
+                        .ordered
+                        .count_before(&(score, member.clone()), !inclusive),
+                    RangeBound::Unbounded => unbounded,
+                };
+                let start = position(min, false, 0);
+                let end = position(max, true, self.len());
+                start..end.max(start)
+            }
+            _ => 0..self.len(),
+        };
+        // the bounds are checked again for the scores which are not the same
+        self.ordered.iter(ranks).filter(move |(member, _)| {
+            let above = match min {
+                RangeBound::Inclusive(min) => *member >= min,
+                RangeBound::Exclusive(min) => *member > min,
+                RangeBound::Unbounded => true,
+            };
+            let below = match max {
+                RangeBound::Inclusive(max) => *member <= max,
+                RangeBound::Exclusive(max) => *member < max,
+                RangeBound::Unbounded => true,
+            };
+            above && below
+        })
+    }
+}
+
+impl PartialEq for SortedSet {
+    fn eq(&self, other: &Self) -> bool {
+        // the order follows from the scores
+        self.scores == other.scores
+    }
+}
+
+impl Serialize for SortedSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter().map(|(member, score)| (member, Score(score))))
+    }
+}
+
+impl<'de> Deserialize<'de> for SortedSet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let members = Vec::<(Vec<u8>, Score)>::deserialize(deserializer)?;
+        let mut set = SortedSet::new();
+        for (member, score) in members {
+            set.insert(member, score);
+        }
+        Ok(set)
+    }
+}
+
+impl Tree {
+    /// the entry should not be in the tree yet
+    fn insert(&mut self, entry: Entry) {
+        // every RandomState is seeded differently, which is random enough
+        // for the priorities
+        let priority = RandomState::new().build_hasher().finish();
+        let (before, after) = split(self.root.take(), &entry);
+        let node = Box::new(Node {
+            entry,
+            priority,
+            size: 1,
+            left: None,
+            right: None,
+        });
+        self.root = merge(merge(before, Some(node)), after);
+    }
+
+    /// return whether the entry is removed
+    fn remove(&mut self, entry: &Entry) -> bool {
+        remove(&mut self.root, entry)
+    }
+
+    /// the number of entries before the entry, or not after it if inclusive
+    fn count_before(&self, entry: &Entry, inclusive: bool) -> usize {
+        let mut count = 0;
+        let mut link = &self.root;
+        while let Some(node) = link {
+            let before = match node.entry.cmp(entry) {
+                Ordering::Less => true,
+                Ordering::Equal => inclusive,
+                Ordering::Greater => false,
+            };
+            if before {
+                count += size(&node.left) + 1;
+                link = &node.right;
+            } else {
+                link = &node.left;
+            }
+        }
+        count
+    }
+
+    /// the entry at the rank
+    fn get(&self, mut rank: usize) -> Option<&Entry> {
+        let mut link = &self.root;
+        while let Some(node) = link {
+            let left = size(&node.left);
+            match rank.cmp(&left) {
+                Ordering::Less => link = &node.left,
+                Ordering::Equal => return Some(&node.entry),
+                Ordering::Greater => {
+                    rank -= left + 1;
+                    link = &node.right;
+                }
+            }
+        }
+        None
+    }
+
+    fn iter(&self, ranks: Range<usize>) -> Iter<'_> {
+        Iter { tree: self, ranks }
+    }
+}
+
+impl Node {
+    fn update_size(&mut self) {
+        self.size = size(&self.left) + 1 + size(&self.right);
+    }
+}
+
+fn size(link: &Link) -> usize {
+    link.as_ref().map_or(0, |node| node.size)
+}
+
+/// split the tree into the entries before the entry and the others
+fn split(link: Link, entry: &Entry) -> (Link, Link) {
+    match link {
+        None => (None, None),
+        Some(mut node) if node.entry < *entry => {
+            let (before, after) = split(node.right.take(), entry);
+            node.right = before;
+            node.update_size();
+            (Some(node), after)
+        }
+        Some(mut node) => {
+            let (before, after) = split(node.left.take(), entry);
+            node.left = after;
+            node.update_size();
+            (before, Some(node))
+        }
+    }
+}
+
+/// join two trees, all the entries of the first are before the second ones
+fn merge(first: Link, second: Link) -> Link {
+    match (first, second) {
+        (None, link) | (link, None) => link,
+        (Some(mut first), Some(mut second)) => {
+            if first.priority > second.priority {
+                first.right = merge(first.right.take(), Some(second));
+                first.update_size();
+                Some(first)
+            } else {
+                second.left = merge(Some(first), second.left.take());
+                second.update_size();
+                Some(second)
+            }
+        }
+    }
+}
+
+fn remove(link: &mut Link, entry: &Entry) -> bool {
+    let node = match link {
+        Some(node) => node,
+        None => return false,
+    };
+    let removed = match entry.cmp(&node.entry) {
+        Ordering::Less => remove(&mut node.left, entry),
+        Ordering::Greater => remove(&mut node.right, entry),
+        Ordering::Equal => {
+            let node = link.take().unwrap();
+            *link = merge(node.left, node.right);
+            return true;
+        }
+    };
+    if removed {
+        node.size -= 1;
+    }
+    removed
+}
+
+impl<'a> Iter<'a> {
+    fn member(&self, rank: usize) -> Option<(&'a Vec<u8>, f64)> {
+        let (score, member) = self.tree.get(rank)?;
+        Some((member, score.0))
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a Vec<u8>, f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rank = self.ranks.next()?;
+        self.member(rank)
+    }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        let rank = self.ranks.nth(n)?;
+        self.member(rank)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.ranks.size_hint()
+    }
+}
+
+impl DoubleEndedIterator for Iter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let rank = self.ranks.next_back()?;
+        self.member(rank)
+    }
+
+    fn nth_back(&mut self, n: usize) -> Option<Self::Item> {
+        let rank = self.ranks.nth_back(n)?;
+        self.member(rank)
+    }
+}
+
+impl ExactSizeIterator for Iter<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sorted_set() {
+        let mut set = SortedSet::new();
+        let score = |score: f64| Score::new(score).unwrap();
+        assert!(set.insert(Vec::from("b"), score(2.0)));
+        assert!(set.insert(Vec::from("a"), score(2.0)));
+        assert!(set.insert(Vec::from("c"), score(f64::NEG_INFINITY)));
+        assert!(!set.insert(Vec::from("c"), score(3.0)));
+        assert!(set.insert(Vec::from("d"), score(f64::INFINITY)));
+        let members = |iter: &mut dyn Iterator<Item = (&Vec<u8>, f64)>| -> String {
+            iter.map(|(member, _)| String::from_utf8(member.clone()).unwrap())
+                .collect()
+        };
+        assert_eq!("abcd", members(&mut set.iter()));
+        assert_eq!(Some(2), set.rank(b"c"));
+        assert_eq!(Some(3.0), set.score(b"c"));
+
+        use RangeBound::*;
+        let by_score = |min, max| members(&mut set.range_by_score(min, max));
+        assert_eq!("abc", by_score(Inclusive(2.0), Inclusive(3.0)));
+        assert_eq!("c", by_score(Exclusive(2.0), Exclusive(f64::INFINITY)));
+        assert_eq!("cd", by_score(Exclusive(2.0), Unbounded));
+        assert_eq!(
+            "d",
+            by_score(Inclusive(f64::INFINITY), Inclusive(f64::INFINITY))
+        );
+        assert_eq!("", by_score(Exclusive(f64::INFINITY), Unbounded));
+        assert_eq!("", by_score(Inclusive(3.0), Exclusive(2.0)));
+        assert_eq!(
+            "ba",
+            members(&mut set.range_by_score(Unbounded, Exclusive(3.0)).rev())
+        );
+        let (min, max) = (Exclusive(Vec::from("a")), Inclusive(Vec::from("c")));
+        assert_eq!("bc", members(&mut set.range_by_lex(&min, &max)));
+
+        // the infinite scores survive json
+        let data = serde_json::to_vec(&set).unwrap();
+        assert_eq!(set, serde_json::from_slice(&data).unwrap());
+
+        assert!(set.remove(b"a"));
+        assert!(!set.remove(b"a"));
+        assert_eq!(3, set.len());
+        assert_eq!(None, Score::new(f64::NAN));
+    }
+
+    #[test]
+    fn test_ranks() {
+        let mut set = SortedSet::new();
+        let mut expected = Vec::new();
+        for i in 0..1000 {
+            let member = format!("{:04}", i).into_bytes();
+            let score = f64::from(i % 10);
+            set.insert(member.clone(), Score::new(score).unwrap());
+            if i % 3 != 0 {
+                expected.push((Score(score), member));
+            }
+        }
+        for i in (0..1000).step_by(3) {
+            assert!(set.remove(format!("{:04}", i).as_bytes()));
+        }
+        expected.sort();
+        assert_eq!(expected.len(), set.len());
+        for (rank, (_, member)) in expected.iter().enumerate() {
+            assert_eq!(Some(rank), set.rank(member));
+        }
+        let members: Vec<_> = set.iter().map(|(member, _)| member.clone()).collect();
+        let expected: Vec<_> = expected.into_iter().map(|(_, member)| member).collect();
+        assert_eq!(expected, members);
+        assert_eq!(Some(&expected[500]), set.iter().nth(500).map(|(m, _)| m));
+        assert_eq!(
+            Some(&expected[100]),
+            set.iter().rev().nth(565).map(|(m, _)| m)
+        );
+
+        use RangeBound::*;
+        let count = |min, max| set.range_by_score(min, max).count();
+        assert_eq!(66, count(Inclusive(9.0), Unbounded));
+        assert_eq!(134, count(Exclusive(0.0), Exclusive(3.0)));
+
+        // the members of the same score are found by their bytes
+        let mut set = SortedSet::new();
+        for i in 0..100 {
+            set.insert(format!("{:02}", i).into_bytes(), Score::new(1.0).unwrap());
+        }
+        let (min, max) = (Exclusive(Vec::from("10")), Inclusive(Vec::from("20")));
+        let members: Vec<_> = set
+            .range_by_lex(&min, &max)
+            .map(|(m, _)| m.clone())
+            .collect();
+        let expected: Vec<_> = (11..=20)
+            .map(|i| format!("{:02}", i).into_bytes())
+            .collect();
+        assert_eq!(expected, members);
+        assert_eq!(0, set.range_by_lex(&max, &min).count());
+    }
+}
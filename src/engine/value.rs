@@ -0,0 +1,419 @@
+//! The data types other than string, which both engines keep in memory.
+//!
+//! A collection is changed only through a `Change`, which is also the record
+//! written to the wal, so replaying the changes in order rebuilds the value.
+
+use super::sorted_set::{Score, SortedSet};
+use crate::model::{KvdError, KvdErrorKind, KvdResult};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueType {
+    String,
+    Hash,
+    List,
+    Set,
+    SortedSet,
+}
+
+/// A field/value map, ordered so that HSCAN can resume from a position.
+pub type Hash = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// A list of elements, pushed and popped at both ends.
+pub type List = VecDeque<Vec<u8>>;
+
+/// A set of members, ordered so that SMEMBERS replies them in a stable order.
+pub type Set = BTreeSet<Vec<u8>>;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Collection {
+    Hash(#[serde(with = "pairs")] Hash),
+    List(List),
+    Set(Set),
+    SortedSet(SortedSet),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Change {
+    /// replace the value, written by a compaction
+    Restore(Collection),
+    /// set the fields of a hash, the hash is created if missing
+    HSet(Vec<(Vec<u8>, Vec<u8>)>),
+    /// delete the fields of a hash
+    HDel(Vec<Vec<u8>>),
+    /// push the elements to the head of a list one by one, the list is created if missing
+    LPush(Vec<Vec<u8>>),
+    /// push the elements to the tail of a list, the list is created if missing
+    RPush(Vec<Vec<u8>>),
+    /// remove so many elements from the head of a list
+    LPop(usize),
+    /// remove so many elements from the tail of a list
+    RPop(usize),
+    /// replace the element at the index, which is in the list
+    LSet(usize, Vec<u8>),
+    /// remove count elements equal to the value from the head, or from the
+    /// tail if count is negative, or all of them if count is 0
+    LRem(i64, Vec<u8>),
+    /// keep only the elements from start to end exclusive
+    LTrim(usize, usize),
+    /// add the members to a set, the set is created if missing
+    SAdd(Vec<Vec<u8>>),
+    /// remove the members from a set
+    SRem(Vec<Vec<u8>>),
+    /// set the scores of the members of a sorted set, the sorted set is created if missing
+    ZAdd(Vec<(Vec<u8>, Score)>),
+    /// remove the members from a sorted set
+    ZRem(Vec<Vec<u8>>),
+}
+
+impl ValueType {
+    /// the name shown by TYPE
+    pub fn name(self) -> &'static str {
+        match self {
+            ValueType::String => "string",
+            ValueType::Hash => "hash",
+            ValueType::List => "list",
+            ValueType::Set => "set",
+            ValueType::SortedSet => "zset",
+        }
+    }
+}
+
+impl Collection {
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Collection::Hash(_) => ValueType::Hash,
+            Collection::List(_) => ValueType::List,
+            Collection::Set(_) => ValueType::Set,
+            Collection::SortedSet(_) => ValueType::SortedSet,
+        }
+    }
+
+    /// fail with WrongType if it is not a hash
+    pub fn as_hash(&self) -> KvdResult<&Hash> {
+        match self {
+            Collection::Hash(hash) => Ok(hash),
+            _ => Err(KvdError::from(KvdErrorKind::WrongType)),
+        }
+    }
+
+    /// fail with WrongType if it is not a list
+    pub fn as_list(&self) -> KvdResult<&List> {
+        match self {
+            Collection::List(list) => Ok(list),
+            _ => Err(KvdError::from(KvdErrorKind::WrongType)),
+        }
+    }
+
+    /// fail with WrongType if it is not a set
+    pub fn as_set(&self) -> KvdResult<&Set> {
+        match self {
+            Collection::Set(set) => Ok(set),
+            _ => Err(KvdError::from(KvdErrorKind::WrongType)),
+        }
+    }
+
+    /// fail with WrongType if it is not a sorted set
+    pub fn as_sorted_set(&self) -> KvdResult<&SortedSet> {
+        match self {
+            Collection::SortedSet(set) => Ok(set),
+            _ => Err(KvdError::from(KvdErrorKind::WrongType)),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            Collection::Hash(hash) => hash.is_empty(),
+            Collection::List(list) => list.is_empty(),
+            Collection::Set(set) => set.is_empty(),
+            Collection::SortedSet(set) => set.is_empty(),
+        }
+    }
+}
+
+impl Change {
+    /// the name of the change, like the command it is made by
+    pub fn name(&self) -> &'static str {
+        match self {
+            Change::Restore(_) => "restore",
+            Change::HSet(_) => "hset",
+            Change::HDel(_) => "hdel",
+            Change::LPush(_) => "lpush",
+            Change::RPush(_) => "rpush",
+            Change::LPop(_) => "lpop",
+            Change::RPop(_) => "rpop",
+            Change::LSet(..) => "lset",
+            Change::LRem(..) => "lrem",
+            Change::LTrim(..) => "ltrim",
+            Change::SAdd(_) => "sadd",
+            Change::SRem(_) => "srem",
+            Change::ZAdd(_) => "zadd",
+            Change::ZRem(_) => "zrem",
+        }
+    }
+
+    /// the type of the value changed, None if any type can be changed
+    pub fn value_type(&self) -> Option<ValueType> {
+        match self {
+            Change::Restore(_) => None,
+            Change::HSet(_) | Change::HDel(_) => Some(ValueType::Hash),
+            Change::LPush(_)
+            | Change::RPush(_)
+            | Change::LPop(_)
+            | Change::RPop(_)
+            | Change::LSet(..)
+            | Change::LRem(..)
+            | Change::LTrim(..) => Some(ValueType::List),
+            Change::SAdd(_) | Change::SRem(_) => Some(ValueType::Set),
+            Change::ZAdd(_) | Change::ZRem(_) => Some(ValueType::SortedSet),
+        }
+    }
+
+    /// fail with WrongType if the change can not be applied to the current value
+    pub fn check(&self, current: Option<&Collection>) -> KvdResult<()> {
+        match (self.value_type(), current) {
+            (Some(value_type), Some(current)) if value_type != current.value_type() => {
+                Err(KvdError::from(KvdErrorKind::WrongType))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Apply the change to the current value, which is checked by `check`
+    /// before. None means the key is removed, since a collection which
+    /// becomes empty is removed.
+    pub fn apply(self, current: Option<Collection>) -> Option<Collection> {
+        let value = match (self, current) {
+            (Change::Restore(value), _) => value,
+            (Change::HSet(fields), current) => {
+                let mut hash = match current {
+                    Some(Collection::Hash(hash)) => hash,
+                    _ => Hash::new(),
+                };
+                hash.extend(fields);
+                Collection::Hash(hash)
+            }
+            (Change::HDel(fields), Some(Collection::Hash(mut hash))) => {
+                for field in fields.iter() {
+                    hash.remove(field);
+                }
+                Collection::Hash(hash)
+            }
+            (Change::LPush(elements), current) => {
+                let mut list = list_or_new(current);
+                for element in elements {
+                    list.push_front(element);
+                }
+                Collection::List(list)
+            }
+            (Change::RPush(elements), current) => {
+                let mut list = list_or_new(current);
+                list.extend(elements);
+                Collection::List(list)
+            }
+            (Change::LPop(count), Some(Collection::List(mut list))) => {
+                list.drain(..count.min(list.len()));
+                Collection::List(list)
+            }
+            (Change::RPop(count), Some(Collection::List(mut list))) => {
+                list.truncate(list.len().saturating_sub(count));
+                Collection::List(list)
+            }
+            (Change::LSet(index, element), Some(Collection::List(mut list))) => {
+                if let Some(current) = list.get_mut(index) {
+                    *current = element;
+                }
+                Collection::List(list)
+            }
+            (Change::LRem(count, element), Some(Collection::List(list))) => {
+                Collection::List(remove_elements(list, count, &element))
+            }
+            (Change::LTrim(start, end), Some(Collection::List(mut list))) => {
+                list.truncate(end);
+                list.drain(..start.min(list.len()));
+                Collection::List(list)
+            }
+            (Change::SAdd(members), current) => {
+                let mut set = match current {
+                    Some(Collection::Set(set)) => set,
+                    _ => Set::new(),
+                };
+                set.extend(members);
+                Collection::Set(set)
+            }
+            (Change::SRem(members), Some(Collection::Set(mut set))) => {
+                for member in members.iter() {
+                    set.remove(member);
+                }
+                Collection::Set(set)
+            }
+            (Change::ZAdd(members), current) => {
+                let mut set = match current {
+                    Some(Collection::SortedSet(set)) => set,
+                    _ => SortedSet::new(),
+                };
+                for (member, score) in members {
+                    set.insert(member, score);
+                }
+                Collection::SortedSet(set)
+            }
+            (Change::ZRem(members), Some(Collection::SortedSet(mut set))) => {
+                for member in members.iter() {
+                    set.remove(member);
+                }
+                Collection::SortedSet(set)
+            }
+            (_, _) => return None,
+        };
+        if value.is_empty() {
+            return None;
+        }
+        Some(value)
+    }
+}
+
+fn list_or_new(current: Option<Collection>) -> List {
+    match current {
+        Some(Collection::List(list)) => list,
+        _ => List::new(),
+    }
+}
+
+/// the list without count elements equal to the element, see `Change::LRem`
+fn remove_elements(list: List, count: i64, element: &[u8]) -> List {
+    let limit = if count == 0 {
+        usize::MAX
+    } else {
+        count.unsigned_abs() as usize
+    };
+    let mut removed = 0;
+    let mut keep = |e: &Vec<u8>| {
+        if removed < limit && e.as_slice() == element {
+            removed += 1;
+            return false;
+        }
+        true
+    };
+    if count >= 0 {
+        list.into_iter().filter(|e| keep(e)).collect()
+    } else {
+        let mut kept: List = list.into_iter().rev().filter(|e| keep(e)).collect();
+        kept.make_contiguous().reverse();
+        kept
+    }
+}
+
+/// Serializes a map as a list of pairs, since the keys of a json object
+/// must be strings.
+mod pairs {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::collections::BTreeMap;
+
+    pub fn serialize<S: Serializer>(
+        map: &BTreeMap<Vec<u8>, Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(map.iter())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<Vec<u8>, Vec<u8>>, D::Error> {
+        let pairs = Vec::<(Vec<u8>, Vec<u8>)>::deserialize(deserializer)?;
+        Ok(pairs.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_changes() {
+        let field = |name: &str| Vec::from(name);
+        let hash = Change::HSet(vec![(field("a"), field("1")), (field("b"), field("2"))])
+            .apply(None)
+            .unwrap();
+        let hash = Change::HDel(vec![field("a"), field("c")])
+            .apply(Some(hash))
+            .unwrap();
+        let mut expect = Hash::new();
+        expect.insert(field("b"), field("2"));
+        assert_eq!(&expect, hash.as_hash().unwrap());
+
+        // the collection survives the wal
+        let data = serde_json::to_vec(&Change::Restore(hash.clone())).unwrap();
+        let restored = serde_json::from_slice::<Change>(&data).unwrap().apply(None);
+        assert_eq!(Some(hash.clone()), restored);
+
+        // an empty hash is removed
+        assert_eq!(None, Change::HDel(vec![field("b")]).apply(Some(hash)));
+        assert_eq!(None, Change::HDel(vec![field("b")]).apply(None));
+    }
+
+    #[test]
+    fn test_list_changes() {
+        let elements = |names: &str| -> Vec<Vec<u8>> { names.split(' ').map(Vec::from).collect() };
+        let list = |names: &str| Some(Collection::List(elements(names).into_iter().collect()));
+
+        let value = Change::LPush(elements("b a")).apply(None);
+        let value = Change::RPush(elements("c x c x c")).apply(value);
+        assert_eq!(list("a b c x c x c"), value);
+        let value = Change::LRem(2, Vec::from("c")).apply(value);
+        assert_eq!(list("a b x x c"), value);
+        let value = Change::LRem(-1, Vec::from("x")).apply(value);
+        assert_eq!(list("a b x c"), value);
+        let value = Change::LSet(2, Vec::from("y")).apply(value);
+        assert_eq!(list("a b y c"), value);
+        let value = Change::LPop(1).apply(value);
+        let value = Change::RPop(1).apply(value);
+        assert_eq!(list("b y"), value);
+        let value = Change::LTrim(1, 2).apply(value);
+        assert_eq!(list("y"), value);
+        assert_eq!(None, Change::LRem(0, Vec::from("y")).apply(value.clone()));
+        assert_eq!(None, Change::LPop(5).apply(value));
+
+        // a list change does not apply to a hash
+        let hash = Change::HSet(vec![(Vec::from("a"), Vec::from("1"))]).apply(None);
+        assert!(Change::LPop(1).check(hash.as_ref()).is_err());
+        assert!(Change::HDel(Vec::new()).check(list("a").as_ref()).is_err());
+    }
+
+    #[test]
+    fn test_set_changes() {
+        let members = |names: &str| -> Vec<Vec<u8>> { names.split(' ').map(Vec::from).collect() };
+        let set = Change::SAdd(members("a b c a")).apply(None);
+        let set = Change::SRem(members("b x")).apply(set).unwrap();
+        let expect: Set = members("a c").into_iter().collect();
+        assert_eq!(&expect, set.as_set().unwrap());
+        assert_eq!(ValueType::Set, set.value_type());
+        assert!(set.as_list().is_err());
+
+        let data = serde_json::to_vec(&Change::Restore(set.clone())).unwrap();
+        let restored = serde_json::from_slice::<Change>(&data).unwrap().apply(None);
+        assert_eq!(Some(set.clone()), restored);
+        assert_eq!(None, Change::SRem(members("a c")).apply(Some(set)));
+    }
+
+    #[test]
+    fn test_sorted_set_changes() {
+        let score = |score: f64| Score::new(score).unwrap();
+        let set = Change::ZAdd(vec![
+            (Vec::from("a"), score(1.0)),
+            (Vec::from("b"), score(f64::INFINITY)),
+            (Vec::from("a"), score(3.0)),
+        ])
+        .apply(None);
+        let set = Change::ZRem(vec![Vec::from("b"), Vec::from("x")])
+            .apply(set)
+            .unwrap();
+        assert_eq!(Some(3.0), set.as_sorted_set().unwrap().score(b"a"));
+        assert_eq!(1, set.as_sorted_set().unwrap().len());
+
+        let data = serde_json::to_vec(&Change::Restore(set.clone())).unwrap();
+        let restored = serde_json::from_slice::<Change>(&data).unwrap().apply(None);
+        assert_eq!(Some(set.clone()), restored);
+        assert_eq!(None, Change::ZRem(vec![Vec::from("a")]).apply(Some(set)));
+    }
+}
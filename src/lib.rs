@@ -1,11 +1,16 @@
+#[cfg(feature = "async")]
+pub mod async_server;
+pub mod client;
+pub mod cluster;
 pub mod engine;
+pub mod logging;
+pub mod metrics;
 pub mod model;
+pub mod protocol;
+pub mod raft;
 pub mod server;
 
-#[macro_use]
-extern crate failure_derive;
 extern crate config;
+extern crate failure_derive;
 #[macro_use]
 extern crate slog;
-#[macro_use]
-extern crate log;
@@ -0,0 +1,97 @@
+//! The level filter of the slog logger, which can be changed while serving.
+
+use crate::model::{KvdError, KvdErrorKind, KvdResult};
+use slog::{Drain, Level, OwnedKVList, Record};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// A log level shared by the logger and the server, cheap to clone.
+#[derive(Clone, Debug)]
+pub struct LogLevel(Arc<AtomicUsize>);
+
+/// Drops the records less severe than a `LogLevel`.
+pub struct LevelFilter<D: Drain> {
+    drain: D,
+    level: LogLevel,
+}
+
+impl LogLevel {
+    pub fn new(level: Level) -> LogLevel {
+        LogLevel(Arc::new(AtomicUsize::new(level.as_usize())))
+    }
+
+    pub fn get(&self) -> Level {
+        Level::from_usize(self.0.load(Ordering::Relaxed)).unwrap_or(Level::Info)
+    }
+
+    pub fn set(&self, level: Level) {
+        self.0.store(level.as_usize(), Ordering::Relaxed);
+    }
+
+    /// parse a level name like "info" or "debug"
+    pub fn parse(name: &str) -> KvdResult<Level> {
+        name.parse::<Level>()
+            .map_err(|_| KvdError::from(KvdErrorKind::InvalidLogLevel))
+    }
+}
+
+impl Default for LogLevel {
+    fn default() -> Self {
+        LogLevel::new(Level::Info)
+    }
+}
+
+impl<D: Drain> LevelFilter<D> {
+    pub fn new(drain: D, level: LogLevel) -> LevelFilter<D> {
+        LevelFilter { drain, level }
+    }
+}
+
+impl<D: Drain> Drain for LevelFilter<D> {
+    type Ok = Option<D::Ok>;
+    type Err = D::Err;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        if record.level().is_at_least(self.level.get()) {
+            self.drain.log(record, values).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn is_enabled(&self, level: Level) -> bool {
+        level.is_at_least(self.level.get()) && self.drain.is_enabled(level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog::Logger;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_level_filter() {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let drain = {
+            let messages = messages.clone();
+            slog::Filter::new(slog::Discard, move |record: &Record| {
+                messages.lock().unwrap().push(record.msg().to_string());
+                true
+            })
+        };
+        let level = LogLevel::new(Level::Info);
+        let logger = Logger::root(LevelFilter::new(drain, level.clone()).fuse(), o!());
+
+        debug!(logger, "hidden");
+        info!(logger, "shown");
+        level.set(LogLevel::parse("debug").unwrap());
+        debug!(logger, "debug");
+        level.set(LogLevel::parse("warn").unwrap());
+        info!(logger, "hidden again");
+        assert_eq!(vec!["shown", "debug"], *messages.lock().unwrap());
+
+        assert_eq!(Level::Warning, level.get());
+        assert!(LogLevel::parse("loud").is_err());
+    }
+}
@@ -0,0 +1,206 @@
+//! Metrics exported in the Prometheus text format.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// the upper bounds in seconds of the latency buckets, most requests are served in microseconds
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0,
+];
+
+/// A latency histogram with fixed buckets.
+pub struct Histogram {
+    /// the count of every bucket, the last one is +Inf
+    buckets: Vec<AtomicU64>,
+    sum_nanos: AtomicU64,
+    count: AtomicU64,
+}
+
+/// The metrics of a write ahead log, shared by the engine and the metrics endpoint.
+pub struct WalMetrics {
+    bytes_written: AtomicU64,
+    fsync_duration: Histogram,
+    compaction_runs: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            buckets: (0..=LATENCY_BUCKETS.len())
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            sum_nanos: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// write the cumulative buckets, the sum and the count of the histogram
+    pub fn write_to(&self, out: &mut String, name: &str, labels: &[(&str, &str)]) {
+        let mut cumulative = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = LATENCY_BUCKETS
+                .get(i)
+                .map_or("+Inf".to_string(), |bound| bound.to_string());
+            let mut bucket_labels = labels.to_vec();
+            bucket_labels.push(("le", &le));
+            write_sample(out, &format!("{}_bucket", name), &bucket_labels, cumulative);
+        }
+        let sum = self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        write_sample(out, &format!("{}_sum", name), labels, sum);
+        write_sample(out, &format!("{}_count", name), labels, self.count());
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WalMetrics {
+    pub fn new() -> WalMetrics {
+        WalMetrics {
+            bytes_written: AtomicU64::new(0),
+            fsync_duration: Histogram::new(),
+            compaction_runs: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn add_bytes_written(&self, n: usize) {
+        self.bytes_written.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn observe_fsync(&self, duration: Duration) {
+        self.fsync_duration.observe(duration);
+    }
+
+    pub(crate) fn record_compaction(&self) {
+        self.compaction_runs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    pub fn compaction_runs(&self) -> u64 {
+        self.compaction_runs.load(Ordering::Relaxed)
+    }
+
+    pub fn write_to(&self, out: &mut String) {
+        write_header(
+            out,
+            "kvd_wal_bytes_written_total",
+            "counter",
+            "Bytes appended to the write ahead log.",
+        );
+        write_sample(
+            out,
+            "kvd_wal_bytes_written_total",
+            &[],
+            self.bytes_written(),
+        );
+        write_header(
+            out,
+            "kvd_wal_fsync_duration_seconds",
+            "histogram",
+            "Latency of syncing a write ahead log file to disk.",
+        );
+        self.fsync_duration
+            .write_to(out, "kvd_wal_fsync_duration_seconds", &[]);
+        write_header(
+            out,
+            "kvd_compaction_runs_total",
+            "counter",
+            "Compactions of the write ahead log.",
+        );
+        write_sample(
+            out,
+            "kvd_compaction_runs_total",
+            &[],
+            self.compaction_runs(),
+        );
+    }
+}
+
+impl Default for WalMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+pub fn write_sample<V: ToString>(out: &mut String, name: &str, labels: &[(&str, &str)], value: V) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        out.push('{');
+        for (i, (key, value)) in labels.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            write!(out, "{}=\"{}\"", key, escape_label_value(value)).unwrap();
+        }
+        out.push('}');
+    }
+    writeln!(out, " {}", value.to_string()).unwrap();
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::new();
+        histogram.observe(Duration::from_micros(5));
+        histogram.observe(Duration::from_micros(700));
+        histogram.observe(Duration::from_secs(10));
+        assert_eq!(3, histogram.count());
+
+        let mut out = String::new();
+        histogram.write_to(&mut out, "latency", &[("cmd", "get")]);
+        assert!(out.contains("latency_bucket{cmd=\"get\",le=\"0.00001\"} 1\n"));
+        assert!(out.contains("latency_bucket{cmd=\"get\",le=\"0.0005\"} 1\n"));
+        assert!(out.contains("latency_bucket{cmd=\"get\",le=\"0.001\"} 2\n"));
+        assert!(out.contains("latency_bucket{cmd=\"get\",le=\"5\"} 2\n"));
+        assert!(out.contains("latency_bucket{cmd=\"get\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("latency_sum{cmd=\"get\"} 10.000705\n"));
+        assert!(out.contains("latency_count{cmd=\"get\"} 3\n"));
+    }
+
+    #[test]
+    fn test_write_sample() {
+        let mut out = String::new();
+        write_sample(&mut out, "total", &[], 1);
+        write_sample(&mut out, "errors", &[("kind", "a\"b\\c\nd")], 2);
+        assert_eq!("total 1\nerrors{kind=\"a\\\"b\\\\c\\nd\"} 2\n", out);
+    }
+}
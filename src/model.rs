@@ -1,12 +1,14 @@
+// the impls generated by failure_derive are not local to KvdErrorKind
+#![allow(non_local_definitions)]
+
 use config::ConfigError;
 use failure::_core::fmt::Display;
 use failure::_core::str::Utf8Error;
 use failure::{Backtrace, Context, Fail};
 use std::fmt::{Error, Formatter};
 use std::io;
-use std::string::FromUtf8Error;
 
-type Request = Vec<Vec<u8>>;
+pub type Request = Vec<Vec<u8>>;
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug, Fail)]
 pub enum KvdErrorKind {
@@ -28,6 +30,74 @@ pub enum KvdErrorKind {
     Config,
     #[fail(display = "string convert error")]
     StringConvertError,
+    #[fail(display = "max number of clients reached")]
+    MaxClients,
+    #[fail(display = "request too large")]
+    RequestTooLarge,
+    #[fail(display = "no such client")]
+    NoSuchClient,
+    #[fail(display = "unknown command")]
+    UnknownCommand,
+    #[fail(display = "invalid log level")]
+    InvalidLogLevel,
+    #[fail(display = "invalid expire time")]
+    InvalidExpireTime,
+    #[fail(display = "value is not an integer or out of range")]
+    NotAnInteger,
+    #[fail(display = "value is not a valid float")]
+    NotAFloat,
+    #[fail(display = "increment or decrement would overflow")]
+    IncrementOverflow,
+    #[fail(display = "string exceeds maximum allowed size")]
+    ValueTooLarge,
+    #[fail(display = "Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[fail(display = "index out of range")]
+    IndexOutOfRange,
+    #[fail(display = "timeout is negative or not a number")]
+    InvalidTimeout,
+    #[fail(display = "resulting score is not a number (NaN)")]
+    ScoreIsNaN,
+    #[fail(display = "min or max not valid string range item")]
+    InvalidLexRange,
+    #[fail(display = "only (P)SUBSCRIBE / (P)UNSUBSCRIBE are allowed in this context")]
+    SubscriberMode,
+    #[fail(display = "invalid keyspace event classes")]
+    InvalidEventClasses,
+    #[fail(display = "the engine does not keep a change log")]
+    NoChangeLog,
+    #[fail(display = "invalid reply")]
+    InvalidReply,
+    #[fail(display = "You can't write against a read only follower.")]
+    ReadOnly,
+    #[fail(display = "no leader is elected yet")]
+    NoLeader,
+    #[fail(display = "the command can not be replicated by raft")]
+    NondeterministicCommand,
+    #[fail(display = "the write is dropped by a new leader")]
+    WriteDropped,
+    #[fail(display = "the write is not committed in time")]
+    CommitTimeout,
+    #[fail(display = "raft is not enabled")]
+    RaftDisabled,
+    #[fail(display = "This instance has cluster support disabled")]
+    ClusterDisabled,
+    #[fail(display = "Keys in request don't hash to the same slot")]
+    CrossSlot,
+    #[fail(display = "Hash slot not served")]
+    SlotNotServed,
+    #[fail(display = "unknown node")]
+    UnknownNode,
+    #[fail(display = "too many redirects")]
+    TooManyRedirects,
+    #[fail(display = "Multiple keys request during rehashing of slot")]
+    MigratingKeys,
+    #[fail(display = "I'm not the owner of hash slot")]
+    NotSlotOwner,
+    #[fail(display = "the slot is not migrating")]
+    SlotNotMigrating,
+    #[fail(display = "kvd is loading the dataset in memory")]
+    Loading,
 }
 
 #[derive(Debug)]
@@ -79,7 +149,7 @@ impl From<Context<KvdErrorKind>> for KvdError {
 }
 
 impl From<io::Error> for KvdError {
-    fn from(e: io::Error) -> Self {
+    fn from(_: io::Error) -> Self {
         KvdError {
             ctx: Context::new(KvdErrorKind::Io),
         }
@@ -87,7 +157,7 @@ impl From<io::Error> for KvdError {
 }
 
 impl From<serde_json::error::Error> for KvdError {
-    fn from(e: serde_json::error::Error) -> Self {
+    fn from(_: serde_json::error::Error) -> Self {
         KvdError {
             ctx: Context::new(KvdErrorKind::Serde),
         }
@@ -95,7 +165,7 @@ impl From<serde_json::error::Error> for KvdError {
 }
 
 impl From<ConfigError> for KvdError {
-    fn from(e: ConfigError) -> Self {
+    fn from(_: ConfigError) -> Self {
         KvdError {
             ctx: Context::new(KvdErrorKind::Config),
         }
@@ -103,7 +173,7 @@ impl From<ConfigError> for KvdError {
 }
 
 impl From<Utf8Error> for KvdError {
-    fn from(e: Utf8Error) -> Self {
+    fn from(_: Utf8Error) -> Self {
         KvdError {
             ctx: Context::new(KvdErrorKind::StringConvertError),
         }
@@ -114,9 +184,9 @@ pub type KvdResult<T> = Result<T, KvdError>;
 
 pub fn parse_request_from_line(line: String) -> KvdResult<Request> {
     let tokens = line
-        .split(" ")
+        .split(' ')
         .filter(|s| !s.is_empty())
-        .map(|s| Vec::from(s))
+        .map(Vec::from)
         .collect();
     Ok(tokens)
 }
@@ -0,0 +1,540 @@
+//! The wire protocol spoken between kvd and its clients.
+//!
+//! Requests are accepted either as inline commands (`set key value\n`) or as
+//! RESP arrays of bulk strings, the same way Redis accepts them. Replies are
+//! always encoded as RESP, so a client can tell where one reply ends and the
+//! next one begins even when many requests are pipelined on one connection.
+//! The replies are parsed back by `ReplyBuffer` on the client side.
+
+use crate::model::{self, KvdError, KvdErrorKind, KvdResult, Request};
+use std::io;
+use std::io::{Read, Write};
+use std::str;
+
+pub(crate) const READ_CHUNK_SIZE: usize = 16 * 1024;
+pub const DEFAULT_MAX_REQUEST_SIZE: usize = 64 * 1024 * 1024;
+/// the longest line of a request, an inline request or a header of a RESP
+/// array, the same as the PROTO_INLINE_MAX_SIZE of redis
+const MAX_INLINE_SIZE: usize = 64 * 1024;
+/// the largest bulk a reply may announce, the same as the proto-max-bulk-len of redis
+const MAX_BULK_SIZE: usize = 512 * 1024 * 1024;
+
+/// Accumulates the bytes read from a connection and splits them into requests.
+pub struct RequestBuffer {
+    buf: Vec<u8>,
+    start: usize,
+    max_request_size: usize,
+}
+
+/// Accumulates the bytes read from a server and splits them into replies.
+///
+/// The replies of the arrays which are not complete yet are kept, so the
+/// bytes of a large reply are parsed only once however it is split.
+pub struct ReplyBuffer {
+    buf: Vec<u8>,
+    start: usize,
+    /// the arrays being parsed, with the number of their replies left
+    arrays: Vec<(usize, Vec<Reply>)>,
+}
+
+/// A reply parsed from its first line and its bulk if any, or the header of
+/// an array whose replies follow.
+enum Parsed {
+    Reply(Reply),
+    Array(usize),
+}
+
+/// A reply to a single request, encoded as RESP on the wire.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Nil,
+    Array(Vec<Reply>),
+    /// nothing is written, the reply is queued with the messages of a
+    /// subscriber, e.g. the confirmations of SUBSCRIBE
+    Empty,
+}
+
+impl RequestBuffer {
+    pub fn new() -> RequestBuffer {
+        Self::with_max_request_size(DEFAULT_MAX_REQUEST_SIZE)
+    }
+
+    /// a request larger than max_request_size is rejected before it is fully buffered
+    pub fn with_max_request_size(max_request_size: usize) -> RequestBuffer {
+        RequestBuffer {
+            buf: Vec::new(),
+            start: 0,
+            max_request_size,
+        }
+    }
+
+    /// read once from the reader and append the data to the buffer,
+    /// return the number of bytes read, 0 means the reader reaches EOF
+    pub fn read_from<R: Read>(&mut self, reader: &mut R) -> io::Result<usize> {
+        self.compact();
+        read_chunk(&mut self.buf, reader)
+    }
+
+    pub fn extend(&mut self, data: &[u8]) {
+        self.compact();
+        self.buf.extend_from_slice(data);
+    }
+
+    /// whether there are bytes that are not consumed by any request yet
+    pub fn is_empty(&self) -> bool {
+        self.start == self.buf.len()
+    }
+
+    /// take out the next complete request in the buffer,
+    /// return None if the buffered data is not a complete request yet
+    pub fn next_request(&mut self) -> KvdResult<Option<Request>> {
+        loop {
+            let data = &self.buf[self.start..];
+            if data.is_empty() {
+                return Ok(None);
+            }
+            let parsed = if data[0] == b'*' {
+                parse_multibulk(data, self.max_request_size)?
+            } else {
+                parse_inline(data)?
+            };
+            match parsed {
+                Some((request, consumed)) => {
+                    self.start += consumed;
+                    // blank lines are skipped, just like redis does
+                    if !request.is_empty() {
+                        return Ok(Some(request));
+                    }
+                }
+                None if data.len() > self.max_request_size => {
+                    return Err(KvdError::from(KvdErrorKind::RequestTooLarge));
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn compact(&mut self) {
+        if self.start > 0 {
+            self.buf.drain(..self.start);
+            self.start = 0;
+        }
+    }
+}
+
+impl Default for RequestBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReplyBuffer {
+    pub fn new() -> ReplyBuffer {
+        ReplyBuffer {
+            buf: Vec::new(),
+            start: 0,
+            arrays: Vec::new(),
+        }
+    }
+
+    /// read once from the reader and append the data to the buffer,
+    /// return the number of bytes read, 0 means the reader reaches EOF
+    pub fn read_from<R: Read>(&mut self, reader: &mut R) -> io::Result<usize> {
+        self.compact();
+        read_chunk(&mut self.buf, reader)
+    }
+
+    pub fn extend(&mut self, data: &[u8]) {
+        self.compact();
+        self.buf.extend_from_slice(data);
+    }
+
+    /// take out the next complete reply in the buffer,
+    /// return None if the buffered data is not a complete reply yet
+    pub fn next_reply(&mut self) -> KvdResult<Option<Reply>> {
+        loop {
+            let reply = match self.arrays.last() {
+                // an array whose replies are all parsed is complete
+                Some((0, _)) => Reply::Array(self.arrays.pop().unwrap().1),
+                _ => match parse_reply(&self.buf[self.start..])? {
+                    Some((parsed, consumed)) => {
+                        self.start += consumed;
+                        match parsed {
+                            Parsed::Reply(reply) => reply,
+                            Parsed::Array(count) => {
+                                // the count is sent by the server, do not trust a huge one blindly
+                                let replies = Vec::with_capacity(count.min(1024));
+                                self.arrays.push((count, replies));
+                                continue;
+                            }
+                        }
+                    }
+                    None => return Ok(None),
+                },
+            };
+            match self.arrays.last_mut() {
+                Some((left, replies)) => {
+                    *left -= 1;
+                    replies.push(reply);
+                }
+                None => return Ok(Some(reply)),
+            }
+        }
+    }
+
+    fn compact(&mut self) {
+        if self.start > 0 {
+            self.buf.drain(..self.start);
+            self.start = 0;
+        }
+    }
+}
+
+impl Default for ReplyBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Reply {
+    pub fn ok() -> Reply {
+        Reply::Status("OK".to_string())
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Reply::Status(s) => write!(writer, "+{}\r\n", s),
+            Reply::Error(s) => write!(writer, "-{}\r\n", s),
+            Reply::Integer(i) => write!(writer, ":{}\r\n", i),
+            Reply::Bulk(data) => {
+                write!(writer, "${}\r\n", data.len())?;
+                writer.write_all(data)?;
+                writer.write_all(b"\r\n")
+            }
+            Reply::Nil => writer.write_all(b"$-1\r\n"),
+            Reply::Array(replies) => {
+                write!(writer, "*{}\r\n", replies.len())?;
+                for reply in replies {
+                    reply.write_to(writer)?;
+                }
+                Ok(())
+            }
+            Reply::Empty => Ok(()),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        // writing to a Vec never fails
+        self.write_to(&mut data).unwrap();
+        data
+    }
+}
+
+impl From<KvdError> for Reply {
+    fn from(e: KvdError) -> Self {
+        match e.kind() {
+            // the same prefix as redis, which clients match on
+            KvdErrorKind::WrongType => Reply::Error(format!("WRONGTYPE {}", e)),
+            KvdErrorKind::ReadOnly => Reply::Error(format!("READONLY {}", e)),
+            KvdErrorKind::NoLeader => Reply::Error(format!("TRYAGAIN {}", e)),
+            KvdErrorKind::CrossSlot => Reply::Error(format!("CROSSSLOT {}", e)),
+            KvdErrorKind::SlotNotServed => Reply::Error(format!("CLUSTERDOWN {}", e)),
+            KvdErrorKind::MigratingKeys => Reply::Error(format!("TRYAGAIN {}", e)),
+            KvdErrorKind::Loading => Reply::Error(format!("LOADING {}", e)),
+            _ => Reply::Error(format!("ERR {}", e)),
+        }
+    }
+}
+
+/// read once from the reader and append the data to buf
+fn read_chunk<R: Read>(buf: &mut Vec<u8>, reader: &mut R) -> io::Result<usize> {
+    let len = buf.len();
+    buf.resize(len + READ_CHUNK_SIZE, 0);
+    match reader.read(&mut buf[len..]) {
+        Ok(n) => {
+            buf.truncate(len + n);
+            Ok(n)
+        }
+        Err(e) => {
+            buf.truncate(len);
+            Err(e)
+        }
+    }
+}
+
+/// find the first line in data, return the line without "\r\n" and the consumed length
+fn read_line(data: &[u8]) -> Option<(&[u8], usize)> {
+    let end = data.iter().position(|b| *b == b'\n')?;
+    let line = &data[..end];
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    Some((line, end + 1))
+}
+
+/// Find the first line of a request like `read_line`. A line is never
+/// looked for past MAX_INLINE_SIZE, so the bytes of a request without a
+/// newline are not scanned again and again on every read.
+fn read_request_line(data: &[u8]) -> KvdResult<Option<(&[u8], usize)>> {
+    match read_line(&data[..data.len().min(MAX_INLINE_SIZE)]) {
+        Some(r) => Ok(Some(r)),
+        None if data.len() >= MAX_INLINE_SIZE => Err(KvdError::from(KvdErrorKind::InvalidRequest)),
+        None => Ok(None),
+    }
+}
+
+fn parse_inline(data: &[u8]) -> KvdResult<Option<(Request, usize)>> {
+    let (line, consumed) = match read_request_line(data)? {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+    let line = str::from_utf8(line)?;
+    let request = model::parse_request_from_line(line.to_string())?;
+    Ok(Some((request, consumed)))
+}
+
+fn parse_multibulk(data: &[u8], max_size: usize) -> KvdResult<Option<(Request, usize)>> {
+    let (line, mut consumed) = match read_request_line(data)? {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+    let count = parse_length(&line[1..])?;
+    // every argument takes at least 4 bytes, do not trust a huge count blindly
+    if count > max_size / 4 {
+        return Err(KvdError::from(KvdErrorKind::RequestTooLarge));
+    }
+    let mut request = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let (line, len) = match read_request_line(&data[consumed..])? {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        if line.first() != Some(&b'$') {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let size = parse_length(&line[1..])?;
+        if size > max_size {
+            return Err(KvdError::from(KvdErrorKind::RequestTooLarge));
+        }
+        let begin = consumed + len;
+        let end = begin + size;
+        if data.len() < end + 2 {
+            return Ok(None);
+        }
+        if &data[end..end + 2] != b"\r\n" {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        request.push(data[begin..end].to_vec());
+        consumed = end + 2;
+    }
+    Ok(Some((request, consumed)))
+}
+
+/// Parse the first reply in data, or only the header of an array, return it
+/// and the consumed length.
+fn parse_reply(data: &[u8]) -> KvdResult<Option<(Parsed, usize)>> {
+    let (line, mut consumed) = match read_line(data) {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+    let invalid = || KvdError::from(KvdErrorKind::InvalidReply);
+    let (kind, rest) = line.split_first().ok_or_else(invalid)?;
+    let text = || str::from_utf8(rest).map(str::to_string);
+    let reply = match kind {
+        b'+' => Reply::Status(text()?),
+        b'-' => Reply::Error(text()?),
+        b':' => Reply::Integer(text()?.parse().map_err(|_| invalid())?),
+        b'$' | b'*' if rest == b"-1" => Reply::Nil,
+        b'$' => {
+            let size = parse_length(rest).map_err(|_| invalid())?;
+            if size > MAX_BULK_SIZE {
+                return Err(invalid());
+            }
+            let end = consumed.checked_add(size).ok_or_else(invalid)?;
+            if data.len() < end.saturating_add(2) {
+                return Ok(None);
+            }
+            if &data[end..end + 2] != b"\r\n" {
+                return Err(invalid());
+            }
+            let bulk = data[consumed..end].to_vec();
+            consumed = end + 2;
+            Reply::Bulk(bulk)
+        }
+        b'*' => {
+            let count = parse_length(rest).map_err(|_| invalid())?;
+            // every reply takes at least 3 bytes
+            if count > MAX_BULK_SIZE / 3 {
+                return Err(invalid());
+            }
+            return Ok(Some((Parsed::Array(count), consumed)));
+        }
+        _ => return Err(invalid()),
+    };
+    Ok(Some((Parsed::Reply(reply), consumed)))
+}
+
+fn parse_length(data: &[u8]) -> KvdResult<usize> {
+    str::from_utf8(data)?
+        .parse::<usize>()
+        .map_err(|_| KvdError::from(KvdErrorKind::InvalidRequest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requests(buffer: &mut RequestBuffer) -> Vec<Request> {
+        let mut result = Vec::new();
+        while let Some(request) = buffer.next_request().unwrap() {
+            result.push(request);
+        }
+        result
+    }
+
+    #[test]
+    fn test_parse_pipelined_inline_requests() {
+        let mut buffer = RequestBuffer::new();
+        buffer.extend(b"set key value\r\nget key\n\nget");
+        let result = requests(&mut buffer);
+        assert_eq!(
+            vec![
+                vec![b"set".to_vec(), b"key".to_vec(), b"value".to_vec()],
+                vec![b"get".to_vec(), b"key".to_vec()],
+            ],
+            result
+        );
+        assert!(!buffer.is_empty());
+
+        buffer.extend(b" key\n");
+        let result = requests(&mut buffer);
+        assert_eq!(vec![vec![b"get".to_vec(), b"key".to_vec()]], result);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_parse_multibulk_requests() {
+        let mut buffer = RequestBuffer::new();
+        buffer.extend(
+            b"*3\r\n$3\r\nset\r\n$3\r\nkey\r\n$7\r\nva\r\nlue\r\n*2\r\n$3\r\nget\r\n$3\r\nk",
+        );
+        let result = requests(&mut buffer);
+        assert_eq!(
+            vec![vec![
+                b"set".to_vec(),
+                b"key".to_vec(),
+                b"va\r\nlue".to_vec()
+            ]],
+            result
+        );
+
+        buffer.extend(b"ey\r\n");
+        let result = requests(&mut buffer);
+        assert_eq!(vec![vec![b"get".to_vec(), b"key".to_vec()]], result);
+    }
+
+    #[test]
+    fn test_parse_invalid_multibulk_request() {
+        let mut buffer = RequestBuffer::new();
+        buffer.extend(b"*1\r\n$3\r\ngetx\r\n");
+        let result = buffer.next_request();
+        assert_eq!(Err(KvdError::from(KvdErrorKind::InvalidRequest)), result);
+    }
+
+    #[test]
+    fn test_reject_too_large_request() {
+        let mut buffer = RequestBuffer::with_max_request_size(16);
+        buffer.extend(b"set key 0123456789");
+        let result = buffer.next_request();
+        assert_eq!(Err(KvdError::from(KvdErrorKind::RequestTooLarge)), result);
+
+        let mut buffer = RequestBuffer::with_max_request_size(16);
+        buffer.extend(b"*3\r\n$3\r\nset\r\n$3\r\nkey\r\n$100\r\n");
+        let result = buffer.next_request();
+        assert_eq!(Err(KvdError::from(KvdErrorKind::RequestTooLarge)), result);
+
+        // a line is capped far below the request size
+        let mut buffer = RequestBuffer::new();
+        buffer.extend(&vec![b'a'; MAX_INLINE_SIZE - 1]);
+        assert_eq!(Ok(None), buffer.next_request());
+        buffer.extend(b"a");
+        let result = buffer.next_request();
+        assert_eq!(Err(KvdError::from(KvdErrorKind::InvalidRequest)), result);
+        let mut buffer = RequestBuffer::new();
+        buffer.extend(b"*1\r\n$");
+        buffer.extend(&vec![b'1'; MAX_INLINE_SIZE]);
+        let result = buffer.next_request();
+        assert_eq!(Err(KvdError::from(KvdErrorKind::InvalidRequest)), result);
+    }
+
+    #[test]
+    fn test_parse_replies() {
+        let reply = Reply::Array(vec![
+            Reply::ok(),
+            Reply::Integer(-3),
+            Reply::Bulk(b"va\r\nlue".to_vec()),
+            Reply::Nil,
+            Reply::Array(vec![Reply::Error("ERR invalid request".to_string())]),
+        ]);
+        let data = reply.to_bytes();
+        let mut buffer = ReplyBuffer::new();
+        buffer.extend(&data[..data.len() - 3]);
+        assert_eq!(None, buffer.next_reply().unwrap());
+        buffer.extend(&data[data.len() - 3..]);
+        buffer.extend(b":1\r\n");
+        assert_eq!(Some(reply.clone()), buffer.next_reply().unwrap());
+        assert_eq!(Some(Reply::Integer(1)), buffer.next_reply().unwrap());
+        assert_eq!(None, buffer.next_reply().unwrap());
+
+        // a reply split anywhere is parsed once complete
+        let data = Reply::Array(vec![Reply::Array(vec![]), reply.clone(), Reply::Nil]).to_bytes();
+        for byte in &data[..data.len() - 1] {
+            buffer.extend(&[*byte]);
+            assert_eq!(None, buffer.next_reply().unwrap());
+        }
+        buffer.extend(&data[data.len() - 1..]);
+        buffer.extend(b"*-1\r\n");
+        assert_eq!(
+            Some(Reply::Array(vec![Reply::Array(vec![]), reply, Reply::Nil])),
+            buffer.next_reply().unwrap()
+        );
+        assert_eq!(Some(Reply::Nil), buffer.next_reply().unwrap());
+
+        // a huge size is not trusted
+        for data in &[
+            &b"$18446744073709551615\r\n"[..],
+            b"*9223372036854775807\r\n",
+        ] {
+            let mut buffer = ReplyBuffer::new();
+            buffer.extend(data);
+            assert_eq!(
+                Err(KvdError::from(KvdErrorKind::InvalidReply)),
+                buffer.next_reply()
+            );
+        }
+
+        buffer.extend(b"?\r\n");
+        assert_eq!(
+            Err(KvdError::from(KvdErrorKind::InvalidReply)),
+            buffer.next_reply()
+        );
+    }
+
+    #[test]
+    fn test_encode_reply() {
+        let reply = Reply::Array(vec![
+            Reply::ok(),
+            Reply::Integer(3),
+            Reply::Bulk(b"value".to_vec()),
+            Reply::Nil,
+            Reply::from(KvdError::from(KvdErrorKind::InvalidRequest)),
+        ]);
+        assert_eq!(
+            b"*5\r\n+OK\r\n:3\r\n$5\r\nvalue\r\n$-1\r\n-ERR invalid request\r\n".to_vec(),
+            reply.to_bytes()
+        );
+    }
+}
@@ -0,0 +1,303 @@
+//! The persistent state of a raft node: the term, the vote, the entries of
+//! the log and the last snapshot.
+//!
+//! The state is kept in memory, and every change of it is appended to the
+//! wal as a json record and synced before it returns, so a node never
+//! forgets a vote it gave or an entry it acknowledged. A snapshot is written
+//! to its own file, then the wal is rewritten with the entries after it.
+
+use super::{Entry, NodeId, Snapshot};
+use crate::model::{KvdError, KvdErrorKind, KvdResult};
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const WAL_FILE: &str = "raft.wal";
+const SNAPSHOT_FILE: &str = "raft.snapshot";
+
+pub struct RaftLog {
+    dir: PathBuf,
+    writer: BufWriter<File>,
+    term: u64,
+    vote: Option<NodeId>,
+    snapshot: Snapshot,
+    /// the entries after the snapshot, in the order of their indexes
+    entries: Vec<Entry>,
+}
+
+/// A record of the wal.
+#[derive(Serialize, Deserialize, Debug)]
+enum Record {
+    HardState {
+        term: u64,
+        vote: Option<NodeId>,
+    },
+    Entry(Entry),
+    /// the entries from the index on are dropped
+    Truncate {
+        index: u64,
+    },
+}
+
+impl RaftLog {
+    /// Open the log in the directory, which is created if missing. A record
+    /// torn by a crash can only be the last one, it is dropped.
+    pub fn open(dir: PathBuf) -> KvdResult<RaftLog> {
+        fs::create_dir_all(&dir)?;
+        let snapshot = match fs::read(dir.join(SNAPSHOT_FILE)) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Snapshot::default(),
+            Err(e) => return Err(KvdError::from(e)),
+        };
+        let (mut term, mut vote) = (0, None);
+        let mut entries: Vec<Entry> = Vec::new();
+        let wal_path = dir.join(WAL_FILE);
+        if wal_path.exists() {
+            let reader = BufReader::new(File::open(&wal_path)?);
+            for record in Deserializer::from_reader(reader).into_iter::<Record>() {
+                let record = match record {
+                    Ok(record) => record,
+                    Err(ref e) if e.is_eof() => break,
+                    Err(e) => return Err(KvdError::from(e)),
+                };
+                match record {
+                    Record::HardState { term: t, vote: v } => {
+                        term = t;
+                        vote = v;
+                    }
+                    Record::Entry(entry) => {
+                        if entry.index <= snapshot.index {
+                            continue;
+                        }
+                        if entry.index != snapshot.index + entries.len() as u64 + 1 {
+                            return Err(KvdError::from(KvdErrorKind::InvalidCommand));
+                        }
+                        entries.push(entry);
+                    }
+                    Record::Truncate { index } => {
+                        let len = index.saturating_sub(snapshot.index + 1) as usize;
+                        entries.truncate(len);
+                    }
+                }
+            }
+        }
+        let mut log = RaftLog {
+            writer: BufWriter::new(Self::open_wal(&wal_path)?),
+            dir,
+            term,
+            vote,
+            snapshot,
+            entries,
+        };
+        // the wal is rewritten to drop the torn record and the dropped entries
+        log.rewrite()?;
+        Ok(log)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    /// the node voted for in the current term
+    pub fn vote(&self) -> Option<NodeId> {
+        self.vote
+    }
+
+    pub fn set_hard_state(&mut self, term: u64, vote: Option<NodeId>) -> KvdResult<()> {
+        self.term = term;
+        self.vote = vote;
+        self.write(&[Record::HardState { term, vote }])
+    }
+
+    /// the last snapshot, the entries up to its index are only kept in it
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.snapshot
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.snapshot.index + self.entries.len() as u64
+    }
+
+    pub fn last_term(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.snapshot.term, |entry| entry.term)
+    }
+
+    /// the term of the entry, None if it is missing or compacted into the snapshot
+    pub fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot.index {
+            return Some(self.snapshot.term);
+        }
+        self.entry(index).map(|entry| entry.term)
+    }
+
+    pub fn entry(&self, index: u64) -> Option<&Entry> {
+        let i = index.checked_sub(self.snapshot.index + 1)?;
+        self.entries.get(i as usize)
+    }
+
+    /// at most max entries from the index, which should be after the snapshot
+    pub fn entries(&self, from: u64, max: usize) -> &[Entry] {
+        let start = (from.saturating_sub(self.snapshot.index + 1) as usize).min(self.entries.len());
+        let end = start.saturating_add(max).min(self.entries.len());
+        &self.entries[start..end]
+    }
+
+    /// append the entries, which follow the last one
+    pub fn append(&mut self, entries: Vec<Entry>) -> KvdResult<()> {
+        let records: Vec<Record> = entries.iter().cloned().map(Record::Entry).collect();
+        self.write(&records)?;
+        self.entries.extend(entries);
+        Ok(())
+    }
+
+    /// drop the entries from the index on, which should be after the snapshot
+    pub fn truncate(&mut self, index: u64) -> KvdResult<()> {
+        self.write(&[Record::Truncate { index }])?;
+        let len = index.saturating_sub(self.snapshot.index + 1) as usize;
+        self.entries.truncate(len);
+        Ok(())
+    }
+
+    /// Replace the entries up to the index of the snapshot with it, the
+    /// snapshot is taken from the applied entries.
+    pub fn compact(&mut self, snapshot: Snapshot) -> KvdResult<()> {
+        let len = snapshot.index.saturating_sub(self.snapshot.index) as usize;
+        self.entries.drain(..len.min(self.entries.len()));
+        self.save_snapshot(snapshot)
+    }
+
+    /// replace the whole log with a snapshot received from the leader
+    pub fn install(&mut self, snapshot: Snapshot) -> KvdResult<()> {
+        self.entries.clear();
+        self.save_snapshot(snapshot)
+    }
+
+    fn save_snapshot(&mut self, snapshot: Snapshot) -> KvdResult<()> {
+        let tmp_path = self.dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+        let mut file = File::create(&tmp_path)?;
+        serde_json::to_writer(&mut file, &snapshot)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, self.dir.join(SNAPSHOT_FILE))?;
+        self.snapshot = snapshot;
+        self.rewrite()
+    }
+
+    /// write the hard state and the entries into a new wal, which replaces the old one
+    fn rewrite(&mut self) -> KvdResult<()> {
+        let tmp_path = self.dir.join(format!("{}.tmp", WAL_FILE));
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        let state = Record::HardState {
+            term: self.term,
+            vote: self.vote,
+        };
+        serde_json::to_writer(&mut writer, &state)?;
+        for entry in self.entries.iter() {
+            serde_json::to_writer(&mut writer, &Record::Entry(entry.clone()))?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        let wal_path = self.dir.join(WAL_FILE);
+        fs::rename(tmp_path, &wal_path)?;
+        self.writer = BufWriter::new(Self::open_wal(&wal_path)?);
+        Ok(())
+    }
+
+    fn write(&mut self, records: &[Record]) -> KvdResult<()> {
+        for record in records {
+            serde_json::to_writer(&mut self.writer, record)?;
+        }
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    fn open_wal(path: &Path) -> KvdResult<File> {
+        Ok(OpenOptions::new().create(true).append(true).open(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::bitcask::Command;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn entry(term: u64, index: u64) -> Entry {
+        Entry {
+            term,
+            index,
+            request: vec![
+                b"set".to_vec(),
+                b"a".to_vec(),
+                index.to_string().into_bytes(),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_raft_log() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = PathBuf::from(format!("/tmp/kvd_store/{}", nanos));
+        {
+            let mut log = RaftLog::open(dir.clone()).unwrap();
+            assert_eq!((0, 0, None), (log.last_index(), log.term(), log.vote()));
+            log.set_hard_state(2, Some(3)).unwrap();
+            log.append((1..=5).map(|i| entry(1, i)).collect()).unwrap();
+            log.truncate(4).unwrap();
+            log.append(vec![entry(2, 4)]).unwrap();
+            assert_eq!(Some(2), log.term_at(4));
+            assert_eq!(vec![entry(1, 2), entry(1, 3)], log.entries(2, 2));
+        }
+
+        let mut log = RaftLog::open(dir.clone()).unwrap();
+        assert_eq!((2, Some(3)), (log.term(), log.vote()));
+        assert_eq!((4, 2), (log.last_index(), log.last_term()));
+        let snapshot = Snapshot {
+            index: 2,
+            term: 1,
+            data: vec![Command::set(b"a".to_vec(), b"2".to_vec())],
+        };
+        log.compact(snapshot.clone()).unwrap();
+        assert_eq!(None, log.term_at(1));
+        assert_eq!(Some(1), log.term_at(2));
+        assert_eq!(vec![entry(1, 3), entry(2, 4)], log.entries(3, 10));
+        log.append(vec![entry(2, 5)]).unwrap();
+        drop(log);
+
+        // a torn record at the end is dropped
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.join(WAL_FILE))
+            .unwrap();
+        file.write_all(br#"{"Entry":{"term":2,"ind"#).unwrap();
+        drop(file);
+        let mut log = RaftLog::open(dir.clone()).unwrap();
+        assert_eq!(&snapshot, log.snapshot());
+        assert_eq!(
+            vec![entry(1, 3), entry(2, 4), entry(2, 5)],
+            log.entries(0, 10)
+        );
+
+        let installed = Snapshot {
+            index: 9,
+            term: 3,
+            data: Vec::new(),
+        };
+        log.install(installed.clone()).unwrap();
+        drop(log);
+        let log = RaftLog::open(dir).unwrap();
+        assert_eq!(&installed, log.snapshot());
+        assert_eq!((9, 3), (log.last_index(), log.last_term()));
+    }
+}
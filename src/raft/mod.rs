@@ -0,0 +1,953 @@
+//! Raft consensus of a group of kvd nodes.
+//!
+//! `RaftNode` is the state machine of the protocol without any io besides
+//! its log: the messages it receives are passed to `step`, the time passes
+//! by `tick`, and the messages to send and the entries committed are taken
+//! out by the caller. So a group can be driven over the network by the
+//! server, or by a simulated network in the tests, which can drop the
+//! messages between any nodes.
+//!
+//! An entry is a write request, which is applied by every node in the order
+//! of the log. A leader which can not hear from a majority steps down, so a
+//! client of a leader cut off from the group is redirected soon.
+
+pub mod log;
+
+use crate::engine::bitcask::Command;
+use crate::model::{KvdResult, Request};
+use log::RaftLog;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{BuildHasher, Hasher};
+use std::path::PathBuf;
+
+pub type NodeId = u64;
+
+/// the ticks without hearing from a leader before a follower starts an
+/// election, randomized up to twice of it so that the nodes rarely split
+/// the votes
+pub const ELECTION_TICKS: u32 = 10;
+/// the ticks between the heartbeats of a leader
+pub const HEARTBEAT_TICKS: u32 = 2;
+/// the max number of entries sent in one message
+const MAX_APPEND_ENTRIES: usize = 256;
+pub const DEFAULT_SNAPSHOT_ENTRIES: u64 = 10000;
+
+#[derive(Clone, Debug)]
+pub struct RaftConfig {
+    /// the id of this node, which should be one of the nodes
+    pub id: NodeId,
+    /// the id and the address, host:port, of every node of the group
+    pub nodes: Vec<(NodeId, String)>,
+    /// the directory of the raft log
+    pub dir: PathBuf,
+    /// a snapshot is taken after so many entries are applied
+    pub snapshot_entries: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub term: u64,
+    pub index: u64,
+    /// the write request, empty for the entry a new leader appends
+    pub request: Request,
+}
+
+/// The state of the keys after the entries up to the index are applied.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    pub index: u64,
+    /// the term of the entry at the index
+    pub term: u64,
+    /// every key as a change which replaces it
+    pub data: Vec<Command>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Message {
+    RequestVote {
+        term: u64,
+        from: NodeId,
+        last_index: u64,
+        last_term: u64,
+    },
+    Vote {
+        term: u64,
+        from: NodeId,
+        granted: bool,
+    },
+    Append {
+        term: u64,
+        from: NodeId,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+    },
+    AppendReply {
+        term: u64,
+        from: NodeId,
+        success: bool,
+        /// the last index matched on success, or the index the leader
+        /// should go back to on failure
+        index: u64,
+    },
+    InstallSnapshot {
+        term: u64,
+        from: NodeId,
+        snapshot: Snapshot,
+    },
+}
+
+/// What a leader knows about the log of a follower.
+struct Progress {
+    /// the index of the next entry to send
+    next: u64,
+    /// the last index known to match the log of the leader
+    matched: u64,
+    /// whether the follower replied since the last check of the quorum
+    active: bool,
+}
+
+pub struct RaftNode {
+    id: NodeId,
+    peers: Vec<NodeId>,
+    log: RaftLog,
+    role: Role,
+    leader: Option<NodeId>,
+    commit: u64,
+    applied: u64,
+    /// the ticks since the last election or the last message of the leader
+    elapsed: u32,
+    timeout: u32,
+    snapshot_entries: u64,
+    votes: BTreeSet<NodeId>,
+    progress: BTreeMap<NodeId, Progress>,
+    messages: Vec<(NodeId, Message)>,
+    /// a snapshot which the keys should be replaced with before the next
+    /// entries are applied
+    pending_snapshot: Option<Snapshot>,
+}
+
+impl Message {
+    pub fn term(&self) -> u64 {
+        match self {
+            Message::RequestVote { term, .. }
+            | Message::Vote { term, .. }
+            | Message::Append { term, .. }
+            | Message::AppendReply { term, .. }
+            | Message::InstallSnapshot { term, .. } => *term,
+        }
+    }
+}
+
+impl RaftNode {
+    /// A node of the group of itself and the peers, which starts as a
+    /// follower. The snapshot in the log is the first thing to apply.
+    pub fn new(id: NodeId, peers: Vec<NodeId>, log: RaftLog) -> RaftNode {
+        let snapshot = log.snapshot().clone();
+        let mut node = RaftNode {
+            id,
+            peers,
+            role: Role::Follower,
+            leader: None,
+            commit: snapshot.index,
+            applied: snapshot.index,
+            elapsed: 0,
+            timeout: ELECTION_TICKS,
+            snapshot_entries: DEFAULT_SNAPSHOT_ENTRIES,
+            votes: BTreeSet::new(),
+            progress: BTreeMap::new(),
+            messages: Vec::new(),
+            pending_snapshot: Some(snapshot).filter(|snapshot| snapshot.index > 0),
+            log,
+        };
+        node.reset_timeout();
+        node
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// the leader of the current term, if known
+    pub fn leader(&self) -> Option<NodeId> {
+        self.leader
+    }
+
+    pub fn term(&self) -> u64 {
+        self.log.term()
+    }
+
+    pub fn commit(&self) -> u64 {
+        self.commit
+    }
+
+    pub fn applied(&self) -> u64 {
+        self.applied
+    }
+
+    pub fn log(&self) -> &RaftLog {
+        &self.log
+    }
+
+    /// take a snapshot after so many entries are applied since the last one
+    pub fn set_snapshot_entries(&mut self, entries: u64) {
+        self.snapshot_entries = entries;
+    }
+
+    /// Append the request to the log if the node is the leader, return its
+    /// index, or Err with the leader known to redirect to.
+    pub fn propose(&mut self, request: Request) -> KvdResult<Result<u64, Option<NodeId>>> {
+        if self.role != Role::Leader {
+            return Ok(Err(self.leader));
+        }
+        self.append_entry(request).map(Ok)
+    }
+
+    pub fn tick(&mut self) -> KvdResult<()> {
+        self.elapsed += 1;
+        if self.role != Role::Leader {
+            if self.elapsed >= self.timeout {
+                self.campaign()?;
+            }
+            return Ok(());
+        }
+        if self.elapsed.is_multiple_of(HEARTBEAT_TICKS) {
+            self.broadcast_append();
+        }
+        if self.elapsed >= ELECTION_TICKS {
+            // Step down if a majority did not reply in the window, it may be
+            // partitioned. The window is not random, so a leader cut off
+            // steps down within two windows.
+            self.elapsed = 0;
+            let active = 1 + self.progress.values().filter(|p| p.active).count();
+            for progress in self.progress.values_mut() {
+                progress.active = false;
+            }
+            if active < self.quorum() {
+                let term = self.term();
+                self.become_follower(term, None)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// start an election of a new term
+    pub fn campaign(&mut self) -> KvdResult<()> {
+        let term = self.term() + 1;
+        self.log.set_hard_state(term, Some(self.id))?;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.elapsed = 0;
+        self.reset_timeout();
+        self.votes = BTreeSet::new();
+        self.votes.insert(self.id);
+        if self.votes.len() >= self.quorum() {
+            return self.become_leader();
+        }
+        let (last_index, last_term) = (self.log.last_index(), self.log.last_term());
+        for peer in self.peers.clone() {
+            self.send(
+                peer,
+                Message::RequestVote {
+                    term,
+                    from: self.id,
+                    last_index,
+                    last_term,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// handle a message from another node
+    pub fn step(&mut self, msg: Message) -> KvdResult<()> {
+        let term = msg.term();
+        if term > self.term() {
+            let leader = match msg {
+                Message::Append { from, .. } | Message::InstallSnapshot { from, .. } => Some(from),
+                _ => None,
+            };
+            self.become_follower(term, leader)?;
+        } else if term < self.term() {
+            // tell the stale leader or candidate about the new term
+            let term = self.term();
+            match msg {
+                Message::RequestVote { from, .. } => self.send(
+                    from,
+                    Message::Vote {
+                        term,
+                        from: self.id,
+                        granted: false,
+                    },
+                ),
+                Message::Append { from, .. } | Message::InstallSnapshot { from, .. } => self.send(
+                    from,
+                    Message::AppendReply {
+                        term,
+                        from: self.id,
+                        success: false,
+                        index: self.log.last_index(),
+                    },
+                ),
+                _ => {}
+            }
+            return Ok(());
+        }
+
+        match msg {
+            Message::RequestVote {
+                from,
+                last_index,
+                last_term,
+                ..
+            } => self.handle_request_vote(from, last_index, last_term),
+            Message::Vote { from, granted, .. } => self.handle_vote(from, granted),
+            Message::Append {
+                from,
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+                ..
+            } => self.handle_append(from, prev_index, prev_term, entries, commit),
+            Message::AppendReply {
+                from,
+                success,
+                index,
+                ..
+            } => {
+                self.handle_append_reply(from, success, index);
+                Ok(())
+            }
+            Message::InstallSnapshot { from, snapshot, .. } => {
+                self.handle_install_snapshot(from, snapshot)
+            }
+        }
+    }
+
+    /// take out the messages to send, with the node to send to
+    pub fn take_messages(&mut self) -> Vec<(NodeId, Message)> {
+        std::mem::take(&mut self.messages)
+    }
+
+    /// take out the snapshot to replace the keys with, it goes before the
+    /// entries committed
+    pub fn take_snapshot(&mut self) -> Option<Snapshot> {
+        self.pending_snapshot.take()
+    }
+
+    /// take out the entries committed but not applied yet, they are
+    /// counted as applied
+    pub fn take_committed(&mut self) -> Vec<Entry> {
+        if self.commit <= self.applied {
+            return Vec::new();
+        }
+        let count = (self.commit - self.applied) as usize;
+        let entries = self.log.entries(self.applied + 1, count).to_vec();
+        self.applied = self.commit;
+        entries
+    }
+
+    /// whether enough entries are applied since the last snapshot
+    pub fn should_snapshot(&self) -> bool {
+        self.applied - self.log.snapshot().index >= self.snapshot_entries
+    }
+
+    /// Replace the entries applied with the snapshot of the keys after them.
+    pub fn compact(&mut self, data: Vec<Command>) -> KvdResult<()> {
+        let index = self.applied;
+        let term = self.log.term_at(index).unwrap_or_default();
+        self.log.compact(Snapshot { index, term, data })
+    }
+
+    fn quorum(&self) -> usize {
+        let nodes = self.peers.len() + 1;
+        nodes / 2 + 1
+    }
+
+    fn reset_timeout(&mut self) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(self.id);
+        self.timeout = ELECTION_TICKS + (hasher.finish() % ELECTION_TICKS as u64) as u32;
+    }
+
+    fn send(&mut self, to: NodeId, msg: Message) {
+        self.messages.push((to, msg));
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) -> KvdResult<()> {
+        if term > self.term() {
+            self.log.set_hard_state(term, None)?;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.elapsed = 0;
+        self.reset_timeout();
+        Ok(())
+    }
+
+    fn become_leader(&mut self) -> KvdResult<()> {
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.elapsed = 0;
+        let next = self.log.last_index() + 1;
+        self.progress = self
+            .peers
+            .iter()
+            .map(|&peer| {
+                let progress = Progress {
+                    next,
+                    matched: 0,
+                    active: true,
+                };
+                (peer, progress)
+            })
+            .collect();
+        // the entries of the previous terms are committed with an entry of this term
+        self.append_entry(Vec::new())?;
+        Ok(())
+    }
+
+    fn append_entry(&mut self, request: Request) -> KvdResult<u64> {
+        let index = self.log.last_index() + 1;
+        let entry = Entry {
+            term: self.term(),
+            index,
+            request,
+        };
+        self.log.append(vec![entry])?;
+        self.maybe_commit();
+        self.broadcast_append();
+        Ok(index)
+    }
+
+    fn broadcast_append(&mut self) {
+        for peer in self.peers.clone() {
+            self.send_append(peer);
+        }
+    }
+
+    /// send the entries the peer does not have yet, or the snapshot if they
+    /// are compacted away
+    fn send_append(&mut self, peer: NodeId) {
+        let next = match self.progress.get(&peer) {
+            Some(progress) => progress.next,
+            None => return,
+        };
+        let term = self.term();
+        let prev_index = next - 1;
+        let msg = match self.log.term_at(prev_index) {
+            Some(prev_term) => Message::Append {
+                term,
+                from: self.id,
+                prev_index,
+                prev_term,
+                entries: self.log.entries(next, MAX_APPEND_ENTRIES).to_vec(),
+                commit: self.commit,
+            },
+            None => Message::InstallSnapshot {
+                term,
+                from: self.id,
+                snapshot: self.log.snapshot().clone(),
+            },
+        };
+        self.send(peer, msg);
+    }
+
+    /// commit the last entry stored by a majority, if it is of the current term
+    fn maybe_commit(&mut self) {
+        let mut matched: Vec<u64> = self
+            .progress
+            .values()
+            .map(|progress| progress.matched)
+            .chain(Some(self.log.last_index()))
+            .collect();
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let index = matched[self.quorum() - 1];
+        if index > self.commit && self.log.term_at(index) == Some(self.term()) {
+            self.commit = index;
+        }
+    }
+
+    fn handle_request_vote(
+        &mut self,
+        from: NodeId,
+        last_index: u64,
+        last_term: u64,
+    ) -> KvdResult<()> {
+        // only a candidate with all the committed entries can be elected
+        let up_to_date = (last_term, last_index) >= (self.log.last_term(), self.log.last_index());
+        let granted = up_to_date && self.log.vote().is_none_or(|vote| vote == from);
+        if granted {
+            let term = self.term();
+            self.log.set_hard_state(term, Some(from))?;
+            self.elapsed = 0;
+        }
+        let term = self.term();
+        self.send(
+            from,
+            Message::Vote {
+                term,
+                from: self.id,
+                granted,
+            },
+        );
+        Ok(())
+    }
+
+    fn handle_vote(&mut self, from: NodeId, granted: bool) -> KvdResult<()> {
+        if self.role != Role::Candidate || !granted {
+            return Ok(());
+        }
+        self.votes.insert(from);
+        if self.votes.len() >= self.quorum() {
+            self.become_leader()?;
+        }
+        Ok(())
+    }
+
+    fn handle_append(
+        &mut self,
+        from: NodeId,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+    ) -> KvdResult<()> {
+        if self.role != Role::Follower {
+            let term = self.term();
+            self.become_follower(term, Some(from))?;
+        }
+        self.leader = Some(from);
+        self.elapsed = 0;
+        let term = self.term();
+        let reject = |node: &mut RaftNode, index: u64| {
+            node.send(
+                from,
+                Message::AppendReply {
+                    term,
+                    from: node.id,
+                    success: false,
+                    index,
+                },
+            )
+        };
+        let last_index = self.log.last_index();
+        if prev_index > last_index {
+            reject(self, last_index);
+            return Ok(());
+        }
+        // an index compacted into the snapshot is committed, so it matches
+        if self.log.term_at(prev_index).is_some_and(|t| t != prev_term) {
+            reject(self, prev_index - 1);
+            return Ok(());
+        }
+
+        let match_index = prev_index + entries.len() as u64;
+        // the entries already stored are skipped, a conflicting one is
+        // dropped with all the entries after it
+        let mut new_entries = Vec::new();
+        for entry in entries {
+            if !new_entries.is_empty() {
+                new_entries.push(entry);
+                continue;
+            }
+            if entry.index <= self.log.snapshot().index {
+                continue;
+            }
+            match self.log.term_at(entry.index) {
+                Some(t) if t == entry.term => {}
+                Some(_) => {
+                    self.log.truncate(entry.index)?;
+                    new_entries.push(entry);
+                }
+                None => new_entries.push(entry),
+            }
+        }
+        if !new_entries.is_empty() {
+            self.log.append(new_entries)?;
+        }
+        self.commit = self.commit.max(commit.min(match_index));
+        self.send(
+            from,
+            Message::AppendReply {
+                term,
+                from: self.id,
+                success: true,
+                index: match_index,
+            },
+        );
+        Ok(())
+    }
+
+    fn handle_append_reply(&mut self, from: NodeId, success: bool, index: u64) {
+        if self.role != Role::Leader {
+            return;
+        }
+        let last_index = self.log.last_index();
+        let progress = match self.progress.get_mut(&from) {
+            Some(progress) => progress,
+            None => return,
+        };
+        progress.active = true;
+        if success {
+            progress.matched = progress.matched.max(index);
+            progress.next = progress.matched + 1;
+            let more = progress.next <= last_index;
+            self.maybe_commit();
+            if more {
+                self.send_append(from);
+            }
+        } else {
+            // go back to the hint of the follower, at least by one entry
+            progress.next = (index + 1).min(progress.next - 1).max(progress.matched + 1);
+            self.send_append(from);
+        }
+    }
+
+    fn handle_install_snapshot(&mut self, from: NodeId, snapshot: Snapshot) -> KvdResult<()> {
+        if self.role != Role::Follower {
+            let term = self.term();
+            self.become_follower(term, Some(from))?;
+        }
+        self.leader = Some(from);
+        self.elapsed = 0;
+        let index = snapshot.index;
+        if index > self.commit {
+            self.log.install(snapshot.clone())?;
+            self.commit = index;
+            self.applied = index;
+            self.pending_snapshot = Some(snapshot);
+        }
+        let term = self.term();
+        self.send(
+            from,
+            Message::AppendReply {
+                term,
+                from: self.id,
+                success: true,
+                index,
+            },
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    /// A group connected by a simulated network, whose state machines are
+    /// maps set by "set key value" requests.
+    struct Network {
+        nodes: BTreeMap<NodeId, RaftNode>,
+        keys: BTreeMap<NodeId, BTreeMap<Vec<u8>, Vec<u8>>>,
+        /// the nodes which can reach each other, all of them if empty
+        partition: Vec<BTreeSet<NodeId>>,
+    }
+
+    impl Network {
+        fn new(size: u64) -> Network {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos();
+            let ids: Vec<NodeId> = (1..=size).collect();
+            let nodes = ids
+                .iter()
+                .map(|&id| {
+                    let dir = PathBuf::from(format!("/tmp/kvd_store/{}/{}", nanos, id));
+                    let peers = ids.iter().copied().filter(|&peer| peer != id).collect();
+                    (id, RaftNode::new(id, peers, RaftLog::open(dir).unwrap()))
+                })
+                .collect();
+            Network {
+                nodes,
+                keys: ids.iter().map(|&id| (id, BTreeMap::new())).collect(),
+                partition: Vec::new(),
+            }
+        }
+
+        fn can_reach(&self, from: NodeId, to: NodeId) -> bool {
+            self.partition.is_empty()
+                || self
+                    .partition
+                    .iter()
+                    .any(|group| group.contains(&from) && group.contains(&to))
+        }
+
+        /// split the nodes into groups which can not reach each other
+        fn split(&mut self, groups: &[&[NodeId]]) {
+            self.partition = groups
+                .iter()
+                .map(|group| group.iter().copied().collect())
+                .collect();
+        }
+
+        fn heal(&mut self) {
+            self.partition.clear();
+        }
+
+        /// deliver the messages until there is none, and apply the entries committed
+        fn deliver(&mut self) {
+            loop {
+                let mut messages = Vec::new();
+                for (&from, node) in self.nodes.iter_mut() {
+                    for (to, msg) in node.take_messages() {
+                        messages.push((from, to, msg));
+                    }
+                }
+                if messages.is_empty() {
+                    break;
+                }
+                for (from, to, msg) in messages {
+                    if self.can_reach(from, to) {
+                        self.nodes.get_mut(&to).unwrap().step(msg).unwrap();
+                    }
+                }
+            }
+            for (id, node) in self.nodes.iter_mut() {
+                let keys = self.keys.get_mut(id).unwrap();
+                if let Some(snapshot) = node.take_snapshot() {
+                    keys.clear();
+                    for cmd in snapshot.data {
+                        if let Command::Set { key, value, .. } = cmd {
+                            keys.insert(key, value);
+                        }
+                    }
+                }
+                for entry in node.take_committed() {
+                    if let [_, key, value] = entry.request.as_slice() {
+                        keys.insert(key.clone(), value.clone());
+                    }
+                }
+                if node.should_snapshot() {
+                    let data = keys
+                        .iter()
+                        .map(|(key, value)| Command::set(key.clone(), value.clone()))
+                        .collect();
+                    node.compact(data).unwrap();
+                }
+            }
+        }
+
+        fn tick(&mut self, ticks: u32) {
+            for _ in 0..ticks {
+                for node in self.nodes.values_mut() {
+                    node.tick().unwrap();
+                }
+                self.deliver();
+            }
+        }
+
+        /// tick until the nodes have one leader of the latest term, which is returned
+        fn wait_leader(&mut self, nodes: &[NodeId]) -> NodeId {
+            for _ in 0..100 {
+                self.tick(1);
+                let leaders: Vec<NodeId> = nodes
+                    .iter()
+                    .copied()
+                    .filter(|id| self.nodes[id].role() == Role::Leader)
+                    .collect();
+                if let [leader] = leaders.as_slice() {
+                    let term = self.nodes[leader].term();
+                    if nodes
+                        .iter()
+                        .all(|id| self.nodes[id].leader() == Some(*leader))
+                        && nodes.iter().all(|id| self.nodes[id].term() == term)
+                    {
+                        return *leader;
+                    }
+                }
+            }
+            panic!("no leader is elected");
+        }
+
+        fn set(&mut self, id: NodeId, key: &str, value: &str) -> Result<u64, Option<NodeId>> {
+            let request = vec![b"set".to_vec(), Vec::from(key), Vec::from(value)];
+            let result = self.nodes.get_mut(&id).unwrap().propose(request).unwrap();
+            self.deliver();
+            result
+        }
+
+        fn get(&self, id: NodeId, key: &str) -> Option<&[u8]> {
+            self.keys[&id].get(key.as_bytes()).map(Vec::as_slice)
+        }
+    }
+
+    #[test]
+    fn test_election() {
+        let mut network = Network::new(3);
+        let leader = network.wait_leader(&[1, 2, 3]);
+        let term = network.nodes[&leader].term();
+        // the heartbeats keep the leader
+        network.tick(ELECTION_TICKS * 5);
+        assert_eq!(Role::Leader, network.nodes[&leader].role());
+        assert_eq!(term, network.nodes[&leader].term());
+
+        // a single node elects itself
+        let mut network = Network::new(1);
+        network.nodes.get_mut(&1).unwrap().campaign().unwrap();
+        assert_eq!(Role::Leader, network.nodes[&1].role());
+        assert_eq!(Ok(2), network.set(1, "a", "1"));
+        assert_eq!(Some(&b"1"[..]), network.get(1, "a"));
+    }
+
+    #[test]
+    fn test_replication() {
+        let mut network = Network::new(3);
+        network.nodes.get_mut(&1).unwrap().campaign().unwrap();
+        network.deliver();
+        assert_eq!(Role::Leader, network.nodes[&1].role());
+
+        // the entry appended by the new leader is at index 1
+        assert_eq!(Ok(2), network.set(1, "a", "1"));
+        assert_eq!(Err(Some(1)), network.set(2, "a", "2"));
+        // the followers learn the commit index by the next message
+        network.tick(HEARTBEAT_TICKS);
+        for id in 1..=3 {
+            assert_eq!(Some(&b"1"[..]), network.get(id, "a"));
+            assert_eq!(2, network.nodes[&id].applied());
+        }
+
+        // a follower cut off catches up after the partition heals
+        network.split(&[&[1, 2], &[3]]);
+        assert_eq!(Ok(3), network.set(1, "b", "2"));
+        assert_eq!(3, network.nodes[&1].commit());
+        network.heal();
+        network.tick(HEARTBEAT_TICKS);
+        assert_eq!(Some(&b"2"[..]), network.get(3, "b"));
+    }
+
+    #[test]
+    fn test_leader_failure() {
+        let mut network = Network::new(5);
+        let old = network.wait_leader(&[1, 2, 3, 4, 5]);
+        network.set(old, "a", "1").unwrap();
+        network.tick(HEARTBEAT_TICKS);
+
+        // the leader is cut off with another node, a write to it never commits
+        let follower = if old == 1 { 2 } else { 1 };
+        let minority = [old, follower];
+        let majority: Vec<NodeId> = (1..=5).filter(|id| !minority.contains(id)).collect();
+        network.split(&[&minority, &majority]);
+        let index = network.set(old, "a", "lost").unwrap();
+        let leader = network.wait_leader(&majority);
+        assert!(network.nodes[&leader].term() > network.nodes[&old].term());
+        network.set(leader, "a", "2").unwrap();
+        network.tick(HEARTBEAT_TICKS);
+        assert!(network.nodes[&old].commit() < index);
+
+        // the old leader steps down since it can not reach a majority
+        for _ in 0..ELECTION_TICKS * 4 {
+            if network.nodes[&old].role() != Role::Leader {
+                break;
+            }
+            network.tick(1);
+        }
+        assert_ne!(Role::Leader, network.nodes[&old].role());
+        assert_eq!(Err(None), network.set(old, "b", "1"));
+
+        // after the partition heals the lost write is replaced by the log of the new leader
+        network.heal();
+        let leader = network.wait_leader(&[1, 2, 3, 4, 5]);
+        network.set(leader, "b", "3").unwrap();
+        network.tick(HEARTBEAT_TICKS);
+        for id in 1..=5 {
+            assert_eq!(Some(&b"2"[..]), network.get(id, "a"));
+            assert_eq!(Some(&b"3"[..]), network.get(id, "b"));
+            assert_eq!(
+                network.nodes[&leader].log().last_index(),
+                network.nodes[&id].applied()
+            );
+        }
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut network = Network::new(3);
+        for node in network.nodes.values_mut() {
+            node.set_snapshot_entries(5);
+        }
+        network.nodes.get_mut(&1).unwrap().campaign().unwrap();
+        network.deliver();
+
+        // a follower cut off misses the entries compacted into the snapshot
+        network.split(&[&[1, 2], &[3]]);
+        for i in 0..10 {
+            network.set(1, &format!("k{}", i), &i.to_string()).unwrap();
+        }
+        network.tick(HEARTBEAT_TICKS);
+        assert!(network.nodes[&1].log().snapshot().index >= 5);
+        assert_eq!(
+            Some(&b"0"[..]),
+            network.nodes[&1]
+                .log()
+                .snapshot()
+                .data
+                .iter()
+                .find_map(|cmd| match cmd {
+                    Command::Set { key, value, .. } if key == b"k0" => Some(&value[..]),
+                    _ => None,
+                })
+        );
+
+        network.heal();
+        network.tick(HEARTBEAT_TICKS);
+        network.set(1, "k10", "10").unwrap();
+        network.tick(HEARTBEAT_TICKS);
+        for i in 0..=10 {
+            let value = i.to_string();
+            assert_eq!(Some(value.as_bytes()), network.get(3, &format!("k{}", i)));
+        }
+        assert_eq!(network.nodes[&1].applied(), network.nodes[&3].applied());
+    }
+
+    #[test]
+    fn test_restart() {
+        let mut network = Network::new(3);
+        network.nodes.get_mut(&1).unwrap().campaign().unwrap();
+        network.deliver();
+        for node in network.nodes.values_mut() {
+            node.set_snapshot_entries(3);
+        }
+        for i in 0..5 {
+            network.set(1, "a", &i.to_string()).unwrap();
+        }
+        network.tick(HEARTBEAT_TICKS);
+
+        // a node restarted loads its snapshot, then applies the entries after
+        // it once the leader tells the commit index
+        let term = network.nodes[&2].term();
+        let log = RaftLog::open(network.nodes[&2].log.dir().to_path_buf()).unwrap();
+        let mut node = RaftNode::new(2, vec![1, 3], log);
+        assert_eq!(term, node.term());
+        let snapshot = node.take_snapshot().unwrap();
+        assert_eq!(snapshot.index, node.applied());
+        network.nodes.insert(2, node);
+        network.keys.get_mut(&2).unwrap().clear();
+        for cmd in snapshot.data {
+            if let Command::Set { key, value, .. } = cmd {
+                network.keys.get_mut(&2).unwrap().insert(key, value);
+            }
+        }
+        network.tick(HEARTBEAT_TICKS);
+        assert_eq!(Some(&b"4"[..]), network.get(2, "a"));
+        assert_eq!(network.nodes[&1].applied(), network.nodes[&2].applied());
+    }
+}
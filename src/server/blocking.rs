@@ -0,0 +1,205 @@
+//! The clients blocked by BLPOP and BRPOP until a list is pushed.
+//!
+//! A client registers a `Waiter` on every key it waits for while it still
+//! holds the engine lock, and a push wakes the waiters of the key while it
+//! holds the lock too, so a push is never missed between the failed pop and
+//! the registration.
+
+use super::pubsub::Waker;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+#[cfg(feature = "async")]
+use std::time::Instant;
+
+/// A blocked client, woken when one of its keys may have an element.
+pub(crate) struct Waiter {
+    keys: Vec<Vec<u8>>,
+    woken: Mutex<bool>,
+    condvar: Condvar,
+    /// wakes a connection of the event loop, which does not wait on the condvar
+    waker: Mutex<Option<Waker>>,
+}
+
+/// A BLPOP or BRPOP parked by a connection of the event loop, which waits
+/// for the waiter without holding a thread. The waiter is unblocked when it
+/// is dropped.
+#[cfg(feature = "async")]
+pub(crate) struct BlockedPop {
+    blocked: Arc<BlockedClients>,
+    waiter: Arc<Waiter>,
+    pub(crate) front: bool,
+    pub(crate) deadline: Option<Instant>,
+}
+
+/// The waiters of every key, in the order they are blocked.
+pub struct BlockedClients {
+    waiters: Mutex<HashMap<Vec<u8>, VecDeque<Arc<Waiter>>>>,
+    /// the number of waiters, shown in INFO
+    blocked: AtomicUsize,
+}
+
+impl Waiter {
+    /// Wait until the waiter is woken or the timeout is reached, return
+    /// whether it is woken.
+    pub(crate) fn wait(&self, timeout: Duration) -> bool {
+        let woken = self.woken.lock().unwrap();
+        let (woken, _) = self
+            .condvar
+            .wait_timeout_while(woken, timeout, |woken| !*woken)
+            .unwrap();
+        *woken
+    }
+
+    pub(crate) fn is_woken(&self) -> bool {
+        *self.woken.lock().unwrap()
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn keys(&self) -> &[Vec<u8>] {
+        &self.keys
+    }
+
+    /// set the waker called when the waiter is woken, or call it now if
+    /// the waiter is already woken
+    #[cfg(feature = "async")]
+    pub(crate) fn set_waker(&self, waker: Waker) {
+        let woken = self.woken.lock().unwrap();
+        if *woken {
+            waker();
+        }
+        *self.waker.lock().unwrap() = Some(waker);
+    }
+
+    fn wake(&self) {
+        let mut woken = self.woken.lock().unwrap();
+        *woken = true;
+        self.condvar.notify_one();
+        if let Some(waker) = &*self.waker.lock().unwrap() {
+            waker();
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl BlockedPop {
+    pub(crate) fn new(
+        blocked: Arc<BlockedClients>,
+        waiter: Arc<Waiter>,
+        front: bool,
+        deadline: Option<Instant>,
+    ) -> BlockedPop {
+        BlockedPop {
+            blocked,
+            waiter,
+            front,
+            deadline,
+        }
+    }
+
+    pub(crate) fn waiter(&self) -> &Arc<Waiter> {
+        &self.waiter
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for BlockedPop {
+    fn drop(&mut self) {
+        self.blocked.unblock(&self.waiter);
+    }
+}
+
+impl BlockedClients {
+    pub fn new() -> BlockedClients {
+        BlockedClients {
+            waiters: Mutex::new(HashMap::new()),
+            blocked: AtomicUsize::new(0),
+        }
+    }
+
+    /// the number of blocked clients
+    pub fn len(&self) -> usize {
+        self.blocked.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// register a waiter on the keys, it should be removed by `unblock`
+    pub(crate) fn block(&self, keys: Vec<Vec<u8>>) -> Arc<Waiter> {
+        let waiter = Arc::new(Waiter {
+            keys,
+            woken: Mutex::new(false),
+            condvar: Condvar::new(),
+            waker: Mutex::new(None),
+        });
+        let mut waiters = self.waiters.lock().unwrap();
+        for key in waiter.keys.iter() {
+            waiters
+                .entry(key.clone())
+                .or_default()
+                .push_back(waiter.clone());
+        }
+        self.blocked.fetch_add(1, Ordering::Relaxed);
+        waiter
+    }
+
+    pub(crate) fn unblock(&self, waiter: &Arc<Waiter>) {
+        let mut waiters = self.waiters.lock().unwrap();
+        for key in waiter.keys.iter() {
+            if let Some(queue) = waiters.get_mut(key) {
+                queue.retain(|w| !Arc::ptr_eq(w, waiter));
+                if queue.is_empty() {
+                    waiters.remove(key);
+                }
+            }
+        }
+        self.blocked.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Wake the first count waiters of the key which are not woken yet, one
+    /// for every element pushed.
+    pub(crate) fn wake(&self, key: &[u8], count: usize) {
+        let waiters = self.waiters.lock().unwrap();
+        if let Some(queue) = waiters.get(key) {
+            queue
+                .iter()
+                .filter(|waiter| !waiter.is_woken())
+                .take(count)
+                .for_each(|waiter| waiter.wake());
+        }
+    }
+}
+
+impl Default for BlockedClients {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wake_in_order() {
+        let blocked = BlockedClients::new();
+        let first = blocked.block(vec![b"a".to_vec(), b"b".to_vec()]);
+        let second = blocked.block(vec![b"a".to_vec()]);
+        assert_eq!(2, blocked.len());
+
+        blocked.wake(b"a", 1);
+        assert!(first.wait(Duration::from_millis(0)));
+        assert!(!second.wait(Duration::from_millis(0)));
+        // a woken waiter is skipped until it is blocked again
+        blocked.wake(b"b", 1);
+        blocked.wake(b"a", 1);
+        assert!(second.wait(Duration::from_millis(0)));
+
+        blocked.unblock(&first);
+        blocked.unblock(&second);
+        assert!(blocked.is_empty());
+    }
+}
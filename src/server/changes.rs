@@ -0,0 +1,250 @@
+//! CHANGES, which streams the changes logged by the engine in order.
+//!
+//! The changes already logged are read from the wal files, then the new ones
+//! are polled for, so a consumer which restarts resumes from the last
+//! sequence number it has seen.
+
+use super::clients::Client;
+use super::{Server, KILL_POLL_INTERVAL};
+use crate::engine::bitcask::Command;
+use crate::engine::KvdEngine;
+use crate::model::{KvdError, KvdErrorKind, KvdResult};
+use crate::protocol::Reply;
+use std::convert::TryFrom;
+use std::io::Write;
+use std::str;
+use std::thread;
+
+/// the max number of changes read from the engine at once
+const CHANGES_BATCH_SIZE: usize = 256;
+
+impl<T: KvdEngine> Server<T> {
+    /// CHANGES seq, the changes after seq are streamed after +OK
+    pub(super) fn handle_changes(&self, client: &Client, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        if request.len() != 2 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let seq = str::from_utf8(&request[1])?
+            .parse::<u64>()
+            .map_err(|_| KvdError::from(KvdErrorKind::InvalidRequest))?;
+        // fail before streaming if the history is not available
+        if let Err(oldest) = self.engine().changes_since(seq, 0)? {
+            return Ok(compacted_reply(seq, oldest));
+        }
+        client.request_changes(seq);
+        Ok(Reply::ok())
+    }
+
+    /// Write the changes after seq to the output and move seq to the last
+    /// one. Return false if the stream ends, since the changes are compacted
+    /// away before they are read.
+    pub(crate) fn next_changes(&self, seq: &mut u64, output: &mut Vec<u8>) -> KvdResult<bool> {
+        let changes = match self.engine().changes_since(*seq, CHANGES_BATCH_SIZE)? {
+            Ok(changes) => changes,
+            Err(oldest) => {
+                compacted_reply(*seq, oldest).write_to(output)?;
+                return Ok(false);
+            }
+        };
+        for cmd in changes {
+            *seq = cmd.seq();
+            change_reply(cmd).write_to(output)?;
+        }
+        Ok(true)
+    }
+
+    /// Stream the changes after seq until the client is killed.
+    pub(super) fn serve_changes<W: Write>(
+        &self,
+        client: &Client,
+        mut seq: u64,
+        mut writer: W,
+    ) -> KvdResult<()> {
+        let mut output = Vec::new();
+        while !client.is_killed() {
+            let more = self.next_changes(&mut seq, &mut output)?;
+            if output.is_empty() {
+                thread::sleep(KILL_POLL_INTERVAL);
+                continue;
+            }
+            self.record_bytes_out(client, output.len());
+            writer.write_all(&output)?;
+            writer.flush()?;
+            output.clear();
+            if !more {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// the error replied when the changes after seq are compacted away
+pub(crate) fn compacted_reply(seq: u64, oldest: u64) -> Reply {
+    Reply::Error(format!(
+        "COMPACTED the changes after {} are compacted away, the oldest sequence number available is {}",
+        seq, oldest
+    ))
+}
+
+/// A change is replied as an array of the sequence number, the operation,
+/// the key, the data and the expire time. The data is the value of a set,
+/// or the json of the change of a collection.
+pub(crate) fn change_reply(cmd: Command) -> Reply {
+    let (seq, op, key, data, expire_at) = match cmd {
+        Command::Set {
+            seq,
+            key,
+            value,
+            expire_at,
+        } => (seq, "set", key, Reply::Bulk(value), expire_at),
+        Command::Del { seq, key } => (seq, "del", key, Reply::Nil, None),
+        Command::Change { seq, key, change } => {
+            // serializing a change never fails
+            let data = serde_json::to_vec(&change).unwrap_or_default();
+            (seq, "change", key, Reply::Bulk(data), None)
+        }
+        // never read as a change, since they have no sequence number
+        Command::Batch { .. } | Command::Compaction { .. } => unreachable!("not a change"),
+    };
+    Reply::Array(vec![
+        Reply::Integer(seq as i64),
+        Reply::Bulk(op.as_bytes().to_vec()),
+        Reply::Bulk(key),
+        data,
+        expire_at.map_or(Reply::Nil, |at| Reply::Integer(at as i64)),
+    ])
+}
+
+/// parse a change replied by `change_reply` back
+pub(crate) fn parse_change(reply: Reply) -> KvdResult<Command> {
+    let invalid = || KvdError::from(KvdErrorKind::InvalidReply);
+    let fields = match reply {
+        Reply::Array(fields) => <[Reply; 5]>::try_from(fields).map_err(|_| invalid())?,
+        _ => return Err(invalid()),
+    };
+    let [seq, op, key, data, expire_at] = fields;
+    let (seq, op, key) = match (seq, op, key) {
+        (Reply::Integer(seq), Reply::Bulk(op), Reply::Bulk(key)) if seq >= 0 => {
+            (seq as u64, op, key)
+        }
+        _ => return Err(invalid()),
+    };
+    let expire_at = match expire_at {
+        Reply::Integer(at) if at >= 0 => Some(at as u64),
+        Reply::Nil => None,
+        _ => return Err(invalid()),
+    };
+    match (op.as_slice(), data) {
+        (b"set", Reply::Bulk(value)) => Ok(Command::Set {
+            seq,
+            key,
+            value,
+            expire_at,
+        }),
+        (b"del", Reply::Nil) => Ok(Command::Del { seq, key }),
+        (b"change", Reply::Bulk(data)) => Ok(Command::Change {
+            seq,
+            key,
+            change: serde_json::from_slice(&data).map_err(|_| invalid())?,
+        }),
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{request, start_server};
+    use super::*;
+    use crate::engine::bitcask::BitcaskEngine;
+    use crate::engine::value::Change;
+    use crate::server::ServerConfig;
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::path::PathBuf;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn test_changes() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let path = PathBuf::from(format!("/tmp/kvd_store/{}", nanos));
+        let mut engine = BitcaskEngine::open(path).unwrap();
+        engine.set(Vec::from("old"), Vec::from("0")).unwrap();
+        engine.compact().unwrap();
+        let server = Server::with_config(engine, ServerConfig::default()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || server.serve_listener(listener));
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        assert_eq!(b"+OK\r\n".to_vec(), request(&mut client, b"set a 1\r\n"));
+        assert_eq!(b":1\r\n".to_vec(), request(&mut client, b"sadd s m\r\n"));
+
+        let mut consumer = TcpStream::connect(addr).unwrap();
+        assert_eq!(
+            b"-COMPACTED the changes after 0 are compacted away, \
+              the oldest sequence number available is 1\r\n"
+                .to_vec(),
+            request(&mut consumer, b"changes 0\r\n")
+        );
+        assert_eq!(
+            b"-ERR invalid request\r\n".to_vec(),
+            request(&mut consumer, b"changes -1\r\n")
+        );
+        consumer.write_all(b"changes 1\r\n").unwrap();
+        // the changes logged before are streamed, then the new ones
+        let expect = "+OK\r\n\
+                      *5\r\n:2\r\n$3\r\nset\r\n$1\r\na\r\n$1\r\n1\r\n$-1\r\n\
+                      *5\r\n:3\r\n$6\r\nchange\r\n$1\r\ns\r\n$16\r\n{\"SAdd\":[[109]]}\r\n$-1\r\n";
+        let mut reply = vec![0; expect.len()];
+        consumer.read_exact(&mut reply).unwrap();
+        assert_eq!(expect, String::from_utf8(reply).unwrap());
+
+        assert_eq!(b":1\r\n".to_vec(), request(&mut client, b"del a\r\n"));
+        let expect = "*5\r\n:4\r\n$3\r\ndel\r\n$1\r\na\r\n$-1\r\n$-1\r\n";
+        let mut reply = vec![0; expect.len()];
+        consumer.read_exact(&mut reply).unwrap();
+        assert_eq!(expect, String::from_utf8(reply).unwrap());
+    }
+
+    #[test]
+    fn test_parse_change() {
+        let changes = vec![
+            Command::Set {
+                seq: 1,
+                key: Vec::from("a"),
+                value: Vec::from("1"),
+                expire_at: Some(100),
+            },
+            Command::Del {
+                seq: 2,
+                key: Vec::from("a"),
+            },
+            Command::Change {
+                seq: 3,
+                key: Vec::from("s"),
+                change: Change::SAdd(vec![Vec::from("m")]),
+            },
+        ];
+        for cmd in changes {
+            assert_eq!(Ok(cmd.clone()), parse_change(change_reply(cmd)));
+        }
+        assert_eq!(
+            Err(KvdError::from(KvdErrorKind::InvalidReply)),
+            parse_change(Reply::Array(vec![Reply::Integer(1)]))
+        );
+    }
+
+    #[test]
+    fn test_no_change_log() {
+        let addr = start_server(ServerConfig::default());
+        let mut client = TcpStream::connect(addr).unwrap();
+        assert_eq!(
+            b"-ERR the engine does not keep a change log\r\n".to_vec(),
+            request(&mut client, b"changes 0\r\n")
+        );
+    }
+}
@@ -0,0 +1,309 @@
+#[cfg(feature = "async")]
+use super::blocking::BlockedPop;
+use slog::Logger;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+pub(crate) type Closer = Box<dyn Fn() + Send + Sync>;
+
+/// All the connected clients of a server.
+pub struct ClientRegistry {
+    clients: Mutex<BTreeMap<u64, Arc<Client>>>,
+    next_id: AtomicU64,
+    max_clients: usize,
+    logger: Logger,
+}
+
+/// The metadata of a connection.
+pub struct Client {
+    id: u64,
+    addr: String,
+    created_at: Instant,
+    state: Mutex<ClientState>,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    killed: AtomicBool,
+    /// the number of channels and patterns the client is subscribed to
+    subscriptions: AtomicUsize,
+    /// the client has subscribed, so its replies are queued with its messages
+    subscriber: AtomicBool,
+    /// the connection is served by the event loop, so a blocking command is
+    /// parked instead of blocking the thread
+    #[cfg(feature = "async")]
+    nonblocking: AtomicBool,
+    closer: Option<Closer>,
+    /// logs with the id and the address of the client
+    logger: Logger,
+}
+
+struct ClientState {
+    name: Option<String>,
+    last_active: Instant,
+    last_command: String,
+    /// the client sent MONITOR, and the connection should start streaming
+    monitor_requested: bool,
+    /// the client sent CHANGES, and the connection should start streaming
+    /// the changes after the sequence number
+    changes_requested: Option<u64>,
+    /// the client sent ASKING, so its next request can be served by a node
+    /// importing the slot of its keys
+    asking: bool,
+    /// the BLPOP or BRPOP parked by the connection of the event loop
+    #[cfg(feature = "async")]
+    blocked_pop: Option<BlockedPop>,
+}
+
+/// Keeps the client registered until it is dropped.
+pub(crate) struct ClientGuard {
+    registry: Arc<ClientRegistry>,
+    client: Arc<Client>,
+}
+
+impl ClientRegistry {
+    pub fn new(max_clients: usize, logger: Logger) -> ClientRegistry {
+        ClientRegistry {
+            clients: Mutex::new(BTreeMap::new()),
+            next_id: AtomicU64::new(1),
+            max_clients,
+            logger,
+        }
+    }
+
+    /// Register a new client, return None if there are too many clients already.
+    ///
+    /// The closer is called when the client is killed by another connection,
+    /// it should make the connection stop serving, e.g. by shutting down the socket.
+    pub(crate) fn register(
+        self: &Arc<Self>,
+        addr: String,
+        closer: Option<Closer>,
+    ) -> Option<ClientGuard> {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= self.max_clients {
+            return None;
+        }
+        let now = Instant::now();
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let client = Arc::new(Client {
+            id,
+            logger: self.logger.new(o!("conn_id" => id, "peer" => addr.clone())),
+            addr,
+            created_at: now,
+            state: Mutex::new(ClientState {
+                name: None,
+                last_active: now,
+                last_command: "NULL".to_string(),
+                monitor_requested: false,
+                changes_requested: None,
+                asking: false,
+                #[cfg(feature = "async")]
+                blocked_pop: None,
+            }),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            killed: AtomicBool::new(false),
+            subscriptions: AtomicUsize::new(0),
+            subscriber: AtomicBool::new(false),
+            #[cfg(feature = "async")]
+            nonblocking: AtomicBool::new(false),
+            closer,
+        });
+        clients.insert(client.id, client.clone());
+        Some(ClientGuard {
+            registry: self.clone(),
+            client,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn list(&self) -> Vec<Arc<Client>> {
+        self.clients.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Kill the clients matching the filter, return the number of killed clients.
+    ///
+    /// The current client is only marked as killed, so that the reply of the
+    /// kill request can still be sent before its connection is closed.
+    pub fn kill<F: Fn(&Client) -> bool>(&self, current: &Client, filter: F) -> usize {
+        let clients = self.list();
+        let mut killed = 0;
+        // a killed client may stay registered until its connection is closed
+        for client in clients.iter().filter(|c| !c.is_killed() && filter(c)) {
+            if client.id != current.id {
+                client.close();
+            } else {
+                client.killed.store(true, Ordering::SeqCst);
+            }
+            killed += 1;
+        }
+        killed
+    }
+}
+
+impl Client {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    pub fn name(&self) -> Option<String> {
+        self.state.lock().unwrap().name.clone()
+    }
+
+    pub fn set_name(&self, name: Option<String>) {
+        self.state.lock().unwrap().name = name;
+    }
+
+    pub fn logger(&self) -> &Logger {
+        &self.logger
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.killed.load(Ordering::SeqCst)
+    }
+
+    /// mark the client as killed and stop its connection
+    pub(crate) fn close(&self) {
+        self.killed.store(true, Ordering::SeqCst);
+        if let Some(closer) = &self.closer {
+            closer();
+        }
+    }
+
+    pub fn subscriptions(&self) -> usize {
+        self.subscriptions.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_subscriptions(&self, n: usize) {
+        self.subscriptions.store(n, Ordering::SeqCst);
+    }
+
+    /// whether the replies of the client are queued in the pubsub, which
+    /// stays true once the client subscribes
+    pub(crate) fn is_subscriber(&self) -> bool {
+        self.subscriber.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_subscriber(&self, subscriber: bool) {
+        self.subscriber.store(subscriber, Ordering::SeqCst);
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn is_nonblocking(&self) -> bool {
+        self.nonblocking.load(Ordering::SeqCst)
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn set_nonblocking(&self) {
+        self.nonblocking.store(true, Ordering::SeqCst);
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn park_blocked_pop(&self, pop: BlockedPop) {
+        self.state.lock().unwrap().blocked_pop = Some(pop);
+    }
+
+    /// the BLPOP or BRPOP parked since the last call, its reply is sent once
+    /// it is resumed
+    #[cfg(feature = "async")]
+    pub(crate) fn take_blocked_pop(&self) -> Option<BlockedPop> {
+        self.state.lock().unwrap().blocked_pop.take()
+    }
+
+    pub(crate) fn request_monitor(&self) {
+        self.state.lock().unwrap().monitor_requested = true;
+    }
+
+    /// whether the client sent MONITOR since the last call
+    pub(crate) fn take_monitor_request(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        std::mem::replace(&mut state.monitor_requested, false)
+    }
+
+    pub(crate) fn request_changes(&self, seq: u64) {
+        self.state.lock().unwrap().changes_requested = Some(seq);
+    }
+
+    /// the sequence number the client sent CHANGES with since the last call
+    pub(crate) fn take_changes_request(&self) -> Option<u64> {
+        self.state.lock().unwrap().changes_requested.take()
+    }
+
+    pub(crate) fn set_asking(&self) {
+        self.state.lock().unwrap().asking = true;
+    }
+
+    /// whether the client sent ASKING since the last call
+    pub(crate) fn take_asking(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        std::mem::replace(&mut state.asking, false)
+    }
+
+    /// an unknown command only refreshes the idle time, the last command
+    /// shown is the last known one
+    pub(crate) fn record_command(&self, cmd: Option<&str>) {
+        let mut state = self.state.lock().unwrap();
+        state.last_active = Instant::now();
+        if let Some(cmd) = cmd {
+            state.last_command = cmd.to_string();
+        }
+    }
+
+    pub(crate) fn add_bytes_in(&self, n: usize) {
+        self.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_bytes_out(&self, n: usize) {
+        self.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// one line of CLIENT LIST
+    pub fn info_line(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut line = String::new();
+        write!(
+            line,
+            "id={} addr={} name={} age={} idle={} cmd={} net-in={} net-out={} sub={}",
+            self.id,
+            self.addr,
+            state.name.as_deref().unwrap_or(""),
+            self.created_at.elapsed().as_secs(),
+            state.last_active.elapsed().as_secs(),
+            state.last_command,
+            self.bytes_in.load(Ordering::Relaxed),
+            self.bytes_out.load(Ordering::Relaxed),
+            self.subscriptions(),
+        )
+        .unwrap();
+        line
+    }
+}
+
+impl ClientGuard {
+    pub fn client(&self) -> &Arc<Client> {
+        &self.client
+    }
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.registry
+            .clients
+            .lock()
+            .unwrap()
+            .remove(&self.client.id);
+    }
+}
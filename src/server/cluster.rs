@@ -0,0 +1,826 @@
+//! Serving the slots of a cluster.
+//!
+//! A node serves the keys of the slots assigned to it, a request with a key
+//! of another slot is redirected to the node serving it with -MOVED, and the
+//! keys of a request should all be in one slot. The slot map is given by the
+//! config of every node, and can be changed by CLUSTER SETSLOT, which should
+//! be sent to every node. A changed map is saved to the nodes file, which is
+//! loaded instead of the config on a restart.
+//!
+//! A slot is moved online like in redis: the target is set IMPORTING it and
+//! the source MIGRATING it, then the keys of the slot are moved by MIGRATE
+//! in batches. Meanwhile the source serves the keys it still has and replies
+//! -ASK with the target for the others, and the target serves a request of
+//! the slot only after ASKING. At last the slot is assigned to the target on
+//! every node by CLUSTER SETSLOT slot NODE id.
+
+use super::clients::Client;
+use super::replication::apply_change;
+use super::Server;
+use crate::client::Connection;
+use crate::cluster::{command_keys, key_slot, parse_nodes, parse_slot, ClusterConfig, SLOTS};
+use crate::engine::bitcask::Command;
+use crate::engine::KvdEngine;
+use crate::model::{KvdError, KvdErrorKind, KvdResult};
+use crate::protocol::Reply;
+use crate::raft::NodeId;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::Write as _;
+use std::path::PathBuf;
+use std::str;
+use std::sync::{Mutex, RwLock, RwLockReadGuard};
+use std::time::Duration;
+
+/// the read timeout of the replies of the target of MIGRATE
+const MIGRATE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The cluster state of a node.
+pub struct Cluster {
+    myself: NodeId,
+    /// the address of every node
+    nodes: BTreeMap<NodeId, String>,
+    /// the node serving each slot
+    slots: Mutex<Vec<Option<NodeId>>>,
+    /// the slots being moved from or to this node
+    migrations: Mutex<BTreeMap<u16, Migration>>,
+    /// one lock per slot, held by MIGRATE to write, and by a request of a
+    /// migrating slot to read, so that its keys are not moved between the
+    /// check and the execution of the request
+    moving: Vec<RwLock<()>>,
+    /// the file the slot map is saved to
+    nodes_file: Option<PathBuf>,
+}
+
+/// how a slot is being moved
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Migration {
+    /// the keys of the slot are moved to the node
+    Migrating(NodeId),
+    /// the keys of the slot are moved from the node
+    Importing(NodeId),
+}
+
+/// Where a request is served.
+pub(super) enum Route<'a> {
+    /// by this node, while the guard is held if the slot is migrating
+    Serve(Option<RwLockReadGuard<'a, ()>>),
+    Redirect(Reply),
+}
+
+impl Cluster {
+    /// Load the slot map from the nodes file if it was saved, or from the
+    /// nodes of the config.
+    pub fn new(config: ClusterConfig) -> KvdResult<Cluster> {
+        let config_nodes = match config.nodes_file.as_ref().filter(|path| path.exists()) {
+            Some(path) => parse_nodes(fs::read_to_string(path)?.trim())?,
+            None => config.nodes,
+        };
+        let mut slots = vec![None; SLOTS as usize];
+        let mut nodes = BTreeMap::new();
+        for node in config_nodes {
+            for &(start, end) in node.slots.iter() {
+                for slot in &mut slots[start as usize..=end as usize] {
+                    // a slot can only be served by one node
+                    if slot.replace(node.id).is_some() {
+                        return Err(KvdError::from(KvdErrorKind::Config));
+                    }
+                }
+            }
+            nodes.insert(node.id, node.addr);
+        }
+        if !nodes.contains_key(&config.id) {
+            return Err(KvdError::from(KvdErrorKind::UnknownNode));
+        }
+        Ok(Cluster {
+            myself: config.id,
+            nodes,
+            slots: Mutex::new(slots),
+            migrations: Mutex::new(BTreeMap::new()),
+            moving: (0..SLOTS).map(|_| RwLock::new(())).collect(),
+            nodes_file: config.nodes_file,
+        })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.myself
+    }
+
+    /// the address of the node, None if it is not in the cluster
+    pub fn address(&self, id: NodeId) -> Option<&str> {
+        self.nodes.get(&id).map(String::as_str)
+    }
+
+    /// the node serving the slot, None if it is not assigned
+    pub fn owner(&self, slot: u16) -> Option<NodeId> {
+        self.slots.lock().unwrap()[slot as usize]
+    }
+
+    /// assign the slot to the node, which ends the migration of the slot
+    pub fn set_owner(&self, slot: u16, id: NodeId) -> KvdResult<()> {
+        if !self.nodes.contains_key(&id) {
+            return Err(KvdError::from(KvdErrorKind::UnknownNode));
+        }
+        // waits for a MIGRATE of the slot to finish
+        let _moving = self.moving[slot as usize].write().unwrap();
+        let mut slots = self.slots.lock().unwrap();
+        slots[slot as usize] = Some(id);
+        self.migrations.lock().unwrap().remove(&slot);
+        // saved while the slots are locked, so the last change is saved last
+        self.save(&slots)
+    }
+
+    /// Write the slot map into the nodes file like the nodes of the config,
+    /// into a temporary file first so that a crash leaves the old one.
+    fn save(&self, slots: &[Option<NodeId>]) -> KvdResult<()> {
+        let path = match self.nodes_file.as_ref() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let ranges = slot_ranges(slots);
+        let nodes: Vec<String> = self
+            .nodes
+            .iter()
+            .map(|(id, addr)| {
+                let mut node = format!("{}@{}", id, addr);
+                for (start, end, _) in ranges.iter().filter(|(_, _, owner)| owner == id) {
+                    write!(node, " {}-{}", start, end).unwrap();
+                }
+                node
+            })
+            .collect();
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        writeln!(file, "{}", nodes.join(","))?;
+        file.sync_all()?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    fn migration(&self, slot: u16) -> Option<Migration> {
+        self.migrations.lock().unwrap().get(&slot).copied()
+    }
+
+    /// Start moving the slot, or stop it if the migration is None. Only the
+    /// owner of a slot can migrate it, and only another node can import it.
+    fn set_migration(&self, slot: u16, migration: Option<Migration>) -> KvdResult<()> {
+        // waits for a MIGRATE of the slot to finish
+        let _moving = self.moving[slot as usize].write().unwrap();
+        let mut migrations = self.migrations.lock().unwrap();
+        let migration = match migration {
+            Some(migration) => migration,
+            None => {
+                migrations.remove(&slot);
+                return Ok(());
+            }
+        };
+        let (Migration::Migrating(id) | Migration::Importing(id)) = migration;
+        if id == self.myself || !self.nodes.contains_key(&id) {
+            return Err(KvdError::from(KvdErrorKind::UnknownNode));
+        }
+        let owned = self.owner(slot) == Some(self.myself);
+        if owned != matches!(migration, Migration::Migrating(_)) {
+            return Err(KvdError::from(KvdErrorKind::NotSlotOwner));
+        }
+        migrations.insert(slot, migration);
+        Ok(())
+    }
+
+    fn redirect(&self, kind: &str, slot: u16, id: NodeId) -> Route<'_> {
+        let addr = self.address(id).unwrap_or_default();
+        Route::Redirect(Reply::Error(format!("{} {} {}", kind, slot, addr)))
+    }
+
+    /// the ranges of the slots served by the same node, in the order of the slots
+    fn ranges(&self) -> Vec<(u16, u16, NodeId)> {
+        slot_ranges(&self.slots.lock().unwrap())
+    }
+    /// the fields shown in the cluster section of INFO
+    pub(super) fn info(&self) -> Vec<(String, String)> {
+        let slots = self.slots.lock().unwrap();
+        let assigned = slots.iter().filter(|owner| owner.is_some()).count();
+        let served = slots
+            .iter()
+            .filter(|owner| **owner == Some(self.myself))
+            .count();
+        vec![
+            ("cluster_enabled".to_string(), "1".to_string()),
+            ("cluster_id".to_string(), self.myself.to_string()),
+            (
+                "cluster_known_nodes".to_string(),
+                self.nodes.len().to_string(),
+            ),
+            ("cluster_slots_assigned".to_string(), assigned.to_string()),
+            ("cluster_slots_served".to_string(), served.to_string()),
+            (
+                "cluster_slots_migrating".to_string(),
+                self.migrations.lock().unwrap().len().to_string(),
+            ),
+        ]
+    }
+}
+
+/// the ranges of the slots served by the same node, in the order of the slots
+fn slot_ranges(slots: &[Option<NodeId>]) -> Vec<(u16, u16, NodeId)> {
+    let mut ranges: Vec<(u16, u16, NodeId)> = Vec::new();
+    for (slot, owner) in slots.iter().enumerate() {
+        let (slot, owner) = match owner {
+            Some(owner) => (slot as u16, *owner),
+            None => continue,
+        };
+        match ranges.last_mut() {
+            Some((_, end, id)) if *id == owner && *end + 1 == slot => *end = slot,
+            _ => ranges.push((slot, slot, owner)),
+        }
+    }
+    ranges
+}
+
+impl<T: KvdEngine> Server<T> {
+    /// Check that the keys of the request are served by this node, return
+    /// the redirect to the node serving them if they are not.
+    pub(super) fn cluster_route<'a>(
+        &self,
+        cluster: &'a Cluster,
+        client: &Client,
+        cmd: &[u8],
+        request: &[Vec<u8>],
+    ) -> KvdResult<Route<'a>> {
+        // ASKING only applies to the next request
+        let asking = cmd != b"asking" && client.take_asking();
+        let keys = command_keys(request);
+        let slot = match keys.split_first() {
+            Some((key, rest)) => {
+                let slot = key_slot(key);
+                if rest.iter().any(|key| key_slot(key) != slot) {
+                    return Err(KvdError::from(KvdErrorKind::CrossSlot));
+                }
+                slot
+            }
+            None => return Ok(Route::Serve(None)),
+        };
+        let owner = cluster
+            .owner(slot)
+            .ok_or_else(|| KvdError::from(KvdErrorKind::SlotNotServed))?;
+        match cluster.migration(slot) {
+            Some(Migration::Migrating(target)) if owner == cluster.myself => {
+                let guard = cluster.moving[slot as usize].read().unwrap();
+                let mut engine = self.engine();
+                let mut present = 0;
+                for key in keys.iter() {
+                    if engine.key_type(key)?.is_some() {
+                        present += 1;
+                    }
+                }
+                // the keys moved already, or not created yet, are served by the target
+                if present == keys.len() {
+                    Ok(Route::Serve(Some(guard)))
+                } else if present == 0 {
+                    Ok(cluster.redirect("ASK", slot, target))
+                } else {
+                    Err(KvdError::from(KvdErrorKind::MigratingKeys))
+                }
+            }
+            _ if owner == cluster.myself => Ok(Route::Serve(None)),
+            Some(Migration::Importing(_)) if asking || cmd == b"restore" => Ok(Route::Serve(None)),
+            _ => Ok(cluster.redirect("MOVED", slot, owner)),
+        }
+    }
+
+    /// ASKING, the next request is served if its slot is being imported
+    pub(super) fn handle_asking(&self, client: &Client, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        if self.cluster.is_none() {
+            return Err(KvdError::from(KvdErrorKind::ClusterDisabled));
+        }
+        if request.len() != 1 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        client.set_asking();
+        Ok(Reply::ok())
+    }
+
+    /// MIGRATE host:port key [key ...]
+    ///
+    /// Move the keys to the node at the address by RESTORE, and reply the
+    /// number of keys moved. A missing key is skipped. The slots of the keys
+    /// must be migrating from this node. No request of these slots is served
+    /// until the target replies, so no write to the keys is lost, while the
+    /// engine is unlocked for the other slots.
+    pub(super) fn handle_migrate(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        let cluster = self
+            .cluster
+            .as_ref()
+            .ok_or_else(|| KvdError::from(KvdErrorKind::ClusterDisabled))?;
+        if request.len() < 3 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let addr = str::from_utf8(&request[1])?;
+        // locked in the order of the slots, so two MIGRATE never deadlock
+        let slots: BTreeSet<u16> = request[2..].iter().map(|key| key_slot(key)).collect();
+        let _moving: Vec<_> = slots
+            .iter()
+            .map(|&slot| cluster.moving[slot as usize].write().unwrap())
+            .collect();
+        // only the requests of a migrating slot wait for the locks above
+        for &slot in slots.iter() {
+            let migrating = matches!(cluster.migration(slot), Some(Migration::Migrating(_)));
+            if !migrating || cluster.owner(slot) != Some(cluster.myself) {
+                return Err(KvdError::from(KvdErrorKind::SlotNotMigrating));
+            }
+        }
+        let mut dumps = Vec::new();
+        {
+            let mut engine = self.engine();
+            for key in request[2..].iter() {
+                if let Some(cmd) = engine.dump(key)? {
+                    dumps.push((key, serde_json::to_vec(&cmd)?));
+                }
+            }
+        }
+        if dumps.is_empty() {
+            return Ok(Reply::Integer(0));
+        }
+        let mut conn = Connection::connect(addr)?;
+        conn.set_timeout(Some(MIGRATE_TIMEOUT))?;
+        for (key, dump) in dumps.iter() {
+            conn.send(&[b"restore", key, dump])?;
+        }
+        // every reply is read, the keys restored are deleted even if some fail
+        let mut moved = Vec::new();
+        let mut failed = None;
+        for (key, _) in dumps {
+            match conn.read_reply()? {
+                Reply::Status(_) => moved.push(key.clone()),
+                reply => failed = failed.or(Some(reply)),
+            }
+        }
+        let count = self.engine().mdel(moved)?;
+        Ok(failed.unwrap_or(Reply::Integer(count as i64)))
+    }
+
+    /// RESTORE key value, replace the key with a value dumped by MIGRATE
+    pub(super) fn handle_restore(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        if request.len() != 3 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let cmd: Command = serde_json::from_slice(&request[2])?;
+        match &cmd {
+            Command::Set { key, .. } | Command::Change { key, .. } if *key == request[1] => {}
+            _ => return Err(KvdError::from(KvdErrorKind::InvalidRequest)),
+        }
+        let mut engine = self.engine();
+        engine.mdel(vec![request[1].clone()])?;
+        apply_change(&mut *engine, cmd)?;
+        Ok(Reply::ok())
+    }
+
+    /// CLUSTER KEYSLOT key | MYID | SLOTS | NODES | COUNTKEYSINSLOT slot |
+    /// GETKEYSINSLOT slot count | SETSLOT slot NODE id | SETSLOT slot
+    /// MIGRATING id | SETSLOT slot IMPORTING id | SETSLOT slot STABLE
+    pub(super) fn handle_cluster(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        let cluster = self
+            .cluster
+            .as_ref()
+            .ok_or_else(|| KvdError::from(KvdErrorKind::ClusterDisabled))?;
+        let invalid = || KvdError::from(KvdErrorKind::InvalidRequest);
+        let subcommand = request.get(1).ok_or_else(invalid)?.to_ascii_lowercase();
+        match (subcommand.as_slice(), request.len()) {
+            (b"keyslot", 3) => Ok(Reply::Integer(key_slot(&request[2]) as i64)),
+            (b"myid", 2) => Ok(Reply::Bulk(cluster.myself.to_string().into_bytes())),
+            (b"slots", 2) => Ok(Reply::Array(
+                cluster
+                    .ranges()
+                    .into_iter()
+                    .map(|(start, end, id)| {
+                        let addr = cluster.address(id).unwrap_or_default();
+                        let (host, port) = addr.rsplit_once(':').unwrap_or((addr, "0"));
+                        Reply::Array(vec![
+                            Reply::Integer(start as i64),
+                            Reply::Integer(end as i64),
+                            Reply::Array(vec![
+                                Reply::Bulk(host.as_bytes().to_vec()),
+                                Reply::Integer(port.parse().unwrap_or_default()),
+                                Reply::Bulk(id.to_string().into_bytes()),
+                            ]),
+                        ])
+                    })
+                    .collect(),
+            )),
+            (b"nodes", 2) => {
+                let ranges = cluster.ranges();
+                let mut nodes = String::new();
+                for (id, addr) in cluster.nodes.iter() {
+                    let flags = if *id == cluster.myself {
+                        "myself,master"
+                    } else {
+                        "master"
+                    };
+                    write!(nodes, "{} {} {} - 0 0 0 connected", id, addr, flags).unwrap();
+                    for (start, end, _) in ranges.iter().filter(|(_, _, owner)| owner == id) {
+                        if start == end {
+                            write!(nodes, " {}", start).unwrap();
+                        } else {
+                            write!(nodes, " {}-{}", start, end).unwrap();
+                        }
+                    }
+                    if *id == cluster.myself {
+                        for (slot, migration) in cluster.migrations.lock().unwrap().iter() {
+                            match migration {
+                                Migration::Migrating(to) => write!(nodes, " [{}->-{}]", slot, to),
+                                Migration::Importing(from) => {
+                                    write!(nodes, " [{}-<-{}]", slot, from)
+                                }
+                            }
+                            .unwrap();
+                        }
+                    }
+                    nodes.push('\n');
+                }
+                Ok(Reply::Bulk(nodes.into_bytes()))
+            }
+            (b"countkeysinslot", 3) => {
+                let slot = parse_slot(&request[2]).ok_or_else(invalid)?;
+                let keys = self.engine().keys();
+                let count = keys.iter().filter(|key| key_slot(key) == slot).count();
+                Ok(Reply::Integer(count as i64))
+            }
+            (b"getkeysinslot", 4) => {
+                let slot = parse_slot(&request[2]).ok_or_else(invalid)?;
+                let count = str::from_utf8(&request[3])?
+                    .parse::<usize>()
+                    .map_err(|_| invalid())?;
+                let keys = self.engine().keys();
+                Ok(Reply::Array(
+                    keys.into_iter()
+                        .filter(|key| key_slot(key) == slot)
+                        .take(count)
+                        .map(Reply::Bulk)
+                        .collect(),
+                ))
+            }
+            (b"setslot", 4) if request[3].eq_ignore_ascii_case(b"stable") => {
+                let slot = parse_slot(&request[2]).ok_or_else(invalid)?;
+                cluster.set_migration(slot, None)?;
+                Ok(Reply::ok())
+            }
+            (b"setslot", 5) => {
+                let slot = parse_slot(&request[2]).ok_or_else(invalid)?;
+                let id = str::from_utf8(&request[4])?
+                    .parse::<NodeId>()
+                    .map_err(|_| KvdError::from(KvdErrorKind::UnknownNode))?;
+                match request[3].to_ascii_lowercase().as_slice() {
+                    b"node" => cluster.set_owner(slot, id)?,
+                    b"migrating" => cluster.set_migration(slot, Some(Migration::Migrating(id)))?,
+                    b"importing" => cluster.set_migration(slot, Some(Migration::Importing(id)))?,
+                    _ => return Err(invalid()),
+                }
+                Ok(Reply::ok())
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{request, start_server};
+    use super::*;
+    use crate::client::ClusterClient;
+    use crate::cluster::ClusterNode;
+    use crate::engine::memory::MemoryEngine;
+    use crate::server::ServerConfig;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn test_cluster_map() {
+        let config = |id| ClusterConfig {
+            id,
+            nodes: parse_nodes("1@127.0.0.1:7001 0-100 200,2@127.0.0.1:7002 101-199").unwrap(),
+            nodes_file: None,
+        };
+        let cluster = Cluster::new(config(1)).unwrap();
+        assert_eq!(Some(1), cluster.owner(0));
+        assert_eq!(Some(2), cluster.owner(199));
+        assert_eq!(None, cluster.owner(201));
+        assert_eq!(
+            vec![(0, 100, 1), (101, 199, 2), (200, 200, 1)],
+            cluster.ranges()
+        );
+        cluster.set_owner(101, 1).unwrap();
+        assert_eq!(
+            vec![(0, 101, 1), (102, 199, 2), (200, 200, 1)],
+            cluster.ranges()
+        );
+        assert_eq!(
+            KvdErrorKind::UnknownNode,
+            cluster.set_owner(101, 3).unwrap_err().kind()
+        );
+        assert_eq!(
+            KvdErrorKind::UnknownNode,
+            Cluster::new(config(3)).err().unwrap().kind()
+        );
+        let overlapping = ClusterConfig {
+            id: 1,
+            nodes: parse_nodes("1@127.0.0.1:7001 0-100,2@127.0.0.1:7002 100").unwrap(),
+            nodes_file: None,
+        };
+        assert_eq!(
+            KvdErrorKind::Config,
+            Cluster::new(overlapping).err().unwrap().kind()
+        );
+    }
+
+    #[test]
+    fn test_nodes_file() {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let dir = PathBuf::from(format!("/tmp/kvd_store/{}", time.as_nanos()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = ClusterConfig {
+            id: 1,
+            nodes: parse_nodes("1@127.0.0.1:7001 0-100,2@127.0.0.1:7002 101-199,3@127.0.0.1:7003")
+                .unwrap(),
+            nodes_file: Some(dir.join("nodes.conf")),
+        };
+        let cluster = Cluster::new(config.clone()).unwrap();
+        cluster.set_owner(101, 1).unwrap();
+        cluster.set_owner(200, 3).unwrap();
+        assert_eq!(
+            "1@127.0.0.1:7001 0-101,2@127.0.0.1:7002 102-199,3@127.0.0.1:7003 200-200\n",
+            fs::read_to_string(dir.join("nodes.conf")).unwrap()
+        );
+        // the saved map replaces the nodes of the config
+        let cluster = Cluster::new(config).unwrap();
+        assert_eq!(
+            vec![(0, 101, 1), (102, 199, 2), (200, 200, 3)],
+            cluster.ranges()
+        );
+        assert_eq!(Some("127.0.0.1:7003"), cluster.address(3));
+    }
+
+    #[test]
+    fn test_cluster() {
+        let listeners: Vec<TcpListener> = (0..2)
+            .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let addrs: Vec<String> = listeners
+            .iter()
+            .map(|l| l.local_addr().unwrap().to_string())
+            .collect();
+        // the keys a and b are in the slots 15495 and 3300
+        let nodes = vec![
+            ClusterNode {
+                id: 1,
+                addr: addrs[0].clone(),
+                slots: vec![(0, 8191)],
+            },
+            ClusterNode {
+                id: 2,
+                addr: addrs[1].clone(),
+                slots: vec![(8192, 16383)],
+            },
+        ];
+        let addr = |i: usize| {
+            let config = ServerConfig {
+                cluster: Some(ClusterConfig {
+                    id: nodes[i].id,
+                    nodes: nodes.clone(),
+                    nodes_file: None,
+                }),
+                ..ServerConfig::default()
+            };
+            start_server_on(config, listeners[i].try_clone().unwrap())
+        };
+        let (first, second) = (addr(0), addr(1));
+
+        let mut conn = TcpStream::connect(&first).unwrap();
+        assert_eq!(b"+OK\r\n".to_vec(), request(&mut conn, b"set b 1\r\n"));
+        assert_eq!(
+            format!("-MOVED 15495 {}\r\n", second).into_bytes(),
+            request(&mut conn, b"set a 1\r\n")
+        );
+        assert_eq!(
+            b"-CROSSSLOT Keys in request don't hash to the same slot\r\n".to_vec(),
+            request(&mut conn, b"mget a b\r\n")
+        );
+        // the keys with the same tag are in the same slot
+        assert_eq!(b"+OK\r\n".to_vec(), request(&mut conn, b"set {b}1 1\r\n"));
+        assert_eq!(
+            b"*2\r\n$1\r\n1\r\n$-1\r\n".to_vec(),
+            request(&mut conn, b"mget {b}1 {b}2\r\n")
+        );
+        assert_eq!(
+            b":15495\r\n".to_vec(),
+            request(&mut conn, b"cluster keyslot a\r\n")
+        );
+        let slots = format!(
+            "*2\r\n*3\r\n:0\r\n:8191\r\n*3\r\n$9\r\n127.0.0.1\r\n:{}\r\n$1\r\n1\r\n\
+             *3\r\n:8192\r\n:16383\r\n*3\r\n$9\r\n127.0.0.1\r\n:{}\r\n$1\r\n2\r\n",
+            first.rsplit(':').next().unwrap(),
+            second.rsplit(':').next().unwrap()
+        );
+        assert_eq!(slots.into_bytes(), request(&mut conn, b"cluster slots\r\n"));
+        let nodes_info = format!(
+            "1 {} myself,master - 0 0 0 connected 0-8191\n2 {} master - 0 0 0 connected 8192-16383\n",
+            first, second
+        );
+        assert_eq!(
+            format!("${}\r\n{}\r\n", nodes_info.len(), nodes_info).into_bytes(),
+            request(&mut conn, b"cluster nodes\r\n")
+        );
+        let info = String::from_utf8(request(&mut conn, b"info cluster\r\n")).unwrap();
+        assert!(info.contains("cluster_slots_served:8192\r\n"));
+
+        // the client routes by its cache of the slot map, and follows MOVED
+        // after the map is changed
+        let mut client = ClusterClient::connect(&[first.as_str()]).unwrap();
+        assert_eq!(Reply::ok(), client.request(&[b"set", b"a", b"2"]).unwrap());
+        assert_eq!(Some(second.as_str()), client.node_of(b"a"));
+        let mut other = TcpStream::connect(&second).unwrap();
+        assert_eq!(b"$1\r\n2\r\n".to_vec(), request(&mut other, b"get a\r\n"));
+        for conn in [&mut conn, &mut other] {
+            assert_eq!(
+                b"+OK\r\n".to_vec(),
+                request(conn, b"cluster setslot 15495 node 1\r\n")
+            );
+        }
+        assert_eq!(Reply::Nil, client.request(&[b"get", b"a"]).unwrap());
+        assert_eq!(Some(first.as_str()), client.node_of(b"a"));
+
+        assert_eq!(
+            b"-ERR This instance has cluster support disabled\r\n".to_vec(),
+            request(
+                &mut TcpStream::connect(start_server(ServerConfig::default())).unwrap(),
+                b"cluster slots\r\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_migration() {
+        let listeners: Vec<TcpListener> = (0..2)
+            .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let addrs: Vec<String> = listeners
+            .iter()
+            .map(|l| l.local_addr().unwrap().to_string())
+            .collect();
+        // the second node serves no slot until the slot 15495 of {a} is moved to it
+        let nodes = vec![
+            ClusterNode {
+                id: 1,
+                addr: addrs[0].clone(),
+                slots: vec![(0, 16383)],
+            },
+            ClusterNode {
+                id: 2,
+                addr: addrs[1].clone(),
+                slots: Vec::new(),
+            },
+        ];
+        let addr = |i: usize| {
+            let config = ServerConfig {
+                cluster: Some(ClusterConfig {
+                    id: nodes[i].id,
+                    nodes: nodes.clone(),
+                    nodes_file: None,
+                }),
+                ..ServerConfig::default()
+            };
+            start_server_on(config, listeners[i].try_clone().unwrap())
+        };
+        let (first, second) = (addr(0), addr(1));
+        let mut source = TcpStream::connect(&first).unwrap();
+        let mut target = TcpStream::connect(&second).unwrap();
+        assert_eq!(b"+OK\r\n".to_vec(), request(&mut source, b"set {a}1 1\r\n"));
+        assert_eq!(b"+OK\r\n".to_vec(), request(&mut source, b"set {a}2 2\r\n"));
+        assert_eq!(
+            b":2\r\n".to_vec(),
+            request(&mut source, b"rpush {a}l x y\r\n")
+        );
+
+        assert_eq!(
+            b"-ERR I'm not the owner of hash slot\r\n".to_vec(),
+            request(&mut target, b"cluster setslot 15495 migrating 1\r\n")
+        );
+        assert_eq!(
+            b"+OK\r\n".to_vec(),
+            request(&mut target, b"cluster setslot 15495 importing 1\r\n")
+        );
+        let migrate = format!("migrate {} {{a}}1\r\n", second);
+        assert_eq!(
+            b"-ERR the slot is not migrating\r\n".to_vec(),
+            request(&mut source, migrate.as_bytes())
+        );
+        assert_eq!(
+            b"+OK\r\n".to_vec(),
+            request(&mut source, b"cluster setslot 15495 migrating 2\r\n")
+        );
+        assert_eq!(
+            b":3\r\n".to_vec(),
+            request(&mut source, b"cluster countkeysinslot 15495\r\n")
+        );
+        let migrate = format!("migrate {} {{a}}1 {{a}}l {{a}}none\r\n", second);
+        assert_eq!(b":2\r\n".to_vec(), request(&mut source, migrate.as_bytes()));
+
+        // the source serves the keys it still has and asks for the others
+        assert_eq!(
+            b"$1\r\n2\r\n".to_vec(),
+            request(&mut source, b"get {a}2\r\n")
+        );
+        let ask = format!("-ASK 15495 {}\r\n", second).into_bytes();
+        assert_eq!(ask, request(&mut source, b"get {a}1\r\n"));
+        assert_eq!(ask, request(&mut source, b"set {a}3 3\r\n"));
+        assert_eq!(
+            b"-TRYAGAIN Multiple keys request during rehashing of slot\r\n".to_vec(),
+            request(&mut source, b"mget {a}1 {a}2\r\n")
+        );
+        // the target serves the slot only after ASKING
+        assert_eq!(
+            format!("-MOVED 15495 {}\r\n", first).into_bytes(),
+            request(&mut target, b"get {a}1\r\n")
+        );
+        assert_eq!(b"+OK\r\n".to_vec(), request(&mut target, b"asking\r\n"));
+        assert_eq!(
+            b"$1\r\n1\r\n".to_vec(),
+            request(&mut target, b"get {a}1\r\n")
+        );
+        assert_eq!(b"+OK\r\n".to_vec(), request(&mut target, b"asking\r\n"));
+        assert_eq!(
+            b"*2\r\n$1\r\nx\r\n$1\r\ny\r\n".to_vec(),
+            request(&mut target, b"lrange {a}l 0 -1\r\n")
+        );
+        let nodes_info = String::from_utf8(request(&mut source, b"cluster nodes\r\n")).unwrap();
+        assert!(nodes_info.contains("myself,master - 0 0 0 connected 0-16383 [15495->-2]\n"));
+
+        // the client follows the ASK, and finishes moving the slot
+        let mut client = ClusterClient::connect(&[first.as_str()]).unwrap();
+        assert_eq!(
+            Reply::ok(),
+            client.request(&[b"set", b"{a}3", b"3"]).unwrap()
+        );
+        assert_eq!(1, client.migrate_slots(15495, 15495, &second).unwrap());
+        assert_eq!(Some(second.as_str()), client.node_of(b"{a}1"));
+        assert_eq!(
+            b":4\r\n".to_vec(),
+            request(&mut target, b"cluster countkeysinslot 15495\r\n")
+        );
+        for (key, value) in [(b"{a}1", b"1"), (b"{a}2", b"2"), (b"{a}3", b"3")] {
+            assert_eq!(
+                Reply::Bulk(value.to_vec()),
+                client.request(&[b"get", key]).unwrap()
+            );
+        }
+        assert_eq!(
+            format!("-MOVED 15495 {}\r\n", second).into_bytes(),
+            request(&mut source, b"get {a}2\r\n")
+        );
+        let info = String::from_utf8(request(&mut target, b"info cluster\r\n")).unwrap();
+        assert!(info.contains("cluster_slots_served:1\r\n"));
+        assert!(info.contains("cluster_slots_migrating:0\r\n"));
+
+        // the other slots are served while the target of a MIGRATE is slow
+        let slow = TcpListener::bind("127.0.0.1:0").unwrap();
+        let slow_addr = slow.local_addr().unwrap().to_string();
+        let (read_tx, read_rx) = mpsc::channel();
+        let (reply_tx, reply_rx) = mpsc::channel::<()>();
+        thread::spawn(move || {
+            let (mut conn, _) = slow.accept().unwrap();
+            let mut data = [0; 1024];
+            let _ = conn.read(&mut data).unwrap();
+            read_tx.send(()).unwrap();
+            reply_rx.recv().unwrap();
+            conn.write_all(b"+OK\r\n").unwrap();
+        });
+        assert_eq!(b"+OK\r\n".to_vec(), request(&mut source, b"set {c}1 1\r\n"));
+        let setslot = format!("cluster setslot {} migrating 2\r\n", key_slot(b"{c}"));
+        assert_eq!(
+            b"+OK\r\n".to_vec(),
+            request(&mut source, setslot.as_bytes())
+        );
+        let migrating = thread::spawn(move || {
+            let migrate = format!("migrate {} {{c}}1\r\n", slow_addr);
+            request(&mut source, migrate.as_bytes())
+        });
+        read_rx.recv().unwrap();
+        // the target has the RESTORE and has not replied yet
+        let mut other = TcpStream::connect(&first).unwrap();
+        other
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(b"$-1\r\n".to_vec(), request(&mut other, b"get b\r\n"));
+        reply_tx.send(()).unwrap();
+        assert_eq!(b":1\r\n".to_vec(), migrating.join().unwrap());
+    }
+
+    fn start_server_on(config: ServerConfig, listener: TcpListener) -> String {
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = Server::with_config(MemoryEngine::new(), config).unwrap();
+        thread::spawn(move || server.serve_listener(listener));
+        addr
+    }
+}
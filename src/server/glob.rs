@@ -0,0 +1,136 @@
+//! The glob-style patterns of redis, used by MATCH options.
+//!
+//! `*` matches any bytes, `?` one byte, `[abc]`, `[a-z]` and `[^a]` a byte of
+//! a class, and `\` escapes the next byte.
+
+/// whether the whole of the text is matched by the pattern
+pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    // the position to resume from when the last star should match one more byte
+    let mut backtrack: Option<(usize, usize)> = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    backtrack = Some((p, t + 1));
+                    p += 1;
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                b'[' => {
+                    if let Some((matched, next)) = match_class(pattern, p, text[t]) {
+                        if matched {
+                            p = next;
+                            t += 1;
+                            continue;
+                        }
+                    }
+                }
+                b'\\' if p + 1 < pattern.len() => {
+                    if pattern[p + 1] == text[t] {
+                        p += 2;
+                        t += 1;
+                        continue;
+                    }
+                }
+                b => {
+                    if b == text[t] {
+                        p += 1;
+                        t += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+        match backtrack {
+            Some((star, next)) => {
+                p = star + 1;
+                t = next;
+                backtrack = Some((star, next + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+/// Match a byte against the class starting at `start`, return whether it
+/// matches and the position after the class. None if the class is not closed.
+fn match_class(pattern: &[u8], start: usize, byte: u8) -> Option<(bool, usize)> {
+    let mut p = start + 1;
+    let negated = pattern.get(p) == Some(&b'^');
+    if negated {
+        p += 1;
+    }
+    let mut matched = false;
+    loop {
+        match *pattern.get(p)? {
+            b']' => break,
+            b'\\' => {
+                p += 1;
+                matched |= *pattern.get(p)? == byte;
+                p += 1;
+            }
+            low if pattern.get(p + 1) == Some(&b'-')
+                && pattern.get(p + 2).is_some_and(|&b| b != b']') =>
+            {
+                let high = pattern[p + 2];
+                let (low, high) = if low <= high {
+                    (low, high)
+                } else {
+                    (high, low)
+                };
+                matched |= low <= byte && byte <= high;
+                p += 3;
+            }
+            b => {
+                matched |= b == byte;
+                p += 1;
+            }
+        }
+    }
+    Some((matched != negated, p + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("*", "", true),
+            ("*", "anything", true),
+            ("h?llo", "hello", true),
+            ("h?llo", "hllo", false),
+            ("h*llo", "heeeello", true),
+            ("h*llo", "hello world", false),
+            ("*:name", "user:1:name", true),
+            ("user:*:name", "user:1:age", false),
+            ("h[ae]llo", "hallo", true),
+            ("h[ae]llo", "hillo", false),
+            ("h[^e]llo", "hallo", true),
+            ("h[^e]llo", "hello", false),
+            ("h[a-c]llo", "hbllo", true),
+            ("h[a-c]llo", "hdllo", false),
+            ("h\\*llo", "h*llo", true),
+            ("h\\*llo", "hello", false),
+            ("a*b*c", "aXbYbZc", true),
+            ("a*b*c", "aXbYbZ", false),
+            ("[unclosed", "u", false),
+        ];
+        for (pattern, text, expect) in cases {
+            assert_eq!(
+                *expect,
+                glob_match(pattern.as_bytes(), text.as_bytes()),
+                "{} {}",
+                pattern,
+                text
+            );
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use super::glob::glob_match;
+use super::{key_of, parse_pairs, Server};
+use crate::engine::parse_number;
+use crate::engine::value::{Change, Collection, Hash};
+use crate::engine::KvdEngine;
+use crate::model::{KvdError, KvdErrorKind, KvdResult};
+use crate::protocol::Reply;
+use std::collections::BTreeSet;
+
+/// the number of fields HSCAN returns if COUNT is not given
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+impl<T: KvdEngine> Server<T> {
+    /// HSET key field value [field value ...], reply the number of fields added
+    pub(super) fn handle_hset(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        let key = key_of(request, 4)?;
+        let fields = parse_pairs(&request[2..])?;
+        let mut engine = self.engine();
+        let hash = read_hash(&mut *engine, key)?;
+        let added = fields
+            .iter()
+            .map(|(field, _)| field)
+            .filter(|field| !hash.is_some_and(|hash| hash.contains_key(*field)))
+            .collect::<BTreeSet<_>>()
+            .len();
+        engine.change(key.clone(), Change::HSet(fields))?;
+        Ok(Reply::Integer(added as i64))
+    }
+
+    /// HGET key field
+    pub(super) fn handle_hget(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        if request.len() != 3 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let mut engine = self.engine();
+        let value = read_hash(&mut *engine, &request[1])?.and_then(|hash| hash.get(&request[2]));
+        Ok(value.cloned().map_or(Reply::Nil, Reply::Bulk))
+    }
+
+    /// HMGET key field [field ...]
+    pub(super) fn handle_hmget(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        let key = key_of(request, 3)?;
+        let mut engine = self.engine();
+        let hash = read_hash(&mut *engine, key)?;
+        Ok(Reply::Array(
+            request[2..]
+                .iter()
+                .map(|field| {
+                    hash.and_then(|hash| hash.get(field))
+                        .cloned()
+                        .map_or(Reply::Nil, Reply::Bulk)
+                })
+                .collect(),
+        ))
+    }
+
+    /// HDEL key field [field ...], reply the number of fields removed
+    pub(super) fn handle_hdel(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        let key = key_of(request, 3)?;
+        let mut engine = self.engine();
+        let fields = match read_hash(&mut *engine, key)? {
+            Some(hash) => request[2..]
+                .iter()
+                .filter(|field| hash.contains_key(*field))
+                .cloned()
+                .collect::<BTreeSet<_>>(),
+            None => return Ok(Reply::Integer(0)),
+        };
+        if !fields.is_empty() {
+            let change = Change::HDel(fields.iter().cloned().collect());
+            engine.change(key.clone(), change)?;
+        }
+        Ok(Reply::Integer(fields.len() as i64))
+    }
+
+    /// HEXISTS key field
+    pub(super) fn handle_hexists(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        if request.len() != 3 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let mut engine = self.engine();
+        let exists = read_hash(&mut *engine, &request[1])?
+            .is_some_and(|hash| hash.contains_key(&request[2]));
+        Ok(Reply::Integer(exists as i64))
+    }
+
+    /// HLEN key
+    pub(super) fn handle_hlen(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        if request.len() != 2 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let mut engine = self.engine();
+        let len = read_hash(&mut *engine, &request[1])?.map_or(0, |hash| hash.len());
+        Ok(Reply::Integer(len as i64))
+    }
+
+    /// HKEYS key | HVALS key | HGETALL key
+    pub(super) fn handle_hgetall(
+        &self,
+        request: &[Vec<u8>],
+        with_fields: bool,
+        with_values: bool,
+    ) -> KvdResult<Reply> {
+        if request.len() != 2 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let mut engine = self.engine();
+        let mut replies = Vec::new();
+        if let Some(hash) = read_hash(&mut *engine, &request[1])? {
+            for (field, value) in hash.iter() {
+                if with_fields {
+                    replies.push(Reply::Bulk(field.clone()));
+                }
+                if with_values {
+                    replies.push(Reply::Bulk(value.clone()));
+                }
+            }
+        }
+        Ok(Reply::Array(replies))
+    }
+
+    /// HINCRBY key field increment, a missing field counts as 0
+    pub(super) fn handle_hincrby(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        if request.len() != 4 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let key = &request[1];
+        let field = &request[2];
+        let delta: i64 =
+            parse_number(&request[3]).ok_or_else(|| KvdError::from(KvdErrorKind::NotAnInteger))?;
+        let mut engine = self.engine();
+        let current = match read_hash(&mut *engine, key)?.and_then(|hash| hash.get(field)) {
+            Some(value) => parse_number::<i64>(value)
+                .ok_or_else(|| KvdError::from(KvdErrorKind::NotAnInteger))?,
+            None => 0,
+        };
+        let new = current
+            .checked_add(delta)
+            .ok_or_else(|| KvdError::from(KvdErrorKind::IncrementOverflow))?;
+        let change = Change::HSet(vec![(field.clone(), new.to_string().into_bytes())]);
+        engine.change(key.clone(), change)?;
+        Ok(Reply::Integer(new))
+    }
+
+    /// HSCAN key cursor [MATCH pattern] [COUNT count]
+    ///
+    /// The cursor is the position in the fields ordered by their bytes, so a
+    /// field added or removed before the cursor while scanning may cause a
+    /// field to be returned twice or to be missed.
+    pub(super) fn handle_hscan(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        let key = key_of(request, 3)?;
+        let cursor: usize = parse_number(&request[2])
+            .ok_or_else(|| KvdError::from(KvdErrorKind::InvalidRequest))?;
+        let mut pattern = None;
+        let mut count = DEFAULT_SCAN_COUNT;
+        let mut args = request[3..].iter();
+        while let Some(arg) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| KvdError::from(KvdErrorKind::InvalidRequest))?;
+            match arg.to_ascii_lowercase().as_slice() {
+                b"match" => pattern = Some(value),
+                b"count" => {
+                    count = parse_number(value)
+                        .filter(|&count| count > 0)
+                        .ok_or_else(|| KvdError::from(KvdErrorKind::InvalidRequest))?
+                }
+                _ => return Err(KvdError::from(KvdErrorKind::InvalidRequest)),
+            }
+        }
+
+        let mut engine = self.engine();
+        let hash = match read_hash(&mut *engine, key)? {
+            Some(hash) => hash,
+            None => return Ok(scan_reply(0, Vec::new())),
+        };
+        let mut replies = Vec::new();
+        // the pattern is applied after the fields are taken, like redis does
+        for (field, value) in hash.iter().skip(cursor).take(count) {
+            if pattern.is_none_or(|pattern| glob_match(pattern, field)) {
+                replies.push(Reply::Bulk(field.clone()));
+                replies.push(Reply::Bulk(value.clone()));
+            }
+        }
+        let next = cursor.saturating_add(count);
+        let next = if next >= hash.len() { 0 } else { next };
+        Ok(scan_reply(next, replies))
+    }
+}
+
+/// the hash of the key, WrongType if the key holds another type
+fn read_hash<'a, T: KvdEngine>(engine: &'a mut T, key: &[u8]) -> KvdResult<Option<&'a Hash>> {
+    engine.collection(key)?.map(Collection::as_hash).transpose()
+}
+
+/// the next cursor, 0 when the scan is complete, and the elements returned
+fn scan_reply(cursor: usize, elements: Vec<Reply>) -> Reply {
+    Reply::Array(vec![
+        Reply::Bulk(cursor.to_string().into_bytes()),
+        Reply::Array(elements),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::memory::MemoryEngine;
+    use crate::server::tests::handle_input;
+    use crate::server::Server;
+
+    #[test]
+    fn test_hash_commands() {
+        let server = Server::new(MemoryEngine::new(), 0).unwrap();
+        let output = handle_input(
+            &server,
+            b"hset h a 1 b 2 a 3\r\nhset h b 4 c 5\r\nhget h a\r\nhget h x\r\nhmget h a x c\r\nhlen h\r\nhexists h b\r\nhexists h x\r\n",
+        );
+        assert_eq!(
+            ":2\r\n:1\r\n$1\r\n3\r\n$-1\r\n*3\r\n$1\r\n3\r\n$-1\r\n$1\r\n5\r\n:3\r\n:1\r\n:0\r\n",
+            output
+        );
+
+        let output = handle_input(
+            &server,
+            b"hkeys h\r\nhvals h\r\nhgetall h\r\nhincrby h a 10\r\nhincrby h n -1\r\nhdel h a a x\r\nhgetall x\r\n",
+        );
+        assert_eq!(
+            "*3\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n*3\r\n$1\r\n3\r\n$1\r\n4\r\n$1\r\n5\r\n\
+             *6\r\n$1\r\na\r\n$1\r\n3\r\n$1\r\nb\r\n$1\r\n4\r\n$1\r\nc\r\n$1\r\n5\r\n:13\r\n:-1\r\n:1\r\n*0\r\n",
+            output
+        );
+
+        // the key is removed with its last field
+        let output = handle_input(&server, b"hdel h b c n\r\nexists h\r\nhdel h b\r\n");
+        assert_eq!(":3\r\n:0\r\n:0\r\n", output);
+    }
+
+    #[test]
+    fn test_hash_wrong_type() {
+        let server = Server::new(MemoryEngine::new(), 0).unwrap();
+        let output = handle_input(
+            &server,
+            b"set s 1\r\nhset s a 1\r\nhget s a\r\nhset h a x\r\nget h\r\nincr h\r\nhincrby h a 1\r\nmget s h\r\n",
+        );
+        let wrong_type = "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n";
+        assert_eq!(
+            format!(
+                "+OK\r\n{0}{0}:1\r\n{0}{0}-ERR value is not an integer or out of range\r\n*2\r\n$1\r\n1\r\n$-1\r\n",
+                wrong_type
+            ),
+            output
+        );
+
+        // set and del work on any type
+        let output = handle_input(&server, b"set h 1\r\nget h\r\nhset h a 1\r\ndel h\r\n");
+        assert_eq!(format!("+OK\r\n$1\r\n1\r\n{}:1\r\n", wrong_type), output);
+    }
+
+    #[test]
+    fn test_hscan() {
+        let server = Server::new(MemoryEngine::new(), 0).unwrap();
+        handle_input(&server, b"hset h a1 1 a2 2 b1 3 b2 4 c1 5\r\n");
+        let output = handle_input(
+            &server,
+            b"hscan h 0 count 2\r\nhscan h 2 count 2\r\nhscan h 4 count 2\r\n",
+        );
+        assert_eq!(
+            "*2\r\n$1\r\n2\r\n*4\r\n$2\r\na1\r\n$1\r\n1\r\n$2\r\na2\r\n$1\r\n2\r\n\
+             *2\r\n$1\r\n4\r\n*4\r\n$2\r\nb1\r\n$1\r\n3\r\n$2\r\nb2\r\n$1\r\n4\r\n\
+             *2\r\n$1\r\n0\r\n*2\r\n$2\r\nc1\r\n$1\r\n5\r\n",
+            output
+        );
+        let output = handle_input(
+            &server,
+            b"hscan h 0 match *1\r\nhscan x 0\r\nhscan h 0 count 0\r\n",
+        );
+        assert_eq!(
+            "*2\r\n$1\r\n0\r\n*6\r\n$2\r\na1\r\n$1\r\n1\r\n$2\r\nb1\r\n$1\r\n3\r\n$2\r\nc1\r\n$1\r\n5\r\n\
+             *2\r\n$1\r\n0\r\n*0\r\n-ERR invalid request\r\n",
+            output
+        );
+    }
+}
@@ -0,0 +1,442 @@
+#[cfg(feature = "async")]
+use super::blocking::BlockedPop;
+use super::blocking::Waiter;
+use super::clients::Client;
+use super::{key_of, Server, KILL_POLL_INTERVAL};
+use crate::engine::parse_number;
+use crate::engine::resolve_range;
+use crate::engine::value::{Change, Collection, List};
+use crate::engine::KvdEngine;
+use crate::model::{KvdError, KvdErrorKind, KvdResult};
+use crate::protocol::Reply;
+use std::time::{Duration, Instant};
+
+impl<T: KvdEngine> Server<T> {
+    /// LPUSH key element [element ...] | RPUSH key element [element ...],
+    /// reply the length of the list after the push
+    pub(super) fn handle_push(&self, request: &[Vec<u8>], front: bool) -> KvdResult<Reply> {
+        let key = key_of(request, 3)?;
+        let elements = request[2..].to_vec();
+        let count = elements.len();
+        let change = if front {
+            Change::LPush(elements)
+        } else {
+            Change::RPush(elements)
+        };
+        let mut engine = self.engine();
+        engine.change(key.clone(), change)?;
+        // woken while the engine is locked, so a client blocked just before
+        // the push does not miss it
+        self.blocked.wake(key, count);
+        let len = read_list(&mut *engine, key)?.map_or(0, List::len);
+        Ok(Reply::Integer(len as i64))
+    }
+
+    /// LPOP key [count] | RPOP key [count], an array is replied if the count
+    /// is given
+    pub(super) fn handle_pop(&self, request: &[Vec<u8>], front: bool) -> KvdResult<Reply> {
+        let key = key_of(request, 2)?;
+        let count = match request.len() {
+            2 => None,
+            3 => Some(
+                parse_number::<usize>(&request[2])
+                    .ok_or_else(|| KvdError::from(KvdErrorKind::NotAnInteger))?,
+            ),
+            _ => return Err(KvdError::from(KvdErrorKind::InvalidRequest)),
+        };
+        let mut engine = self.engine();
+        let elements = pop(&mut *engine, key, count.unwrap_or(1), front)?;
+        Ok(match (elements, count) {
+            (None, _) => Reply::Nil,
+            (Some(mut elements), None) => elements.pop().map_or(Reply::Nil, Reply::Bulk),
+            (Some(elements), Some(_)) => {
+                Reply::Array(elements.into_iter().map(Reply::Bulk).collect())
+            }
+        })
+    }
+
+    /// BLPOP key [key ...] timeout | BRPOP key [key ...] timeout
+    ///
+    /// Pop from the first of the keys which is not empty, or block until one
+    /// of them is pushed. The timeout is in seconds and 0 means forever. The
+    /// key and the element are replied, or nil if the timeout is reached.
+    pub(super) fn handle_blocking_pop(
+        &self,
+        client: &Client,
+        request: &[Vec<u8>],
+        front: bool,
+    ) -> KvdResult<Reply> {
+        key_of(request, 3)?;
+        let keys = &request[1..request.len() - 1];
+        // a timeout too far away to be represented is the same as forever
+        let deadline = parse_timeout(&request[request.len() - 1])?
+            .and_then(|timeout| Instant::now().checked_add(timeout));
+        let mut engine = self.engine();
+        let mut woken = false;
+        let result = loop {
+            match pop_first(&mut *engine, keys, front) {
+                Ok(Some(reply)) => break Ok(reply),
+                Ok(None) => {}
+                Err(e) => break Err(e),
+            }
+            let expired = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+            if expired || client.is_killed() {
+                break Ok(Reply::Nil);
+            }
+            let waiter = self.blocked.block(keys.to_vec());
+            #[cfg(feature = "async")]
+            if client.is_nonblocking() {
+                // the connection waits for the waiter without a thread and
+                // resumes the pop, this reply is not sent
+                let pop = BlockedPop::new(self.blocked.clone(), waiter, front, deadline);
+                client.park_blocked_pop(pop);
+                return Ok(Reply::Nil);
+            }
+            drop(engine);
+            wait(client, &waiter, deadline);
+            engine = self.engine();
+            self.blocked.unblock(&waiter);
+            woken |= waiter.is_woken();
+        };
+        if woken {
+            self.wake_left(&mut *engine, keys);
+        }
+        result
+    }
+
+    /// Pop again for a BLPOP or BRPOP parked by `handle_blocking_pop`, once
+    /// it is woken or its deadline is reached. None is replied if it is
+    /// parked again, since another client took the element.
+    #[cfg(feature = "async")]
+    pub(crate) fn resume_blocking_pop(&self, client: &Client, pop: BlockedPop) -> Option<Reply> {
+        let mut engine = self.engine();
+        let keys = pop.waiter().keys().to_vec();
+        let woken = pop.waiter().is_woken();
+        let (front, deadline) = (pop.front, pop.deadline);
+        // unblocked first, so a client parked again waits behind the others
+        drop(pop);
+        let result = match pop_first(&mut *engine, &keys, front) {
+            Ok(Some(reply)) => Ok(reply),
+            Ok(None) => {
+                let expired = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+                if !expired && !client.is_killed() {
+                    let waiter = self.blocked.block(keys);
+                    let pop = BlockedPop::new(self.blocked.clone(), waiter, front, deadline);
+                    client.park_blocked_pop(pop);
+                    return None;
+                }
+                Ok(Reply::Nil)
+            }
+            Err(e) => Err(e),
+        };
+        if woken {
+            self.wake_left(&mut *engine, &keys);
+        }
+        Some(result.unwrap_or_else(|e| {
+            self.stats.record_error(e.kind());
+            Reply::from(e)
+        }))
+    }
+
+    /// The push which woke a client may be taken by another one, or be left
+    /// for the other clients blocked on the key, so they are woken in turn
+    /// for what is left.
+    fn wake_left(&self, engine: &mut T, keys: &[Vec<u8>]) {
+        for key in keys {
+            if let Ok(Some(list)) = read_list(engine, key) {
+                self.blocked.wake(key, list.len());
+            }
+        }
+    }
+
+    /// LLEN key
+    pub(super) fn handle_llen(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        if request.len() != 2 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let mut engine = self.engine();
+        let len = read_list(&mut *engine, &request[1])?.map_or(0, List::len);
+        Ok(Reply::Integer(len as i64))
+    }
+
+    /// LRANGE key start stop, the offsets are inclusive and a negative one
+    /// counts from the end
+    pub(super) fn handle_lrange(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        if request.len() != 4 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let (start, end) = (parse_index(&request[2])?, parse_index(&request[3])?);
+        let mut engine = self.engine();
+        let list = match read_list(&mut *engine, &request[1])? {
+            Some(list) => list,
+            None => return Ok(Reply::Array(Vec::new())),
+        };
+        let elements = match resolve_range(list.len(), start, end) {
+            Some(range) => list.range(range).cloned().map(Reply::Bulk).collect(),
+            None => Vec::new(),
+        };
+        Ok(Reply::Array(elements))
+    }
+
+    /// LINDEX key index
+    pub(super) fn handle_lindex(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        if request.len() != 3 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let index = parse_index(&request[2])?;
+        let mut engine = self.engine();
+        let element = read_list(&mut *engine, &request[1])?
+            .and_then(|list| resolve_index(list.len(), index).and_then(|i| list.get(i)));
+        Ok(element.cloned().map_or(Reply::Nil, Reply::Bulk))
+    }
+
+    /// LSET key index element, the index should be in the list
+    pub(super) fn handle_lset(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        if request.len() != 4 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let key = &request[1];
+        let index = parse_index(&request[2])?;
+        let mut engine = self.engine();
+        let len = read_list(&mut *engine, key)?
+            .ok_or_else(|| KvdError::from(KvdErrorKind::KeyNotFound))?
+            .len();
+        let index = resolve_index(len, index)
+            .ok_or_else(|| KvdError::from(KvdErrorKind::IndexOutOfRange))?;
+        engine.change(key.clone(), Change::LSet(index, request[3].clone()))?;
+        Ok(Reply::ok())
+    }
+
+    /// LREM key count element, reply the number of elements removed
+    pub(super) fn handle_lrem(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        if request.len() != 4 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let key = &request[1];
+        let count = parse_index(&request[2])?;
+        let element = &request[3];
+        let mut engine = self.engine();
+        let found = read_list(&mut *engine, key)?
+            .map_or(0, |list| list.iter().filter(|e| *e == element).count());
+        let removed = match count {
+            0 => found,
+            count => found.min(count.unsigned_abs() as usize),
+        };
+        if removed > 0 {
+            engine.change(key.clone(), Change::LRem(count, element.clone()))?;
+        }
+        Ok(Reply::Integer(removed as i64))
+    }
+
+    /// LTRIM key start stop, keep only the elements in the inclusive range
+    pub(super) fn handle_ltrim(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        if request.len() != 4 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let key = &request[1];
+        let (start, end) = (parse_index(&request[2])?, parse_index(&request[3])?);
+        let mut engine = self.engine();
+        let len = match read_list(&mut *engine, key)? {
+            Some(list) => list.len(),
+            None => return Ok(Reply::ok()),
+        };
+        let range = resolve_range(len, start, end).unwrap_or(0..0);
+        if range != (0..len) {
+            engine.change(key.clone(), Change::LTrim(range.start, range.end))?;
+        }
+        Ok(Reply::ok())
+    }
+}
+
+/// the list of the key, WrongType if the key holds another type
+fn read_list<'a, T: KvdEngine>(engine: &'a mut T, key: &[u8]) -> KvdResult<Option<&'a List>> {
+    engine.collection(key)?.map(Collection::as_list).transpose()
+}
+
+/// pop at most count elements in the order they are popped, None if the key is missing
+fn pop<T: KvdEngine>(
+    engine: &mut T,
+    key: &[u8],
+    count: usize,
+    front: bool,
+) -> KvdResult<Option<Vec<Vec<u8>>>> {
+    let list = match read_list(engine, key)? {
+        Some(list) => list,
+        None => return Ok(None),
+    };
+    let count = count.min(list.len());
+    let (elements, change) = if front {
+        let elements = list.iter().take(count).cloned().collect();
+        (elements, Change::LPop(count))
+    } else {
+        let elements = list.iter().rev().take(count).cloned().collect();
+        (elements, Change::RPop(count))
+    };
+    if count > 0 {
+        engine.change(key.to_vec(), change)?;
+    }
+    Ok(Some(elements))
+}
+
+/// Pop an element from the first key which has one, reply the key and the
+/// element. All the keys are checked to be lists before any is popped.
+fn pop_first<T: KvdEngine>(
+    engine: &mut T,
+    keys: &[Vec<u8>],
+    front: bool,
+) -> KvdResult<Option<Reply>> {
+    for key in keys {
+        read_list(engine, key)?;
+    }
+    for key in keys {
+        if let Some(element) = pop(engine, key, 1, front)?.and_then(|mut e| e.pop()) {
+            return Ok(Some(Reply::Array(vec![
+                Reply::Bulk(key.clone()),
+                Reply::Bulk(element),
+            ])));
+        }
+    }
+    Ok(None)
+}
+
+/// Block until the waiter is woken, the client is killed or the deadline is
+/// reached, None means no deadline.
+fn wait(client: &Client, waiter: &Waiter, deadline: Option<Instant>) {
+    while !client.is_killed() {
+        let timeout = match deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(left) => left.min(KILL_POLL_INTERVAL),
+                None => return,
+            },
+            None => KILL_POLL_INTERVAL,
+        };
+        if waiter.wait(timeout) {
+            return;
+        }
+    }
+}
+
+/// the timeout of a blocking command in seconds, None if it is 0 which means forever
+fn parse_timeout(timeout: &[u8]) -> KvdResult<Option<Duration>> {
+    let timeout = parse_number::<f64>(timeout)
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| KvdError::from(KvdErrorKind::InvalidTimeout))?;
+    Ok(Some(timeout).filter(|timeout| !timeout.is_zero()))
+}
+
+fn parse_index(index: &[u8]) -> KvdResult<i64> {
+    parse_number(index).ok_or_else(|| KvdError::from(KvdErrorKind::NotAnInteger))
+}
+
+/// the position of an index in a list of the length, a negative one counts from the end
+fn resolve_index(len: usize, index: i64) -> Option<usize> {
+    let index = if index < 0 {
+        index.checked_add(len as i64)?
+    } else {
+        index
+    };
+    Some(index as usize).filter(|&index| index < len)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::memory::MemoryEngine;
+    use crate::server::tests::{handle_input, request, start_server};
+    use crate::server::{Server, ServerConfig};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_list_commands() {
+        let server = Server::new(MemoryEngine::new(), 0).unwrap();
+        let output = handle_input(
+            &server,
+            b"rpush l a b c\r\nlpush l y x\r\nllen l\r\nlrange l 0 -1\r\nlrange l -2 100\r\nlrange l 3 1\r\nlindex l -1\r\nlindex l 5\r\n",
+        );
+        assert_eq!(
+            ":3\r\n:5\r\n:5\r\n*5\r\n$1\r\nx\r\n$1\r\ny\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n\
+             *2\r\n$1\r\nb\r\n$1\r\nc\r\n*0\r\n$1\r\nc\r\n$-1\r\n",
+            output
+        );
+
+        let output = handle_input(
+            &server,
+            b"lpop l\r\nrpop l 2\r\nlset l -1 z\r\nlset l 2 z\r\nlset x 0 z\r\nlrange l 0 -1\r\n",
+        );
+        assert_eq!(
+            "$1\r\nx\r\n*2\r\n$1\r\nc\r\n$1\r\nb\r\n+OK\r\n-ERR index out of range\r\n\
+             -ERR key not found\r\n*2\r\n$1\r\ny\r\n$1\r\nz\r\n",
+            output
+        );
+
+        let output = handle_input(
+            &server,
+            b"rpush r a b a c a\r\nlrem r -2 a\r\nlrange r 0 -1\r\nlrem r 0 x\r\nltrim r 1 -1\r\nlrange r 0 -1\r\n",
+        );
+        assert_eq!(
+            ":5\r\n:2\r\n*3\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n:0\r\n+OK\r\n*2\r\n$1\r\nb\r\n$1\r\nc\r\n",
+            output
+        );
+
+        // the key is removed with its last element
+        let output = handle_input(
+            &server,
+            b"ltrim r 5 10\r\nexists r\r\nlpop r\r\nrpop r 1\r\n",
+        );
+        assert_eq!("+OK\r\n:0\r\n$-1\r\n$-1\r\n", output);
+
+        let output = handle_input(&server, b"set s 1\r\nlpush s a\r\nllen s\r\nget l\r\n");
+        let wrong_type = "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n";
+        assert_eq!(format!("+OK\r\n{0}{0}{0}", wrong_type), output);
+    }
+
+    #[test]
+    fn test_blocking_pop() {
+        let server = Server::new(MemoryEngine::new(), 0).unwrap();
+        let output = handle_input(
+            &server,
+            b"rpush b x y\r\nblpop a b 0\r\nbrpop a b 1\r\nblpop a 0.05\r\nblpop a -1\r\nblpop a\r\n",
+        );
+        assert_eq!(
+            ":2\r\n*2\r\n$1\r\nb\r\n$1\r\nx\r\n*2\r\n$1\r\nb\r\n$1\r\ny\r\n$-1\r\n\
+             -ERR timeout is negative or not a number\r\n-ERR invalid request\r\n",
+            output
+        );
+
+        let addr = start_server(ServerConfig::default());
+        let mut first = TcpStream::connect(addr).unwrap();
+        let mut second = TcpStream::connect(addr).unwrap();
+        let mut pusher = TcpStream::connect(addr).unwrap();
+        first.write_all(b"brpop a b 0\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        second.write_all(b"blpop b 0\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(b":2\r\n".to_vec(), request(&mut pusher, b"rpush b 1 2\r\n"));
+
+        // the clients are served in the order they are blocked
+        let reply = |conn: &mut TcpStream| {
+            let mut reply = vec![0; 64];
+            let n = conn.read(&mut reply).unwrap();
+            String::from_utf8(reply[..n].to_vec()).unwrap()
+        };
+        assert_eq!("*2\r\n$1\r\nb\r\n$1\r\n2\r\n", reply(&mut first));
+        assert_eq!("*2\r\n$1\r\nb\r\n$1\r\n1\r\n", reply(&mut second));
+        assert_eq!(b":0\r\n".to_vec(), request(&mut pusher, b"exists b\r\n"));
+
+        // a blocked client times out, and a killed one is closed
+        let start = Instant::now();
+        assert_eq!(b"$-1\r\n".to_vec(), request(&mut first, b"blpop a 0.2\r\n"));
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        second.write_all(b"blpop a 0\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(
+            b":1\r\n".to_vec(),
+            request(&mut pusher, b"client kill id 2\r\n")
+        );
+        let mut rest = Vec::new();
+        second.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+}
@@ -0,0 +1,169 @@
+use super::Server;
+use crate::engine::KvdEngine;
+use crate::metrics::{write_header, write_sample};
+use crate::model::KvdResult;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// the max size of the request line and the headers of a scrape
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl<T: KvdEngine> Server<T> {
+    /// Render all the metrics in the Prometheus text format.
+    pub fn metrics(&self) -> String {
+        let (key_count, wal_metrics) = {
+            let engine = self.engine();
+            (engine.key_count(), engine.wal_metrics())
+        };
+
+        let mut out = String::new();
+        self.stats.write_metrics(&mut out);
+        write_header(
+            &mut out,
+            "kvd_connected_clients",
+            "gauge",
+            "Connections currently open.",
+        );
+        write_sample(&mut out, "kvd_connected_clients", &[], self.clients.len());
+        write_header(&mut out, "kvd_keys", "gauge", "Keys stored in the engine.");
+        write_sample(&mut out, "kvd_keys", &[], key_count);
+        if let Some(wal_metrics) = wal_metrics {
+            wal_metrics.write_to(&mut out);
+        }
+        out
+    }
+
+    /// Start serving the metrics endpoint in the background if a port is configured.
+    pub fn spawn_metrics_listener(&self) -> KvdResult<()> {
+        if let Some(port) = self.config.metrics_port {
+            let listener = TcpListener::bind(("0.0.0.0", port))?;
+            let server = self.clone();
+            thread::spawn(move || server.serve_metrics(listener));
+        }
+        Ok(())
+    }
+
+    /// Serve `GET /metrics` over HTTP, the scrapes are handled one by one.
+    pub fn serve_metrics(&self, listener: TcpListener) {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = self.handle_scrape(stream) {
+                        warn!(self.config.logger, "handle metrics scrape error"; "error" => %e);
+                    }
+                }
+                Err(e) => warn!(self.config.logger, "accept metrics stream error"; "error" => %e),
+            }
+        }
+    }
+
+    fn handle_scrape(&self, mut conn: TcpStream) -> KvdResult<()> {
+        conn.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+        conn.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+        let head = read_head(&mut conn)?;
+        let request_line = head.lines().next().unwrap_or_default();
+        let mut parts = request_line.split(' ');
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", self.metrics()),
+            (Some("GET"), _) => ("404 Not Found", "not found\n".to_string()),
+            _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+        };
+        write!(
+            conn,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )?;
+        conn.flush()?;
+        let _ = conn.shutdown(Shutdown::Both);
+        Ok(())
+    }
+}
+
+/// read the request line and the headers, the body of a GET request is ignored
+fn read_head(conn: &mut TcpStream) -> KvdResult<String> {
+    let mut head = Vec::new();
+    let mut data = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_HEAD_SIZE {
+        let n = conn.read(&mut data)?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&data[..n]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::bitcask::BitcaskEngine;
+    use crate::engine::memory::MemoryEngine;
+    use std::path::PathBuf;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn test_metrics() {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let path = PathBuf::from(format!("/tmp/kvd_store/{}", time.as_nanos()));
+        let server = Server::new(BitcaskEngine::open(path).unwrap(), 0).unwrap();
+        let guard = server.register_client("test".to_string(), None).unwrap();
+        let input: &[u8] = b"set a 1\r\nset b 2\r\nget a\r\ndel c\r\nget\r\nfoo\r\n";
+        server
+            .handle_stream(guard.client(), input, Vec::new())
+            .unwrap();
+
+        let metrics = server.metrics();
+        for sample in &[
+            "kvd_commands_total{cmd=\"set\"} 2\n",
+            "kvd_commands_total{cmd=\"del\"} 1\n",
+            "kvd_command_duration_seconds_bucket{cmd=\"get\",le=\"+Inf\"} 2\n",
+            "kvd_command_duration_seconds_count{cmd=\"set\"} 2\n",
+            "kvd_errors_total{kind=\"InvalidRequest\"} 1\n",
+            "kvd_errors_total{kind=\"UnknownCommand\"} 1\n",
+            "kvd_connected_clients 1\n",
+            "kvd_keys 2\n",
+            "# TYPE kvd_wal_fsync_duration_seconds histogram\n",
+            "kvd_compaction_runs_total 0\n",
+        ] {
+            assert!(metrics.contains(sample), "{} is not in {}", sample, metrics);
+        }
+        let bytes_written = metrics
+            .lines()
+            .find_map(|line| line.strip_prefix("kvd_wal_bytes_written_total "))
+            .unwrap();
+        assert!(bytes_written.parse::<u64>().unwrap() > 0);
+    }
+
+    #[test]
+    fn test_serve_metrics() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::new(MemoryEngine::new(), 0).unwrap();
+        thread::spawn(move || server.serve_metrics(listener));
+
+        let response = scrape(addr, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\n\r\n# HELP kvd_commands_total"));
+        assert!(response.ends_with("kvd_keys 0\n"));
+        // there is no wal in the memory engine
+        assert!(!response.contains("kvd_wal_bytes_written_total"));
+
+        let response = scrape(addr, "GET / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = scrape(addr, "POST /metrics HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+
+    fn scrape(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut conn = TcpStream::connect(addr).unwrap();
+        conn.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).unwrap();
+        response
+    }
+}
@@ -0,0 +1,2049 @@
+pub mod blocking;
+mod changes;
+pub mod clients;
+pub mod cluster;
+mod glob;
+mod hash;
+mod list;
+mod metrics;
+pub mod monitor;
+pub mod pubsub;
+pub mod raft;
+pub mod replication;
+mod set;
+pub mod slowlog;
+mod sorted_set;
+pub mod stats;
+
+use crate::cluster::{command_keys, ClusterConfig};
+use crate::engine::notify::{EventClasses, EventFilter, Notifier};
+use crate::engine::value::ValueType;
+use crate::engine::{now_millis, parse_number, KvdEngine, SetCondition, SetOptions};
+use crate::logging::LogLevel;
+use crate::model::{KvdError, KvdErrorKind, KvdResult, Request};
+use crate::protocol::DEFAULT_MAX_REQUEST_SIZE;
+use crate::protocol::{Reply, RequestBuffer};
+use crate::raft::RaftConfig;
+use blocking::BlockedClients;
+use clients::{Client, ClientGuard, ClientRegistry, Closer};
+use cluster::Cluster;
+use monitor::{MonitorGuard, Monitors, MONITOR_BUFFER_SIZE};
+use pubsub::{PubSub, Signal, DEFAULT_PUBSUB_BUFFER_LIMIT};
+use raft::RaftGroup;
+use replication::Replica;
+use set::SetOp;
+use slog::{Drain, Logger};
+use slowlog::SlowLog;
+use stats::ServerStats;
+use std::convert::TryFrom;
+use std::fmt::Write as _;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::str;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_PORT: u16 = 2048;
+const DEFAULT_MAX_CLIENTS: usize = 10000;
+const DEFAULT_SLOWLOG_THRESHOLD: Duration = Duration::from_millis(10);
+const DEFAULT_SLOWLOG_MAX_LEN: usize = 128;
+/// the number of entries replied by SLOWLOG GET without a count
+const DEFAULT_SLOWLOG_GET_COUNT: usize = 10;
+/// the keyspace events of a key are published to the channel of the prefix and the key
+const KEYSPACE_CHANNEL_PREFIX: &[u8] = b"__keyspace__:";
+/// the channel of the new values of the keys, if the value class is enabled
+const KEYVALUE_CHANNEL_PREFIX: &[u8] = b"__keyvalue__:";
+/// how often a monitor or a blocked connection checks whether it is killed,
+/// and a stream of changes polls for new ones
+pub(crate) const KILL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The server is cheap to clone, all the clones share the same engine.
+pub struct Server<T: KvdEngine> {
+    engine: Arc<Mutex<T>>,
+    config: Arc<ServerConfig>,
+    clients: Arc<ClientRegistry>,
+    stats: Arc<ServerStats>,
+    slowlog: Arc<SlowLog>,
+    monitors: Arc<Monitors>,
+    blocked: Arc<BlockedClients>,
+    pubsub: Arc<PubSub>,
+    /// the replication state if the server is a follower
+    replica: Option<Arc<Replica>>,
+    /// the raft state if the server is a node of a raft group
+    raft: Option<Arc<RaftGroup>>,
+    /// the slot map if the server is a node of a cluster
+    cluster: Option<Arc<Cluster>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub port: u16,
+    /// connections beyond the limit are rejected with an error
+    pub max_clients: usize,
+    /// close the connection if no request is received in time, None means never
+    pub idle_timeout: Option<Duration>,
+    /// the read and write timeout of a connection when a request or a reply is in flight
+    pub io_timeout: Option<Duration>,
+    /// the max size of a request line or a bulk
+    pub max_request_size: usize,
+    /// the config file the server is started with, shown in INFO
+    pub config_file: Option<String>,
+    /// the port of the Prometheus metrics endpoint, None means it is disabled
+    pub metrics_port: Option<u16>,
+    /// commands executed slower than the threshold are logged, None means never
+    pub slowlog_threshold: Option<Duration>,
+    /// the max number of entries kept in the slow log
+    pub slowlog_max_len: usize,
+    /// a subscriber is closed when the bytes queued for it exceed the limit
+    pub pubsub_buffer_limit: usize,
+    /// the classes of the keyspace events published, which can be changed by
+    /// CONFIG SET notify-keyspace-events, none by default
+    pub notify_keyspace_events: EventFilter,
+    /// the address of the leader, host:port, the server is a read only
+    /// follower of it if set
+    pub replica_of: Option<String>,
+    /// the raft group the server is a node of, the writes are replicated
+    /// to a majority of the nodes before they are replied if set. The keys
+    /// are rebuilt from the raft log, so the engine should not keep them
+    /// by itself.
+    pub raft: Option<RaftConfig>,
+    /// the cluster the server is a node of, it only serves the keys of the
+    /// slots assigned to it if set
+    pub cluster: Option<ClusterConfig>,
+    pub logger: Logger,
+    /// the level of the logger, which can be changed by CONFIG SET loglevel
+    pub log_level: LogLevel,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            port: DEFAULT_PORT,
+            max_clients: DEFAULT_MAX_CLIENTS,
+            idle_timeout: None,
+            io_timeout: None,
+            max_request_size: DEFAULT_MAX_REQUEST_SIZE,
+            config_file: None,
+            metrics_port: None,
+            slowlog_threshold: Some(DEFAULT_SLOWLOG_THRESHOLD),
+            slowlog_max_len: DEFAULT_SLOWLOG_MAX_LEN,
+            pubsub_buffer_limit: DEFAULT_PUBSUB_BUFFER_LIMIT,
+            notify_keyspace_events: EventFilter::default(),
+            replica_of: None,
+            raft: None,
+            cluster: None,
+            // the records are passed to the log crate if no logger is given
+            logger: Logger::root(slog_stdlog::StdLog.fuse(), o!()),
+            log_level: LogLevel::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// the timeout used to wake up a blocked read
+    pub(crate) fn read_timeout(&self) -> Option<Duration> {
+        match (self.idle_timeout, self.io_timeout) {
+            (Some(idle), Some(io)) => Some(idle.min(io)),
+            (idle, io) => idle.or(io),
+        }
+    }
+
+    /// whether a connection should be closed after its read is timed out
+    pub(crate) fn is_expired(&self, buffer: &RequestBuffer, last_active: Instant) -> bool {
+        if !buffer.is_empty() {
+            // the timeout is reached in the middle of a request
+            return true;
+        }
+        match self.idle_timeout {
+            Some(idle_timeout) => last_active.elapsed() >= idle_timeout,
+            None => false,
+        }
+    }
+}
+
+impl<T: KvdEngine> Clone for Server<T> {
+    fn clone(&self) -> Self {
+        Server {
+            engine: self.engine.clone(),
+            config: self.config.clone(),
+            clients: self.clients.clone(),
+            stats: self.stats.clone(),
+            slowlog: self.slowlog.clone(),
+            monitors: self.monitors.clone(),
+            blocked: self.blocked.clone(),
+            pubsub: self.pubsub.clone(),
+            replica: self.replica.clone(),
+            raft: self.raft.clone(),
+            cluster: self.cluster.clone(),
+        }
+    }
+}
+
+impl<T: KvdEngine> Server<T> {
+    pub fn new(engine: T, port: u16) -> KvdResult<Server<T>> {
+        let config = ServerConfig {
+            port,
+            ..ServerConfig::default()
+        };
+        Self::with_config(engine, config)
+    }
+
+    pub fn with_config(mut engine: T, config: ServerConfig) -> KvdResult<Server<T>> {
+        if config.raft.is_some() && (config.replica_of.is_some() || config.cluster.is_some()) {
+            return Err(KvdError::from(KvdErrorKind::Config));
+        }
+        let pubsub = Arc::new(PubSub::new(config.pubsub_buffer_limit));
+        let publisher = pubsub.clone();
+        engine.set_notifier(Notifier::new(
+            config.notify_keyspace_events.clone(),
+            Box::new(move |event| {
+                let mut channel = KEYSPACE_CHANNEL_PREFIX.to_vec();
+                channel.extend(&event.key);
+                publisher.publish(&channel, event.op.as_bytes());
+                if let Some(value) = event.value {
+                    let mut channel = KEYVALUE_CHANNEL_PREFIX.to_vec();
+                    channel.extend(&event.key);
+                    publisher.publish(&channel, &value);
+                }
+            }),
+        ));
+        let server = Server {
+            engine: Arc::new(Mutex::new(engine)),
+            clients: Arc::new(ClientRegistry::new(
+                config.max_clients,
+                config.logger.clone(),
+            )),
+            stats: Arc::new(ServerStats::new()),
+            slowlog: Arc::new(SlowLog::new(
+                config.slowlog_threshold,
+                config.slowlog_max_len,
+            )),
+            monitors: Arc::new(Monitors::new()),
+            blocked: Arc::new(BlockedClients::new()),
+            pubsub,
+            replica: config
+                .replica_of
+                .clone()
+                .map(|leader| Arc::new(Replica::new(leader))),
+            raft: config
+                .raft
+                .clone()
+                .map(RaftGroup::open)
+                .transpose()?
+                .map(Arc::new),
+            cluster: config
+                .cluster
+                .clone()
+                .map(Cluster::new)
+                .transpose()?
+                .map(Arc::new),
+            config: Arc::new(config),
+        };
+        Ok(server)
+    }
+
+    pub fn port(&self) -> u16 {
+        self.config.port
+    }
+
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    pub fn clients(&self) -> &Arc<ClientRegistry> {
+        &self.clients
+    }
+
+    pub fn stats(&self) -> &Arc<ServerStats> {
+        &self.stats
+    }
+
+    pub fn slowlog(&self) -> &Arc<SlowLog> {
+        &self.slowlog
+    }
+
+    pub fn monitors(&self) -> &Arc<Monitors> {
+        &self.monitors
+    }
+
+    pub fn blocked(&self) -> &Arc<BlockedClients> {
+        &self.blocked
+    }
+
+    pub fn pubsub(&self) -> &Arc<PubSub> {
+        &self.pubsub
+    }
+
+    /// the replication state, None if the server is a leader
+    pub fn replica(&self) -> Option<&Arc<Replica>> {
+        self.replica.as_ref()
+    }
+
+    /// the raft state, None if raft is not enabled
+    pub fn raft(&self) -> Option<&Arc<RaftGroup>> {
+        self.raft.as_ref()
+    }
+
+    /// the cluster state, None if the server is not a node of a cluster
+    pub fn cluster(&self) -> Option<&Arc<Cluster>> {
+        self.cluster.as_ref()
+    }
+
+    /// register a new connection, return None if there are too many clients already
+    pub(crate) fn register_client(
+        &self,
+        addr: String,
+        closer: Option<Closer>,
+    ) -> Option<ClientGuard> {
+        self.stats.record_connection();
+        self.clients.register(addr, closer)
+    }
+
+    pub(crate) fn record_bytes_in(&self, client: &Client, n: usize) {
+        client.add_bytes_in(n);
+        self.stats.add_bytes_read(n);
+    }
+
+    pub(crate) fn record_bytes_out(&self, client: &Client, n: usize) {
+        client.add_bytes_out(n);
+        self.stats.add_bytes_written(n);
+    }
+
+    pub fn serve(&self) -> KvdResult<()> {
+        let guard = self
+            .register_client("stdin".to_string(), None)
+            .ok_or_else(|| KvdError::from(KvdErrorKind::MaxClients))?;
+        let stdin = io::stdin();
+        let stdout = io::stdout();
+        // the output is written by another thread after the client subscribes
+        self.handle_stream(guard.client(), stdin.lock(), stdout)
+    }
+
+    pub fn serve_net(&self) -> KvdResult<()> {
+        let listener = TcpListener::bind(("0.0.0.0", self.port()))?;
+        self.spawn_metrics_listener()?;
+        self.spawn_replication();
+        self.spawn_raft()?;
+        self.serve_listener(listener)
+    }
+
+    /// Serve every connection accepted by the listener in its own thread.
+    pub fn serve_listener(&self, listener: TcpListener) -> KvdResult<()> {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let guard = match self.register_conn(&stream) {
+                        Some(guard) => guard,
+                        None => {
+                            reject_conn(stream);
+                            continue;
+                        }
+                    };
+                    let server = self.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = server.handle_conn(guard.client(), stream) {
+                            warn!(guard.client().logger(), "handle connection error"; "error" => %e);
+                        }
+                    });
+                }
+                Err(e) => warn!(self.config.logger, "accept stream error"; "error" => %e),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn register_conn(&self, conn: &TcpStream) -> Option<ClientGuard> {
+        let addr = conn
+            .peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default();
+        // a killed client is closed by shutting down its socket
+        let closer = conn.try_clone().ok().map(|conn| {
+            Box::new(move || {
+                let _ = conn.shutdown(Shutdown::Both);
+            }) as Box<dyn Fn() + Send + Sync>
+        });
+        self.register_client(addr, closer)
+    }
+
+    fn handle_conn(&self, client: &Arc<Client>, conn: TcpStream) -> KvdResult<()> {
+        conn.set_nodelay(true)?;
+        conn.set_read_timeout(self.config.read_timeout())?;
+        conn.set_write_timeout(self.config.io_timeout)?;
+        self.handle_stream(client, &conn, &conn)
+    }
+
+    /// Read requests from the reader and write the replies to the writer.
+    ///
+    /// Every complete request already buffered is executed in order before the
+    /// replies are flushed, so a pipelining client pays one flush per batch
+    /// instead of one per request.
+    ///
+    /// A connection is closed when it has been idle for longer than the idle
+    /// timeout, or when a half received request is not completed within the io
+    /// timeout.
+    ///
+    /// After the client sends MONITOR, the connection only streams the
+    /// commands of the other clients. After CHANGES, it only streams the
+    /// changes logged by the engine.
+    ///
+    /// After the client subscribes, its replies and messages are written by
+    /// another thread, which is woken up when they are queued.
+    fn handle_stream<R: Read, W: Write + Send>(
+        &self,
+        client: &Arc<Client>,
+        reader: R,
+        writer: W,
+    ) -> KvdResult<()> {
+        let writer = Mutex::new(writer);
+        thread::scope(|scope| {
+            // dropped before the pusher is joined, which makes it stop
+            let _subscriber = self.pubsub.guard(client);
+            let result = self.serve_stream(client, reader, &writer, scope);
+            // the replies queued for a subscriber are still written when the
+            // stream ends, e.g. after the input of a pipe is closed
+            if client.is_subscriber() && !client.is_killed() {
+                let _ = self.write_queued(client, &writer);
+            }
+            result
+        })
+    }
+
+    fn serve_stream<'scope, R: Read, W: Write + Send>(
+        &'scope self,
+        client: &'scope Arc<Client>,
+        mut reader: R,
+        writer: &'scope Mutex<W>,
+        scope: &'scope thread::Scope<'scope, '_>,
+    ) -> KvdResult<()> {
+        let mut pushing = false;
+        let mut buffer = RequestBuffer::with_max_request_size(self.config.max_request_size);
+        let mut output = Vec::new();
+        let mut last_active = Instant::now();
+        loop {
+            match buffer.read_from(&mut reader) {
+                Ok(0) => return Ok(()),
+                Ok(n) => {
+                    self.record_bytes_in(client, n);
+                    last_active = Instant::now();
+                }
+                Err(ref e) if is_timeout(e) => {
+                    // a subscriber waiting for messages is not idle
+                    let waiting = client.subscriptions() > 0 && buffer.is_empty();
+                    if self.config.is_expired(&buffer, last_active) && !waiting {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                Err(e) => return Err(KvdError::from(e)),
+            }
+            let mut closing = false;
+            loop {
+                match buffer.next_request() {
+                    Ok(Some(request)) => {
+                        let reply = self.handle_request(client, request);
+                        self.write_reply(client, reply, &mut output)?;
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        // the rest of the stream can not be parsed, give up the connection
+                        self.write_reply(client, Reply::from(e), &mut output)?;
+                        closing = true;
+                        break;
+                    }
+                }
+            }
+            if !pushing && client.is_subscriber() {
+                scope.spawn(move || self.push_output(client, writer));
+                pushing = true;
+            }
+            // a monitor is subscribed before the reply is sent, so it does not
+            // miss the commands sent by the others right after the reply
+            let monitor = if client.take_monitor_request() {
+                Some(self.subscribe_monitor(client))
+            } else {
+                None
+            };
+            let changes = client.take_changes_request();
+            if !output.is_empty() {
+                self.record_bytes_out(client, output.len());
+                let mut writer = writer.lock().unwrap();
+                writer.write_all(&output)?;
+                writer.flush()?;
+                output.clear();
+                // a client blocked by a command is not idle, its idle time
+                // starts again when the reply is sent
+                last_active = Instant::now();
+            }
+            if closing || client.is_killed() {
+                return Ok(());
+            }
+            if let Some((_guard, receiver)) = monitor {
+                let mut writer = writer.lock().unwrap();
+                return self.serve_monitor(client, receiver, &mut *writer);
+            }
+            if let Some(seq) = changes {
+                let mut writer = writer.lock().unwrap();
+                return self.serve_changes(client, seq, &mut *writer);
+            }
+        }
+    }
+
+    /// Write the reply to the output, or queue it with the messages of a
+    /// subscriber so that they are written in order.
+    pub(crate) fn write_reply(
+        &self,
+        client: &Client,
+        reply: Reply,
+        output: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        if !client.is_subscriber() {
+            return reply.write_to(output);
+        }
+        // the output not written yet is replied before the client subscribed
+        self.pubsub
+            .queue(client, std::mem::take(output), &reply.to_bytes());
+        Ok(())
+    }
+
+    /// Write the output queued for a subscriber until its connection ends.
+    fn push_output<W: Write>(&self, client: &Client, writer: &Mutex<W>) {
+        let signal = Arc::new(Signal::new());
+        let waker = signal.clone();
+        self.pubsub
+            .set_waker(client, Box::new(move || waker.notify()));
+        loop {
+            match self.write_queued(client, writer) {
+                Ok(true) => signal.wait(),
+                Ok(false) => return,
+                Err(_) => {
+                    // the connection is broken, stop reading from it too
+                    client.close();
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Write the output queued for a subscriber, return false if it is not a
+    /// subscriber any more. The output is taken under the lock of the writer,
+    /// so it is written in the order it is queued.
+    fn write_queued<W: Write>(&self, client: &Client, writer: &Mutex<W>) -> io::Result<bool> {
+        let mut writer = writer.lock().unwrap();
+        let output = match self.pubsub.take_output(client) {
+            Some(output) => output,
+            None => return Ok(false),
+        };
+        if !output.is_empty() {
+            self.record_bytes_out(client, output.len());
+            writer.write_all(&output)?;
+            writer.flush()?;
+        }
+        Ok(true)
+    }
+
+    /// Write the commands of the other clients until the monitor is killed or too slow.
+    fn subscribe_monitor(&self, client: &Arc<Client>) -> (MonitorGuard, Receiver<Vec<u8>>) {
+        let (sender, receiver) = mpsc::sync_channel(MONITOR_BUFFER_SIZE);
+        let guard = self.monitors.subscribe(
+            client.clone(),
+            Box::new(move |line| sender.try_send(line).is_ok()),
+        );
+        (guard, receiver)
+    }
+
+    fn serve_monitor<W: Write>(
+        &self,
+        client: &Arc<Client>,
+        receiver: Receiver<Vec<u8>>,
+        mut writer: W,
+    ) -> KvdResult<()> {
+        let mut output = Vec::new();
+        while !client.is_killed() {
+            match receiver.recv_timeout(KILL_POLL_INTERVAL) {
+                Ok(line) => {
+                    // the lines already queued are sent in one write
+                    output.extend(line);
+                    for line in receiver.try_iter() {
+                        output.extend(line);
+                    }
+                    self.record_bytes_out(client, output.len());
+                    writer.write_all(&output)?;
+                    writer.flush()?;
+                    output.clear();
+                }
+                Err(RecvTimeoutError::Timeout) => continue,
+                // the monitor is dropped for being too slow
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn handle_request(&self, client: &Arc<Client>, request: Request) -> Reply {
+        let cmd = match request.first() {
+            Some(cmd) => String::from_utf8_lossy(cmd).to_lowercase(),
+            None => return Reply::from(KvdError::from(KvdErrorKind::InvalidRequest)),
+        };
+        // the stats are only kept for the known commands, so that a client
+        // sending random names can not grow them without a bound
+        let known = is_known_command(cmd.as_bytes());
+        client.record_command(known.then_some(cmd.as_str()));
+        self.monitors.feed(client, &request);
+        let start = Instant::now();
+        let result = self.dispatch_request(client, &request);
+        let duration = start.elapsed();
+        let latency_us = duration.as_micros() as u64;
+        match &result {
+            Ok(_) => debug!(client.logger(), "request"; "cmd" => &cmd, "latency_us" => latency_us),
+            Err(e) => debug!(client.logger(), "request failed";
+                "cmd" => &cmd,
+                "latency_us" => latency_us,
+                "error_kind" => ?e.kind(),
+            ),
+        }
+        if known {
+            self.stats.record_command(&cmd, duration);
+            let name = client.name().unwrap_or_default();
+            self.slowlog
+                .record(client.logger(), &request, duration, client.addr(), &name);
+        }
+        match result {
+            Ok(reply) => reply,
+            Err(e) => {
+                self.stats.record_error(e.kind());
+                Reply::from(e)
+            }
+        }
+    }
+
+    fn dispatch_request(&self, client: &Arc<Client>, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        let cmd = request
+            .first()
+            .ok_or_else(|| KvdError::from(KvdErrorKind::InvalidRequest))?
+            .to_ascii_lowercase();
+        if !is_known_command(&cmd) {
+            return Err(KvdError::from(KvdErrorKind::UnknownCommand));
+        }
+        if client.subscriptions() > 0 && !is_subscribe_command(&cmd) {
+            return Err(KvdError::from(KvdErrorKind::SubscriberMode));
+        }
+        // the keys of a migrating slot are not moved until the request is executed
+        let _moving = match &self.cluster {
+            Some(cluster) => match self.cluster_route(cluster, client, &cmd, request)? {
+                cluster::Route::Serve(guard) => guard,
+                cluster::Route::Redirect(redirect) => return Ok(redirect),
+            },
+            None => None,
+        };
+        if let Some(replica) = &self.replica {
+            if replica.is_loading() && !command_keys(request).is_empty() {
+                return Err(KvdError::from(KvdErrorKind::Loading));
+            }
+            if is_write_command(&cmd) {
+                return Err(KvdError::from(KvdErrorKind::ReadOnly));
+            }
+        }
+        if let Some(raft) = self.raft.as_ref().filter(|_| is_write_command(&cmd)) {
+            return self.propose_request(raft, &cmd, request);
+        }
+        self.execute_request(client, request)
+    }
+
+    /// Execute the request on the engine of the server, which is also how a
+    /// write committed by raft is applied.
+    fn execute_request(&self, client: &Arc<Client>, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        let cmd = request
+            .first()
+            .ok_or_else(|| KvdError::from(KvdErrorKind::InvalidRequest))?
+            .to_ascii_lowercase();
+        match cmd.as_slice() {
+            b"client" => self.handle_client(client, request),
+            b"config" => self.handle_config(request),
+            b"info" => self.handle_info(request),
+            b"monitor" if request.len() == 1 => {
+                client.request_monitor();
+                Ok(Reply::ok())
+            }
+            b"slowlog" => self.handle_slowlog(request),
+            b"changes" => self.handle_changes(client, request),
+            b"sync" => self.handle_sync(request),
+            b"raft" => self.handle_raft(request),
+            b"cluster" => self.handle_cluster(request),
+            b"asking" => self.handle_asking(client, request),
+            b"migrate" => self.handle_migrate(request),
+            b"restore" => self.handle_restore(request),
+            b"subscribe" if request.len() > 1 => {
+                self.pubsub.subscribe(client, &request[1..], false);
+                Ok(Reply::Empty)
+            }
+            b"psubscribe" if request.len() > 1 => {
+                self.pubsub.subscribe(client, &request[1..], true);
+                Ok(Reply::Empty)
+            }
+            b"unsubscribe" => {
+                self.pubsub.unsubscribe(client, &request[1..], false);
+                Ok(Reply::Empty)
+            }
+            b"punsubscribe" => {
+                self.pubsub.unsubscribe(client, &request[1..], true);
+                Ok(Reply::Empty)
+            }
+            b"publish" if request.len() == 3 => {
+                let received = self.pubsub.publish(&request[1], &request[2]);
+                Ok(Reply::Integer(received as i64))
+            }
+            b"get" => self
+                .handle_get(request)
+                .map(|r| r.map_or(Reply::Nil, Reply::Bulk)),
+            b"set" => self.handle_set(request),
+            b"setnx" => self
+                .handle_setnx(request)
+                .map(|set| Reply::Integer(set as i64)),
+            b"getset" => self
+                .handle_getset(request)
+                .map(|r| r.map_or(Reply::Nil, Reply::Bulk)),
+            b"cas" => self.handle_cas(request),
+            b"hset" => self.handle_hset(request),
+            b"hget" => self.handle_hget(request),
+            b"hmget" => self.handle_hmget(request),
+            b"hdel" => self.handle_hdel(request),
+            b"hexists" => self.handle_hexists(request),
+            b"hlen" => self.handle_hlen(request),
+            b"hkeys" => self.handle_hgetall(request, true, false),
+            b"hvals" => self.handle_hgetall(request, false, true),
+            b"hgetall" => self.handle_hgetall(request, true, true),
+            b"hincrby" => self.handle_hincrby(request),
+            b"hscan" => self.handle_hscan(request),
+            b"lpush" => self.handle_push(request, true),
+            b"rpush" => self.handle_push(request, false),
+            b"lpop" => self.handle_pop(request, true),
+            b"rpop" => self.handle_pop(request, false),
+            b"blpop" => self.handle_blocking_pop(client, request, true),
+            b"brpop" => self.handle_blocking_pop(client, request, false),
+            b"llen" => self.handle_llen(request),
+            b"lrange" => self.handle_lrange(request),
+            b"lindex" => self.handle_lindex(request),
+            b"lset" => self.handle_lset(request),
+            b"lrem" => self.handle_lrem(request),
+            b"ltrim" => self.handle_ltrim(request),
+            b"sadd" => self.handle_sadd(request),
+            b"srem" => self.handle_srem(request),
+            b"sismember" => self.handle_sismember(request),
+            b"smembers" => self.handle_smembers(request),
+            b"scard" => self.handle_scard(request),
+            b"spop" => self.handle_spop(request),
+            b"srandmember" => self.handle_srandmember(request),
+            b"sinter" => self.handle_set_op(request, SetOp::Inter),
+            b"sunion" => self.handle_set_op(request, SetOp::Union),
+            b"sdiff" => self.handle_set_op(request, SetOp::Diff),
+            b"sinterstore" => self.handle_set_op_store(request, SetOp::Inter),
+            b"sunionstore" => self.handle_set_op_store(request, SetOp::Union),
+            b"sdiffstore" => self.handle_set_op_store(request, SetOp::Diff),
+            b"zadd" => self.handle_zadd(request),
+            b"zrem" => self.handle_zrem(request),
+            b"zscore" => self.handle_zscore(request),
+            b"zincrby" => self.handle_zincrby(request),
+            b"zcard" => self.handle_zcard(request),
+            b"zrank" => self.handle_zrank(request),
+            b"zcount" => self.handle_zcount(request),
+            b"zremrangebyscore" => self.handle_zremrangebyscore(request),
+            b"zrange" => self.handle_zrange(request),
+            b"type" => self.handle_type(request),
+            b"append" => self
+                .handle_append(request)
+                .map(|len| Reply::Integer(len as i64)),
+            b"strlen" => self
+                .handle_strlen(request)
+                .map(|len| Reply::Integer(len as i64)),
+            b"getrange" => self.handle_getrange(request).map(Reply::Bulk),
+            b"setrange" => self
+                .handle_setrange(request)
+                .map(|len| Reply::Integer(len as i64)),
+            b"incr" => self.handle_incr(request, 1).map(Reply::Integer),
+            b"decr" => self.handle_incr(request, -1).map(Reply::Integer),
+            b"incrby" => self.handle_incrby(request).map(Reply::Integer),
+            b"incrbyfloat" => self
+                .handle_incrbyfloat(request)
+                .map(|value| Reply::Bulk(value.to_string().into_bytes())),
+            b"getdel" => self
+                .handle_getdel(request)
+                .map(|r| r.map_or(Reply::Nil, Reply::Bulk)),
+            b"del" => self.handle_del(request).map(|n| Reply::Integer(n as i64)),
+            b"exists" => self
+                .handle_exists(request)
+                .map(|n| Reply::Integer(n as i64)),
+            b"mget" => self.handle_mget(request).map(|values| {
+                Reply::Array(
+                    values
+                        .into_iter()
+                        .map(|v| v.map_or(Reply::Nil, Reply::Bulk))
+                        .collect(),
+                )
+            }),
+            b"mset" => self.handle_mset(request).map(|_| Reply::ok()),
+            b"msetnx" => self
+                .handle_msetnx(request)
+                .map(|set| Reply::Integer(set as i64)),
+            _ => Err(KvdError::from(KvdErrorKind::UnknownCommand)),
+        }
+    }
+
+    /// TYPE key, reply none if the key is missing
+    fn handle_type(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        if request.len() != 2 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let value_type = self.engine().key_type(&request[1])?;
+        let name = value_type.map_or("none", ValueType::name);
+        Ok(Reply::Status(name.to_string()))
+    }
+
+    fn handle_get(&self, request: &[Vec<u8>]) -> KvdResult<Option<Vec<u8>>> {
+        if request.len() != 2 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let result = self.engine().get(request.get(1).unwrap().clone())?;
+        Ok(result)
+    }
+
+    /// SET key value [NX|XX] [GET] [EX seconds|PX milliseconds|EXAT unix-time-seconds|PXAT unix-time-milliseconds]
+    ///
+    /// The reply is the old value with GET, otherwise OK if the value is set
+    /// and nil if it is not.
+    fn handle_set(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        if request.len() < 3 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let mut options = SetOptions::default();
+        let mut args = request[3..].iter();
+        while let Some(arg) = args.next() {
+            match arg.to_ascii_lowercase().as_slice() {
+                b"nx" if options.condition == SetCondition::Always => {
+                    options.condition = SetCondition::NotExists
+                }
+                b"xx" if options.condition == SetCondition::Always => {
+                    options.condition = SetCondition::Exists
+                }
+                b"get" => options.get = true,
+                unit @ b"ex" | unit @ b"px" if options.expire_at.is_none() => {
+                    let ttl = args
+                        .next()
+                        .ok_or_else(|| KvdError::from(KvdErrorKind::InvalidRequest))?;
+                    let unit_millis = if unit == b"ex" { 1000 } else { 1 };
+                    options.expire_at = Some(parse_expire_at(ttl, unit_millis)?);
+                }
+                unit @ b"exat" | unit @ b"pxat" if options.expire_at.is_none() => {
+                    let at = args
+                        .next()
+                        .ok_or_else(|| KvdError::from(KvdErrorKind::InvalidRequest))?;
+                    let unit_millis = if unit == b"exat" { 1000 } else { 1 };
+                    options.expire_at = Some(parse_unix_time(at, unit_millis)?);
+                }
+                _ => return Err(KvdError::from(KvdErrorKind::InvalidRequest)),
+            }
+        }
+        let (set, old) = self
+            .engine()
+            .set_with(request[1].clone(), request[2].clone(), options)?;
+        Ok(if options.get {
+            old.map_or(Reply::Nil, Reply::Bulk)
+        } else if set {
+            Reply::ok()
+        } else {
+            Reply::Nil
+        })
+    }
+
+    /// SETNX key value
+    fn handle_setnx(&self, request: &[Vec<u8>]) -> KvdResult<bool> {
+        if request.len() != 3 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let options = SetOptions {
+            condition: SetCondition::NotExists,
+            ..SetOptions::default()
+        };
+        let (set, _) = self
+            .engine()
+            .set_with(request[1].clone(), request[2].clone(), options)?;
+        Ok(set)
+    }
+
+    /// GETSET key value
+    fn handle_getset(&self, request: &[Vec<u8>]) -> KvdResult<Option<Vec<u8>>> {
+        if request.len() != 3 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let options = SetOptions {
+            get: true,
+            ..SetOptions::default()
+        };
+        let (_, old) = self
+            .engine()
+            .set_with(request[1].clone(), request[2].clone(), options)?;
+        Ok(old)
+    }
+
+    /// CAS key (OLD expected | NX) (NEW value | DEL)
+    ///
+    /// NX expects the key to be missing and DEL deletes it. The reply is
+    /// whether the value is swapped and the value stored after the command.
+    fn handle_cas(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        let invalid = || KvdError::from(KvdErrorKind::InvalidRequest);
+        let key = request.get(1).ok_or_else(invalid)?;
+        let mut args = request[2..].iter();
+        let mut operand = |with_value: &[u8], without_value: &[u8]| {
+            let arg = args.next().ok_or_else(invalid)?.to_ascii_lowercase();
+            if arg == with_value {
+                args.next().cloned().map(Some).ok_or_else(invalid)
+            } else if arg == without_value {
+                Ok(None)
+            } else {
+                Err(invalid())
+            }
+        };
+        let expected = operand(b"old", b"nx")?;
+        let new = operand(b"new", b"del")?;
+        if args.next().is_some() {
+            return Err(invalid());
+        }
+        let (swapped, current) =
+            match self
+                .engine()
+                .compare_and_swap(key.clone(), expected, new.clone())?
+            {
+                Ok(()) => (true, new),
+                Err(current) => (false, current),
+            };
+        Ok(Reply::Array(vec![
+            Reply::Integer(swapped as i64),
+            current.map_or(Reply::Nil, Reply::Bulk),
+        ]))
+    }
+
+    /// APPEND key value
+    fn handle_append(&self, request: &[Vec<u8>]) -> KvdResult<usize> {
+        if request.len() != 3 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        self.engine().append(request[1].clone(), request[2].clone())
+    }
+
+    /// STRLEN key
+    fn handle_strlen(&self, request: &[Vec<u8>]) -> KvdResult<usize> {
+        if request.len() != 2 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        self.engine().strlen(request[1].clone())
+    }
+
+    /// GETRANGE key start end
+    fn handle_getrange(&self, request: &[Vec<u8>]) -> KvdResult<Vec<u8>> {
+        if request.len() != 4 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let start =
+            parse_number(&request[2]).ok_or_else(|| KvdError::from(KvdErrorKind::NotAnInteger))?;
+        let end =
+            parse_number(&request[3]).ok_or_else(|| KvdError::from(KvdErrorKind::NotAnInteger))?;
+        self.engine().get_range(request[1].clone(), start, end)
+    }
+
+    /// SETRANGE key offset value
+    fn handle_setrange(&self, request: &[Vec<u8>]) -> KvdResult<usize> {
+        if request.len() != 4 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let offset =
+            parse_number(&request[2]).ok_or_else(|| KvdError::from(KvdErrorKind::NotAnInteger))?;
+        self.engine()
+            .set_range(request[1].clone(), offset, request[3].clone())
+    }
+
+    /// INCR key | DECR key
+    fn handle_incr(&self, request: &[Vec<u8>], delta: i64) -> KvdResult<i64> {
+        if request.len() != 2 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        self.engine().incr_by(request[1].clone(), delta)
+    }
+
+    /// INCRBY key increment
+    fn handle_incrby(&self, request: &[Vec<u8>]) -> KvdResult<i64> {
+        if request.len() != 3 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let delta =
+            parse_number(&request[2]).ok_or_else(|| KvdError::from(KvdErrorKind::NotAnInteger))?;
+        self.engine().incr_by(request[1].clone(), delta)
+    }
+
+    /// INCRBYFLOAT key increment
+    fn handle_incrbyfloat(&self, request: &[Vec<u8>]) -> KvdResult<f64> {
+        if request.len() != 3 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let delta = parse_number::<f64>(&request[2])
+            .filter(|delta| delta.is_finite())
+            .ok_or_else(|| KvdError::from(KvdErrorKind::NotAFloat))?;
+        self.engine().incr_by_float(request[1].clone(), delta)
+    }
+
+    /// GETDEL key
+    fn handle_getdel(&self, request: &[Vec<u8>]) -> KvdResult<Option<Vec<u8>>> {
+        if request.len() != 2 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        self.engine().getdel(request[1].clone())
+    }
+
+    /// DEL key [key ...]
+    fn handle_del(&self, request: &[Vec<u8>]) -> KvdResult<usize> {
+        if request.len() < 2 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        self.engine().mdel(request[1..].to_vec())
+    }
+
+    /// EXISTS key [key ...], a key given twice is counted twice
+    fn handle_exists(&self, request: &[Vec<u8>]) -> KvdResult<usize> {
+        if request.len() < 2 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let mut engine = self.engine();
+        let mut count = 0;
+        for key in request[1..].iter() {
+            if engine.exists(key.clone())? {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// MGET key [key ...]
+    fn handle_mget(&self, request: &[Vec<u8>]) -> KvdResult<Vec<Option<Vec<u8>>>> {
+        if request.len() < 2 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        self.engine().mget(request[1..].to_vec())
+    }
+
+    /// MSET key value [key value ...]
+    fn handle_mset(&self, request: &[Vec<u8>]) -> KvdResult<()> {
+        let pairs = parse_pairs(&request[1..])?;
+        self.engine().mset(pairs)
+    }
+
+    /// MSETNX key value [key value ...]
+    fn handle_msetnx(&self, request: &[Vec<u8>]) -> KvdResult<bool> {
+        let pairs = parse_pairs(&request[1..])?;
+        self.engine().msetnx(pairs)
+    }
+
+    fn handle_client(&self, client: &Client, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        let sub_cmd = request
+            .get(1)
+            .ok_or_else(|| KvdError::from(KvdErrorKind::InvalidRequest))?
+            .to_ascii_lowercase();
+        match (sub_cmd.as_slice(), request.len()) {
+            (b"list", 2) => {
+                let mut list = String::new();
+                for c in self.clients.list() {
+                    list.push_str(&c.info_line());
+                    list.push('\n');
+                }
+                Ok(Reply::Bulk(list.into_bytes()))
+            }
+            (b"id", 2) => Ok(Reply::Integer(client.id() as i64)),
+            (b"getname", 2) => Ok(client
+                .name()
+                .map_or(Reply::Nil, |n| Reply::Bulk(n.into_bytes()))),
+            (b"setname", 3) => {
+                let name = str::from_utf8(&request[2])?;
+                if name.contains(char::is_whitespace) {
+                    return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+                }
+                client.set_name(Some(name.to_string()).filter(|n| !n.is_empty()));
+                Ok(Reply::ok())
+            }
+            (b"kill", 3) => {
+                // the old form, CLIENT KILL addr
+                let addr = str::from_utf8(&request[2])?;
+                match self.clients.kill(client, |c| c.addr() == addr) {
+                    0 => Err(KvdError::from(KvdErrorKind::NoSuchClient)),
+                    _ => Ok(Reply::ok()),
+                }
+            }
+            (b"kill", n) if n > 3 && n % 2 == 0 => {
+                // the new form, CLIENT KILL [ID id] [ADDR addr] [SKIPME yes/no]
+                let mut id = None;
+                let mut addr = None;
+                let mut skip_me = true;
+                for pair in request[2..].chunks(2) {
+                    let value = str::from_utf8(&pair[1])?;
+                    match pair[0].to_ascii_lowercase().as_slice() {
+                        b"id" => {
+                            id = Some(
+                                value
+                                    .parse::<u64>()
+                                    .map_err(|_| KvdError::from(KvdErrorKind::InvalidRequest))?,
+                            )
+                        }
+                        b"addr" => addr = Some(value.to_string()),
+                        b"skipme" => skip_me = value.eq_ignore_ascii_case("yes"),
+                        _ => return Err(KvdError::from(KvdErrorKind::InvalidRequest)),
+                    }
+                }
+                let killed = self.clients.kill(client, |c| {
+                    id.is_none_or(|id| c.id() == id)
+                        && addr.as_ref().is_none_or(|addr| c.addr() == addr)
+                        && !(skip_me && c.id() == client.id())
+                });
+                Ok(Reply::Integer(killed as i64))
+            }
+            _ => Err(KvdError::from(KvdErrorKind::InvalidRequest)),
+        }
+    }
+
+    /// INFO [section]
+    fn handle_info(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        let section = match request.len() {
+            1 => "default".to_string(),
+            2 => str::from_utf8(&request[1])?.to_lowercase(),
+            _ => return Err(KvdError::from(KvdErrorKind::InvalidRequest)),
+        };
+
+        let (key_count, engine_info) = {
+            let engine = self.engine();
+            (engine.key_count(), engine.info())
+        };
+        let sections = vec![
+            (
+                "Server",
+                vec![
+                    (
+                        "kvd_version".to_string(),
+                        env!("CARGO_PKG_VERSION").to_string(),
+                    ),
+                    ("process_id".to_string(), std::process::id().to_string()),
+                    ("tcp_port".to_string(), self.config.port.to_string()),
+                    (
+                        "uptime_in_seconds".to_string(),
+                        self.stats.uptime().as_secs().to_string(),
+                    ),
+                    (
+                        "config_file".to_string(),
+                        self.config.config_file.clone().unwrap_or_default(),
+                    ),
+                ],
+            ),
+            (
+                "Clients",
+                vec![
+                    (
+                        "connected_clients".to_string(),
+                        self.clients.len().to_string(),
+                    ),
+                    (
+                        "blocked_clients".to_string(),
+                        self.blocked.len().to_string(),
+                    ),
+                    (
+                        "max_clients".to_string(),
+                        self.config.max_clients.to_string(),
+                    ),
+                ],
+            ),
+            (
+                "Stats",
+                self.stats
+                    .info()
+                    .into_iter()
+                    .chain(vec![
+                        (
+                            "pubsub_channels".to_string(),
+                            self.pubsub.channels().to_string(),
+                        ),
+                        (
+                            "pubsub_patterns".to_string(),
+                            self.pubsub.patterns().to_string(),
+                        ),
+                    ])
+                    .collect(),
+            ),
+            (
+                "Keyspace",
+                vec![("keys".to_string(), key_count.to_string())],
+            ),
+            (
+                "Replication",
+                match (&self.raft, &self.replica) {
+                    (Some(raft), _) => raft.info(),
+                    (None, Some(replica)) => replica.info(),
+                    (None, None) => vec![("role".to_string(), "leader".to_string())],
+                },
+            ),
+            (
+                "Cluster",
+                self.cluster.as_ref().map_or_else(
+                    || vec![("cluster_enabled".to_string(), "0".to_string())],
+                    |cluster| cluster.info(),
+                ),
+            ),
+            ("Engine", engine_info),
+        ];
+
+        let mut info = String::new();
+        for (name, fields) in sections {
+            if section != "default" && section != "all" && section != name.to_lowercase() {
+                continue;
+            }
+            if !info.is_empty() {
+                info.push_str("\r\n");
+            }
+            write!(info, "# {}\r\n", name).unwrap();
+            for (key, value) in fields {
+                write!(info, "{}:{}\r\n", key, value).unwrap();
+            }
+        }
+        Ok(Reply::Bulk(info.into_bytes()))
+    }
+
+    /// CONFIG GET loglevel | CONFIG SET loglevel level
+    fn handle_config(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        let sub_cmd = request
+            .get(1)
+            .ok_or_else(|| KvdError::from(KvdErrorKind::InvalidRequest))?
+            .to_ascii_lowercase();
+        let param = request
+            .get(2)
+            .ok_or_else(|| KvdError::from(KvdErrorKind::InvalidRequest))?
+            .to_ascii_lowercase();
+        match (sub_cmd.as_slice(), param.as_slice(), request.len()) {
+            (b"get", b"loglevel", 3) => {
+                let level = self.config.log_level.get().as_str().to_lowercase();
+                Ok(Reply::Array(vec![
+                    Reply::Bulk(param),
+                    Reply::Bulk(level.into_bytes()),
+                ]))
+            }
+            (b"set", b"loglevel", 4) => {
+                let level = LogLevel::parse(str::from_utf8(&request[3])?)?;
+                self.config.log_level.set(level);
+                info!(self.config.logger, "log level changed"; "new_level" => level.as_str());
+                Ok(Reply::ok())
+            }
+            (b"get", b"notify-keyspace-events", 3) => {
+                let classes = self.config.notify_keyspace_events.get().to_string();
+                Ok(Reply::Array(vec![
+                    Reply::Bulk(param),
+                    Reply::Bulk(classes.into_bytes()),
+                ]))
+            }
+            (b"set", b"notify-keyspace-events", 4) => {
+                let classes = EventClasses::parse(str::from_utf8(&request[3])?)?;
+                self.config.notify_keyspace_events.set(classes);
+                info!(self.config.logger, "keyspace events changed"; "classes" => %classes);
+                Ok(Reply::ok())
+            }
+            _ => Err(KvdError::from(KvdErrorKind::InvalidRequest)),
+        }
+    }
+
+    /// SLOWLOG GET [count] | LEN | RESET
+    fn handle_slowlog(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        let sub_cmd = request
+            .get(1)
+            .ok_or_else(|| KvdError::from(KvdErrorKind::InvalidRequest))?
+            .to_ascii_lowercase();
+        match (sub_cmd.as_slice(), request.len()) {
+            (b"get", 2) => Ok(Reply::Array(self.slowlog.get(DEFAULT_SLOWLOG_GET_COUNT))),
+            (b"get", 3) => {
+                // a negative count means all the entries
+                let count = str::from_utf8(&request[2])?
+                    .parse::<i64>()
+                    .map_err(|_| KvdError::from(KvdErrorKind::InvalidRequest))?;
+                let count = usize::try_from(count).unwrap_or(usize::MAX);
+                Ok(Reply::Array(self.slowlog.get(count)))
+            }
+            (b"len", 2) => Ok(Reply::Integer(self.slowlog.len() as i64)),
+            (b"reset", 2) => {
+                self.slowlog.reset();
+                Ok(Reply::ok())
+            }
+            _ => Err(KvdError::from(KvdErrorKind::InvalidRequest)),
+        }
+    }
+
+    fn engine(&self) -> MutexGuard<'_, T> {
+        // a panic in another connection does not corrupt the engine itself
+        self.engine.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// parse a positive time to live into the unix time in milliseconds when it ends
+fn parse_expire_at(ttl: &[u8], unit_millis: u64) -> KvdResult<u64> {
+    parse_unix_time(ttl, unit_millis)?
+        .checked_add(now_millis())
+        .ok_or_else(|| KvdError::from(KvdErrorKind::InvalidExpireTime))
+}
+
+/// parse a positive time in the unit into milliseconds
+fn parse_unix_time(time: &[u8], unit_millis: u64) -> KvdResult<u64> {
+    str::from_utf8(time)
+        .ok()
+        .and_then(|time| time.parse::<u64>().ok())
+        .filter(|&time| time > 0)
+        .and_then(|time| time.checked_mul(unit_millis))
+        .ok_or_else(|| KvdError::from(KvdErrorKind::InvalidExpireTime))
+}
+
+/// the commands allowed after a client subscribes
+/// the commands executed by `execute_request`
+fn is_known_command(cmd: &[u8]) -> bool {
+    is_write_command(cmd)
+        || matches!(
+            cmd,
+            b"client"
+                | b"config"
+                | b"info"
+                | b"monitor"
+                | b"slowlog"
+                | b"changes"
+                | b"sync"
+                | b"raft"
+                | b"cluster"
+                | b"asking"
+                | b"subscribe"
+                | b"psubscribe"
+                | b"unsubscribe"
+                | b"punsubscribe"
+                | b"publish"
+                | b"get"
+                | b"hget"
+                | b"hmget"
+                | b"hexists"
+                | b"hlen"
+                | b"hkeys"
+                | b"hvals"
+                | b"hgetall"
+                | b"hscan"
+                | b"llen"
+                | b"lrange"
+                | b"lindex"
+                | b"sismember"
+                | b"smembers"
+                | b"scard"
+                | b"srandmember"
+                | b"sinter"
+                | b"sunion"
+                | b"sdiff"
+                | b"zscore"
+                | b"zcard"
+                | b"zrank"
+                | b"zcount"
+                | b"zrange"
+                | b"type"
+                | b"strlen"
+                | b"getrange"
+                | b"exists"
+                | b"mget"
+        )
+}
+
+fn is_subscribe_command(cmd: &[u8]) -> bool {
+    matches!(
+        cmd,
+        b"subscribe" | b"psubscribe" | b"unsubscribe" | b"punsubscribe"
+    )
+}
+
+/// the commands which change the keys, which are rejected by a follower
+fn is_write_command(cmd: &[u8]) -> bool {
+    matches!(
+        cmd,
+        b"set"
+            | b"setnx"
+            | b"getset"
+            | b"cas"
+            | b"append"
+            | b"setrange"
+            | b"incr"
+            | b"decr"
+            | b"incrby"
+            | b"incrbyfloat"
+            | b"getdel"
+            | b"del"
+            | b"mset"
+            | b"msetnx"
+            | b"hset"
+            | b"hdel"
+            | b"hincrby"
+            | b"lpush"
+            | b"rpush"
+            | b"lpop"
+            | b"rpop"
+            | b"blpop"
+            | b"brpop"
+            | b"lset"
+            | b"lrem"
+            | b"ltrim"
+            | b"sadd"
+            | b"srem"
+            | b"spop"
+            | b"sinterstore"
+            | b"sunionstore"
+            | b"sdiffstore"
+            | b"zadd"
+            | b"zrem"
+            | b"zincrby"
+            | b"zremrangebyscore"
+            | b"migrate"
+            | b"restore"
+    )
+}
+
+/// the key of a request with at least min_len arguments
+fn key_of(request: &[Vec<u8>], min_len: usize) -> KvdResult<&Vec<u8>> {
+    if request.len() < min_len {
+        return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+    }
+    Ok(&request[1])
+}
+
+/// parse the arguments as key value pairs, there should be at least one pair
+fn parse_pairs(args: &[Vec<u8>]) -> KvdResult<Vec<(Vec<u8>, Vec<u8>)>> {
+    if args.is_empty() || !args.len().is_multiple_of(2) {
+        return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+    }
+    Ok(args
+        .chunks(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect())
+}
+
+/// tell the client that it is rejected, the error is ignored since the connection is dropped anyway
+fn reject_conn(conn: TcpStream) {
+    let reply = Reply::from(KvdError::from(KvdErrorKind::MaxClients));
+    let _ = conn.set_write_timeout(Some(Duration::from_secs(1)));
+    let _ = (&conn).write_all(&reply.to_bytes());
+    let _ = conn.shutdown(Shutdown::Both);
+}
+
+pub(crate) fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::memory::MemoryEngine;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_new_server() {
+        let engine = MemoryEngine::new();
+        Server::new(engine, 0).unwrap();
+    }
+
+    #[test]
+    fn test_handle_pipelined_requests() {
+        let engine = MemoryEngine::new();
+        let server = Server::new(engine, 0).unwrap();
+        let guard = server.clients().register("test".to_string(), None).unwrap();
+        let input: &[u8] =
+            b"set a 1\r\nset b 2\r\nget a\r\n*2\r\n$3\r\nGET\r\n$1\r\nb\r\nget c\r\n";
+        let mut output = Vec::new();
+        server
+            .handle_stream(guard.client(), input, &mut output)
+            .unwrap();
+        assert_eq!(
+            b"+OK\r\n+OK\r\n$1\r\n1\r\n$1\r\n2\r\n$-1\r\n".to_vec(),
+            output
+        );
+    }
+
+    #[test]
+    fn test_max_clients() {
+        let config = ServerConfig {
+            max_clients: 1,
+            ..ServerConfig::default()
+        };
+        let addr = start_server(config);
+
+        let mut first = TcpStream::connect(addr).unwrap();
+        assert_eq!(b"$-1\r\n".to_vec(), request(&mut first, b"get key\r\n"));
+
+        let mut second = TcpStream::connect(addr).unwrap();
+        let mut reply = Vec::new();
+        second.read_to_end(&mut reply).unwrap();
+        assert_eq!(b"-ERR max number of clients reached\r\n".to_vec(), reply);
+
+        // the slot is released after the first client is gone
+        drop(first);
+        thread::sleep(Duration::from_millis(100));
+        let mut third = TcpStream::connect(addr).unwrap();
+        assert_eq!(b"$-1\r\n".to_vec(), request(&mut third, b"get key\r\n"));
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let config = ServerConfig {
+            idle_timeout: Some(Duration::from_millis(200)),
+            io_timeout: Some(Duration::from_millis(50)),
+            ..ServerConfig::default()
+        };
+        let addr = start_server(config);
+
+        let mut conn = TcpStream::connect(addr).unwrap();
+        // a request in time keeps the connection alive
+        thread::sleep(Duration::from_millis(120));
+        assert_eq!(
+            b"+OK\r\n".to_vec(),
+            request(&mut conn, b"set key value\r\n")
+        );
+        thread::sleep(Duration::from_millis(120));
+        assert_eq!(
+            b"$5\r\nvalue\r\n".to_vec(),
+            request(&mut conn, b"get key\r\n")
+        );
+
+        // a silent connection is closed
+        let mut reply = Vec::new();
+        let start = Instant::now();
+        conn.read_to_end(&mut reply).unwrap();
+        assert!(reply.is_empty());
+        assert!(start.elapsed() >= Duration::from_millis(150));
+
+        // a request sent in pieces keeps the connection alive
+        let mut conn = TcpStream::connect(addr).unwrap();
+        for piece in [&b"get"[..], b" ", b"key"] {
+            conn.write_all(piece).unwrap();
+            thread::sleep(Duration::from_millis(30));
+        }
+        assert_eq!(b"$5\r\nvalue\r\n".to_vec(), request(&mut conn, b"\r\n"));
+
+        // the idle time of a client blocked longer than the idle timeout
+        // starts after the reply
+        assert_eq!(
+            b"$-1\r\n".to_vec(),
+            request(&mut conn, b"blpop list 0.3\r\n")
+        );
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(
+            b"$5\r\nvalue\r\n".to_vec(),
+            request(&mut conn, b"get key\r\n")
+        );
+    }
+
+    #[test]
+    fn test_request_too_large() {
+        let config = ServerConfig {
+            max_request_size: 16,
+            ..ServerConfig::default()
+        };
+        let addr = start_server(config);
+
+        let mut conn = TcpStream::connect(addr).unwrap();
+        conn.write_all(b"set key 0123456789abcdef").unwrap();
+        let mut reply = Vec::new();
+        conn.read_to_end(&mut reply).unwrap();
+        assert_eq!(b"-ERR request too large\r\n".to_vec(), reply);
+    }
+
+    #[test]
+    fn test_client_commands() {
+        let addr = start_server(ServerConfig::default());
+
+        let mut first = TcpStream::connect(addr).unwrap();
+        let mut second = TcpStream::connect(addr).unwrap();
+        assert_eq!(
+            b"$-1\r\n".to_vec(),
+            request(&mut first, b"client getname\r\n")
+        );
+        assert_eq!(
+            b"+OK\r\n".to_vec(),
+            request(&mut first, b"client setname first\r\n")
+        );
+        assert_eq!(
+            b"$5\r\nfirst\r\n".to_vec(),
+            request(&mut first, b"client getname\r\n")
+        );
+        assert_eq!(b":1\r\n".to_vec(), request(&mut first, b"client id\r\n"));
+
+        let list = String::from_utf8(request(&mut second, b"client list\r\n")).unwrap();
+        let lines: Vec<&str> = list.lines().filter(|l| l.starts_with("id=")).collect();
+        assert_eq!(2, lines.len());
+        let first_addr = first.local_addr().unwrap().to_string();
+        assert!(lines[0].starts_with(&format!("id=1 addr={} name=first ", first_addr)));
+        assert!(lines[0].contains(" cmd=client net-in=65 net-out=25"));
+        assert!(lines[1].starts_with("id=2 "));
+
+        // kill the first client from the second connection
+        let reply = request(
+            &mut second,
+            format!("client kill {}\r\n", first_addr).as_bytes(),
+        );
+        assert_eq!(b"+OK\r\n".to_vec(), reply);
+        let mut reply = Vec::new();
+        first.read_to_end(&mut reply).unwrap();
+        assert!(reply.is_empty());
+
+        let reply = request(&mut second, b"client kill id 1\r\n");
+        assert_eq!(b":0\r\n".to_vec(), reply);
+        let reply = request(&mut second, b"client kill 127.0.0.1:1\r\n");
+        assert_eq!(b"-ERR no such client\r\n".to_vec(), reply);
+    }
+
+    #[test]
+    fn test_client_kill_killed() {
+        // the client killing itself stays registered until its replies are sent
+        let server = Server::new(MemoryEngine::new(), 0).unwrap();
+        let output = handle_input(
+            &server,
+            b"client kill id 1 skipme no\r\nclient kill id 1 skipme no\r\n",
+        );
+        assert_eq!(":1\r\n:0\r\n", output);
+    }
+
+    #[test]
+    fn test_info() {
+        let server = Server::new(MemoryEngine::new(), 0).unwrap();
+        let output = handle_input(&server, b"set a 1\r\nset b 2\r\nget a\r\nget\r\nfoo\r\n");
+        assert!(output.ends_with("-ERR invalid request\r\n-ERR unknown command\r\n"));
+
+        let info = handle_input(&server, b"info\r\n");
+        for field in &[
+            "# Server\r\n",
+            "\r\ntcp_port:0\r\n",
+            "\r\n# Clients\r\nconnected_clients:1\r\n",
+            "\r\ntotal_connections_received:2\r\n",
+            "\r\ntotal_commands_processed:4\r\n",
+            "\r\ntotal_net_input_bytes:41\r\n",
+            "\r\ncmdstat_get:calls=2\r\n",
+            "\r\ncmdstat_set:calls=2\r\n",
+            "\r\nerrorstat_InvalidRequest:count=1\r\n",
+            "\r\nerrorstat_UnknownCommand:count=1\r\n",
+            "\r\n# Keyspace\r\nkeys:2\r\n",
+            "\r\n# Engine\r\nengine:memory\r\n",
+        ] {
+            assert!(info.contains(field), "{} is not in {}", field, info);
+        }
+        assert!(!info.contains("cmdstat_foo"));
+
+        let info = handle_input(&server, b"info keyspace\r\n");
+        assert!(info.ends_with("\r\n# Keyspace\r\nkeys:2\r\n\r\n"));
+        assert!(!info.contains("# Server"));
+    }
+
+    #[test]
+    fn test_slowlog() {
+        let config = ServerConfig {
+            slowlog_threshold: Some(Duration::from_secs(0)),
+            slowlog_max_len: 3,
+            ..ServerConfig::default()
+        };
+        let server = Server::with_config(MemoryEngine::new(), config).unwrap();
+        let output = handle_input(
+            &server,
+            b"slowlog reset\r\nset a 1\r\nget a\r\nfoo\r\nslowlog len\r\n",
+        );
+        // the unknown command is not logged, and the reset is dropped since the log is full
+        assert!(output.ends_with(":3\r\n"));
+        assert_eq!(3, server.slowlog().len());
+
+        let output = handle_input(&server, b"slowlog get 1\r\n");
+        // the newest entry is the slowlog len command
+        assert!(output.starts_with("*1\r\n*6\r\n:3\r\n:"));
+        assert!(output.ends_with("*2\r\n$7\r\nslowlog\r\n$3\r\nlen\r\n$4\r\ntest\r\n$0\r\n\r\n"));
+
+        let output = handle_input(&server, b"slowlog get -1\r\nslowlog get\r\n");
+        assert!(output.starts_with("*3\r\n"));
+        let output = handle_input(&server, b"slowlog reset\r\nslowlog len\r\nslowlog foo\r\n");
+        assert_eq!("+OK\r\n:1\r\n-ERR invalid request\r\n", output);
+    }
+
+    #[test]
+    fn test_config_loglevel() {
+        let server = Server::new(MemoryEngine::new(), 0).unwrap();
+        let output = handle_input(
+            &server,
+            b"config get loglevel\r\nconfig set loglevel DEBUG\r\nconfig get loglevel\r\nconfig set loglevel loud\r\nconfig get port\r\n",
+        );
+        assert_eq!(
+            "*2\r\n$8\r\nloglevel\r\n$4\r\ninfo\r\n+OK\r\n*2\r\n$8\r\nloglevel\r\n$5\r\ndebug\r\n-ERR invalid log level\r\n-ERR invalid request\r\n",
+            output
+        );
+        assert_eq!(slog::Level::Debug, server.config().log_level.get());
+    }
+
+    #[test]
+    fn test_multi_key_commands() {
+        let server = Server::new(MemoryEngine::new(), 0).unwrap();
+        let output = handle_input(
+            &server,
+            b"mset a 1 b 2\r\nmget a b c\r\nexists a a c\r\nmsetnx c 3 a 4\r\nmsetnx c 3 d 4\r\nmget a c\r\n",
+        );
+        assert_eq!(
+            "+OK\r\n*3\r\n$1\r\n1\r\n$1\r\n2\r\n$-1\r\n:2\r\n:0\r\n:1\r\n*2\r\n$1\r\n1\r\n$1\r\n3\r\n",
+            output
+        );
+
+        let output = handle_input(&server, b"del a b x\r\ndel a\r\nexists a b c\r\n");
+        assert_eq!(":2\r\n:0\r\n:1\r\n", output);
+
+        let output = handle_input(
+            &server,
+            b"mset a\r\nmset a 1 b\r\nmget\r\ndel\r\nexists\r\n",
+        );
+        assert_eq!("-ERR invalid request\r\n".repeat(5), output);
+    }
+
+    #[test]
+    fn test_conditional_set() {
+        let server = Server::new(MemoryEngine::new(), 0).unwrap();
+        let output = handle_input(
+            &server,
+            b"set a 1 xx\r\nset a 1 nx\r\nset a 2 nx\r\nset a 2 xx get\r\nsetnx a 3\r\nsetnx b 3\r\n",
+        );
+        assert_eq!("$-1\r\n+OK\r\n$-1\r\n$1\r\n1\r\n:0\r\n:1\r\n", output);
+
+        let output = handle_input(
+            &server,
+            b"getset a 4\r\ngetset c 5\r\ngetdel a\r\ngetdel a\r\nexists a\r\nset c 6 NX GET\r\n",
+        );
+        assert_eq!(
+            "$1\r\n2\r\n$-1\r\n$1\r\n4\r\n$-1\r\n:0\r\n$1\r\n5\r\n",
+            output
+        );
+
+        let output = handle_input(
+            &server,
+            b"set a 1 nx xx\r\nset a 1 ex\r\nset a 1 px 1 ex 1\r\nset a 1 foo\r\nsetnx a\r\ngetdel\r\n",
+        );
+        assert_eq!("-ERR invalid request\r\n".repeat(6), output);
+        let output = handle_input(
+            &server,
+            b"set a 1 ex 0\r\nset a 1 px -1\r\nset a 1 ex x\r\n",
+        );
+        assert_eq!("-ERR invalid expire time\r\n".repeat(3), output);
+    }
+
+    #[test]
+    fn test_cas() {
+        let server = Server::new(MemoryEngine::new(), 0).unwrap();
+        let output = handle_input(
+            &server,
+            b"cas a nx new 1\r\ncas a nx new 2\r\ncas a old 1 new 2\r\ncas a old 1 del\r\ncas a old 2 del\r\nexists a\r\n",
+        );
+        assert_eq!(
+            "*2\r\n:1\r\n$1\r\n1\r\n*2\r\n:0\r\n$1\r\n1\r\n*2\r\n:1\r\n$1\r\n2\r\n*2\r\n:0\r\n$1\r\n2\r\n*2\r\n:1\r\n$-1\r\n:0\r\n",
+            output
+        );
+
+        let output = handle_input(
+            &server,
+            b"cas a\r\ncas a nx\r\ncas a old\r\ncas a nx new\r\ncas a foo del\r\ncas a nx del x\r\n",
+        );
+        assert_eq!("-ERR invalid request\r\n".repeat(6), output);
+    }
+
+    #[test]
+    fn test_incr() {
+        let server = Server::new(MemoryEngine::new(), 0).unwrap();
+        let output = handle_input(
+            &server,
+            b"incr a\r\nincrby a 10\r\ndecr a\r\nincrby a -20\r\nincrbyfloat a 0.5\r\nincrbyfloat b 2\r\nget a\r\n",
+        );
+        assert_eq!(
+            ":1\r\n:11\r\n:10\r\n:-10\r\n$4\r\n-9.5\r\n$1\r\n2\r\n$4\r\n-9.5\r\n",
+            output
+        );
+
+        let output = handle_input(
+            &server,
+            b"incr a\r\nincrby b x\r\nincrbyfloat b nan\r\nset c 9223372036854775807\r\nincr c\r\nincr\r\n",
+        );
+        assert_eq!(
+            "-ERR value is not an integer or out of range\r\n-ERR value is not an integer or out of range\r\n\
+             -ERR value is not a valid float\r\n+OK\r\n-ERR increment or decrement would overflow\r\n\
+             -ERR invalid request\r\n",
+            output
+        );
+    }
+
+    #[test]
+    fn test_string_commands() {
+        let server = Server::new(MemoryEngine::new(), 0).unwrap();
+        let output = handle_input(
+            &server,
+            b"append a hello\r\nappend a _world\r\nstrlen a\r\nstrlen b\r\ngetrange a 0 4\r\ngetrange a -5 -1\r\ngetrange a 3 1\r\n",
+        );
+        assert_eq!(
+            ":5\r\n:11\r\n:11\r\n:0\r\n$5\r\nhello\r\n$5\r\nworld\r\n$0\r\n\r\n",
+            output
+        );
+
+        let output = handle_input(
+            &server,
+            b"setrange a 6 WORLD\r\ngetrange a 4 6\r\nsetrange b 2 x\r\nget b\r\n*4\r\n$8\r\nsetrange\r\n$1\r\nc\r\n$1\r\n1\r\n$0\r\n\r\nexists c\r\n",
+        );
+        assert_eq!(
+            ":11\r\n$3\r\no_W\r\n:3\r\n$3\r\n\0\0x\r\n:0\r\n:0\r\n",
+            output
+        );
+
+        let output = handle_input(
+            &server,
+            b"getrange a x 1\r\nsetrange a -1 x\r\nsetrange a 536870912 x\r\nappend a\r\n",
+        );
+        assert_eq!(
+            "-ERR value is not an integer or out of range\r\n-ERR value is not an integer or out of range\r\n\
+             -ERR string exceeds maximum allowed size\r\n-ERR invalid request\r\n",
+            output
+        );
+    }
+
+    #[test]
+    fn test_set_expire() {
+        let server = Server::new(MemoryEngine::new(), 0).unwrap();
+        let output = handle_input(
+            &server,
+            b"set a 1 px 50\r\nset b 1 ex 100\r\nset c 1 px 50\r\nset c 2\r\n",
+        );
+        assert_eq!("+OK\r\n".repeat(4), output);
+        thread::sleep(Duration::from_millis(60));
+        // a plain set removes the expire time
+        let output = handle_input(&server, b"exists a b c\r\nset a 2 xx\r\n");
+        assert_eq!(":2\r\n$-1\r\n", output);
+
+        // a time in the past expires the key at once
+        let at = now_millis() + 100_000;
+        let input = format!("set d 1 pxat {}\r\nset e 1 exat 1\r\nexists d e\r\n", at);
+        let output = handle_input(&server, input.as_bytes());
+        assert_eq!("+OK\r\n+OK\r\n:1\r\n", output);
+        let output = handle_input(&server, b"set a 1 exat 0\r\nset a 1 ex 1 pxat 1\r\n");
+        assert_eq!(
+            "-ERR invalid expire time\r\n-ERR invalid request\r\n",
+            output
+        );
+    }
+
+    #[test]
+    fn test_monitor() {
+        let addr = start_server(ServerConfig::default());
+        let mut monitor = TcpStream::connect(addr).unwrap();
+        assert_eq!(b"+OK\r\n".to_vec(), request(&mut monitor, b"monitor\r\n"));
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        let client_addr = client.local_addr().unwrap().to_string();
+        assert_eq!(
+            b"+OK\r\n".to_vec(),
+            request(&mut client, b"set key \"v\r\n")
+        );
+        assert_eq!(
+            b"+OK\r\n".to_vec(),
+            request(&mut client, b"client setname c\r\n")
+        );
+        // the commands of the monitor itself are not streamed
+        monitor.write_all(b"get key\r\n").unwrap();
+
+        let mut lines = String::new();
+        let mut reader = io::BufReader::new(&monitor);
+        for _ in 0..2 {
+            io::BufRead::read_line(&mut reader, &mut lines).unwrap();
+        }
+        let expect = format!(" [{}] \"set\" \"key\" \"\\\"v\"\r\n", client_addr);
+        assert!(lines.starts_with('+'));
+        assert!(lines.contains(&expect), "{} is not in {}", expect, lines);
+        assert!(lines.ends_with("\"client\" \"setname\" \"c\"\r\n"));
+
+        // the connection of a killed monitor is closed
+        assert_eq!(
+            b":1\r\n".to_vec(),
+            request(&mut client, b"client kill skipme yes id 1\r\n")
+        );
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+    }
+
+    #[test]
+    fn test_subscriber_mode() {
+        let server = Server::new(MemoryEngine::new(), 0).unwrap();
+        let output = handle_input(
+            &server,
+            b"get k\r\nsubscribe a b\r\nget k\r\npunsubscribe\r\nunsubscribe\r\nget k\r\n",
+        );
+        assert_eq!(
+            "$-1\r\n\
+             *3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n:1\r\n\
+             *3\r\n$9\r\nsubscribe\r\n$1\r\nb\r\n:2\r\n\
+             -ERR only (P)SUBSCRIBE / (P)UNSUBSCRIBE are allowed in this context\r\n\
+             *3\r\n$12\r\npunsubscribe\r\n$-1\r\n:2\r\n\
+             *3\r\n$11\r\nunsubscribe\r\n$1\r\na\r\n:1\r\n\
+             *3\r\n$11\r\nunsubscribe\r\n$1\r\nb\r\n:0\r\n\
+             $-1\r\n",
+            output
+        );
+        assert_eq!(0, server.pubsub().channels());
+
+        // an unknown command is rejected before the subscriber mode, and
+        // kept out of the stats like any other unknown command
+        let output = handle_input(&server, b"subscribe a\r\nfoo\r\nget k\r\n");
+        assert!(output.ends_with(
+            "-ERR unknown command\r\n\
+             -ERR only (P)SUBSCRIBE / (P)UNSUBSCRIBE are allowed in this context\r\n"
+        ));
+        let info = handle_input(&server, b"info stats\r\n");
+        assert!(!info.contains("cmdstat_foo"));
+    }
+
+    #[test]
+    fn test_publish() {
+        let config = ServerConfig {
+            idle_timeout: Some(Duration::from_millis(100)),
+            pubsub_buffer_limit: 1024,
+            ..ServerConfig::default()
+        };
+        let addr = start_server(config);
+        let mut subscriber = TcpStream::connect(addr).unwrap();
+        assert_eq!(
+            b"*3\r\n$10\r\npsubscribe\r\n$2\r\nn*\r\n:1\r\n".to_vec(),
+            request(&mut subscriber, b"psubscribe n*\r\n")
+        );
+        let mut slow = TcpStream::connect(addr).unwrap();
+        assert_eq!(
+            b"*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n".to_vec(),
+            request(&mut slow, b"subscribe news\r\n")
+        );
+
+        // a subscriber waiting for messages is not closed for being idle
+        thread::sleep(Duration::from_millis(300));
+        let mut publisher = TcpStream::connect(addr).unwrap();
+        assert_eq!(
+            b":2\r\n".to_vec(),
+            request(&mut publisher, b"publish news hello\r\n")
+        );
+        let mut reply = vec![0; 4096];
+        let n = subscriber.read(&mut reply).unwrap();
+        assert_eq!(
+            b"*4\r\n$8\r\npmessage\r\n$2\r\nn*\r\n$4\r\nnews\r\n$5\r\nhello\r\n".to_vec(),
+            reply[..n].to_vec()
+        );
+        assert_eq!(
+            b":1\r\n".to_vec(),
+            request(&mut publisher, b"publish nothing x\r\n")
+        );
+        let n = subscriber.read(&mut reply).unwrap();
+        assert_eq!(
+            b"*4\r\n$8\r\npmessage\r\n$2\r\nn*\r\n$7\r\nnothing\r\n$1\r\nx\r\n".to_vec(),
+            reply[..n].to_vec()
+        );
+        assert_eq!(
+            b"*3\r\n$12\r\npunsubscribe\r\n$2\r\nn*\r\n:0\r\n".to_vec(),
+            request(&mut subscriber, b"punsubscribe n*\r\n")
+        );
+
+        // the slow subscriber which does not read is closed beyond the buffer limit,
+        // as the messages do not fit in the socket buffers
+        let message = format!("publish news {}\r\n", "x".repeat(1000));
+        let mut received = 1;
+        for _ in 0..10000 {
+            received = request(&mut publisher, message.as_bytes())[1] - b'0';
+            if received == 0 {
+                break;
+            }
+        }
+        assert_eq!(0, received);
+        let mut data = Vec::new();
+        let _ = slow.read_to_end(&mut data);
+    }
+
+    #[test]
+    fn test_keyspace_events() {
+        let addr = start_server(ServerConfig::default());
+        let mut subscriber = TcpStream::connect(addr).unwrap();
+        request(&mut subscriber, b"subscribe __keyspace__:k\r\n");
+        let mut client = TcpStream::connect(addr).unwrap();
+        // no event is published by default
+        assert_eq!(b"+OK\r\n".to_vec(), request(&mut client, b"set k 1\r\n"));
+        assert_eq!(
+            b"*2\r\n$22\r\nnotify-keyspace-events\r\n$0\r\n\r\n".to_vec(),
+            request(&mut client, b"config get notify-keyspace-events\r\n")
+        );
+        assert_eq!(
+            b"-ERR invalid keyspace event classes\r\n".to_vec(),
+            request(&mut client, b"config set notify-keyspace-events gq\r\n")
+        );
+        assert_eq!(
+            b"+OK\r\n".to_vec(),
+            request(&mut client, b"config set notify-keyspace-events $g\r\n")
+        );
+        assert_eq!(
+            b"*2\r\n$22\r\nnotify-keyspace-events\r\n$2\r\ng$\r\n".to_vec(),
+            request(&mut client, b"config get notify-keyspace-events\r\n")
+        );
+
+        assert_eq!(
+            b"+OK\r\n".to_vec(),
+            request(&mut client, b"set k 2 px 60000\r\n")
+        );
+        assert_eq!(b":1\r\n".to_vec(), request(&mut client, b"del k\r\n"));
+        let expect = "*3\r\n$7\r\nmessage\r\n$14\r\n__keyspace__:k\r\n$3\r\nset\r\n\
+                      *3\r\n$7\r\nmessage\r\n$14\r\n__keyspace__:k\r\n$6\r\nexpire\r\n\
+                      *3\r\n$7\r\nmessage\r\n$14\r\n__keyspace__:k\r\n$3\r\ndel\r\n";
+        let mut reply = vec![0; expect.len()];
+        subscriber.read_exact(&mut reply).unwrap();
+        assert_eq!(expect, String::from_utf8(reply).unwrap());
+    }
+
+    #[test]
+    fn test_keyspace_values() {
+        let addr = start_server(ServerConfig::default());
+        let mut subscriber = TcpStream::connect(addr).unwrap();
+        request(&mut subscriber, b"subscribe __keyvalue__:k\r\n");
+        let mut client = TcpStream::connect(addr).unwrap();
+        request(&mut client, b"config set notify-keyspace-events $\r\n");
+        // the value is only published with the value class
+        assert_eq!(b"+OK\r\n".to_vec(), request(&mut client, b"set k 1\r\n"));
+        request(&mut client, b"config set notify-keyspace-events $v\r\n");
+        assert_eq!(
+            b":12\r\n".to_vec(),
+            request(&mut client, b"incrby k 11\r\n")
+        );
+        let expect = "*3\r\n$7\r\nmessage\r\n$14\r\n__keyvalue__:k\r\n$2\r\n12\r\n";
+        let mut reply = vec![0; expect.len()];
+        subscriber.read_exact(&mut reply).unwrap();
+        assert_eq!(expect, String::from_utf8(reply).unwrap());
+    }
+
+    pub(super) fn handle_input<T: KvdEngine>(server: &Server<T>, input: &[u8]) -> String {
+        let guard = server.register_client("test".to_string(), None).unwrap();
+        let mut output = Vec::new();
+        server
+            .handle_stream(guard.client(), input, &mut output)
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    pub(super) fn start_server(config: ServerConfig) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::with_config(MemoryEngine::new(), config).unwrap();
+        thread::spawn(move || server.serve_listener(listener));
+        addr
+    }
+
+    pub(super) fn request(conn: &mut TcpStream, data: &[u8]) -> Vec<u8> {
+        conn.write_all(data).unwrap();
+        let mut reply = vec![0; 4096];
+        let n = conn.read(&mut reply).unwrap();
+        reply.truncate(n);
+        reply
+    }
+}
@@ -0,0 +1,172 @@
+use super::clients::Client;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// the number of lines buffered for a monitor before it is considered too slow
+pub const MONITOR_BUFFER_SIZE: usize = 10000;
+
+/// Sends a line to a monitor without blocking, returns false if the monitor
+/// can not keep up or is gone.
+pub(crate) type MonitorSink = Box<dyn Fn(Vec<u8>) -> bool + Send + Sync>;
+
+/// Keeps the monitor subscribed until it is dropped.
+pub(crate) struct MonitorGuard {
+    monitors: Arc<Monitors>,
+    client: Arc<Client>,
+}
+
+/// The clients that receive every command executed by the other clients.
+pub struct Monitors {
+    monitors: Mutex<Vec<(Arc<Client>, MonitorSink)>>,
+    /// the number of monitors, checked before a command is formatted
+    active: AtomicUsize,
+}
+
+impl Monitors {
+    pub fn new() -> Monitors {
+        Monitors {
+            monitors: Mutex::new(Vec::new()),
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn subscribe(
+        self: &Arc<Self>,
+        client: Arc<Client>,
+        sink: MonitorSink,
+    ) -> MonitorGuard {
+        let mut monitors = self.monitors.lock().unwrap();
+        monitors.push((client.clone(), sink));
+        self.active.store(monitors.len(), Ordering::Relaxed);
+        MonitorGuard {
+            monitors: self.clone(),
+            client,
+        }
+    }
+
+    fn unsubscribe(&self, client: &Client) {
+        let mut monitors = self.monitors.lock().unwrap();
+        monitors.retain(|(c, _)| c.id() != client.id());
+        self.active.store(monitors.len(), Ordering::Relaxed);
+    }
+
+    pub fn len(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Send the request to every monitor except the client itself.
+    ///
+    /// A monitor whose buffer is full is closed, so that a slow monitor
+    /// never blocks the clients it is watching.
+    pub(crate) fn feed(&self, client: &Client, request: &[Vec<u8>]) {
+        if self.is_empty() {
+            return;
+        }
+        let line = format_line(client.addr(), request);
+        let mut monitors = self.monitors.lock().unwrap();
+        monitors.retain(|(monitor, sink)| {
+            if monitor.id() == client.id() || sink(line.clone()) {
+                return true;
+            }
+            monitor.close();
+            false
+        });
+        self.active.store(monitors.len(), Ordering::Relaxed);
+    }
+}
+
+impl Default for Monitors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for MonitorGuard {
+    fn drop(&mut self) {
+        self.monitors.unsubscribe(&self.client);
+    }
+}
+
+/// the same format as redis, e.g. +1339518083.107412 [127.0.0.1:60866] "set" "key" "value"
+fn format_line(addr: &str, request: &[Vec<u8>]) -> Vec<u8> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut line = String::new();
+    write!(
+        line,
+        "+{}.{:06} [{}]",
+        now.as_secs(),
+        now.subsec_micros(),
+        addr
+    )
+    .unwrap();
+    for arg in request {
+        line.push_str(" \"");
+        for &b in arg {
+            match b {
+                b'\\' => line.push_str("\\\\"),
+                b'"' => line.push_str("\\\""),
+                b'\n' => line.push_str("\\n"),
+                b'\r' => line.push_str("\\r"),
+                b'\t' => line.push_str("\\t"),
+                0x20..=0x7e => line.push(b as char),
+                _ => write!(line, "\\x{:02x}", b).unwrap(),
+            }
+        }
+        line.push('"');
+    }
+    line.push_str("\r\n");
+    line.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::clients::ClientRegistry;
+    use slog::{Discard, Logger};
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_feed() {
+        let registry = Arc::new(ClientRegistry::new(10, Logger::root(Discard, o!())));
+        let monitor = registry.register("monitor".to_string(), None).unwrap();
+        let slow = registry.register("slow".to_string(), None).unwrap();
+        let client = registry.register("client".to_string(), None).unwrap();
+
+        let monitors = Arc::new(Monitors::new());
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let guard = monitors.subscribe(
+            monitor.client().clone(),
+            Box::new(move |line| sender.try_send(line).is_ok()),
+        );
+        let _slow_guard = monitors.subscribe(slow.client().clone(), Box::new(|_| false));
+        assert_eq!(2, monitors.len());
+
+        monitors.feed(
+            client.client(),
+            &[Vec::from("set"), Vec::from("k\"1\n"), vec![0xff]],
+        );
+        let line = String::from_utf8(receiver.try_recv().unwrap()).unwrap();
+        assert!(line.starts_with('+'));
+        assert!(line.ends_with(" [client] \"set\" \"k\\\"1\\n\" \"\\xff\"\r\n"));
+
+        // the slow monitor is dropped
+        assert_eq!(1, monitors.len());
+        assert!(slow.client().is_killed());
+        assert!(!monitor.client().is_killed());
+
+        // the commands of the monitor itself are not sent to it
+        monitors.feed(monitor.client(), &[Vec::from("get")]);
+        assert!(receiver.try_recv().is_err());
+
+        drop(guard);
+        assert!(monitors.is_empty());
+    }
+}
@@ -0,0 +1,395 @@
+//! The channels of PUBLISH and SUBSCRIBE.
+//!
+//! Once a client subscribes, its replies are queued here together with the
+//! messages published to it, so that they are written in order by the
+//! connection when it is woken up. A subscriber whose queue grows beyond the
+//! limit is closed, so that a slow subscriber never blocks the publishers.
+
+use super::clients::Client;
+use super::glob::glob_match;
+use crate::protocol::Reply;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// the max number of bytes queued for a subscriber, like the pubsub output
+/// buffer limit of redis
+pub const DEFAULT_PUBSUB_BUFFER_LIMIT: usize = 32 * 1024 * 1024;
+
+/// Wakes up the connection of a subscriber when its queue is not empty.
+pub(crate) type Waker = Box<dyn Fn() + Send + Sync>;
+
+pub struct PubSub {
+    state: Mutex<PubSubState>,
+    buffer_limit: usize,
+}
+
+#[derive(Default)]
+struct PubSubState {
+    subscribers: HashMap<u64, Subscriber>,
+    /// the ids of the subscribers of every channel
+    channels: BTreeMap<Vec<u8>, BTreeSet<u64>>,
+    /// the ids of the subscribers of every pattern
+    patterns: BTreeMap<Vec<u8>, BTreeSet<u64>>,
+}
+
+struct Subscriber {
+    client: Arc<Client>,
+    channels: BTreeSet<Vec<u8>>,
+    patterns: BTreeSet<Vec<u8>>,
+    /// the replies and the messages not written yet
+    output: Vec<u8>,
+    waker: Option<Waker>,
+}
+
+/// Removes the subscriptions of a client when its connection ends.
+pub(crate) struct SubscriberGuard {
+    pubsub: Arc<PubSub>,
+    client: Arc<Client>,
+}
+
+/// A flag to wait for, used as the waker of a thread.
+pub(crate) struct Signal {
+    notified: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl PubSub {
+    pub fn new(buffer_limit: usize) -> PubSub {
+        PubSub {
+            state: Mutex::new(PubSubState::default()),
+            buffer_limit,
+        }
+    }
+
+    /// the number of channels with at least one subscriber
+    pub fn channels(&self) -> usize {
+        self.state.lock().unwrap().channels.len()
+    }
+
+    /// the number of patterns with at least one subscriber
+    pub fn patterns(&self) -> usize {
+        self.state.lock().unwrap().patterns.len()
+    }
+
+    /// SUBSCRIBE or PSUBSCRIBE, a confirmation is queued for every name
+    pub(crate) fn subscribe(&self, client: &Arc<Client>, names: &[Vec<u8>], pattern: bool) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let subscriber = state
+            .subscribers
+            .entry(client.id())
+            .or_insert_with(|| Subscriber::new(client));
+        // the number of the subscriptions of the other kind
+        let others = if pattern {
+            subscriber.channels.len()
+        } else {
+            subscriber.patterns.len()
+        };
+        let (kind, subscribed, index) = if pattern {
+            ("psubscribe", &mut subscriber.patterns, &mut state.patterns)
+        } else {
+            ("subscribe", &mut subscriber.channels, &mut state.channels)
+        };
+        let mut replies = Vec::new();
+        for name in names {
+            if subscribed.insert(name.clone()) {
+                index.entry(name.clone()).or_default().insert(client.id());
+            }
+            confirm(&mut replies, kind, Some(name), others + subscribed.len());
+        }
+        client.set_subscriptions(others + subscribed.len());
+        subscriber.push(&replies, self.buffer_limit);
+    }
+
+    /// UNSUBSCRIBE or PUNSUBSCRIBE, from every channel or pattern if no name
+    /// is given, a confirmation is queued for every name
+    pub(crate) fn unsubscribe(&self, client: &Arc<Client>, names: &[Vec<u8>], pattern: bool) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let subscriber = state
+            .subscribers
+            .entry(client.id())
+            .or_insert_with(|| Subscriber::new(client));
+        // the number of the subscriptions of the other kind
+        let others = if pattern {
+            subscriber.channels.len()
+        } else {
+            subscriber.patterns.len()
+        };
+        let (kind, subscribed, index) = if pattern {
+            (
+                "punsubscribe",
+                &mut subscriber.patterns,
+                &mut state.patterns,
+            )
+        } else {
+            ("unsubscribe", &mut subscriber.channels, &mut state.channels)
+        };
+        let names = if names.is_empty() {
+            subscribed.iter().cloned().collect()
+        } else {
+            names.to_vec()
+        };
+        let mut replies = Vec::new();
+        for name in names.iter() {
+            if subscribed.remove(name) {
+                remove_id(index, name, client.id());
+            }
+            confirm(&mut replies, kind, Some(name), others + subscribed.len());
+        }
+        if names.is_empty() {
+            // redis confirms an unsubscribe from nothing too
+            confirm(&mut replies, kind, None, others);
+        }
+        client.set_subscriptions(others + subscribed.len());
+        subscriber.push(&replies, self.buffer_limit);
+    }
+
+    /// PUBLISH, return the number of the subscribers which receive the message
+    pub(crate) fn publish(&self, channel: &[u8], message: &[u8]) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let mut received = 0;
+        if let Some(ids) = state.channels.get(channel) {
+            let mut data = Vec::new();
+            write_reply(
+                &mut data,
+                vec![
+                    Reply::Bulk(b"message".to_vec()),
+                    Reply::Bulk(channel.to_vec()),
+                    Reply::Bulk(message.to_vec()),
+                ],
+            );
+            for id in ids {
+                if let Some(subscriber) = state.subscribers.get_mut(id) {
+                    received += subscriber.push(&data, self.buffer_limit) as usize;
+                }
+            }
+        }
+        for (pattern, ids) in state.patterns.iter() {
+            if !glob_match(pattern, channel) {
+                continue;
+            }
+            let mut data = Vec::new();
+            write_reply(
+                &mut data,
+                vec![
+                    Reply::Bulk(b"pmessage".to_vec()),
+                    Reply::Bulk(pattern.clone()),
+                    Reply::Bulk(channel.to_vec()),
+                    Reply::Bulk(message.to_vec()),
+                ],
+            );
+            for id in ids {
+                if let Some(subscriber) = state.subscribers.get_mut(id) {
+                    received += subscriber.push(&data, self.buffer_limit) as usize;
+                }
+            }
+        }
+        received
+    }
+
+    /// Queue a reply of a subscriber, after the output of the connection
+    /// which is not written yet, since it is replied before.
+    pub(crate) fn queue(&self, client: &Client, before: Vec<u8>, reply: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(subscriber) = state.subscribers.get_mut(&client.id()) {
+            subscriber.output.splice(0..0, before);
+            subscriber.push(reply, self.buffer_limit);
+        }
+    }
+
+    pub(crate) fn set_waker(&self, client: &Client, waker: Waker) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(subscriber) = state.subscribers.get_mut(&client.id()) {
+            subscriber.waker = Some(waker);
+        }
+    }
+
+    /// the output queued for the client, None if it is not a subscriber any more
+    pub(crate) fn take_output(&self, client: &Client) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        let subscriber = state.subscribers.get_mut(&client.id())?;
+        Some(std::mem::take(&mut subscriber.output))
+    }
+
+    pub(crate) fn guard(self: &Arc<Self>, client: &Arc<Client>) -> SubscriberGuard {
+        SubscriberGuard {
+            pubsub: self.clone(),
+            client: client.clone(),
+        }
+    }
+
+    fn remove(&self, client: &Client) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let subscriber = match state.subscribers.remove(&client.id()) {
+            Some(subscriber) => subscriber,
+            None => return,
+        };
+        for channel in subscriber.channels.iter() {
+            remove_id(&mut state.channels, channel, client.id());
+        }
+        for pattern in subscriber.patterns.iter() {
+            remove_id(&mut state.patterns, pattern, client.id());
+        }
+        client.set_subscriptions(0);
+        client.set_subscriber(false);
+        // the connection stops waiting for the output
+        if let Some(waker) = &subscriber.waker {
+            waker();
+        }
+    }
+}
+
+impl Subscriber {
+    fn new(client: &Arc<Client>) -> Subscriber {
+        client.set_subscriber(true);
+        Subscriber {
+            client: client.clone(),
+            channels: BTreeSet::new(),
+            patterns: BTreeSet::new(),
+            output: Vec::new(),
+            waker: None,
+        }
+    }
+
+    /// queue the data, return false if the subscriber is closed
+    fn push(&mut self, data: &[u8], limit: usize) -> bool {
+        if self.client.is_killed() {
+            return false;
+        }
+        self.output.extend_from_slice(data);
+        if self.output.len() > limit {
+            self.output = Vec::new();
+            self.client.close();
+            return false;
+        }
+        if let Some(waker) = &self.waker {
+            waker();
+        }
+        true
+    }
+}
+
+impl Drop for SubscriberGuard {
+    fn drop(&mut self) {
+        if self.client.is_subscriber() {
+            self.pubsub.remove(&self.client);
+        }
+    }
+}
+
+impl Signal {
+    pub(crate) fn new() -> Signal {
+        Signal {
+            notified: Mutex::new(false),
+            condvar: Condvar::new(),
+        }
+    }
+
+    pub(crate) fn notify(&self) {
+        *self.notified.lock().unwrap() = true;
+        self.condvar.notify_one();
+    }
+
+    /// wait until the signal is notified, and reset it
+    pub(crate) fn wait(&self) {
+        let notified = self.notified.lock().unwrap();
+        let mut notified = self
+            .condvar
+            .wait_while(notified, |notified| !*notified)
+            .unwrap();
+        *notified = false;
+    }
+}
+
+fn remove_id(index: &mut BTreeMap<Vec<u8>, BTreeSet<u64>>, name: &[u8], id: u64) {
+    if let Some(ids) = index.get_mut(name) {
+        ids.remove(&id);
+        if ids.is_empty() {
+            index.remove(name);
+        }
+    }
+}
+
+fn confirm(output: &mut Vec<u8>, kind: &str, name: Option<&Vec<u8>>, count: usize) {
+    write_reply(
+        output,
+        vec![
+            Reply::Bulk(kind.as_bytes().to_vec()),
+            name.cloned().map_or(Reply::Nil, Reply::Bulk),
+            Reply::Integer(count as i64),
+        ],
+    );
+}
+
+fn write_reply(output: &mut Vec<u8>, replies: Vec<Reply>) {
+    // writing to a Vec never fails
+    Reply::Array(replies).write_to(output).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::clients::ClientRegistry;
+    use slog::{Discard, Logger};
+
+    fn take_string(pubsub: &PubSub, client: &Client) -> String {
+        String::from_utf8(pubsub.take_output(client).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_publish() {
+        let registry = Arc::new(ClientRegistry::new(10, Logger::root(Discard, o!())));
+        let subscriber = registry.register("subscriber".to_string(), None).unwrap();
+        let slow = registry.register("slow".to_string(), None).unwrap();
+        let (subscriber, slow) = (subscriber.client(), slow.client());
+
+        let pubsub = Arc::new(PubSub::new(100));
+        let guard = pubsub.guard(subscriber);
+        pubsub.subscribe(subscriber, &[b"a".to_vec(), b"b".to_vec()], false);
+        pubsub.subscribe(subscriber, &[b"a*".to_vec()], true);
+        assert_eq!(3, subscriber.subscriptions());
+        assert_eq!(
+            "*3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n:1\r\n\
+             *3\r\n$9\r\nsubscribe\r\n$1\r\nb\r\n:2\r\n\
+             *3\r\n$10\r\npsubscribe\r\n$2\r\na*\r\n:3\r\n",
+            take_string(&pubsub, subscriber)
+        );
+        assert_eq!((2, 1), (pubsub.channels(), pubsub.patterns()));
+
+        // received once for the channel and once for the pattern
+        assert_eq!(2, pubsub.publish(b"a", b"m"));
+        assert_eq!(
+            "*3\r\n$7\r\nmessage\r\n$1\r\na\r\n$1\r\nm\r\n\
+             *4\r\n$8\r\npmessage\r\n$2\r\na*\r\n$1\r\na\r\n$1\r\nm\r\n",
+            take_string(&pubsub, subscriber)
+        );
+        assert_eq!(0, pubsub.publish(b"c", b"m"));
+
+        pubsub.unsubscribe(subscriber, &[], false);
+        assert_eq!(1, subscriber.subscriptions());
+        assert_eq!(
+            "*3\r\n$11\r\nunsubscribe\r\n$1\r\na\r\n:2\r\n\
+             *3\r\n$11\r\nunsubscribe\r\n$1\r\nb\r\n:1\r\n",
+            take_string(&pubsub, subscriber)
+        );
+        assert_eq!((0, 1), (pubsub.channels(), pubsub.patterns()));
+
+        // the slow subscriber is closed when its output exceeds the limit
+        pubsub.subscribe(slow, &[b"ab".to_vec()], false);
+        assert_eq!(2, pubsub.publish(b"ab", &[b'x'; 20]));
+        pubsub.take_output(subscriber);
+        assert_eq!(1, pubsub.publish(b"ab", &[b'x'; 20]));
+        assert!(slow.is_killed());
+        assert!(!subscriber.is_killed());
+
+        // the subscriptions are removed with the guard
+        drop(guard);
+        assert!(!subscriber.is_subscriber());
+        assert_eq!(0, subscriber.subscriptions());
+        assert!(pubsub.take_output(subscriber).is_none());
+        assert_eq!((1, 0), (pubsub.channels(), pubsub.patterns()));
+    }
+}
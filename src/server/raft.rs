@@ -0,0 +1,541 @@
+//! Running a server as a node of a raft group.
+//!
+//! A write is proposed to the raft node of the server and replied after it
+//! is committed by a majority and applied, so an acknowledged write is never
+//! lost while a majority of the nodes is alive. Every node applies the
+//! committed writes to its own engine in the order of the log; a node which
+//! is not the leader redirects the writes with NOTLEADER and serves the
+//! reads itself, which may be stale.
+//!
+//! The node is driven by one thread, which receives the ticks, the messages
+//! of the other nodes and the proposals through a channel. The messages are
+//! sent by RAFT requests over a connection to every peer.
+
+use super::clients::Client;
+use super::replication::apply_change;
+use super::Server;
+use crate::client::Connection;
+use crate::engine::KvdEngine;
+use crate::model::{KvdError, KvdErrorKind, KvdResult, Request};
+use crate::protocol::Reply;
+use crate::raft::log::RaftLog;
+use crate::raft::{Message, NodeId, RaftConfig, RaftNode, Role, Snapshot};
+use std::collections::BTreeMap;
+use std::str;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// the time of a tick of the raft node
+const TICK_INTERVAL: Duration = Duration::from_millis(50);
+/// how long a write waits to be committed
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(5);
+/// the read and write timeout of a connection to a peer
+const PEER_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub(crate) enum Event {
+    Tick,
+    Message(Message),
+    /// a write, which is replied to the sender after it is applied
+    Propose(Request, Sender<KvdResult<Reply>>),
+}
+
+/// The raft state of a server.
+pub struct RaftGroup {
+    config: RaftConfig,
+    events: Sender<Event>,
+    status: Mutex<RaftStatus>,
+    /// the node and its events, taken by the thread which drives it
+    driver: Mutex<Option<(RaftNode, Receiver<Event>)>>,
+}
+
+/// the state of the node shown in INFO, updated after every event
+#[derive(Default)]
+struct RaftStatus {
+    role: Option<Role>,
+    term: u64,
+    leader: Option<NodeId>,
+    commit: u64,
+    applied: u64,
+    last_index: u64,
+    snapshot_index: u64,
+}
+
+impl RaftGroup {
+    /// Open the raft log of the node, it is driven after `spawn_raft`.
+    pub fn open(config: RaftConfig) -> KvdResult<RaftGroup> {
+        let log = RaftLog::open(config.dir.clone())?;
+        let peers = config
+            .nodes
+            .iter()
+            .map(|(id, _)| *id)
+            .filter(|&id| id != config.id)
+            .collect();
+        let mut node = RaftNode::new(config.id, peers, log);
+        node.set_snapshot_entries(config.snapshot_entries);
+        let (events, receiver) = mpsc::channel();
+        let group = RaftGroup {
+            config,
+            events,
+            status: Mutex::new(RaftStatus::default()),
+            driver: Mutex::new(None),
+        };
+        group.update_status(&node);
+        *group.driver.lock().unwrap() = Some((node, receiver));
+        Ok(group)
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.config.id
+    }
+
+    /// the address of the node, None if it is not in the group
+    pub fn address(&self, id: NodeId) -> Option<&str> {
+        self.config
+            .nodes
+            .iter()
+            .find(|(node, _)| *node == id)
+            .map(|(_, addr)| addr.as_str())
+    }
+
+    pub fn role(&self) -> Option<Role> {
+        self.status.lock().unwrap().role
+    }
+
+    /// the leader of the current term, if known
+    pub fn leader(&self) -> Option<NodeId> {
+        self.status.lock().unwrap().leader
+    }
+
+    /// the index of the last entry applied to the engine
+    pub fn applied(&self) -> u64 {
+        self.status.lock().unwrap().applied
+    }
+
+    fn update_status(&self, node: &RaftNode) {
+        *self.status.lock().unwrap() = RaftStatus {
+            role: Some(node.role()),
+            term: node.term(),
+            leader: node.leader(),
+            commit: node.commit(),
+            applied: node.applied(),
+            last_index: node.log().last_index(),
+            snapshot_index: node.log().snapshot().index,
+        };
+    }
+
+    /// the reply to a write sent to a node which is not the leader
+    fn redirect(&self, leader: Option<NodeId>) -> KvdResult<Reply> {
+        match leader.and_then(|id| self.address(id)) {
+            Some(addr) => Ok(Reply::Error(format!("NOTLEADER {}", addr))),
+            None => Err(KvdError::from(KvdErrorKind::NoLeader)),
+        }
+    }
+
+    /// the fields shown in the replication section of INFO
+    pub(super) fn info(&self) -> Vec<(String, String)> {
+        let status = self.status.lock().unwrap();
+        let role = match status.role {
+            Some(Role::Leader) => "leader",
+            Some(Role::Candidate) => "candidate",
+            _ => "follower",
+        };
+        vec![
+            ("role".to_string(), role.to_string()),
+            ("raft_id".to_string(), self.config.id.to_string()),
+            ("raft_term".to_string(), status.term.to_string()),
+            (
+                "leader".to_string(),
+                status
+                    .leader
+                    .and_then(|id| self.address(id))
+                    .unwrap_or_default()
+                    .to_string(),
+            ),
+            ("commit_index".to_string(), status.commit.to_string()),
+            ("applied_index".to_string(), status.applied.to_string()),
+            ("last_index".to_string(), status.last_index.to_string()),
+            (
+                "snapshot_index".to_string(),
+                status.snapshot_index.to_string(),
+            ),
+        ]
+    }
+}
+
+impl<T: KvdEngine> Server<T> {
+    /// RAFT message, a message of another node of the group as json
+    pub(super) fn handle_raft(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        let raft = self
+            .raft
+            .as_ref()
+            .ok_or_else(|| KvdError::from(KvdErrorKind::RaftDisabled))?;
+        if request.len() != 2 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let msg: Message = serde_json::from_slice(&request[1])?;
+        // the driver only stops with the process
+        let _ = raft.events.send(Event::Message(msg));
+        Ok(Reply::ok())
+    }
+
+    /// Propose a write to the raft group and wait until it is applied.
+    pub(super) fn propose_request(
+        &self,
+        raft: &RaftGroup,
+        cmd: &[u8],
+        request: &[Vec<u8>],
+    ) -> KvdResult<Reply> {
+        let request = deterministic_request(cmd, request)?;
+        let (sender, receiver) = mpsc::channel();
+        let _ = raft.events.send(Event::Propose(request, sender));
+        receiver
+            .recv_timeout(COMMIT_TIMEOUT)
+            .unwrap_or_else(|_| Err(KvdError::from(KvdErrorKind::CommitTimeout)))
+    }
+
+    /// Drive the raft node in threads if raft is enabled, it can only be
+    /// spawned once. The keys are rebuilt from the raft log, so the engine
+    /// should be empty, like a MemoryEngine.
+    pub fn spawn_raft(&self) -> KvdResult<()> {
+        let raft = match self.raft.clone() {
+            Some(raft) => raft,
+            None => return Ok(()),
+        };
+        // the keys an engine kept from the last run would be applied twice
+        if self.engine().key_count() > 0 {
+            return Err(KvdError::from(KvdErrorKind::Config));
+        }
+        let (node, events) = match raft.driver.lock().unwrap().take() {
+            Some(driver) => driver,
+            None => return Ok(()),
+        };
+        let guard = self
+            .register_client("raft".to_string(), None)
+            .ok_or_else(|| KvdError::from(KvdErrorKind::MaxClients))?;
+
+        let mut peers = BTreeMap::new();
+        for (id, addr) in raft.config.nodes.iter().filter(|(id, _)| *id != raft.id()) {
+            let (sender, receiver) = mpsc::channel();
+            peers.insert(*id, sender);
+            let (server, addr) = (self.clone(), addr.clone());
+            thread::spawn(move || server.send_to_peer(&addr, receiver));
+        }
+        let ticker = raft.events.clone();
+        thread::spawn(move || {
+            while ticker.send(Event::Tick).is_ok() {
+                thread::sleep(TICK_INTERVAL);
+            }
+        });
+        let server = self.clone();
+        thread::spawn(move || server.drive(&raft, node, events, &peers, guard.client()));
+        Ok(())
+    }
+
+    /// Send the messages to a peer in order, a message is dropped if the
+    /// peer can not be reached, raft sends it again if it is still needed.
+    fn send_to_peer(&self, addr: &str, messages: Receiver<Message>) {
+        let mut conn: Option<Connection> = None;
+        for msg in messages.iter() {
+            let data = serde_json::to_vec(&msg).unwrap();
+            let result = match conn.as_mut() {
+                Some(conn) => conn.request(&[b"raft", &data]),
+                None => Connection::connect(addr).and_then(|new| {
+                    new.set_timeout(Some(PEER_TIMEOUT))?;
+                    conn.insert(new).request(&[b"raft", &data])
+                }),
+            };
+            match result {
+                Ok(Reply::Status(_)) => {}
+                Ok(reply) => {
+                    warn!(self.config.logger, "unexpected reply from a raft peer";
+                        "peer" => addr,
+                        "reply" => ?reply,
+                    );
+                }
+                Err(e) => {
+                    if conn.take().is_some() {
+                        debug!(self.config.logger, "raft peer disconnected";
+                            "peer" => addr,
+                            "error" => %e,
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handle the events of the node until the process exits.
+    fn drive(
+        &self,
+        raft: &RaftGroup,
+        mut node: RaftNode,
+        events: Receiver<Event>,
+        peers: &BTreeMap<NodeId, Sender<Message>>,
+        client: &Arc<Client>,
+    ) {
+        // the writes waiting to be applied, by their index, with the term they are proposed in
+        let mut waiters: BTreeMap<u64, (u64, Sender<KvdResult<Reply>>)> = BTreeMap::new();
+        info!(self.config.logger, "raft node started";
+            "id" => node.id(),
+            "term" => node.term(),
+            "last_index" => node.log().last_index(),
+        );
+        for event in events.iter() {
+            let role = node.role();
+            let result = match event {
+                Event::Tick => node.tick(),
+                Event::Message(msg) => node.step(msg),
+                Event::Propose(request, sender) => match node.propose(request) {
+                    Ok(Ok(index)) => {
+                        waiters.insert(index, (node.term(), sender));
+                        Ok(())
+                    }
+                    Ok(Err(leader)) => {
+                        let _ = sender.send(raft.redirect(leader));
+                        Ok(())
+                    }
+                    Err(e) => {
+                        let _ = sender.send(Err(KvdError::from(e.kind())));
+                        Err(e)
+                    }
+                },
+            };
+            let result = result.and_then(|_| self.advance(&mut node, peers, client, &mut waiters));
+            if let Err(e) = result {
+                error!(self.config.logger, "raft node error"; "error" => %e);
+            }
+            if node.role() != role {
+                info!(self.config.logger, "raft role changed";
+                    "role" => ?node.role(),
+                    "term" => node.term(),
+                    "leader" => ?node.leader(),
+                );
+            }
+            raft.update_status(&node);
+        }
+    }
+
+    /// Send the messages of the node, apply what is committed and take a
+    /// snapshot if there are enough entries applied after the last one.
+    fn advance(
+        &self,
+        node: &mut RaftNode,
+        peers: &BTreeMap<NodeId, Sender<Message>>,
+        client: &Arc<Client>,
+        waiters: &mut BTreeMap<u64, (u64, Sender<KvdResult<Reply>>)>,
+    ) -> KvdResult<()> {
+        for (to, msg) in node.take_messages() {
+            if let Some(peer) = peers.get(&to) {
+                let _ = peer.send(msg);
+            }
+        }
+        if let Some(snapshot) = node.take_snapshot() {
+            self.install_snapshot(snapshot)?;
+        }
+        for entry in node.take_committed() {
+            // the entry of a new leader only commits the entries before it
+            let reply = if entry.request.is_empty() {
+                Ok(Reply::ok())
+            } else {
+                self.execute_request(client, &entry.request)
+            };
+            if let Some((term, sender)) = waiters.remove(&entry.index) {
+                let reply = if term == entry.term {
+                    reply
+                } else {
+                    Err(KvdError::from(KvdErrorKind::WriteDropped))
+                };
+                let _ = sender.send(reply);
+            }
+        }
+        if node.should_snapshot() {
+            let (_, data) = self.engine().snapshot()?;
+            node.compact(data)?;
+        }
+        Ok(())
+    }
+
+    /// replace all the keys with the snapshot
+    fn install_snapshot(&self, snapshot: Snapshot) -> KvdResult<()> {
+        let mut engine = self.engine();
+        let keys = engine.keys();
+        engine.mdel(keys)?;
+        let key_count = snapshot.data.len();
+        for cmd in snapshot.data {
+            apply_change(&mut *engine, cmd)?;
+        }
+        info!(self.config.logger, "raft snapshot installed";
+            "index" => snapshot.index,
+            "keys" => key_count,
+        );
+        Ok(())
+    }
+}
+
+/// Rewrite the write so that it has the same result on every node applying
+/// it: a time to live becomes the time it ends. A command with a random or
+/// blocking result can not be proposed.
+fn deterministic_request(cmd: &[u8], request: &[Vec<u8>]) -> KvdResult<Request> {
+    match cmd {
+        b"spop" | b"blpop" | b"brpop" => Err(KvdError::from(KvdErrorKind::NondeterministicCommand)),
+        b"set" => {
+            let mut rewritten = request.to_vec();
+            for i in 3..rewritten.len().saturating_sub(1) {
+                let unit_millis = match rewritten[i].to_ascii_lowercase().as_slice() {
+                    b"ex" => 1000,
+                    b"px" => 1,
+                    _ => continue,
+                };
+                let expire_at = super::parse_expire_at(&rewritten[i + 1], unit_millis)?;
+                rewritten[i] = b"pxat".to_vec();
+                rewritten[i + 1] = expire_at.to_string().into_bytes();
+            }
+            Ok(rewritten)
+        }
+        _ => Ok(request.to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::request;
+    use super::*;
+    use crate::engine::memory::MemoryEngine;
+    use crate::engine::now_millis;
+    use crate::raft::DEFAULT_SNAPSHOT_ENTRIES;
+    use crate::server::ServerConfig;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::path::PathBuf;
+    use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+    fn request_of(args: &[&str]) -> Request {
+        args.iter().map(|arg| arg.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_deterministic_request() {
+        let rewritten =
+            deterministic_request(b"set", &request_of(&["set", "a", "1", "EX", "10"])).unwrap();
+        assert_eq!(b"pxat".to_vec(), rewritten[3]);
+        let expire_at: u64 = str::from_utf8(&rewritten[4]).unwrap().parse().unwrap();
+        assert!(expire_at > now_millis() + 9000 && expire_at <= now_millis() + 10000);
+        let request = request_of(&["set", "a", "1", "nx", "get"]);
+        assert_eq!(request, deterministic_request(b"set", &request).unwrap());
+        assert_eq!(
+            KvdErrorKind::NondeterministicCommand,
+            deterministic_request(b"spop", &request_of(&["spop", "s"]))
+                .unwrap_err()
+                .kind()
+        );
+    }
+
+    /// wait until the reply of the server is the expected one
+    fn wait_for(conn: &mut TcpStream, data: &[u8], expect: &[u8]) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while request(conn, data) != expect {
+            assert!(Instant::now() < deadline, "timed out waiting for the reply");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_raft_group() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let listeners: Vec<TcpListener> = (0..3)
+            .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let addrs: Vec<SocketAddr> = listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+        let nodes: Vec<(NodeId, String)> = addrs
+            .iter()
+            .enumerate()
+            .map(|(i, addr)| (i as NodeId + 1, addr.to_string()))
+            .collect();
+        let servers: Vec<Server<MemoryEngine>> = listeners
+            .into_iter()
+            .zip(nodes.iter())
+            .map(|(listener, (id, _))| {
+                let config = ServerConfig {
+                    raft: Some(RaftConfig {
+                        id: *id,
+                        nodes: nodes.clone(),
+                        dir: PathBuf::from(format!("/tmp/kvd_store/{}/{}", nanos, id)),
+                        snapshot_entries: DEFAULT_SNAPSHOT_ENTRIES,
+                    }),
+                    ..ServerConfig::default()
+                };
+                let server = Server::with_config(MemoryEngine::new(), config).unwrap();
+                server.spawn_raft().unwrap();
+                let serving = server.clone();
+                thread::spawn(move || serving.serve_listener(listener));
+                server
+            })
+            .collect();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let leader = loop {
+            let leaders: Vec<usize> = (0..3)
+                .filter(|&i| servers[i].raft().unwrap().role() == Some(Role::Leader))
+                .collect();
+            if let [leader] = leaders.as_slice() {
+                break *leader;
+            }
+            assert!(Instant::now() < deadline, "no leader is elected");
+            thread::sleep(Duration::from_millis(10));
+        };
+        let follower = (leader + 1) % 3;
+
+        // an engine with keys is rejected, they would be applied twice
+        let mut engine = MemoryEngine::new();
+        engine.set(Vec::from("stale"), Vec::from("1")).unwrap();
+        let config = ServerConfig {
+            raft: Some(RaftConfig {
+                id: 1,
+                nodes: nodes.clone(),
+                dir: PathBuf::from(format!("/tmp/kvd_store/{}/stale", nanos)),
+                snapshot_entries: DEFAULT_SNAPSHOT_ENTRIES,
+            }),
+            ..ServerConfig::default()
+        };
+        let server = Server::with_config(engine, config).unwrap();
+        let result = server.spawn_raft();
+        assert_eq!(KvdErrorKind::Config, result.unwrap_err().kind());
+
+        // a write is replied after it is applied
+        let mut client = TcpStream::connect(addrs[leader]).unwrap();
+        assert_eq!(b"+OK\r\n".to_vec(), request(&mut client, b"set a 1\r\n"));
+        assert_eq!(b":2\r\n".to_vec(), request(&mut client, b"incrby n 2\r\n"));
+        assert_eq!(b"$1\r\n1\r\n".to_vec(), request(&mut client, b"get a\r\n"));
+        assert_eq!(
+            b"-ERR the command can not be replicated by raft\r\n".to_vec(),
+            request(&mut client, b"spop s\r\n")
+        );
+
+        // a follower applies it too, and redirects the writes to the leader
+        let mut reader = TcpStream::connect(addrs[follower]).unwrap();
+        wait_for(&mut reader, b"get n\r\n", b"$1\r\n2\r\n");
+        wait_for(
+            &mut reader,
+            b"set a 2\r\n",
+            format!("-NOTLEADER {}\r\n", addrs[leader]).as_bytes(),
+        );
+        let info = String::from_utf8(request(&mut reader, b"info replication\r\n")).unwrap();
+        assert!(info.contains("role:follower\r\n"));
+        assert!(info.contains(&format!("leader:{}\r\n", addrs[leader])));
+        assert_eq!(
+            b"-ERR raft is not enabled\r\n".to_vec(),
+            request(
+                &mut TcpStream::connect(start_plain_server()).unwrap(),
+                b"raft {}\r\n"
+            )
+        );
+    }
+
+    fn start_plain_server() -> SocketAddr {
+        super::super::tests::start_server(ServerConfig::default())
+    }
+}
@@ -0,0 +1,378 @@
+//! Leader-follower replication.
+//!
+//! A follower loads a snapshot of its leader by SYNC, then applies the
+//! changes streamed by CHANGES after the sequence number of the snapshot.
+//! When the link is broken it reconnects and resumes from the last change
+//! applied, or syncs again if the changes after it are compacted away on
+//! the leader. The clients of a follower can only read, and the requests
+//! with keys are rejected with LOADING while a snapshot is loaded.
+
+use super::changes::{change_reply, parse_change};
+use super::Server;
+use crate::client::Connection;
+use crate::engine::bitcask::Command;
+use crate::engine::value::ValueType;
+use crate::engine::{KvdEngine, SetOptions};
+use crate::model::{KvdError, KvdErrorKind, KvdResult};
+use crate::protocol::Reply;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// how long a follower waits before it reconnects to the leader
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+/// the max number of keys removed or loaded at once while syncing, the
+/// engine is unlocked between them
+const SYNC_BATCH_SIZE: usize = 256;
+
+/// The replication state of a follower.
+pub struct Replica {
+    /// the address of the leader, host:port
+    leader: String,
+    /// the sequence number of the last change of the leader applied, None
+    /// before the first sync
+    offset: Mutex<Option<u64>>,
+    link_up: AtomicBool,
+    /// whether the keys are being replaced by a snapshot, or a load failed
+    /// half way and the next sync replaces them again
+    loading: AtomicBool,
+    full_syncs: AtomicU64,
+}
+
+impl Replica {
+    pub fn new(leader: String) -> Replica {
+        Replica {
+            leader,
+            offset: Mutex::new(None),
+            link_up: AtomicBool::new(false),
+            loading: AtomicBool::new(false),
+            full_syncs: AtomicU64::new(0),
+        }
+    }
+
+    pub fn leader(&self) -> &str {
+        &self.leader
+    }
+
+    pub fn offset(&self) -> Option<u64> {
+        *self.offset.lock().unwrap()
+    }
+
+    fn set_offset(&self, offset: Option<u64>) {
+        *self.offset.lock().unwrap() = offset;
+    }
+
+    /// whether the changes of the leader are being streamed
+    pub fn is_link_up(&self) -> bool {
+        self.link_up.load(Ordering::Relaxed)
+    }
+
+    /// whether the keys are not served since a snapshot is being loaded
+    pub fn is_loading(&self) -> bool {
+        self.loading.load(Ordering::Relaxed)
+    }
+
+    /// the number of snapshots loaded from the leader
+    pub fn full_syncs(&self) -> u64 {
+        self.full_syncs.load(Ordering::Relaxed)
+    }
+
+    /// the fields shown in the replication section of INFO
+    pub(super) fn info(&self) -> Vec<(String, String)> {
+        vec![
+            ("role".to_string(), "follower".to_string()),
+            ("leader".to_string(), self.leader.clone()),
+            (
+                "leader_link_status".to_string(),
+                if self.is_link_up() { "up" } else { "down" }.to_string(),
+            ),
+            (
+                "replica_offset".to_string(),
+                self.offset().map_or(-1, |seq| seq as i64).to_string(),
+            ),
+            ("loading".to_string(), (self.is_loading() as u8).to_string()),
+            ("full_syncs".to_string(), self.full_syncs().to_string()),
+        ]
+    }
+}
+
+impl<T: KvdEngine> Server<T> {
+    /// SYNC, reply the sequence number of the snapshot and its changes
+    pub(super) fn handle_sync(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        if request.len() != 1 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        // the copy is taken at once to match the sequence number, and it is
+        // encoded and written after the engine is unlocked
+        let (seq, cmds) = {
+            let mut engine = self.engine();
+            engine.snapshot()?
+        };
+        Ok(Reply::Array(vec![
+            Reply::Integer(seq as i64),
+            Reply::Array(cmds.into_iter().map(change_reply).collect()),
+        ]))
+    }
+
+    /// Replicate the leader in a thread if the server is a follower.
+    pub fn spawn_replication(&self) {
+        if let Some(replica) = self.replica.clone() {
+            let server = self.clone();
+            thread::spawn(move || server.replicate(&replica));
+        }
+    }
+
+    /// replicate the leader forever, reconnect after the link is broken
+    fn replicate(&self, replica: &Replica) {
+        loop {
+            if let Err(e) = self.follow(replica) {
+                warn!(self.config.logger, "replication link broken";
+                    "leader" => replica.leader(),
+                    "error" => %e,
+                );
+            }
+            replica.link_up.store(false, Ordering::Relaxed);
+            thread::sleep(RECONNECT_INTERVAL);
+        }
+    }
+
+    /// Connect to the leader and apply its changes until the link is broken.
+    fn follow(&self, replica: &Replica) -> KvdResult<()> {
+        let mut conn = Connection::connect(replica.leader())?;
+        let seq = loop {
+            let seq = match replica.offset() {
+                Some(seq) => seq,
+                None => self.full_sync(replica, &mut conn)?,
+            };
+            match conn.request(&[b"changes", seq.to_string().as_bytes()])? {
+                Reply::Status(_) => break seq,
+                // the changes after the offset are compacted away
+                Reply::Error(ref e) if e.starts_with("COMPACTED") => replica.set_offset(None),
+                reply => return Err(self.unexpected_reply(reply)),
+            }
+        };
+        replica.link_up.store(true, Ordering::Relaxed);
+        info!(self.config.logger, "replicating the leader";
+            "leader" => replica.leader(),
+            "offset" => seq,
+        );
+        loop {
+            let cmd = match conn.read_reply()? {
+                // the follower lags behind a compaction, it syncs again after reconnecting
+                Reply::Error(ref e) if e.starts_with("COMPACTED") => {
+                    replica.set_offset(None);
+                    return Ok(());
+                }
+                reply => parse_change(reply)?,
+            };
+            let seq = cmd.seq();
+            if let Err(e) = apply_change(&mut *self.engine(), cmd) {
+                // the data diverged from the leader, it is replaced by the next sync
+                replica.set_offset(None);
+                return Err(e);
+            }
+            replica.set_offset(Some(seq));
+        }
+    }
+
+    /// Replace all the keys with the snapshot of the leader, return the
+    /// sequence number of the snapshot.
+    fn full_sync(&self, replica: &Replica, conn: &mut Connection) -> KvdResult<u64> {
+        let fields = match conn.request(&[b"sync"])? {
+            Reply::Array(fields) => fields,
+            reply => return Err(self.unexpected_reply(reply)),
+        };
+        let (seq, changes) = match <[Reply; 2]>::try_from(fields) {
+            Ok([Reply::Integer(seq), Reply::Array(changes)]) if seq >= 0 => (seq as u64, changes),
+            _ => return Err(KvdError::from(KvdErrorKind::InvalidReply)),
+        };
+        let cmds = changes
+            .into_iter()
+            .map(parse_change)
+            .collect::<KvdResult<Vec<Command>>>()?;
+        let key_count = cmds.len();
+        // The engine is locked for a batch at a time, so the snapshot is not
+        // loaded all under the lock. The clients never see the data half
+        // loaded, since their requests with keys are rejected meanwhile.
+        replica.loading.store(true, Ordering::Relaxed);
+        let keys = self.engine().keys();
+        for keys in keys.chunks(SYNC_BATCH_SIZE) {
+            self.engine().mdel(keys.to_vec())?;
+        }
+        let mut cmds = cmds.into_iter().peekable();
+        while cmds.peek().is_some() {
+            let mut engine = self.engine();
+            for cmd in cmds.by_ref().take(SYNC_BATCH_SIZE) {
+                apply_change(&mut *engine, cmd)?;
+            }
+        }
+        replica.loading.store(false, Ordering::Relaxed);
+        replica.set_offset(Some(seq));
+        replica.full_syncs.fetch_add(1, Ordering::Relaxed);
+        info!(self.config.logger, "synced with the leader";
+            "leader" => replica.leader(),
+            "offset" => seq,
+            "keys" => key_count,
+        );
+        Ok(seq)
+    }
+
+    fn unexpected_reply(&self, reply: Reply) -> KvdError {
+        warn!(self.config.logger, "unexpected reply from the leader"; "reply" => ?reply);
+        KvdError::from(KvdErrorKind::InvalidReply)
+    }
+}
+
+/// Apply a change of the leader, it makes the key the same as on the leader.
+pub(super) fn apply_change<T: KvdEngine>(engine: &mut T, cmd: Command) -> KvdResult<()> {
+    match cmd {
+        Command::Set {
+            key,
+            value,
+            expire_at,
+            ..
+        } => {
+            let options = SetOptions {
+                expire_at,
+                ..SetOptions::default()
+            };
+            engine.set_with(key, value, options)?;
+        }
+        Command::Del { key, .. } => {
+            engine.mdel(vec![key])?;
+        }
+        Command::Change { key, change, .. } => {
+            // a string replaced by a collection is expired on the leader
+            // already, it may not be on the follower yet
+            if engine.key_type(&key)? == Some(ValueType::String) {
+                engine.mdel(vec![key.clone()])?;
+            }
+            engine.change(key, change)?;
+        }
+        Command::Batch { .. } | Command::Compaction { .. } => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::request;
+    use super::*;
+    use crate::engine::bitcask::BitcaskEngine;
+    use crate::engine::memory::MemoryEngine;
+    use crate::server::ServerConfig;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::path::PathBuf;
+    use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+    fn serve<T: KvdEngine>(server: &Server<T>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = server.clone();
+        thread::spawn(move || server.serve_listener(listener));
+        addr
+    }
+
+    /// request until the reply is the expected one
+    fn wait_for(conn: &mut TcpStream, data: &[u8], expect: &[u8]) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while request(conn, data) != expect {
+            assert!(
+                Instant::now() < deadline,
+                "timed out waiting for the change"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// kill the connection which streams the changes to the follower
+    fn kill_follower_link(client: &mut TcpStream) {
+        let list = String::from_utf8(request(client, b"client list\r\n")).unwrap();
+        let addr = list
+            .lines()
+            .find(|line| line.contains("cmd=changes"))
+            .and_then(|line| line.split(' ').find_map(|f| f.strip_prefix("addr=")))
+            .unwrap()
+            .to_string();
+        let kill = format!("client kill {}\r\n", addr);
+        assert_eq!(b"+OK\r\n".to_vec(), request(client, kill.as_bytes()));
+    }
+
+    #[test]
+    fn test_replication() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let path = PathBuf::from(format!("/tmp/kvd_store/{}", nanos));
+        let engine = BitcaskEngine::open(path).unwrap();
+        let leader = Server::with_config(engine, ServerConfig::default()).unwrap();
+        let leader_addr = serve(&leader);
+        let mut client = TcpStream::connect(leader_addr).unwrap();
+        assert_eq!(b"+OK\r\n".to_vec(), request(&mut client, b"set a 1\r\n"));
+        assert_eq!(b":1\r\n".to_vec(), request(&mut client, b"sadd s m\r\n"));
+
+        // the keys of the follower are replaced by the snapshot of the leader
+        let mut engine = MemoryEngine::new();
+        engine.set(Vec::from("stale"), Vec::from("1")).unwrap();
+        let config = ServerConfig {
+            replica_of: Some(leader_addr.to_string()),
+            ..ServerConfig::default()
+        };
+        let follower = Server::with_config(engine, config).unwrap();
+        let mut reader = TcpStream::connect(serve(&follower)).unwrap();
+        follower.spawn_replication();
+        wait_for(&mut reader, b"get a\r\n", b"$1\r\n1\r\n");
+        assert_eq!(
+            b":1\r\n".to_vec(),
+            request(&mut reader, b"sismember s m\r\n")
+        );
+        assert_eq!(b"$-1\r\n".to_vec(), request(&mut reader, b"get stale\r\n"));
+        assert_eq!(
+            b"-READONLY You can't write against a read only follower.\r\n".to_vec(),
+            request(&mut reader, b"set a 2\r\n")
+        );
+        let info = String::from_utf8(request(&mut reader, b"info replication\r\n")).unwrap();
+        assert!(info.contains("role:follower\r\n"));
+        assert!(info.contains("loading:0\r\n"));
+        // only the requests without keys are served while loading
+        let replica = follower.replica().unwrap();
+        replica.loading.store(true, Ordering::Relaxed);
+        assert_eq!(
+            b"-LOADING kvd is loading the dataset in memory\r\n".to_vec(),
+            request(&mut reader, b"get a\r\n")
+        );
+        let info = String::from_utf8(request(&mut reader, b"info replication\r\n")).unwrap();
+        assert!(info.contains("loading:1\r\n"));
+        replica.loading.store(false, Ordering::Relaxed);
+
+        // the changes are streamed after the snapshot
+        assert_eq!(b":1\r\n".to_vec(), request(&mut client, b"del a\r\n"));
+        assert_eq!(b":1\r\n".to_vec(), request(&mut client, b"hset h f v\r\n"));
+        wait_for(&mut reader, b"hget h f\r\n", b"$1\r\nv\r\n");
+        assert_eq!(b"$-1\r\n".to_vec(), request(&mut reader, b"get a\r\n"));
+        assert!(replica.is_link_up());
+        assert_eq!(Some(4), replica.offset());
+
+        // the follower resumes from its offset after the link is broken
+        kill_follower_link(&mut client);
+        assert_eq!(b"+OK\r\n".to_vec(), request(&mut client, b"set b 2\r\n"));
+        wait_for(&mut reader, b"get b\r\n", b"$1\r\n2\r\n");
+        assert_eq!(Some(5), replica.offset());
+        assert_eq!(1, replica.full_syncs());
+
+        // or syncs again if the changes after its offset are compacted away
+        kill_follower_link(&mut client);
+        assert_eq!(b"+OK\r\n".to_vec(), request(&mut client, b"set c 3\r\n"));
+        leader.engine().compact().unwrap();
+        wait_for(&mut reader, b"get c\r\n", b"$1\r\n3\r\n");
+        assert_eq!(Some(6), replica.offset());
+        assert_eq!(2, replica.full_syncs());
+        assert_eq!(
+            b"$1\r\nv\r\n".to_vec(),
+            request(&mut reader, b"hget h f\r\n")
+        );
+    }
+}
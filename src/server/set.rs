@@ -0,0 +1,351 @@
+use super::{key_of, Server};
+use crate::engine::parse_number;
+use crate::engine::value::{Change, Collection, Set};
+use crate::engine::KvdEngine;
+use crate::model::{KvdError, KvdErrorKind, KvdResult};
+use crate::protocol::Reply;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// the most members a negative SRANDMEMBER count may repeat
+const MAX_RANDOM_MEMBERS: u64 = 1024 * 1024;
+
+/// How SINTER, SUNION and SDIFF combine their sets.
+#[derive(Clone, Copy)]
+pub(super) enum SetOp {
+    Inter,
+    Union,
+    Diff,
+}
+
+impl<T: KvdEngine> Server<T> {
+    /// SADD key member [member ...], reply the number of members added
+    pub(super) fn handle_sadd(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        let key = key_of(request, 3)?;
+        let mut engine = self.engine();
+        let added = match read_set(&mut *engine, key)? {
+            Some(set) => distinct(&request[2..], |member| !set.contains(member)),
+            None => distinct(&request[2..], |_| true),
+        };
+        if !added.is_empty() {
+            engine.change(key.clone(), Change::SAdd(added.clone()))?;
+        }
+        Ok(Reply::Integer(added.len() as i64))
+    }
+
+    /// SREM key member [member ...], reply the number of members removed
+    pub(super) fn handle_srem(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        let key = key_of(request, 3)?;
+        let mut engine = self.engine();
+        let removed = match read_set(&mut *engine, key)? {
+            Some(set) => distinct(&request[2..], |member| set.contains(member)),
+            None => return Ok(Reply::Integer(0)),
+        };
+        if !removed.is_empty() {
+            engine.change(key.clone(), Change::SRem(removed.clone()))?;
+        }
+        Ok(Reply::Integer(removed.len() as i64))
+    }
+
+    /// SISMEMBER key member
+    pub(super) fn handle_sismember(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        if request.len() != 3 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let mut engine = self.engine();
+        let found =
+            read_set(&mut *engine, &request[1])?.is_some_and(|set| set.contains(&request[2]));
+        Ok(Reply::Integer(found as i64))
+    }
+
+    /// SMEMBERS key
+    pub(super) fn handle_smembers(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        if request.len() != 2 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let mut engine = self.engine();
+        let members = read_set(&mut *engine, &request[1])?
+            .map(|set| set.iter().cloned().collect())
+            .unwrap_or_default();
+        Ok(members_reply(members))
+    }
+
+    /// SCARD key
+    pub(super) fn handle_scard(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        if request.len() != 2 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let mut engine = self.engine();
+        let len = read_set(&mut *engine, &request[1])?.map_or(0, Set::len);
+        Ok(Reply::Integer(len as i64))
+    }
+
+    /// SPOP key [count], remove random members, an array is replied if the
+    /// count is given
+    pub(super) fn handle_spop(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        let key = key_of(request, 2)?;
+        let count = match request.len() {
+            2 => None,
+            3 => Some(
+                parse_number::<usize>(&request[2])
+                    .ok_or_else(|| KvdError::from(KvdErrorKind::NotAnInteger))?,
+            ),
+            _ => return Err(KvdError::from(KvdErrorKind::InvalidRequest)),
+        };
+        let mut engine = self.engine();
+        let members = match read_set(&mut *engine, key)? {
+            Some(set) => sample(set, count.unwrap_or(1)),
+            None => {
+                return Ok(if count.is_some() {
+                    Reply::Array(Vec::new())
+                } else {
+                    Reply::Nil
+                })
+            }
+        };
+        if !members.is_empty() {
+            // the members popped are written, so the wal replays the same pop
+            engine.change(key.clone(), Change::SRem(members.clone()))?;
+        }
+        Ok(match count {
+            Some(_) => members_reply(members),
+            None => members.into_iter().next().map_or(Reply::Nil, Reply::Bulk),
+        })
+    }
+
+    /// SRANDMEMBER key [count], a negative count may return the same member
+    /// more than once
+    pub(super) fn handle_srandmember(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        let key = key_of(request, 2)?;
+        let count = match request.len() {
+            2 => None,
+            3 => Some(
+                parse_number::<i64>(&request[2])
+                    .ok_or_else(|| KvdError::from(KvdErrorKind::NotAnInteger))?,
+            ),
+            _ => return Err(KvdError::from(KvdErrorKind::InvalidRequest)),
+        };
+        let mut engine = self.engine();
+        let set = read_set(&mut *engine, key)?;
+        Ok(match (set, count) {
+            (None, None) => Reply::Nil,
+            (None, Some(_)) => Reply::Array(Vec::new()),
+            (Some(set), None) => sample(set, 1)
+                .into_iter()
+                .next()
+                .map_or(Reply::Nil, Reply::Bulk),
+            (Some(set), Some(count)) if count >= 0 => members_reply(sample(set, count as usize)),
+            (Some(_), Some(count)) if count.unsigned_abs() > MAX_RANDOM_MEMBERS => {
+                return Err(KvdError::from(KvdErrorKind::NotAnInteger));
+            }
+            (Some(set), Some(count)) => {
+                let members: Vec<_> = set.iter().collect();
+                let picked = (0..count.unsigned_abs())
+                    .map(|_| members[random_index(members.len())].clone())
+                    .collect();
+                members_reply(picked)
+            }
+        })
+    }
+
+    /// SINTER key [key ...] | SUNION key [key ...] | SDIFF key [key ...]
+    pub(super) fn handle_set_op(&self, request: &[Vec<u8>], op: SetOp) -> KvdResult<Reply> {
+        key_of(request, 2)?;
+        let mut engine = self.engine();
+        let result = combine(&mut *engine, &request[1..], op)?;
+        Ok(members_reply(result.into_iter().collect()))
+    }
+
+    /// SINTERSTORE destination key [key ...] and the others, the destination
+    /// is replaced whatever it holds, reply the number of members stored
+    pub(super) fn handle_set_op_store(&self, request: &[Vec<u8>], op: SetOp) -> KvdResult<Reply> {
+        let destination = key_of(request, 3)?;
+        let mut engine = self.engine();
+        let result = combine(&mut *engine, &request[2..], op)?;
+        let len = result.len();
+        // one restore replaces the destination, an empty set removes it
+        if len > 0 || engine.exists(destination.clone())? {
+            let change = Change::Restore(Collection::Set(result));
+            engine.change(destination.clone(), change)?;
+        }
+        Ok(Reply::Integer(len as i64))
+    }
+}
+
+/// the set of the key, WrongType if the key holds another type
+fn read_set<'a, T: KvdEngine>(engine: &'a mut T, key: &[u8]) -> KvdResult<Option<&'a Set>> {
+    engine.collection(key)?.map(Collection::as_set).transpose()
+}
+
+/// the members which pass the filter, each one once
+fn distinct<F: Fn(&Vec<u8>) -> bool>(members: &[Vec<u8>], filter: F) -> Vec<Vec<u8>> {
+    members
+        .iter()
+        .filter(|member| filter(member))
+        .cloned()
+        .collect::<Set>()
+        .into_iter()
+        .collect()
+}
+
+/// combine the sets of the keys, a missing key is an empty set
+fn combine<T: KvdEngine>(engine: &mut T, keys: &[Vec<u8>], op: SetOp) -> KvdResult<Set> {
+    let mut result: Option<Set> = None;
+    for key in keys {
+        let set = read_set(engine, key)?;
+        result = Some(match (result, op) {
+            (None, _) => set.cloned().unwrap_or_default(),
+            (Some(result), SetOp::Inter) => result
+                .into_iter()
+                .filter(|member| set.is_some_and(|set| set.contains(member)))
+                .collect(),
+            (Some(mut result), SetOp::Union) => {
+                result.extend(set.into_iter().flatten().cloned());
+                result
+            }
+            (Some(result), SetOp::Diff) => result
+                .into_iter()
+                .filter(|member| !set.is_some_and(|set| set.contains(member)))
+                .collect(),
+        });
+    }
+    Ok(result.unwrap_or_default())
+}
+
+/// at most count distinct members picked at random
+fn sample(set: &Set, count: usize) -> Vec<Vec<u8>> {
+    let mut members: Vec<_> = set.iter().collect();
+    let count = count.min(members.len());
+    // a partial Fisher-Yates shuffle of the first count members
+    for i in 0..count {
+        let j = i + random_index(members.len() - i);
+        members.swap(i, j);
+    }
+    members.into_iter().take(count).cloned().collect()
+}
+
+/// a random index below len, which should not be 0
+fn random_index(len: usize) -> usize {
+    // every RandomState is seeded differently, which is random enough to
+    // pick members without a dependency on a random crate
+    let random = RandomState::new().build_hasher().finish();
+    (random % len as u64) as usize
+}
+
+fn members_reply(members: Vec<Vec<u8>>) -> Reply {
+    Reply::Array(members.into_iter().map(Reply::Bulk).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::bitcask::BitcaskEngine;
+    use crate::engine::memory::MemoryEngine;
+    use crate::server::tests::handle_input;
+    use crate::server::Server;
+    use std::path::PathBuf;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn test_set_commands() {
+        let server = Server::new(MemoryEngine::new(), 0).unwrap();
+        let output = handle_input(
+            &server,
+            b"sadd s c a b a\r\nsadd s b d\r\nscard s\r\nsismember s a\r\nsismember s x\r\nsmembers s\r\nsrem s a x a\r\nsmembers x\r\n",
+        );
+        assert_eq!(
+            ":3\r\n:1\r\n:4\r\n:1\r\n:0\r\n*4\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n$1\r\nd\r\n:1\r\n*0\r\n",
+            output
+        );
+
+        handle_input(&server, b"sadd t c d e\r\n");
+        let output = handle_input(
+            &server,
+            b"sinter s t\r\nsunion s t x\r\nsdiff s t\r\nsinter s x\r\nsdiffstore d t s\r\nsmembers d\r\nsinterstore d s x\r\nexists d\r\n",
+        );
+        assert_eq!(
+            "*2\r\n$1\r\nc\r\n$1\r\nd\r\n*4\r\n$1\r\nb\r\n$1\r\nc\r\n$1\r\nd\r\n$1\r\ne\r\n\
+             *1\r\n$1\r\nb\r\n*0\r\n:1\r\n*1\r\n$1\r\ne\r\n:0\r\n:0\r\n",
+            output
+        );
+
+        // the destination is replaced whatever it holds
+        let output = handle_input(&server, b"set d 1\r\nsunionstore d s\r\ntype d\r\n");
+        assert_eq!("+OK\r\n:3\r\n+set\r\n", output);
+    }
+
+    #[test]
+    fn test_store_bitcask() {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let path = PathBuf::from(format!("/tmp/kvd_store/{}", time.as_nanos()));
+        let server = Server::new(BitcaskEngine::open(path.clone()).unwrap(), 0).unwrap();
+        // a missing, a string and a set destination are all replaced
+        let output = handle_input(
+            &server,
+            b"sadd a x y\r\nsadd b y z\r\nsunionstore d a b\r\nset s 1\r\nsinterstore s a b\r\nsdiffstore d a b\r\nsinterstore e a c\r\n",
+        );
+        assert_eq!(":2\r\n:2\r\n:3\r\n+OK\r\n:1\r\n:1\r\n:0\r\n", output);
+        drop(server);
+
+        let server = Server::new(BitcaskEngine::open(path).unwrap(), 0).unwrap();
+        let output = handle_input(
+            &server,
+            b"smembers d\r\ntype s\r\nsmembers s\r\nexists e\r\n",
+        );
+        assert_eq!("*1\r\n$1\r\nx\r\n+set\r\n*1\r\n$1\r\ny\r\n:0\r\n", output);
+    }
+
+    #[test]
+    fn test_random_members() {
+        let server = Server::new(MemoryEngine::new(), 0).unwrap();
+        handle_input(&server, b"sadd s a b c\r\n");
+        let output = handle_input(
+            &server,
+            b"srandmember s 5\r\nsrandmember s -5\r\nscard s\r\n",
+        );
+        assert!(output.starts_with("*3\r\n"), "{}", output);
+        assert!(output.contains("\r\n*5\r\n"), "{}", output);
+        assert!(output.ends_with(":3\r\n"), "{}", output);
+        // a huge negative count is not allocated
+        let output = handle_input(&server, b"srandmember s -9223372036854775808\r\n");
+        assert_eq!("-ERR value is not an integer or out of range\r\n", output);
+
+        let output = handle_input(
+            &server,
+            b"spop s 2\r\nscard s\r\nspop s\r\nspop s\r\nspop s 1\r\n",
+        );
+        assert!(output.starts_with("*2\r\n"), "{}", output);
+        assert!(output.contains("\r\n:1\r\n$1\r\n"), "{}", output);
+        assert!(output.ends_with("$-1\r\n*0\r\n"), "{}", output);
+        let output = handle_input(&server, b"exists s\r\nsrandmember s\r\nspop s -1\r\n");
+        assert_eq!(
+            ":0\r\n$-1\r\n-ERR value is not an integer or out of range\r\n",
+            output
+        );
+    }
+
+    #[test]
+    fn test_type_check() {
+        let server = Server::new(MemoryEngine::new(), 0).unwrap();
+        let output = handle_input(
+            &server,
+            b"set str 1\r\nsadd set a\r\nrpush list a\r\nhset hash a 1\r\ntype str\r\ntype set\r\ntype list\r\ntype hash\r\ntype none\r\n",
+        );
+        assert_eq!(
+            "+OK\r\n:1\r\n:1\r\n:1\r\n+string\r\n+set\r\n+list\r\n+hash\r\n+none\r\n",
+            output
+        );
+
+        let output = handle_input(
+            &server,
+            b"sadd str a\r\nsinter set list\r\nsunionstore set hash\r\nlpush set a\r\nblpop list set 1\r\nhget list a\r\n",
+        );
+        let wrong_type = "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n";
+        assert_eq!(wrong_type.repeat(6), output);
+        // nothing is popped since set is checked before the command runs
+        assert_eq!(":1\r\n", handle_input(&server, b"llen list\r\n"));
+
+        // the arguments are checked before the type
+        let output = handle_input(&server, b"sadd list\r\nhget str\r\nsinterstore set\r\n");
+        assert_eq!("-ERR invalid request\r\n".repeat(3), output);
+    }
+}
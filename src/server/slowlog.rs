@@ -0,0 +1,200 @@
+use crate::protocol::Reply;
+use slog::Logger;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// the arguments beyond are replaced by a note of how many are left out
+const MAX_ARGS: usize = 32;
+/// the bytes of an argument beyond are replaced by a note of how many are left out
+const MAX_ARG_LEN: usize = 128;
+
+/// The most recent commands executed slower than a threshold.
+pub struct SlowLog {
+    entries: Mutex<VecDeque<SlowLogEntry>>,
+    next_id: AtomicU64,
+    /// None means nothing is logged
+    threshold: Option<Duration>,
+    max_len: usize,
+}
+
+pub struct SlowLogEntry {
+    pub id: u64,
+    /// the unix time in seconds when the command is logged
+    pub timestamp: u64,
+    pub duration: Duration,
+    /// the command and its arguments, truncated
+    pub args: Vec<Vec<u8>>,
+    pub addr: String,
+    pub name: String,
+}
+
+impl SlowLog {
+    pub fn new(threshold: Option<Duration>, max_len: usize) -> SlowLog {
+        SlowLog {
+            entries: Mutex::new(VecDeque::with_capacity(max_len.min(1024))),
+            next_id: AtomicU64::new(0),
+            threshold,
+            max_len,
+        }
+    }
+
+    pub fn is_slow(&self, duration: Duration) -> bool {
+        self.threshold.is_some_and(|t| duration >= t)
+    }
+
+    /// Record the command if it is slow, the oldest entry is dropped when the log is full.
+    pub(crate) fn record(
+        &self,
+        logger: &Logger,
+        request: &[Vec<u8>],
+        duration: Duration,
+        addr: &str,
+        name: &str,
+    ) {
+        if !self.is_slow(duration) || self.max_len == 0 {
+            return;
+        }
+        let entry = SlowLogEntry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |t| t.as_secs()),
+            duration,
+            args: truncate_args(request),
+            addr: addr.to_string(),
+            name: name.to_string(),
+        };
+        let args = entry
+            .args
+            .iter()
+            .map(|arg| String::from_utf8_lossy(arg))
+            .collect::<Vec<_>>()
+            .join(" ");
+        warn!(logger, "slow command";
+            "slowlog_id" => entry.id,
+            "latency_us" => entry.duration.as_micros() as u64,
+            "args" => args,
+        );
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_len {
+            entries.pop_back();
+        }
+        entries.push_front(entry);
+    }
+
+    /// the newest entries first
+    pub fn get(&self, count: usize) -> Vec<Reply> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .take(count)
+            .map(SlowLogEntry::to_reply)
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn reset(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+impl SlowLogEntry {
+    /// the same layout as a redis slowlog entry
+    fn to_reply(&self) -> Reply {
+        Reply::Array(vec![
+            Reply::Integer(self.id as i64),
+            Reply::Integer(self.timestamp as i64),
+            Reply::Integer(self.duration.as_micros() as i64),
+            Reply::Array(self.args.iter().cloned().map(Reply::Bulk).collect()),
+            Reply::Bulk(self.addr.clone().into_bytes()),
+            Reply::Bulk(self.name.clone().into_bytes()),
+        ])
+    }
+}
+
+fn truncate_args(request: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let mut args: Vec<Vec<u8>> = request
+        .iter()
+        .take(MAX_ARGS)
+        .map(|arg| {
+            if arg.len() <= MAX_ARG_LEN {
+                return arg.clone();
+            }
+            let mut truncated = arg[..MAX_ARG_LEN].to_vec();
+            truncated.extend(format!("... ({} more bytes)", arg.len() - MAX_ARG_LEN).bytes());
+            truncated
+        })
+        .collect();
+    if request.len() > MAX_ARGS {
+        // the last argument is replaced by the note
+        args[MAX_ARGS - 1] =
+            format!("... ({} more arguments)", request.len() - MAX_ARGS + 1).into_bytes();
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog::Discard;
+
+    #[test]
+    fn test_record() {
+        let logger = Logger::root(Discard, o!());
+        let slowlog = SlowLog::new(Some(Duration::from_millis(10)), 2);
+        let request = vec![Vec::from("get"), Vec::from("key")];
+        slowlog.record(&logger, &request, Duration::from_millis(1), "addr", "");
+        assert!(slowlog.is_empty());
+
+        for _ in 0..3 {
+            slowlog.record(&logger, &request, Duration::from_millis(10), "addr", "name");
+        }
+        assert_eq!(2, slowlog.len());
+        let entries = slowlog.get(10);
+        match &entries[0] {
+            Reply::Array(fields) => {
+                assert_eq!(Reply::Integer(2), fields[0]);
+                assert_eq!(Reply::Integer(10000), fields[2]);
+                assert_eq!(
+                    Reply::Array(vec![
+                        Reply::Bulk(Vec::from("get")),
+                        Reply::Bulk(Vec::from("key"))
+                    ]),
+                    fields[3]
+                );
+                assert_eq!(Reply::Bulk(Vec::from("addr")), fields[4]);
+                assert_eq!(Reply::Bulk(Vec::from("name")), fields[5]);
+            }
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+        assert_eq!(1, slowlog.get(1).len());
+
+        slowlog.reset();
+        assert!(slowlog.is_empty());
+
+        let disabled = SlowLog::new(None, 2);
+        disabled.record(&logger, &request, Duration::from_secs(1), "addr", "");
+        assert!(disabled.is_empty());
+    }
+
+    #[test]
+    fn test_truncate_args() {
+        let request: Vec<Vec<u8>> = (0..40).map(|_| vec![b'a'; 200]).collect();
+        let args = truncate_args(&request);
+        assert_eq!(MAX_ARGS, args.len());
+        let mut expect = vec![b'a'; MAX_ARG_LEN];
+        expect.extend(b"... (72 more bytes)");
+        assert_eq!(expect, args[0]);
+        assert_eq!(b"... (9 more arguments)".to_vec(), args[MAX_ARGS - 1]);
+    }
+}
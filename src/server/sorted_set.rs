@@ -0,0 +1,395 @@
+use super::{key_of, Server};
+use crate::engine::parse_number;
+use crate::engine::resolve_range;
+use crate::engine::sorted_set::{RangeBound, Score, SortedSet};
+use crate::engine::value::{Change, Collection};
+use crate::engine::KvdEngine;
+use crate::model::{KvdError, KvdErrorKind, KvdResult};
+use crate::protocol::Reply;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+/// What the start and the stop of ZRANGE are.
+#[derive(Clone, Copy, PartialEq)]
+enum RangeBy {
+    Rank,
+    Score,
+    Lex,
+}
+
+impl<T: KvdEngine> Server<T> {
+    /// ZADD key [NX|XX] [GT|LT] [CH] score member [score member ...]
+    ///
+    /// Reply the number of members added, or the number of members added or
+    /// whose score is changed with CH.
+    pub(super) fn handle_zadd(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        let key = key_of(request, 4)?;
+        let (mut nx, mut xx, mut gt, mut lt, mut ch) = (false, false, false, false, false);
+        let mut args = &request[2..];
+        while let Some(arg) = args.first() {
+            match arg.to_ascii_lowercase().as_slice() {
+                b"nx" => nx = true,
+                b"xx" => xx = true,
+                b"gt" => gt = true,
+                b"lt" => lt = true,
+                b"ch" => ch = true,
+                _ => break,
+            }
+            args = &args[1..];
+        }
+        if args.is_empty()
+            || !args.len().is_multiple_of(2)
+            || (nx && (xx || gt || lt))
+            || (gt && lt)
+        {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let pairs = args
+            .chunks(2)
+            .map(|pair| Ok((parse_score(&pair[0])?, pair[1].clone())))
+            .collect::<KvdResult<Vec<_>>>()?;
+
+        let mut engine = self.engine();
+        let set = read_sorted_set(&mut *engine, key)?;
+        // the scores set by the former pairs of the request
+        let mut updates: HashMap<Vec<u8>, Score> = HashMap::new();
+        let (mut added, mut changed) = (0, 0);
+        for (score, member) in pairs {
+            let current = match updates.get(&member) {
+                Some(score) => Some(score.value()),
+                None => set.and_then(|set| set.score(&member)),
+            };
+            let skip = match current {
+                Some(current) => {
+                    nx || (gt && score.value() <= current) || (lt && score.value() >= current)
+                }
+                None => xx,
+            };
+            if skip {
+                continue;
+            }
+            match current {
+                None => added += 1,
+                Some(current) if current != score.value() => changed += 1,
+                Some(_) => {}
+            }
+            updates.insert(member, score);
+        }
+        if !updates.is_empty() {
+            engine.change(key.clone(), Change::ZAdd(updates.into_iter().collect()))?;
+        }
+        Ok(Reply::Integer(if ch { added + changed } else { added }))
+    }
+
+    /// ZREM key member [member ...], reply the number of members removed
+    pub(super) fn handle_zrem(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        let key = key_of(request, 3)?;
+        let mut engine = self.engine();
+        let mut removed = match read_sorted_set(&mut *engine, key)? {
+            Some(set) => request[2..]
+                .iter()
+                .filter(|member| set.score(member).is_some())
+                .cloned()
+                .collect::<Vec<_>>(),
+            None => return Ok(Reply::Integer(0)),
+        };
+        removed.sort();
+        removed.dedup();
+        let len = removed.len();
+        if !removed.is_empty() {
+            engine.change(key.clone(), Change::ZRem(removed))?;
+        }
+        Ok(Reply::Integer(len as i64))
+    }
+
+    /// ZSCORE key member
+    pub(super) fn handle_zscore(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        if request.len() != 3 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let mut engine = self.engine();
+        let score =
+            read_sorted_set(&mut *engine, &request[1])?.and_then(|set| set.score(&request[2]));
+        Ok(score.map_or(Reply::Nil, score_reply))
+    }
+
+    /// ZINCRBY key increment member, a missing member counts as 0
+    pub(super) fn handle_zincrby(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        if request.len() != 4 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let key = &request[1];
+        let increment = parse_score(&request[2])?;
+        let member = &request[3];
+        let mut engine = self.engine();
+        let current = read_sorted_set(&mut *engine, key)?
+            .and_then(|set| set.score(member))
+            .unwrap_or(0.0);
+        // inf - inf is the only way to get NaN
+        let score = Score::new(current + increment.value())
+            .ok_or_else(|| KvdError::from(KvdErrorKind::ScoreIsNaN))?;
+        engine.change(key.clone(), Change::ZAdd(vec![(member.clone(), score)]))?;
+        Ok(score_reply(score.value()))
+    }
+
+    /// ZCARD key
+    pub(super) fn handle_zcard(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        if request.len() != 2 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let mut engine = self.engine();
+        let len = read_sorted_set(&mut *engine, &request[1])?.map_or(0, SortedSet::len);
+        Ok(Reply::Integer(len as i64))
+    }
+
+    /// ZRANK key member, the rank is counted from the lowest score
+    pub(super) fn handle_zrank(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        if request.len() != 3 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let mut engine = self.engine();
+        let rank =
+            read_sorted_set(&mut *engine, &request[1])?.and_then(|set| set.rank(&request[2]));
+        Ok(rank.map_or(Reply::Nil, |rank| Reply::Integer(rank as i64)))
+    }
+
+    /// ZCOUNT key min max, reply the number of members with a score in the range
+    pub(super) fn handle_zcount(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        if request.len() != 4 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let (min, max) = (
+            parse_score_bound(&request[2])?,
+            parse_score_bound(&request[3])?,
+        );
+        let mut engine = self.engine();
+        let count = read_sorted_set(&mut *engine, &request[1])?
+            .map_or(0, |set| set.range_by_score(min, max).count());
+        Ok(Reply::Integer(count as i64))
+    }
+
+    /// ZREMRANGEBYSCORE key min max, reply the number of members removed
+    pub(super) fn handle_zremrangebyscore(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        if request.len() != 4 {
+            return Err(KvdError::from(KvdErrorKind::InvalidRequest));
+        }
+        let key = &request[1];
+        let (min, max) = (
+            parse_score_bound(&request[2])?,
+            parse_score_bound(&request[3])?,
+        );
+        let mut engine = self.engine();
+        let removed: Vec<_> = match read_sorted_set(&mut *engine, key)? {
+            Some(set) => set
+                .range_by_score(min, max)
+                .map(|(member, _)| member.clone())
+                .collect(),
+            None => Vec::new(),
+        };
+        let len = removed.len();
+        if !removed.is_empty() {
+            engine.change(key.clone(), Change::ZRem(removed))?;
+        }
+        Ok(Reply::Integer(len as i64))
+    }
+
+    /// ZRANGE key start stop [BYSCORE|BYLEX] [REV] [LIMIT offset count] [WITHSCORES]
+    ///
+    /// The start and the stop are ranks by default, a negative one counts
+    /// from the end. With REV the members are replied from the highest score,
+    /// and the start and the stop of BYSCORE or BYLEX are the max and the min.
+    pub(super) fn handle_zrange(&self, request: &[Vec<u8>]) -> KvdResult<Reply> {
+        key_of(request, 4)?;
+        let invalid = || KvdError::from(KvdErrorKind::InvalidRequest);
+        let (mut by, mut rev, mut limit, mut with_scores) = (RangeBy::Rank, false, None, false);
+        let mut args = request[4..].iter();
+        while let Some(arg) = args.next() {
+            match arg.to_ascii_lowercase().as_slice() {
+                b"byscore" => by = RangeBy::Score,
+                b"bylex" => by = RangeBy::Lex,
+                b"rev" => rev = true,
+                b"withscores" => with_scores = true,
+                b"limit" => {
+                    let offset = args.next().ok_or_else(invalid)?;
+                    let count = args.next().ok_or_else(invalid)?;
+                    let offset: i64 = parse_number(offset)
+                        .ok_or_else(|| KvdError::from(KvdErrorKind::NotAnInteger))?;
+                    let count: i64 = parse_number(count)
+                        .ok_or_else(|| KvdError::from(KvdErrorKind::NotAnInteger))?;
+                    // a negative count means all the members after the offset
+                    let count = usize::try_from(count).unwrap_or(usize::MAX);
+                    // nothing is replied after a negative offset
+                    limit = Some(usize::try_from(offset).map_or((0, 0), |offset| (offset, count)));
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        if (limit.is_some() && by == RangeBy::Rank) || (with_scores && by == RangeBy::Lex) {
+            return Err(invalid());
+        }
+        let (start, stop) = (&request[2], &request[3]);
+        let (min, max) = if rev { (stop, start) } else { (start, stop) };
+
+        let mut engine = self.engine();
+        let set = match read_sorted_set(&mut *engine, &request[1])? {
+            Some(set) => set,
+            None => return Ok(Reply::Array(Vec::new())),
+        };
+        let lex_bounds;
+        let members: Box<dyn DoubleEndedIterator<Item = (&Vec<u8>, f64)>> = match by {
+            RangeBy::Rank => {
+                let (start, stop) = (parse_rank(start)?, parse_rank(stop)?);
+                let range = resolve_range(set.len(), start, stop).unwrap_or(0..0);
+                limit = Some((range.start, range.len()));
+                Box::new(set.iter())
+            }
+            RangeBy::Score => {
+                Box::new(set.range_by_score(parse_score_bound(min)?, parse_score_bound(max)?))
+            }
+            RangeBy::Lex => {
+                lex_bounds = (parse_lex_bound(min)?, parse_lex_bound(max)?);
+                // nothing is above + or below -
+                if min.as_slice() == b"+" || max.as_slice() == b"-" {
+                    return Ok(Reply::Array(Vec::new()));
+                }
+                Box::new(set.range_by_lex(&lex_bounds.0, &lex_bounds.1))
+            }
+        };
+        let members = if rev {
+            Box::new(members.rev())
+        } else {
+            members
+        };
+        let (offset, count) = limit.unwrap_or((0, usize::MAX));
+        let mut replies = Vec::new();
+        for (member, score) in members.skip(offset).take(count) {
+            replies.push(Reply::Bulk(member.clone()));
+            if with_scores {
+                replies.push(score_reply(score));
+            }
+        }
+        Ok(Reply::Array(replies))
+    }
+}
+
+/// the sorted set of the key, WrongType if the key holds another type
+fn read_sorted_set<'a, T: KvdEngine>(
+    engine: &'a mut T,
+    key: &[u8],
+) -> KvdResult<Option<&'a SortedSet>> {
+    engine
+        .collection(key)?
+        .map(Collection::as_sorted_set)
+        .transpose()
+}
+
+fn score_reply(score: f64) -> Reply {
+    Reply::Bulk(score.to_string().into_bytes())
+}
+
+/// a score which is not NaN, inf and -inf are allowed
+fn parse_score(score: &[u8]) -> KvdResult<Score> {
+    parse_number(score)
+        .and_then(Score::new)
+        .ok_or_else(|| KvdError::from(KvdErrorKind::NotAFloat))
+}
+
+fn parse_rank(rank: &[u8]) -> KvdResult<i64> {
+    parse_number(rank).ok_or_else(|| KvdError::from(KvdErrorKind::NotAnInteger))
+}
+
+/// a score which is exclusive if it starts with (
+fn parse_score_bound(bound: &[u8]) -> KvdResult<RangeBound<f64>> {
+    Ok(match bound.strip_prefix(b"(") {
+        Some(score) => RangeBound::Exclusive(parse_score(score)?.value()),
+        None => RangeBound::Inclusive(parse_score(bound)?.value()),
+    })
+}
+
+/// [member is inclusive, (member is exclusive, - and + are unbounded
+fn parse_lex_bound(bound: &[u8]) -> KvdResult<RangeBound<Vec<u8>>> {
+    match bound.split_first() {
+        Some((b'[', member)) => Ok(RangeBound::Inclusive(member.to_vec())),
+        Some((b'(', member)) => Ok(RangeBound::Exclusive(member.to_vec())),
+        Some((b'-' | b'+', [])) => Ok(RangeBound::Unbounded),
+        _ => Err(KvdError::from(KvdErrorKind::InvalidLexRange)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::memory::MemoryEngine;
+    use crate::server::tests::handle_input;
+    use crate::server::Server;
+
+    #[test]
+    fn test_zadd() {
+        let server = Server::new(MemoryEngine::new(), 0).unwrap();
+        let output = handle_input(
+            &server,
+            b"zadd z 1 a 2 b 3 a\r\nzscore z a\r\nzadd z nx 5 a 4 c\r\nzadd z xx ch 5 a 5 d\r\nzadd z gt ch 1 a 6 b\r\nzadd z lt ch 1 a 7 b\r\n",
+        );
+        assert_eq!(":2\r\n$1\r\n3\r\n:1\r\n:1\r\n:1\r\n:1\r\n", output);
+        let output = handle_input(
+            &server,
+            b"zrange z 0 -1 withscores\r\nzadd z nx xx 1 a\r\nzadd z gt lt 1 a\r\nzadd z 1\r\nzadd z x a\r\nzadd z nan a\r\n",
+        );
+        assert_eq!(
+            "*6\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nc\r\n$1\r\n4\r\n$1\r\nb\r\n$1\r\n6\r\n\
+             -ERR invalid request\r\n-ERR invalid request\r\n-ERR invalid request\r\n\
+             -ERR value is not a valid float\r\n-ERR value is not a valid float\r\n",
+            output
+        );
+
+        let output = handle_input(
+            &server,
+            b"zincrby z 1.5 a\r\nzincrby z inf n\r\nzincrby z -inf n\r\nzcard z\r\nzrank z c\r\nzrank z x\r\nzrem z a x a\r\nzscore z a\r\n",
+        );
+        assert_eq!(
+            "$3\r\n2.5\r\n$3\r\ninf\r\n-ERR resulting score is not a number (NaN)\r\n:4\r\n:1\r\n$-1\r\n:1\r\n$-1\r\n",
+            output
+        );
+    }
+
+    #[test]
+    fn test_zrange() {
+        let server = Server::new(MemoryEngine::new(), 0).unwrap();
+        handle_input(
+            &server,
+            b"zadd z 1 a 2 b 3 c 4 d 5 e\r\nzadd l 0 a 0 b 0 c 0 d\r\n",
+        );
+        let output = handle_input(
+            &server,
+            b"zrange z 1 2\r\nzrange z 0 1 rev\r\nzrange z (1 3 byscore\r\nzrange z +inf (3 byscore rev limit 1 5\r\nzrange z -inf +inf byscore limit 1 2\r\n",
+        );
+        assert_eq!(
+            "*2\r\n$1\r\nb\r\n$1\r\nc\r\n*2\r\n$1\r\ne\r\n$1\r\nd\r\n*2\r\n$1\r\nb\r\n$1\r\nc\r\n\
+             *1\r\n$1\r\nd\r\n*2\r\n$1\r\nb\r\n$1\r\nc\r\n",
+            output
+        );
+        let output = handle_input(
+            &server,
+            b"zrange l [b + bylex\r\nzrange l (d - bylex rev limit 0 2\r\nzrange l + - bylex\r\nzrange l b c bylex\r\nzrange l - + bylex withscores\r\nzrange z 0 1 limit 0 1\r\n",
+        );
+        assert_eq!(
+            "*3\r\n$1\r\nb\r\n$1\r\nc\r\n$1\r\nd\r\n*2\r\n$1\r\nc\r\n$1\r\nb\r\n*0\r\n\
+             -ERR min or max not valid string range item\r\n-ERR invalid request\r\n-ERR invalid request\r\n",
+            output
+        );
+
+        let output = handle_input(
+            &server,
+            b"zcount z (1 3\r\nzcount z -inf +inf\r\nzremrangebyscore z 2 (4\r\nzrange z 0 -1\r\nzremrangebyscore z -inf inf\r\nexists z\r\n",
+        );
+        assert_eq!(
+            ":2\r\n:5\r\n:2\r\n*3\r\n$1\r\na\r\n$1\r\nd\r\n$1\r\ne\r\n:3\r\n:0\r\n",
+            output
+        );
+
+        let output = handle_input(&server, b"sadd s a\r\nzrange s 0 1\r\ntype l\r\n");
+        assert_eq!(
+            ":1\r\n-WRONGTYPE Operation against a key holding the wrong kind of value\r\n+zset\r\n",
+            output
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use crate::metrics::{write_header, write_sample, Histogram};
+use crate::model::KvdErrorKind;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The counters shown in the stats section of INFO and exported as metrics.
+pub struct ServerStats {
+    started_at: Instant,
+    connections_received: AtomicU64,
+    commands_processed: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    /// the latency of every command, which also counts its calls
+    commands: Mutex<BTreeMap<String, Histogram>>,
+    errors: Mutex<BTreeMap<String, u64>>,
+}
+
+impl ServerStats {
+    pub fn new() -> ServerStats {
+        ServerStats {
+            started_at: Instant::now(),
+            connections_received: AtomicU64::new(0),
+            commands_processed: AtomicU64::new(0),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            commands: Mutex::new(BTreeMap::new()),
+            errors: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    pub(crate) fn record_connection(&self) {
+        self.connections_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// the cmd should be a known command, so that the number of counters is bounded
+    pub(crate) fn record_command(&self, cmd: &str, duration: Duration) {
+        self.commands_processed.fetch_add(1, Ordering::Relaxed);
+        self.commands
+            .lock()
+            .unwrap()
+            .entry(cmd.to_string())
+            .or_default()
+            .observe(duration);
+    }
+
+    pub(crate) fn record_error(&self, kind: KvdErrorKind) {
+        *self
+            .errors
+            .lock()
+            .unwrap()
+            .entry(format!("{:?}", kind))
+            .or_insert(0) += 1;
+    }
+
+    pub(crate) fn add_bytes_read(&self, n: usize) {
+        self.bytes_read.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_bytes_written(&self, n: usize) {
+        self.bytes_written.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// the fields of the stats section
+    pub fn info(&self) -> Vec<(String, String)> {
+        let mut fields = vec![
+            (
+                "total_connections_received".to_string(),
+                self.connections_received
+                    .load(Ordering::Relaxed)
+                    .to_string(),
+            ),
+            (
+                "total_commands_processed".to_string(),
+                self.commands_processed.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                "total_net_input_bytes".to_string(),
+                self.bytes_read.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                "total_net_output_bytes".to_string(),
+                self.bytes_written.load(Ordering::Relaxed).to_string(),
+            ),
+        ];
+        for (cmd, latency) in self.commands.lock().unwrap().iter() {
+            fields.push((
+                format!("cmdstat_{}", cmd),
+                format!("calls={}", latency.count()),
+            ));
+        }
+        for (kind, count) in self.errors.lock().unwrap().iter() {
+            fields.push((format!("errorstat_{}", kind), format!("count={}", count)));
+        }
+        fields
+    }
+
+    /// write the command and error metrics
+    pub fn write_metrics(&self, out: &mut String) {
+        let commands = self.commands.lock().unwrap();
+        write_header(
+            out,
+            "kvd_commands_total",
+            "counter",
+            "Commands processed by the server.",
+        );
+        for (cmd, latency) in commands.iter() {
+            write_sample(out, "kvd_commands_total", &[("cmd", cmd)], latency.count());
+        }
+        write_header(
+            out,
+            "kvd_command_duration_seconds",
+            "histogram",
+            "Latency of executing a command.",
+        );
+        for (cmd, latency) in commands.iter() {
+            latency.write_to(out, "kvd_command_duration_seconds", &[("cmd", cmd)]);
+        }
+        write_header(
+            out,
+            "kvd_errors_total",
+            "counter",
+            "Errors replied to the clients, by kind.",
+        );
+        for (kind, count) in self.errors.lock().unwrap().iter() {
+            write_sample(out, "kvd_errors_total", &[("kind", kind)], count);
+        }
+    }
+}
+
+impl Default for ServerStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,6 +1,16 @@
 use assert_cmd::prelude::*;
+use kvd::client::{ClusterClient, Connection};
+use kvd::cluster::key_slot;
+use kvd::protocol::Reply;
 use predicates::str::contains;
-use std::process::Command;
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 #[test]
 fn test_kvd_no_args() {
@@ -25,8 +35,301 @@ fn test_kvd_create() {
         .success();
 }
 
+#[test]
+fn test_kvd_invalid_config() {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let dir = PathBuf::from(format!("/tmp/kvd_store/{}", nanos));
+    fs::create_dir_all(&dir).unwrap();
+    // a negative or too large number is rejected rather than wrapped around
+    let invalid = [
+        "server_port: 0\nmax_clients: -1",
+        "server_port: 0\nmax_request_size: -1",
+        "server_port: 70000",
+    ];
+    for (i, extra) in invalid.iter().enumerate() {
+        let config_path = dir.join(format!("kvd{}.yml", i));
+        let config = format!(
+            "wal_dir: \"{}\"\nlog_path: \"{}\"\nlog_level: \"info\"\n{}\n",
+            dir.join("wal").display(),
+            dir.join("kvd.log").display(),
+            extra
+        );
+        fs::write(&config_path, config).unwrap();
+        Command::cargo_bin("kvd")
+            .unwrap()
+            .arg(format!("--config={}", config_path.display()))
+            .assert()
+            .failure();
+    }
+}
+
 #[test]
 fn test_kvd_get_set_del() {}
 
 #[test]
 fn test_kvd_quit() {}
+
+/// A kvd process which is killed when dropped.
+struct Kvd(Child);
+
+impl Drop for Kvd {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// start kvd with the data and the log in dir, and wait until it accepts connections
+fn start_kvd(dir: &Path, port: u16, extra_config: &str) -> (Kvd, Connection) {
+    fs::create_dir_all(dir).unwrap();
+    let config_path = dir.join("kvd.yml");
+    let config = format!(
+        "wal_dir: \"{}\"\nlog_path: \"{}\"\nlog_level: \"info\"\nserver_port: {}\n{}\n",
+        dir.join("wal").display(),
+        dir.join("kvd.log").display(),
+        port,
+        extra_config
+    );
+    fs::write(&config_path, config).unwrap();
+    let child = Command::cargo_bin("kvd")
+        .unwrap()
+        .arg(format!("--config={}", config_path.display()))
+        .spawn()
+        .unwrap();
+    let kvd = Kvd(child);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        match Connection::connect(("127.0.0.1", port)) {
+            Ok(conn) => return (kvd, conn),
+            Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+            Err(e) => panic!("kvd is not started: {}", e),
+        }
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// request until the reply is the expected one
+fn wait_for(conn: &mut Connection, args: &[&[u8]], expect: Reply) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while conn.request(args).unwrap() != expect {
+        assert!(
+            Instant::now() < deadline,
+            "timed out waiting for the change"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn test_kvd_replication() {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let dir = PathBuf::from(format!("/tmp/kvd_store/{}", nanos));
+    let (leader_port, follower_port) = (free_port(), free_port());
+    let (leader, mut conn) = start_kvd(&dir.join("leader"), leader_port, "");
+    assert_eq!(Reply::ok(), conn.request(&[b"set", b"a", b"1"]).unwrap());
+
+    let replicaof = format!("replicaof: \"127.0.0.1:{}\"", leader_port);
+    let (_follower, mut reader) = start_kvd(&dir.join("follower"), follower_port, &replicaof);
+    wait_for(&mut reader, &[b"get", b"a"], Reply::Bulk(b"1".to_vec()));
+    match reader.request(&[b"set", b"a", b"2"]).unwrap() {
+        Reply::Error(e) => assert!(e.starts_with("READONLY")),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+
+    // the follower resumes after the leader is restarted
+    drop(leader);
+    let (_leader, mut conn) = start_kvd(&dir.join("leader"), leader_port, "");
+    assert_eq!(Reply::ok(), conn.request(&[b"set", b"b", b"2"]).unwrap());
+    wait_for(&mut reader, &[b"get", b"b"], Reply::Bulk(b"2".to_vec()));
+    match reader.request(&[b"info", b"replication"]).unwrap() {
+        Reply::Bulk(info) => {
+            let info = String::from_utf8(info).unwrap();
+            assert!(info.contains("replica_offset:2\r\n"));
+            assert!(info.contains("full_syncs:1\r\n"));
+        }
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+}
+
+/// Send the write to every node until one of them replies it, return the
+/// index of that node, the leader. The other nodes redirect the write.
+fn raft_write(conns: &mut [Option<Connection>], args: &[&[u8]]) -> usize {
+    let deadline = Instant::now() + Duration::from_secs(20);
+    loop {
+        for (i, conn) in conns.iter_mut().enumerate() {
+            let conn = match conn {
+                Some(conn) => conn,
+                None => continue,
+            };
+            match conn.request(args).unwrap() {
+                Reply::Error(ref e)
+                    if e.starts_with("NOTLEADER")
+                        || e.starts_with("TRYAGAIN")
+                        || e.contains("not committed in time") => {}
+                Reply::Error(e) => panic!("unexpected error {}", e),
+                _ => return i,
+            }
+        }
+        assert!(Instant::now() < deadline, "no leader replies the write");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn test_kvd_raft() {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let dir = PathBuf::from(format!("/tmp/kvd_store/{}", nanos));
+    let ports: Vec<u16> = (0..3).map(|_| free_port()).collect();
+    let nodes: Vec<String> = ports
+        .iter()
+        .enumerate()
+        .map(|(i, port)| format!("{}@127.0.0.1:{}", i + 1, port))
+        .collect();
+    let config = |i: usize| format!("raft_id: {}\nraft_nodes: \"{}\"", i + 1, nodes.join(","));
+    let node_dir = |i: usize| dir.join(format!("node{}", i + 1));
+    let mut kvds = Vec::new();
+    let mut conns = Vec::new();
+    for (i, port) in ports.iter().enumerate() {
+        let (kvd, conn) = start_kvd(&node_dir(i), *port, &config(i));
+        kvds.push(Some(kvd));
+        conns.push(Some(conn));
+    }
+
+    let leader = raft_write(&mut conns, &[b"set", b"a", b"1"]);
+    let follower = (leader + 1) % 3;
+    wait_for(
+        conns[follower].as_mut().unwrap(),
+        &[b"get", b"a"],
+        Reply::Bulk(b"1".to_vec()),
+    );
+
+    // the other nodes elect a new leader after the leader is killed, the
+    // acknowledged write survives
+    kvds[leader] = None;
+    conns[leader] = None;
+    let new_leader = raft_write(&mut conns, &[b"set", b"b", b"2"]);
+    assert_ne!(leader, new_leader);
+    let conn = conns[new_leader].as_mut().unwrap();
+    assert_eq!(
+        Reply::Bulk(b"1".to_vec()),
+        conn.request(&[b"get", b"a"]).unwrap()
+    );
+
+    // the old leader catches up after it restarts
+    let (kvd, mut conn) = start_kvd(&node_dir(leader), ports[leader], &config(leader));
+    kvds[leader] = Some(kvd);
+    wait_for(&mut conn, &[b"get", b"b"], Reply::Bulk(b"2".to_vec()));
+    assert_eq!(
+        Reply::Bulk(b"1".to_vec()),
+        conn.request(&[b"get", b"a"]).unwrap()
+    );
+}
+
+#[test]
+fn test_kvd_migration() {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let dir = PathBuf::from(format!("/tmp/kvd_store/{}", nanos));
+    let ports = [free_port(), free_port()];
+    let addrs: Vec<String> = ports
+        .iter()
+        .map(|port| format!("127.0.0.1:{}", port))
+        .collect();
+    // the second node serves no slot until the first half is moved to it
+    let nodes = format!("1@{} 0-16383,2@{}", addrs[0], addrs[1]);
+    let config = |i: usize| format!("cluster_id: {}\ncluster_nodes: \"{}\"", i + 1, nodes);
+    let first = start_kvd(&dir.join("node1"), ports[0], &config(0));
+    let second = start_kvd(&dir.join("node2"), ports[1], &config(1));
+
+    let mut client = ClusterClient::connect(&[addrs[0].as_str()]).unwrap();
+    for i in 0..200 {
+        let key = format!("key{}", i);
+        let reply = client.request(&[b"set", key.as_bytes(), key.as_bytes()]);
+        assert_eq!(Reply::ok(), reply.unwrap());
+    }
+
+    // the keys are written and read by another client while they are moved,
+    // no write acknowledged is lost
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let stop = stop.clone();
+        let seed = addrs[0].clone();
+        thread::spawn(move || {
+            let mut client = ClusterClient::connect(&[seed.as_str()]).unwrap();
+            let mut written: usize = 0;
+            while !stop.load(Ordering::SeqCst) {
+                let key = format!("key{}", written % 200);
+                let value = written.to_string();
+                let reply = client.request(&[b"set", key.as_bytes(), value.as_bytes()]);
+                assert_eq!(Reply::ok(), reply.unwrap());
+                let reply = client.request(&[b"get", key.as_bytes()]);
+                assert_eq!(Reply::Bulk(value.into_bytes()), reply.unwrap());
+                written += 1;
+                thread::sleep(Duration::from_millis(1));
+            }
+            written
+        })
+    };
+    let moved = client.migrate_slots(0, 8191, &addrs[1]).unwrap();
+    stop.store(true, Ordering::SeqCst);
+    let written = writer.join().unwrap();
+    assert!(moved > 0);
+
+    let check = || {
+        let mut client = ClusterClient::connect(&[addrs[1].as_str()]).unwrap();
+        for i in 0..200 {
+            let key = format!("key{}", i);
+            let node = if key_slot(key.as_bytes()) < 8192 {
+                1
+            } else {
+                0
+            };
+            assert_eq!(Some(addrs[node].as_str()), client.node_of(key.as_bytes()));
+            // the last value written to the key by the writer, if any
+            let last = (written / 200) * 200 + i;
+            let expect = match last.checked_sub(if last < written { 0 } else { 200 }) {
+                Some(last) => last.to_string(),
+                None => key.clone(),
+            };
+            let value = client.request(&[b"get", key.as_bytes()]).unwrap();
+            assert_eq!(Reply::Bulk(expect.into_bytes()), value, "{} is lost", key);
+        }
+        let mut counts = Vec::new();
+        for addr in addrs.iter() {
+            let mut conn = Connection::connect(addr.as_str()).unwrap();
+            match conn.request(&[b"info", b"cluster"]).unwrap() {
+                Reply::Bulk(info) => counts.push(String::from_utf8(info).unwrap()),
+                reply => panic!("unexpected reply {:?}", reply),
+            }
+        }
+        assert!(counts[0].contains("cluster_slots_served:8192\r\n"));
+        assert!(counts[1].contains("cluster_slots_served:8192\r\n"));
+        assert!(counts[1].contains("cluster_slots_migrating:0\r\n"));
+    };
+    check();
+
+    // the moved slots are still served by the target after a restart, though
+    // the config still assigns them to the source
+    drop((first, second));
+    let _first = start_kvd(&dir.join("node1"), ports[0], &config(0));
+    let _second = start_kvd(&dir.join("node2"), ports[1], &config(1));
+    check();
+}